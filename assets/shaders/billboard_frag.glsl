@@ -0,0 +1,46 @@
+#version 450
+#pragma shader_stage(fragment)
+
+layout(location = 0) in vec2 in_uv;
+layout(location = 1) in vec4 in_color;
+layout(location = 2) in vec3 in_world_pos;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+// white for untextured quads, the glyph atlas for text
+layout(set = 2, binding = 0) uniform sampler2D quad_texture;
+
+layout(location = 0) out vec4 out_color;
+
+// same as pbr_frag.glsl
+float fog_opacity(vec3 world_pos) {
+    vec3 ray = world_pos - frame.camera_pos.xyz;
+    float distance = length(ray);
+    float extinction = frame.fog_params.x * distance;
+    float falloff = max(frame.fog_params.z, 0.0001);
+    float height_density = frame.fog_params.y * exp(-falloff * (frame.camera_pos.y - frame.fog_params.w));
+    float rise = falloff * ray.y;
+    extinction += height_density * distance * (abs(rise) > 0.0001 ? (1.0 - exp(-rise)) / rise : 1.0);
+    return (1.0 - exp(-extinction)) * frame.fog_color.a;
+}
+
+void main() {
+    vec4 color = texture(quad_texture, in_uv) * in_color;
+    out_color = vec4(mix(color.rgb, frame.fog_color.rgb, fog_opacity(in_world_pos)), color.a);
+}
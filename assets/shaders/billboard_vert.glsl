@@ -0,0 +1,67 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// one quad per instance, 6 vertices without a vertex buffer
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+// GpuQuad
+struct Quad {
+    // xyz world position, w 1 when facing the camera
+    vec4 anchor;
+    vec4 right;
+    vec4 up;
+    // xy bottom left and zw top right in meters
+    vec4 rect;
+    // xy top left and zw bottom right
+    vec4 uv_rect;
+    vec4 color;
+};
+
+layout(set = 1, binding = 0) readonly buffer Quads {
+    Quad quads[];
+} instances;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_color;
+layout(location = 2) out vec3 out_world_pos;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+    vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main() {
+    Quad quad = instances.quads[gl_InstanceIndex];
+    vec2 corner = CORNERS[gl_VertexIndex];
+
+    vec3 right = quad.right.xyz;
+    vec3 up = quad.up.xyz;
+    if (quad.anchor.w == 1.0) {
+        right = vec3(frame.view[0][0], frame.view[1][0], frame.view[2][0]);
+        up = vec3(frame.view[0][1], frame.view[1][1], frame.view[2][1]);
+    }
+    vec2 offset = mix(quad.rect.xy, quad.rect.zw, corner);
+    vec3 world_pos = quad.anchor.xyz + right * offset.x + up * offset.y;
+
+    out_uv = vec2(mix(quad.uv_rect.x, quad.uv_rect.z, corner.x), mix(quad.uv_rect.w, quad.uv_rect.y, corner.y));
+    out_color = quad.color;
+    out_world_pos = world_pos;
+    gl_Position = frame.proj * frame.view * vec4(world_pos, 1.0);
+}
@@ -0,0 +1,41 @@
+#version 450
+#pragma shader_stage(fragment)
+
+// defines: MSAA_DEPTH when the scene depth is multisampled
+
+layout(location = 0) in vec2 in_uv;
+
+layout(set = 0, binding = 0) uniform sampler2D shadow_map;
+#ifdef MSAA_DEPTH
+layout(set = 0, binding = 1) uniform sampler2DMS scene_depth;
+#else
+layout(set = 0, binding = 1) uniform sampler2D scene_depth;
+#endif
+
+// OverlayConstant
+layout(push_constant) uniform Constants {
+    uint source;
+    uint reverse_z;
+} constants;
+
+layout(location = 0) out vec4 out_color;
+
+const uint SOURCE_SHADOW_MAP = 0;
+
+void main() {
+    float depth;
+    if (constants.source == SOURCE_SHADOW_MAP) {
+        depth = textureLod(shadow_map, in_uv, 0.0).r;
+    } else {
+#ifdef MSAA_DEPTH
+        ivec2 size = textureSize(scene_depth);
+        depth = texelFetch(scene_depth, ivec2(in_uv * vec2(size)), 0).r;
+#else
+        depth = textureLod(scene_depth, in_uv, 0.0).r;
+#endif
+        if (constants.reverse_z != 0) {
+            depth = 1.0 - depth;
+        }
+    }
+    out_color = vec4(vec3(depth), 1.0);
+}
@@ -0,0 +1,67 @@
+#version 450
+#pragma shader_stage(fragment)
+
+// defines: MSAA_DEPTH when the scene depth is multisampled
+
+layout(location = 0) flat in uint in_instance;
+
+#ifdef MSAA_DEPTH
+layout(set = 1, binding = 0) uniform sampler2DMS scene_depth;
+#else
+layout(set = 1, binding = 0) uniform sampler2D scene_depth;
+#endif
+
+// GpuDecal
+struct Decal {
+    mat4 model;
+    mat4 world_to_decal;
+    vec4 color;
+    // x cosine of the angle cutoff, y edge fade
+    vec4 params;
+};
+
+layout(set = 1, binding = 1) readonly buffer Decals {
+    Decal decals[];
+} instances;
+
+layout(set = 2, binding = 0) uniform sampler2D decal_texture;
+
+// DecalConstant
+layout(push_constant) uniform Constants {
+    mat4 inv_view_proj;
+    // pixel rect of the view, xy offset and zw size
+    vec4 viewport;
+} constants;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    Decal decal = instances.decals[in_instance];
+
+    // the first sample of a multisampled depth
+    float depth = texelFetch(scene_depth, ivec2(gl_FragCoord.xy), 0).r;
+    // the view is drawn with a flipped viewport, its top row is +Y in NDC
+    vec2 uv = (gl_FragCoord.xy - constants.viewport.xy) / constants.viewport.zw;
+    vec4 world_pos = constants.inv_view_proj * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    world_pos /= world_pos.w;
+    // derivatives before anything is discarded
+    vec3 normal = normalize(cross(dFdy(world_pos.xyz), dFdx(world_pos.xyz)));
+
+    vec3 local = (decal.world_to_decal * world_pos).xyz;
+    if (any(greaterThan(abs(local), vec3(0.5)))) {
+        discard;
+    }
+    // projected along -Y, surfaces facing either way along the axis are covered
+    vec3 axis = normalize(decal.model[1].xyz);
+    if (abs(dot(normal, axis)) < decal.params.x) {
+        discard;
+    }
+
+    float fade = 1.0;
+    if (decal.params.y > 0.0) {
+        fade = clamp((0.5 - abs(local.y)) / (0.5 * decal.params.y), 0.0, 1.0);
+    }
+    // the textures have a single level, no derivatives are needed after the discards
+    vec4 color = textureLod(decal_texture, local.xz + 0.5, 0.0) * decal.color;
+    out_color = vec4(color.rgb, color.a * fade);
+}
@@ -0,0 +1,53 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// the unit box of each decal instance, 36 vertices without a vertex buffer
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+// GpuDecal
+struct Decal {
+    mat4 model;
+    mat4 world_to_decal;
+    vec4 color;
+    vec4 params;
+};
+
+layout(set = 1, binding = 1) readonly buffer Decals {
+    Decal decals[];
+} instances;
+
+layout(location = 0) flat out uint out_instance;
+
+// corners of the box as bits of x, y and z, two triangles per face
+const uint CORNERS[36] = uint[](
+    0, 2, 1, 1, 2, 3,
+    4, 5, 6, 5, 7, 6,
+    0, 1, 4, 1, 5, 4,
+    2, 6, 3, 3, 6, 7,
+    0, 4, 2, 2, 4, 6,
+    1, 3, 5, 3, 7, 5
+);
+
+void main() {
+    uint corner = CORNERS[gl_VertexIndex];
+    vec3 pos = vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1) - 0.5;
+    out_instance = gl_InstanceIndex;
+    gl_Position = frame.proj * frame.view * instances.decals[gl_InstanceIndex].model * vec4(pos, 1.0);
+}
@@ -0,0 +1,124 @@
+#version 450
+#pragma shader_stage(fragment)
+
+// shades the G-buffer written by the GBUFFER permutation of pbr_frag.glsl
+
+layout(location = 0) in vec2 in_ndc;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+layout(set = 1, binding = 0) uniform sampler2D albedo_map;
+layout(set = 1, binding = 1) uniform sampler2D normal_map;
+// metallic, roughness, occlusion and emissive strength
+layout(set = 1, binding = 2) uniform sampler2D material_map;
+layout(set = 1, binding = 3) uniform sampler2D depth_map;
+layout(set = 1, binding = 4) uniform sampler2D shadow_map;
+
+struct PointLight {
+    vec4 position_range;
+    vec4 color_intensity;
+};
+
+layout(set = 1, binding = 5) readonly buffer PointLights {
+    PointLight lights[];
+} point_lights;
+
+// LightingConstant
+layout(push_constant) uniform Constants {
+    vec4 clear_color;
+    uint light_count;
+    uint reverse_z;
+} constants;
+
+layout(location = 0) out vec4 out_color;
+
+// same as the forward shading of pbr_frag.glsl
+const float AMBIENT = 0.15;
+const float SHADOW_BIAS = 0.002;
+
+float shadow_factor(vec4 light_pos) {
+    vec3 coord = light_pos.xyz / light_pos.w;
+    vec2 uv = coord.xy * 0.5 + 0.5;
+    if (coord.z > 1.0 || any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+        return 1.0;
+    }
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0));
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            float depth = texture(shadow_map, uv + vec2(x, y) * texel).r;
+            lit += coord.z - SHADOW_BIAS <= depth ? 1.0 : 0.0;
+        }
+    }
+    return lit / 9.0;
+}
+
+// opacity of the `Fog` between the camera and `world_pos`, 0 without fog
+float fog_opacity(vec3 world_pos) {
+    vec3 ray = world_pos - frame.camera_pos.xyz;
+    float distance = length(ray);
+    float extinction = frame.fog_params.x * distance;
+    // the height density integrated along the ray
+    float falloff = max(frame.fog_params.z, 0.0001);
+    float height_density = frame.fog_params.y * exp(-falloff * (frame.camera_pos.y - frame.fog_params.w));
+    float rise = falloff * ray.y;
+    extinction += height_density * distance * (abs(rise) > 0.0001 ? (1.0 - exp(-rise)) / rise : 1.0);
+    return (1.0 - exp(-extinction)) * frame.fog_color.a;
+}
+
+vec3 shade(vec3 albedo, vec3 normal, vec3 to_light, vec3 to_camera) {
+    vec3 half_dir = normalize(to_light + to_camera);
+    float diffuse = max(dot(normal, to_light), 0.0);
+    float specular = pow(max(dot(normal, half_dir), 0.0), 32.0) * 0.25;
+    return albedo * diffuse + specular;
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    float depth = texelFetch(depth_map, texel, 0).r;
+    bool empty = constants.reverse_z != 0 ? depth <= 0.0 : depth >= 1.0;
+    if (empty) {
+        out_color = constants.clear_color;
+        return;
+    }
+
+    vec4 world_pos = inverse(frame.proj * frame.view) * vec4(in_ndc, depth, 1.0);
+    world_pos /= world_pos.w;
+
+    vec3 albedo = texelFetch(albedo_map, texel, 0).rgb;
+    vec3 normal = normalize(texelFetch(normal_map, texel, 0).xyz * 2.0 - 1.0);
+    vec4 material = texelFetch(material_map, texel, 0);
+    float occlusion = material.b;
+
+    vec3 to_camera = normalize(frame.camera_pos.xyz - world_pos.xyz);
+    float shadow = shadow_factor(frame.light_matrix * world_pos);
+    vec3 color = albedo * AMBIENT * occlusion + shade(albedo, normal, normalize(-frame.light_dir.xyz), to_camera) * shadow;
+
+    for (uint i = 0; i < constants.light_count; i++) {
+        PointLight light = point_lights.lights[i];
+        vec3 to_light = light.position_range.xyz - world_pos.xyz;
+        float distance = length(to_light);
+        float falloff = clamp(1.0 - distance / light.position_range.w, 0.0, 1.0);
+        vec3 radiance = light.color_intensity.rgb * light.color_intensity.a * falloff * falloff;
+        color += shade(albedo, normal, to_light / max(distance, 0.0001), to_camera) * radiance;
+    }
+
+    color += albedo * material.a;
+    out_color = vec4(mix(color, frame.fog_color.rgb, fog_opacity(world_pos.xyz)), 1.0);
+}
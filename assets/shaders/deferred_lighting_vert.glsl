@@ -0,0 +1,12 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// a single triangle covering the viewport, drawn with the flipped viewport of the G-buffer pass
+
+layout(location = 0) out vec2 out_ndc;
+
+void main() {
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    out_ndc = uv * 2.0 - 1.0;
+    gl_Position = vec4(out_ndc, 0.0, 1.0);
+}
@@ -0,0 +1,14 @@
+#version 450
+#pragma shader_stage(fragment)
+
+layout(location = 0) in vec2 in_uv;
+layout(location = 1) in vec4 in_color;
+
+// white for untextured rects, the image or the glyph atlas otherwise
+layout(set = 1, binding = 0) uniform sampler2D rect_texture;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = texture(rect_texture, in_uv) * in_color;
+}
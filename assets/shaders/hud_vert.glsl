@@ -0,0 +1,39 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// one rect per instance, 6 vertices without a vertex buffer, positions in pixels from the top left
+
+// GpuRect
+struct Rect {
+    // xy top left and zw bottom right in pixels
+    vec4 rect;
+    // xy top left and zw bottom right
+    vec4 uv_rect;
+    vec4 color;
+};
+
+layout(set = 0, binding = 0) readonly buffer Rects {
+    Rect rects[];
+} instances;
+
+layout(push_constant) uniform HudConstant {
+    vec2 extent;
+} constant;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_color;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+    vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main() {
+    Rect rect = instances.rects[gl_InstanceIndex];
+    vec2 corner = CORNERS[gl_VertexIndex];
+
+    vec2 position = mix(rect.rect.xy, rect.rect.zw, corner);
+    out_uv = mix(rect.uv_rect.xy, rect.uv_rect.zw, corner);
+    out_color = rect.color;
+    gl_Position = vec4(position / constant.extent * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,48 @@
+#version 450
+#pragma shader_stage(compute)
+
+// one compare and swap pass of a bitonic sort of the entries by ascending key,
+// one invocation per pair
+
+layout(local_size_x = 64) in;
+
+layout(set = 1, binding = 1) buffer SortEntries {
+    uvec2 entries[];
+};
+
+// GpuEffect, only the counts are read
+layout(set = 1, binding = 2) readonly buffer Effect {
+    vec4 color[32];
+    float size[32];
+    vec4 gravity_drag;
+    vec4 lifetime_speed;
+    vec4 shape;
+    vec4 half_extents;
+    vec4 spin_stretch;
+    vec4 collision;
+    uvec4 counts;
+} effect;
+
+// SortConstant
+layout(push_constant) uniform Constants {
+    uint block;
+    uint step;
+} constants;
+
+void main() {
+    uint pair = gl_GlobalInvocationID.x;
+    uint step = constants.step;
+    uint a = (pair / step) * step * 2 + pair % step;
+    uint b = a + step;
+    if (b >= effect.counts.y) {
+        return;
+    }
+
+    bool ascending = (a & constants.block) == 0;
+    uvec2 first = entries[a];
+    uvec2 second = entries[b];
+    if ((first.x > second.x) == ascending) {
+        entries[a] = second;
+        entries[b] = first;
+    }
+}
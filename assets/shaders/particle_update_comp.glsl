@@ -0,0 +1,246 @@
+#version 450
+#pragma shader_stage(compute)
+
+// defines: MSAA_DEPTH when the scene depth is multisampled
+//
+// Spawns into the ring buffer slots of this frame, moves the live particles and writes
+// the sort entries. One invocation per sort entry, the ones past the capacity pad the
+// entries behind the particles.
+
+layout(local_size_x = 64) in;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+// dead once the age reaches the lifetime
+struct Particle {
+    vec4 position_age;
+    vec4 velocity_lifetime;
+    float rotation;
+    float spin;
+    uint seed;
+    float padding;
+};
+
+layout(set = 1, binding = 0) buffer Particles {
+    Particle particles[];
+};
+
+// sort key and particle index, ascending keys are drawn first
+layout(set = 1, binding = 1) buffer SortEntries {
+    uvec2 entries[];
+};
+
+// GpuEffect
+layout(set = 1, binding = 2) readonly buffer Effect {
+    vec4 color[32];
+    float size[32];
+    vec4 gravity_drag;
+    vec4 lifetime_speed;
+    vec4 shape;
+    vec4 half_extents;
+    vec4 spin_stretch;
+    vec4 collision;
+    uvec4 counts;
+} effect;
+
+#ifdef MSAA_DEPTH
+layout(set = 1, binding = 3) uniform sampler2DMS scene_depth;
+#else
+layout(set = 1, binding = 3) uniform sampler2D scene_depth;
+#endif
+
+// UpdateConstant
+layout(push_constant) uniform Constants {
+    mat4 depth_view_proj;
+    vec4 depth_rect;
+    vec4 position;
+    vec4 rotation;
+    uint spawn_start;
+    uint spawn_count;
+    uint seed;
+    uint flags;
+} constants;
+
+const uint FLAG_DEPTH = 1;
+const uint FLAG_REVERSE_Z = 2;
+const uint FLAG_SORT = 4;
+const uint FLAG_RESET = 8;
+
+const float PI = 3.14159265;
+const uint DEAD_KEY = 0xffffffff;
+
+uint hash(uint x) {
+    x ^= x >> 16;
+    x *= 0x7feb352d;
+    x ^= x >> 15;
+    x *= 0x846ca68b;
+    x ^= x >> 16;
+    return x;
+}
+
+// [0, 1), advances `state`
+float random(inout uint state) {
+    state = hash(state);
+    return float(state >> 8) / 16777216.0;
+}
+
+vec3 rotate(vec4 q, vec3 v) {
+    return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+vec3 random_direction(inout uint state) {
+    float z = random(state) * 2.0 - 1.0;
+    float angle = random(state) * 2.0 * PI;
+    float r = sqrt(max(1.0 - z * z, 0.0));
+    return vec3(r * cos(angle), r * sin(angle), z);
+}
+
+Particle spawn(uint seed) {
+    uint state = seed;
+    vec3 offset = vec3(0.0);
+    vec3 direction = vec3(0.0, 1.0, 0.0);
+    uint shape = uint(effect.shape.x);
+    float radius = effect.shape.y;
+    if (shape == 0) {
+        direction = random_direction(state);
+    } else if (shape == 1) {
+        direction = random_direction(state);
+        offset = direction * radius * pow(random(state), 1.0 / 3.0);
+    } else if (shape == 2) {
+        float angle = random(state) * 2.0 * PI;
+        offset = vec3(cos(angle), 0.0, sin(angle)) * radius * sqrt(random(state));
+        float cos_cone = mix(1.0, cos(effect.shape.z), random(state));
+        float sin_cone = sqrt(max(1.0 - cos_cone * cos_cone, 0.0));
+        float around = random(state) * 2.0 * PI;
+        direction = vec3(sin_cone * cos(around), cos_cone, sin_cone * sin(around));
+    } else {
+        offset = (vec3(random(state), random(state), random(state)) * 2.0 - 1.0) * effect.half_extents.xyz;
+    }
+
+    float speed = mix(effect.lifetime_speed.z, effect.lifetime_speed.w, random(state));
+    float lifetime = mix(effect.lifetime_speed.x, effect.lifetime_speed.y, random(state));
+
+    Particle particle;
+    particle.position_age = vec4(constants.position.xyz + rotate(constants.rotation, offset), 0.0);
+    particle.velocity_lifetime = vec4(rotate(constants.rotation, direction) * speed, max(lifetime, 0.0001));
+    particle.rotation = random(state) * 2.0 * PI;
+    particle.spin = mix(effect.spin_stretch.x, effect.spin_stretch.y, random(state));
+    particle.seed = hash(state);
+    particle.padding = 0.0;
+    return particle;
+}
+
+// pixel of the scene depth the main camera wrote at `world_pos`, false outside of it
+bool depth_pixel(vec3 world_pos, out ivec2 pixel, out float depth) {
+    vec4 clip = constants.depth_view_proj * vec4(world_pos, 1.0);
+    if (clip.w <= 0.0) {
+        return false;
+    }
+    vec3 ndc = clip.xyz / clip.w;
+    // drawn with a flipped viewport, the top row is +Y in NDC
+    vec2 uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThanEqual(uv, vec2(1.0)))) {
+        return false;
+    }
+    pixel = ivec2(constants.depth_rect.xy + uv * constants.depth_rect.zw);
+    depth = ndc.z;
+    return true;
+}
+
+vec3 scene_point(ivec2 pixel) {
+    vec2 uv = (vec2(pixel) + 0.5 - constants.depth_rect.xy) / constants.depth_rect.zw;
+    float depth = texelFetch(scene_depth, pixel, 0).r;
+    vec4 pos = inverse(constants.depth_view_proj) * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return pos.xyz / pos.w;
+}
+
+void collide(inout Particle particle, vec3 previous) {
+    ivec2 pixel;
+    float depth;
+    if (!depth_pixel(particle.position_age.xyz, pixel, depth)) {
+        return;
+    }
+    float surface = texelFetch(scene_depth, pixel, 0).r;
+    bool behind = (constants.flags & FLAG_REVERSE_Z) != 0 ? depth < surface : depth > surface;
+    if (!behind) {
+        return;
+    }
+    vec3 point = scene_point(pixel);
+    if (distance(point, particle.position_age.xyz) > effect.collision.z) {
+        return;
+    }
+
+    if (effect.collision.w == 2.0) {
+        particle.velocity_lifetime.w = 0.0;
+        return;
+    }
+    vec3 normal = normalize(cross(scene_point(pixel + ivec2(0, 1)) - point, scene_point(pixel + ivec2(1, 0)) - point));
+    if (dot(normal, previous - point) < 0.0) {
+        normal = -normal;
+    }
+    vec3 velocity = particle.velocity_lifetime.xyz;
+    vec3 normal_velocity = normal * dot(velocity, normal);
+    vec3 tangent_velocity = velocity - normal_velocity;
+    particle.velocity_lifetime.xyz = tangent_velocity * (1.0 - effect.collision.y) - normal_velocity * effect.collision.x;
+    particle.position_age.xyz = previous;
+}
+
+void main() {
+    uint index = gl_GlobalInvocationID.x;
+    uint capacity = effect.counts.x;
+    if (index >= effect.counts.y) {
+        return;
+    }
+    if (index >= capacity) {
+        entries[index] = uvec2(DEAD_KEY, index);
+        return;
+    }
+
+    Particle particle = particles[index];
+    if ((constants.flags & FLAG_RESET) != 0) {
+        particle.position_age.w = 1.0;
+        particle.velocity_lifetime.w = 0.0;
+    }
+
+    uint slot = (index + capacity - constants.spawn_start) % capacity;
+    if (slot < constants.spawn_count) {
+        particle = spawn(hash(constants.seed ^ hash(index)));
+    } else if (particle.position_age.w < particle.velocity_lifetime.w) {
+        float dt = constants.position.w;
+        vec3 previous = particle.position_age.xyz;
+        vec3 velocity = particle.velocity_lifetime.xyz + effect.gravity_drag.xyz * dt;
+        velocity *= max(1.0 - effect.gravity_drag.w * dt, 0.0);
+        particle.velocity_lifetime.xyz = velocity;
+        particle.position_age += vec4(velocity * dt, dt);
+        particle.rotation += particle.spin * dt;
+        if ((constants.flags & FLAG_DEPTH) != 0 && effect.collision.w != 0.0) {
+            collide(particle, previous);
+        }
+    }
+    particles[index] = particle;
+
+    uint key = index;
+    if (particle.position_age.w >= particle.velocity_lifetime.w) {
+        key = DEAD_KEY;
+    } else if ((constants.flags & FLAG_SORT) != 0) {
+        // the bits of a positive float grow with it, inverted to draw far particles first
+        key = ~floatBitsToUint(distance(particle.position_age.xyz, frame.camera_pos.xyz));
+    }
+    entries[index] = uvec2(key, index);
+}
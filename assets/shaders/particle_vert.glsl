@@ -0,0 +1,124 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// defines: PARTICLE_BILLBOARD, PARTICLE_STRETCHED or PARTICLE_MESH, one instance per
+// sort entry up to the capacity. Dead particles are collapsed outside of the view.
+
+#ifdef PARTICLE_MESH
+layout(location = 0) in vec3 in_pos;
+layout(location = 1) in vec3 in_normal;
+layout(location = 2) in vec2 in_uv;
+#endif
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+struct Particle {
+    vec4 position_age;
+    vec4 velocity_lifetime;
+    float rotation;
+    float spin;
+    uint seed;
+    float padding;
+};
+
+layout(set = 1, binding = 0) readonly buffer Particles {
+    Particle particles[];
+};
+
+layout(set = 1, binding = 1) readonly buffer SortEntries {
+    uvec2 entries[];
+};
+
+// GpuEffect
+layout(set = 1, binding = 2) readonly buffer Effect {
+    vec4 color[32];
+    float size[32];
+    vec4 gravity_drag;
+    vec4 lifetime_speed;
+    vec4 shape;
+    vec4 half_extents;
+    vec4 spin_stretch;
+    vec4 collision;
+    uvec4 counts;
+} effect;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_color;
+layout(location = 2) out vec3 out_world_pos;
+layout(location = 3) out vec3 out_normal;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-0.5, -0.5), vec2(0.5, -0.5), vec2(0.5, 0.5),
+    vec2(-0.5, -0.5), vec2(0.5, 0.5), vec2(-0.5, 0.5)
+);
+
+void main() {
+    uint index = entries[gl_InstanceIndex].y;
+    Particle particle;
+    bool alive = index < effect.counts.x;
+    if (alive) {
+        particle = particles[index];
+        alive = particle.position_age.w < particle.velocity_lifetime.w;
+    }
+    if (!alive) {
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        return;
+    }
+
+    // the baked curves, interpolated over the normalized age
+    float t = clamp(particle.position_age.w / particle.velocity_lifetime.w, 0.0, 1.0) * 31.0;
+    uint i = min(uint(t), 30u);
+    float f = t - float(i);
+    float size = mix(effect.size[i], effect.size[i + 1], f);
+    out_color = mix(effect.color[i], effect.color[i + 1], f);
+
+    vec3 center = particle.position_age.xyz;
+    float s = sin(particle.rotation);
+    float c = cos(particle.rotation);
+#if defined(PARTICLE_MESH)
+    // turned around the Y axis
+    vec3 local = in_pos * size;
+    vec3 world_pos = center + vec3(c * local.x + s * local.z, local.y, c * local.z - s * local.x);
+    out_normal = vec3(c * in_normal.x + s * in_normal.z, in_normal.y, c * in_normal.z - s * in_normal.x);
+    out_uv = in_uv;
+#else
+    vec2 corner = CORNERS[gl_VertexIndex];
+    out_uv = vec2(corner.x, -corner.y) + 0.5;
+    vec3 right = vec3(frame.view[0][0], frame.view[1][0], frame.view[2][0]);
+    vec3 up = vec3(frame.view[0][1], frame.view[1][1], frame.view[2][1]);
+#if defined(PARTICLE_STRETCHED)
+    // along the velocity, longer by the distance covered in the length scale
+    vec3 velocity = particle.velocity_lifetime.xyz;
+    float speed = length(velocity);
+    if (speed > 0.0001) {
+        up = velocity / speed;
+        vec3 side = cross(up, frame.camera_pos.xyz - center);
+        right = length(side) > 0.0001 ? normalize(side) : right;
+    }
+    vec3 world_pos = center + right * corner.x * size + up * corner.y * (size + speed * effect.spin_stretch.z);
+#else
+    vec2 turned = vec2(c * corner.x - s * corner.y, s * corner.x + c * corner.y);
+    vec3 world_pos = center + (right * turned.x + up * turned.y) * size;
+#endif
+    out_normal = frame.camera_pos.xyz - world_pos;
+#endif
+
+    out_world_pos = world_pos;
+    gl_Position = frame.proj * frame.view * vec4(world_pos, 1.0);
+}
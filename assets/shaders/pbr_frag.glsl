@@ -0,0 +1,191 @@
+#version 450
+#pragma shader_stage(fragment)
+
+// defines: see pbr_vert.glsl, DEBUG_* select a debug view instead of the shading, GBUFFER writes
+// the surface to the G-buffer for deferred_lighting_frag.glsl, VELOCITY writes the motion vectors of
+// temporal anti-aliasing instead
+
+#ifdef VELOCITY
+layout(location = 7) in vec4 in_clip_pos;
+layout(location = 8) in vec4 in_prev_clip_pos;
+
+// NDC motion from the previous frame
+layout(location = 0) out vec2 out_velocity;
+
+void main() {
+    out_velocity = in_clip_pos.xy / in_clip_pos.w - in_prev_clip_pos.xy / in_prev_clip_pos.w;
+}
+#else
+
+layout(location = 0) in vec3 in_world_pos;
+layout(location = 1) in vec3 in_normal;
+layout(location = 2) in vec2 in_uv;
+layout(location = 3) in vec2 in_uv1;
+layout(location = 4) in vec4 in_color;
+layout(location = 5) in vec4 in_tangent;
+layout(location = 6) in vec4 in_light_pos;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+layout(set = 1, binding = 0) uniform sampler2D albedo_map;
+layout(set = 1, binding = 1) uniform sampler2D shadow_map;
+#ifdef HAS_NORMAL_MAP
+layout(set = 1, binding = 2) uniform sampler2D normal_map;
+#endif
+// sampled at the window target pixel scaled by `ao_scale`
+layout(set = 1, binding = 4) uniform sampler2D ao_map;
+
+// PrimitiveFragConstant, the metallic and roughness factors in the metallic roughness view
+layout(push_constant) uniform Constants {
+    layout(offset = 64) vec4 color_tex_tilling;
+} constants;
+
+layout(location = 0) out vec4 out_color;
+#ifdef GBUFFER
+layout(location = 1) out vec4 out_normal;
+// metallic, roughness, occlusion and emissive strength
+layout(location = 2) out vec4 out_material;
+#endif
+
+const float AMBIENT = 0.15;
+const float SHADOW_BIAS = 0.002;
+
+bool in_shadow_map(vec3 coord) {
+    vec2 uv = coord.xy * 0.5 + 0.5;
+    return coord.z <= 1.0 && all(greaterThanEqual(uv, vec2(0.0))) && all(lessThanEqual(uv, vec2(1.0)));
+}
+
+// 3x3 PCF over the shadow map, 1 is lit
+float shadow_factor(vec4 light_pos) {
+    vec3 coord = light_pos.xyz / light_pos.w;
+    if (!in_shadow_map(coord)) {
+        return 1.0;
+    }
+    vec2 uv = coord.xy * 0.5 + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0));
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            float depth = texture(shadow_map, uv + vec2(x, y) * texel).r;
+            lit += coord.z - SHADOW_BIAS <= depth ? 1.0 : 0.0;
+        }
+    }
+    return lit / 9.0;
+}
+
+// 1 is not occluded, `ao_strength` is 0 for views the map was not computed for
+float ambient_occlusion() {
+    vec2 uv = gl_FragCoord.xy * frame.ao_scale / vec2(textureSize(ao_map, 0));
+    return mix(1.0, texture(ao_map, uv).r, frame.ao_strength);
+}
+
+// opacity of the `Fog` between the camera and `world_pos`, 0 without fog
+float fog_opacity(vec3 world_pos) {
+    vec3 ray = world_pos - frame.camera_pos.xyz;
+    float distance = length(ray);
+    float extinction = frame.fog_params.x * distance;
+    // the height density integrated along the ray
+    float falloff = max(frame.fog_params.z, 0.0001);
+    float height_density = frame.fog_params.y * exp(-falloff * (frame.camera_pos.y - frame.fog_params.w));
+    float rise = falloff * ray.y;
+    extinction += height_density * distance * (abs(rise) > 0.0001 ? (1.0 - exp(-rise)) / rise : 1.0);
+    return (1.0 - exp(-extinction)) * frame.fog_color.a;
+}
+
+vec3 surface_normal() {
+    vec3 normal = normalize(in_normal);
+#ifdef HAS_NORMAL_MAP
+#ifdef NORMAL_MAP_UV1
+    vec2 uv = in_uv1;
+#else
+    vec2 uv = in_uv;
+#endif
+    vec3 tangent = normalize(in_tangent.xyz - normal * dot(normal, in_tangent.xyz));
+    vec3 bitangent = cross(normal, tangent) * in_tangent.w;
+    // Z is rebuilt, two channel maps are transcoded to BC5
+    vec3 sampled = vec3(texture(normal_map, uv).xy * 2.0 - 1.0, 0.0);
+    sampled.z = sqrt(max(1.0 - dot(sampled.xy, sampled.xy), 0.0));
+    normal = normalize(mat3(tangent, bitangent, normal) * sampled);
+#endif
+    return gl_FrontFacing ? normal : -normal;
+}
+
+void main() {
+#ifdef COLOR_MAP_UV1
+    vec2 uv = in_uv1;
+#else
+    vec2 uv = in_uv;
+#endif
+#ifdef GBUFFER
+    // debug views only write the color
+    out_normal = vec4(0.5, 1.0, 0.5, 0.0);
+    out_material = vec4(0.0, 1.0, 1.0, 0.0);
+#endif
+
+#if defined(DEBUG_METALLIC_ROUGHNESS)
+    // the metallic roughness texture is bound in place of the albedo, glTF keeps
+    // roughness in green and metallic in blue
+    vec2 factors = constants.color_tex_tilling.xy;
+    vec3 metallic_roughness = texture(albedo_map, in_uv).rgb;
+    out_color = vec4(0.0, metallic_roughness.g * factors.y, metallic_roughness.b * factors.x, 1.0);
+    return;
+#elif defined(DEBUG_UV)
+    out_color = vec4(fract(uv), 0.0, 1.0);
+    return;
+#elif defined(DEBUG_SHADOW_CASCADE)
+    // a single cascade, black outside of it
+    vec4 light_pos = in_light_pos;
+    out_color = in_shadow_map(light_pos.xyz / light_pos.w) ? vec4(1.0, 0.2, 0.2, 1.0) : vec4(0.0, 0.0, 0.0, 1.0);
+    return;
+#elif defined(DEBUG_OVERDRAW)
+    // blended additively with the depth test off
+    out_color = vec4(0.08, 0.04, 0.02, 1.0);
+    return;
+#endif
+
+    vec4 albedo = texture(albedo_map, uv * constants.color_tex_tilling.xy + constants.color_tex_tilling.zw) * in_color;
+
+    vec3 normal = surface_normal();
+#if defined(DEBUG_ALBEDO)
+    out_color = vec4(albedo.rgb, 1.0);
+    return;
+#elif defined(DEBUG_NORMALS)
+    out_color = vec4(normal * 0.5 + 0.5, 1.0);
+    return;
+#endif
+
+#ifdef GBUFFER
+    out_color = vec4(albedo.rgb, 1.0);
+    out_normal = vec4(normal * 0.5 + 0.5, 0.0);
+    out_material.b = ambient_occlusion();
+    return;
+#endif
+
+    vec3 to_light = normalize(-frame.light_dir.xyz);
+    vec3 to_camera = normalize(frame.camera_pos.xyz - in_world_pos);
+    vec3 half_dir = normalize(to_light + to_camera);
+
+    float diffuse = max(dot(normal, to_light), 0.0);
+    float specular = pow(max(dot(normal, half_dir), 0.0), 32.0) * 0.25;
+    float shadow = shadow_factor(in_light_pos);
+
+    vec3 color = albedo.rgb * (AMBIENT * ambient_occlusion() + diffuse * shadow) + specular * shadow;
+    out_color = vec4(mix(color, frame.fog_color.rgb, fog_opacity(in_world_pos)), albedo.a);
+}
+#endif
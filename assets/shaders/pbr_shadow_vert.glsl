@@ -0,0 +1,84 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// depth only from the light, defines: SKIN, MORPH_TARGETS
+
+layout(location = 0) in vec3 in_pos;
+#ifdef SKIN
+layout(location = 3) in vec4 in_weights;
+layout(location = 4) in uvec4 in_joints;
+#endif
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+} frame;
+
+// the joint matrices right after the frame data, the layout of every skinned shader is
+// reflected from here
+#ifdef SKIN
+layout(set = 1, binding = 0) uniform SkinJoints {
+    mat4 joints[512];
+} skin;
+#define MORPH_SET 2
+#else
+#define MORPH_SET 1
+#endif
+
+#ifdef MORPH_TARGETS
+// deltas[(target * attribute_count + attribute) * vertex_count + vertex]
+layout(set = MORPH_SET, binding = 0) readonly buffer MorphDeltas {
+    vec4 deltas[];
+} morph;
+#endif
+
+// ModelData and MorphConstant
+layout(push_constant) uniform Constants {
+    mat4 model;
+    vec4 morph_weights[2];
+    uvec2 morph_targets;
+    uint morph_vertex_count;
+    uint morph_attributes;
+} constants;
+
+#ifdef MORPH_TARGETS
+const uint MORPH_POSITION = 1;
+
+// positions come first in the deltas of a target
+vec3 morph_position() {
+    uint attributes = constants.morph_attributes;
+    if ((attributes & MORPH_POSITION) == 0) {
+        return vec3(0.0);
+    }
+    uint attribute_count = bitCount(attributes);
+    vec3 delta = vec3(0.0);
+    for (uint i = 0; i < 8; i++) {
+        float weight = constants.morph_weights[i / 4][i % 4];
+        if (weight == 0.0) {
+            break;
+        }
+        uint target = (constants.morph_targets[i / 4] >> ((i % 4) * 8)) & 0xff;
+        uint index = target * attribute_count * constants.morph_vertex_count + uint(gl_VertexIndex);
+        delta += weight * morph.deltas[index].xyz;
+    }
+    return delta;
+}
+#endif
+
+void main() {
+    vec3 pos = in_pos;
+#ifdef MORPH_TARGETS
+    pos += morph_position();
+#endif
+
+    mat4 model = constants.model;
+#ifdef SKIN
+    model = model * (in_weights.x * skin.joints[in_joints.x] +
+                     in_weights.y * skin.joints[in_joints.y] +
+                     in_weights.z * skin.joints[in_joints.z] +
+                     in_weights.w * skin.joints[in_joints.w]);
+#endif
+
+    gl_Position = frame.light_matrix * model * vec4(pos, 1.0);
+}
@@ -0,0 +1,189 @@
+#version 450
+#pragma shader_stage(vertex)
+
+// defines: IN_NORMAL, IN_TEX_COORD, IN_TEX_COORD1, IN_TANGENT, IN_COLOR for the vertex attributes,
+// SKIN, MORPH_TARGETS, HAS_NORMAL_MAP, COLOR_MAP_UV1, NORMAL_MAP_UV1 for the material,
+// DEPTH_PREPASS for the ambient occlusion prepass which has no fragment stage, VELOCITY for the
+// motion vectors of temporal anti-aliasing
+
+layout(location = 0) in vec3 in_pos;
+#ifdef IN_NORMAL
+layout(location = 1) in vec3 in_normal;
+#endif
+#ifdef IN_TEX_COORD
+layout(location = 2) in vec2 in_tex_coord;
+#endif
+#ifdef SKIN
+layout(location = 3) in vec4 in_weights;
+layout(location = 4) in uvec4 in_joints;
+#endif
+#ifdef IN_TANGENT
+layout(location = 5) in vec4 in_tangent;
+#endif
+#ifdef IN_COLOR
+layout(location = 6) in vec4 in_color;
+#endif
+#ifdef IN_TEX_COORD1
+layout(location = 7) in vec2 in_tex_coord1;
+#endif
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+#ifdef MORPH_TARGETS
+// deltas[(target * attribute_count + attribute) * vertex_count + vertex]
+layout(set = 1, binding = 3) readonly buffer MorphDeltas {
+    vec4 deltas[];
+} morph;
+#endif
+
+#ifdef SKIN
+layout(set = 2, binding = 0) uniform SkinJoints {
+    mat4 joints[512];
+} skin;
+#ifdef VELOCITY
+// the joint matrices of the previous frame
+layout(set = 3, binding = 0) uniform PrevSkinJoints {
+    mat4 joints[512];
+} prev_skin;
+#endif
+#endif
+
+// ModelData, then PrimitiveFragConstant read by the fragment stage and MorphConstant,
+// VelocityModelData and MorphConstant in the velocity pass
+layout(push_constant) uniform Constants {
+    mat4 model;
+#ifdef VELOCITY
+    mat4 prev_model;
+    layout(offset = 128) vec4 morph_weights[2];
+#else
+    layout(offset = 80) vec4 morph_weights[2];
+#endif
+    uvec2 morph_targets;
+    uint morph_vertex_count;
+    uint morph_attributes;
+} constants;
+
+layout(location = 0) out vec3 out_world_pos;
+layout(location = 1) out vec3 out_normal;
+layout(location = 2) out vec2 out_uv;
+layout(location = 3) out vec2 out_uv1;
+layout(location = 4) out vec4 out_color;
+layout(location = 5) out vec4 out_tangent;
+layout(location = 6) out vec4 out_light_pos;
+#ifdef VELOCITY
+// clip positions of this and the previous frame, both without the jitter
+layout(location = 7) out vec4 out_clip_pos;
+layout(location = 8) out vec4 out_prev_clip_pos;
+#endif
+
+#ifdef MORPH_TARGETS
+const uint MORPH_POSITION = 1;
+const uint MORPH_NORMAL = 2;
+const uint MORPH_TANGENT = 4;
+
+// weighted sum of the deltas of one attribute, `attribute` is the bit in `morph_attributes`
+vec3 morph_delta(uint attribute) {
+    uint attributes = constants.morph_attributes;
+    if ((attributes & attribute) == 0) {
+        return vec3(0.0);
+    }
+    uint attribute_count = bitCount(attributes);
+    uint attribute_index = bitCount(attributes & (attribute - 1));
+    vec3 delta = vec3(0.0);
+    for (uint i = 0; i < 8; i++) {
+        float weight = constants.morph_weights[i / 4][i % 4];
+        if (weight == 0.0) {
+            break;
+        }
+        uint target = (constants.morph_targets[i / 4] >> ((i % 4) * 8)) & 0xff;
+        uint index = (target * attribute_count + attribute_index) * constants.morph_vertex_count + uint(gl_VertexIndex);
+        delta += weight * morph.deltas[index].xyz;
+    }
+    return delta;
+}
+#endif
+
+void main() {
+    vec3 pos = in_pos;
+#ifdef IN_NORMAL
+    vec3 normal = in_normal;
+#else
+    vec3 normal = vec3(0.0, 1.0, 0.0);
+#endif
+#ifdef IN_TANGENT
+    vec4 tangent = in_tangent;
+#else
+    vec4 tangent = vec4(1.0, 0.0, 0.0, 1.0);
+#endif
+
+#ifdef MORPH_TARGETS
+    pos += morph_delta(MORPH_POSITION);
+    normal += morph_delta(MORPH_NORMAL);
+    tangent.xyz += morph_delta(MORPH_TANGENT);
+#endif
+
+    mat4 model = constants.model;
+#ifdef SKIN
+    model = model * (in_weights.x * skin.joints[in_joints.x] +
+                     in_weights.y * skin.joints[in_joints.y] +
+                     in_weights.z * skin.joints[in_joints.z] +
+                     in_weights.w * skin.joints[in_joints.w]);
+#endif
+
+    vec4 world_pos = model * vec4(pos, 1.0);
+    gl_Position = frame.proj * frame.view * world_pos;
+#ifdef DEPTH_PREPASS
+    return;
+#endif
+#ifdef VELOCITY
+    // morphed with the weights of this frame, only the node and joint motion is written
+    mat4 prev_model = constants.prev_model;
+#ifdef SKIN
+    prev_model = prev_model * (in_weights.x * prev_skin.joints[in_joints.x] +
+                               in_weights.y * prev_skin.joints[in_joints.y] +
+                               in_weights.z * prev_skin.joints[in_joints.z] +
+                               in_weights.w * prev_skin.joints[in_joints.w]);
+#endif
+    out_clip_pos = frame.unjittered_proj * frame.view * world_pos;
+    out_prev_clip_pos = frame.prev_view_proj * prev_model * vec4(pos, 1.0);
+#endif
+
+    mat3 normal_matrix = mat3(model);
+    out_world_pos = world_pos.xyz;
+    out_normal = normalize(normal_matrix * normal);
+    out_tangent = vec4(normalize(normal_matrix * tangent.xyz), tangent.w);
+
+#ifdef IN_TEX_COORD
+    out_uv = in_tex_coord;
+#else
+    out_uv = vec2(0.0);
+#endif
+#ifdef IN_TEX_COORD1
+    out_uv1 = in_tex_coord1;
+#else
+    out_uv1 = out_uv;
+#endif
+#ifdef IN_COLOR
+    out_color = in_color;
+#else
+    out_color = vec4(1.0);
+#endif
+
+    out_light_pos = frame.light_matrix * world_pos;
+}
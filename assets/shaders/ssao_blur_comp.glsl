@@ -0,0 +1,65 @@
+#version 450
+#pragma shader_stage(compute)
+
+// one direction of a bilateral blur of the occlusion, texels across depth edges are left out
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+layout(set = 1, binding = 0) uniform sampler2D depth_map;
+layout(set = 1, binding = 1) uniform sampler2D input_map;
+layout(set = 1, binding = 2, r32f) uniform writeonly image2D output_map;
+
+// SsaoConstant
+layout(push_constant) uniform Constants {
+    float radius;
+    uint sample_count;
+    ivec2 blur_direction;
+    uint reverse_z;
+} constants;
+
+const int BLUR_RADIUS = 4;
+
+float view_depth(ivec2 texel) {
+    float depth = texelFetch(depth_map, texel, 0).r;
+    vec4 pos = inverse(frame.proj) * vec4(0.0, 0.0, depth, 1.0);
+    return pos.z / pos.w;
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = textureSize(input_map, 0);
+    if (any(greaterThanEqual(texel, size))) {
+        return;
+    }
+
+    float center_depth = view_depth(texel);
+    float sum = 0.0;
+    float weight_sum = 0.0;
+    for (int i = -BLUR_RADIUS; i <= BLUR_RADIUS; i++) {
+        ivec2 sample_texel = clamp(texel + constants.blur_direction * i, ivec2(0), size - 1);
+        float depth_difference = abs(view_depth(sample_texel) - center_depth);
+        float weight = exp(-float(i * i) / 8.0) * (depth_difference < constants.radius ? 1.0 : 0.0);
+        sum += texelFetch(input_map, sample_texel, 0).r * weight;
+        weight_sum += weight;
+    }
+
+    imageStore(output_map, texel, vec4(sum / max(weight_sum, 0.0001)));
+}
@@ -0,0 +1,113 @@
+#version 450
+#pragma shader_stage(compute)
+
+// hemisphere occlusion from the depth prepass, the normals are rebuilt from the depth
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+layout(set = 1, binding = 0) uniform sampler2D depth_map;
+// unused by the occlusion dispatch, the blur reads the previous result from it
+layout(set = 1, binding = 1) uniform sampler2D input_map;
+layout(set = 1, binding = 2, r32f) uniform writeonly image2D output_map;
+
+// SsaoConstant
+layout(push_constant) uniform Constants {
+    float radius;
+    uint sample_count;
+    ivec2 blur_direction;
+    uint reverse_z;
+} constants;
+
+const float BIAS = 0.025;
+
+bool is_background(float depth) {
+    return constants.reverse_z != 0 ? depth <= 0.0 : depth >= 1.0;
+}
+
+// the prepass is drawn with a flipped viewport, the top row is +Y in NDC
+vec3 view_position(vec2 uv, float depth) {
+    vec4 pos = inverse(frame.proj) * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return pos.xyz / pos.w;
+}
+
+vec3 view_position_at(ivec2 texel, vec2 size) {
+    ivec2 clamped = clamp(texel, ivec2(0), ivec2(size) - 1);
+    return view_position((vec2(clamped) + 0.5) / size, texelFetch(depth_map, clamped, 0).r);
+}
+
+float hash(vec2 p) {
+    return fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+    vec2 size = vec2(textureSize(depth_map, 0));
+    if (any(greaterThanEqual(texel, ivec2(size)))) {
+        return;
+    }
+
+    float depth = texelFetch(depth_map, texel, 0).r;
+    if (is_background(depth) || constants.sample_count == 0) {
+        imageStore(output_map, texel, vec4(1.0));
+        return;
+    }
+
+    vec3 pos = view_position((vec2(texel) + 0.5) / size, depth);
+    // the smaller differences avoid the normals bending over depth edges
+    vec3 right = view_position_at(texel + ivec2(1, 0), size) - pos;
+    vec3 left = pos - view_position_at(texel - ivec2(1, 0), size);
+    vec3 down = view_position_at(texel + ivec2(0, 1), size) - pos;
+    vec3 up = pos - view_position_at(texel - ivec2(0, 1), size);
+    vec3 dx = abs(right.z) < abs(left.z) ? right : left;
+    vec3 dy = abs(down.z) < abs(up.z) ? down : up;
+    vec3 normal = normalize(cross(dy, dx));
+    if (dot(normal, pos) > 0.0) {
+        normal = -normal;
+    }
+
+    // a random rotation per texel, the blur hides the noise
+    vec3 random = normalize(vec3(hash(vec2(texel)) * 2.0 - 1.0, hash(vec2(texel) + 17.0) * 2.0 - 1.0, 0.0) + vec3(0.0, 0.0, 0.001));
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    mat3 tbn = mat3(tangent, cross(normal, tangent), normal);
+
+    float occlusion = 0.0;
+    for (uint i = 0; i < constants.sample_count; i++) {
+        float t = float(i) + 0.5;
+        vec2 seed = vec2(texel) + t * vec2(3.1, 7.7);
+        // points in the hemisphere, denser near the center
+        vec3 dir = normalize(vec3(hash(seed) * 2.0 - 1.0, hash(seed + 1.0) * 2.0 - 1.0, hash(seed + 2.0)));
+        float scale = t / float(constants.sample_count);
+        vec3 sample_pos = pos + tbn * dir * constants.radius * mix(0.1, 1.0, scale * scale);
+
+        vec4 clip = frame.proj * vec4(sample_pos, 1.0);
+        vec2 ndc = clip.xy / clip.w;
+        vec2 uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        float scene_depth = textureLod(depth_map, uv, 0.0).r;
+        if (is_background(scene_depth)) {
+            continue;
+        }
+        float scene_z = view_position(uv, scene_depth).z;
+        float range = smoothstep(0.0, 1.0, constants.radius / abs(pos.z - scene_z));
+        occlusion += (scene_z >= sample_pos.z + BIAS ? 1.0 : 0.0) * range;
+    }
+
+    imageStore(output_map, texel, vec4(1.0 - occlusion / float(constants.sample_count)));
+}
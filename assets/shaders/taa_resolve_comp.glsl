@@ -0,0 +1,77 @@
+#version 450
+#pragma shader_stage(compute)
+
+// temporal resolve of the window target, the history is reprojected along the velocity,
+// clamped to the neighborhood of the current pixel and blended with the current frame
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform PerFrameData {
+    mat4 view;
+    mat4 proj;
+    mat4 light_matrix;
+    vec4 light_dir;
+    vec4 camera_pos;
+    vec4 camera_dir;
+    float delta_time;
+    float total_time;
+    float ao_strength;
+    float ao_scale;
+    vec4 fog_color;
+    vec4 fog_params;
+    mat4 prev_view_proj;
+    vec4 jitter;
+    mat4 unjittered_proj;
+} frame;
+
+layout(set = 1, binding = 0) uniform sampler2D color_map;
+// NDC motion from the previous frame, 0 where nothing was drawn into the velocity pass
+layout(set = 1, binding = 1) uniform sampler2D velocity_map;
+layout(set = 1, binding = 2) uniform sampler2D history_map;
+layout(set = 1, binding = 3, rgba16f) uniform writeonly image2D output_map;
+
+// TaaConstant
+layout(push_constant) uniform Constants {
+    float feedback;
+    uint reset;
+} constants;
+
+void main() {
+    ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+    vec2 size = vec2(textureSize(color_map, 0));
+    if (any(greaterThanEqual(texel, ivec2(size)))) {
+        return;
+    }
+
+    vec3 color = texelFetch(color_map, texel, 0).rgb;
+    if (constants.reset != 0) {
+        imageStore(output_map, texel, vec4(color, 1.0));
+        return;
+    }
+
+    // bounds of the 3x3 neighborhood, the history is clamped into them to reject
+    // colors of surfaces that were not visible in the previous frame
+    vec3 neighborhood_min = color;
+    vec3 neighborhood_max = color;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            ivec2 neighbor = clamp(texel + ivec2(x, y), ivec2(0), ivec2(size) - 1);
+            vec3 sampled = texelFetch(color_map, neighbor, 0).rgb;
+            neighborhood_min = min(neighborhood_min, sampled);
+            neighborhood_max = max(neighborhood_max, sampled);
+        }
+    }
+
+    // the target is drawn with a flipped viewport, the top row is +Y in NDC
+    vec2 velocity = texelFetch(velocity_map, texel, 0).xy;
+    vec2 uv = (vec2(texel) + 0.5) / size;
+    vec2 history_uv = uv - vec2(velocity.x, -velocity.y) * 0.5;
+    if (any(lessThan(history_uv, vec2(0.0))) || any(greaterThan(history_uv, vec2(1.0)))) {
+        imageStore(output_map, texel, vec4(color, 1.0));
+        return;
+    }
+
+    vec3 history = textureLod(history_map, history_uv, 0.0).rgb;
+    history = clamp(history, neighborhood_min, neighborhood_max);
+    imageStore(output_map, texel, vec4(mix(color, history, constants.feedback), 1.0));
+}
@@ -1,4 +1,4 @@
-use rich_engine::{ElementState, InputSystem, MouseScrollUnit, MouseWheel, RenderInitEvent, RenderRunner, SystemParam};
+use rich_engine::{ElementState, InputSystem, MouseScrollUnit, MouseWheel, RenderInitEvent, RenderRunner, RenderTargetsRecreatedEvent, SystemParam};
 use rich_engine::prelude::*;
 
 pub use egui;
@@ -268,6 +268,10 @@ pub struct WindowResources<'a> {
 }
 
 fn init_egui_ctx(mut ctx: ResMut<EguiContext>, mut runner: Option<ResMut<RenderRunner>>, mut init_events: EventReader<RenderInitEvent>) {
+    // a second init event means the renderer was recreated, the old objects died with its device
+    if init_events.iter().count() > 0 {
+        ctx.render = None;
+    }
     if ctx.render.is_some() {
         return;
     }
@@ -511,9 +515,16 @@ pub fn process_output(
     mut runner: Option<ResMut<RenderRunner>>,
     #[cfg(feature = "manage_clipboard")] mut egui_clipboard: ResMut<EguiClipboard>,
     winit_windows: Res<WinitWindows>,
+    mut recreated_events: EventReader<RenderTargetsRecreatedEvent>,
 ) {
     if let Some(runner) = &mut runner {
         let egui_context = egui_context.deref_mut();
+        if recreated_events.iter().count() > 0 {
+            if let Some(rt) = &mut egui_context.render {
+                rt.recreate_framebuffer(&runner.context, &runner.forward_render_pass);
+            }
+        }
+
         for id in egui_context.ctx.keys().copied() {
             let (output, shapes) = egui_context.ctx_for_window(id).end_frame();
 
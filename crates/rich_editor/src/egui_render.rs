@@ -1,5 +1,6 @@
 use std::ffi::{c_void, CString};
 use std::include_bytes;
+use std::io::Cursor;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -10,7 +11,7 @@ use egui::{
     paint::ClippedShape,
     CtxRef, Key,
 };
-use rich_engine::{Buffer, ForwardRenderPass, RenderContext, Texture, prelude::*};
+use rich_engine::{Buffer, ForwardRenderPass, RenderContext, ShaderReflection, Texture, prelude::*};
 
 pub struct FontRes {
     image: Texture,
@@ -21,6 +22,8 @@ pub struct FontRes {
 pub struct EguiRender {
     physical_width: u32,
     physical_height: u32,
+    /// size of the scene target egui draws into, differs from the window when the scene is scaled
+    target_extent: vk::Extent2D,
     scale_factor: f64,
     egui_ctx: CtxRef,
 
@@ -53,18 +56,18 @@ impl EguiRender {
         context: &mut RenderContext,
         forward: &ForwardRenderPass,
     ) -> Self {
+        // Layouts come from the embedded shaders
+        let vertex_code = include_bytes!("shaders/spv/vert.spv");
+        let fragment_code = include_bytes!("shaders/spv/frag.spv");
+        let reflection = Self::reflect(&[&vertex_code[..], &fragment_code[..]]);
+        let texture_bindings = reflection.set_layout_bindings(0);
+        let push_constant_ranges = reflection.push_constant_ranges();
+
         // Create DescriptorSetLayouts
         let descriptor_set_layout = {
             unsafe {
                 context.device.create_descriptor_set_layout(
-                    &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                            .descriptor_count(1)
-                            .binding(0)
-                            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                            .build(),
-                    ]),
+                    &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&texture_bindings),
                     None,
                 )
             }.expect("Failed to create descriptor set layout.")
@@ -110,11 +113,7 @@ impl EguiRender {
             context.device.create_pipeline_layout(
                 &vk::PipelineLayoutCreateInfo::builder()
                     .set_layouts(&descriptor_set_layouts)
-                    .push_constant_ranges(&[vk::PushConstantRange::builder()
-                        .stage_flags(vk::ShaderStageFlags::VERTEX)
-                        .offset(0)
-                        .size(std::mem::size_of::<f32>() as u32 * 2) // screen size
-                        .build()]),
+                    .push_constant_ranges(&push_constant_ranges),
                 None,
             )
         }.expect("Failed to create pipeline layout.");
@@ -152,9 +151,10 @@ impl EguiRender {
                     .format(vk::Format::R8G8B8A8_UNORM)
                     .build(),
             ];
+            reflection.check_vertex_attributes(&attributes).expect("egui vertex layout does not match the shader");
 
             let vertex_shader_module = {
-                let bytes_code = include_bytes!("shaders/spv/vert.spv");
+                let bytes_code = vertex_code;
                 let shader_module_create_info = vk::ShaderModuleCreateInfo {
                     code_size: bytes_code.len(),
                     p_code: bytes_code.as_ptr() as *const u32,
@@ -164,7 +164,7 @@ impl EguiRender {
                     .expect("Failed to create vertex shader module.")
             };
             let fragment_shader_module = {
-                let bytes_code = include_bytes!("shaders/spv/frag.spv");
+                let bytes_code = fragment_code;
                 let shader_module_create_info = vk::ShaderModuleCreateInfo {
                     code_size: bytes_code.len(),
                     p_code: bytes_code.as_ptr() as *const u32,
@@ -283,20 +283,7 @@ impl EguiRender {
         }.expect("Failed to create sampler.");
 
         // Create Framebuffer
-        let target_image_view = forward.get_final_render_image_view();
-        let framebuffer = unsafe {
-            let attachments = &[target_image_view];
-            context.device
-                .create_framebuffer(
-                    &vk::FramebufferCreateInfo::builder()
-                        .render_pass(render_pass)
-                        .attachments(attachments)
-                        .width(physical_width)
-                        .height(physical_height)
-                        .layers(1),
-                    None,
-                ).expect("Failed to create framebuffer.")
-        };
+        let framebuffer = Self::create_framebuffer(context, render_pass, forward);
 
         // Create vertex buffer and index buffer
         let vertex_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::VERTEX_BUFFER, Self::vertex_buffer_size() as _);
@@ -305,14 +292,7 @@ impl EguiRender {
         // User Textures
         let user_texture_layout = unsafe {
             context.device.create_descriptor_set_layout(
-                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
-                    vk::DescriptorSetLayoutBinding::builder()
-                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                        .descriptor_count(1)
-                        .binding(0)
-                        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                        .build(),
-                ]),
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&texture_bindings),
                 None,
             )
         }.expect("Failed to create descriptor set layout.");
@@ -330,6 +310,7 @@ impl EguiRender {
         Self {
             physical_width,
             physical_height,
+            target_extent: forward.get_extent(),
             scale_factor,
             egui_ctx,
             //clipboard,
@@ -349,6 +330,43 @@ impl EguiRender {
         }
     }
 
+    /// Merged reflection of the embedded SPIR-V stages.
+    fn reflect(codes: &[&[u8]]) -> ShaderReflection {
+        let mut reflection = ShaderReflection::default();
+        for code in codes {
+            let words = rich_engine::ash::util::read_spv(&mut Cursor::new(*code)).expect("Failed to read egui spv.");
+            let stage = ShaderReflection::parse(&words).expect("Failed to reflect egui spv.");
+            reflection.merge(&stage).expect("egui shader stages do not agree");
+        }
+        reflection
+    }
+
+    fn create_framebuffer(context: &RenderContext, render_pass: vk::RenderPass, forward: &ForwardRenderPass) -> vk::Framebuffer {
+        let extent = forward.get_extent();
+        let attachments = &[forward.get_final_render_image_view()];
+        unsafe {
+            context.device
+                .create_framebuffer(
+                    &vk::FramebufferCreateInfo::builder()
+                        .render_pass(render_pass)
+                        .attachments(attachments)
+                        .width(extent.width)
+                        .height(extent.height)
+                        .layers(1),
+                    None,
+                ).expect("Failed to create framebuffer.")
+        }
+    }
+
+    /// Call after the forward render targets were recreated.
+    pub fn recreate_framebuffer(&mut self, context: &RenderContext, forward: &ForwardRenderPass) {
+        unsafe {
+            context.device.destroy_framebuffer(self.framebuffer, None);
+        }
+        self.framebuffer = Self::create_framebuffer(context, self.render_pass, forward);
+        self.target_extent = forward.get_extent();
+    }
+
     // vertex buffer size
     fn vertex_buffer_size() -> u64 {
         1024 * 1024 * 4
@@ -397,12 +415,7 @@ impl EguiRender {
                     .clear_values(&[])
                     .render_area(
                         vk::Rect2D::builder()
-                            .extent(
-                                vk::Extent2D::builder()
-                                    .width(self.physical_width)
-                                    .height(self.physical_height)
-                                    .build(),
-                            )
+                            .extent(self.target_extent)
                             .build(),
                     ),
                 vk::SubpassContents::INLINE,
@@ -434,8 +447,8 @@ impl EguiRender {
                 &[vk::Viewport::builder()
                     .x(0.0)
                     .y(0.0)
-                    .width(self.physical_width as f32)
-                    .height(self.physical_height as f32)
+                    .width(self.target_extent.width as f32)
+                    .height(self.target_extent.height as f32)
                     .min_depth(0.0)
                     .max_depth(1.0)
                     .build()],
@@ -458,6 +471,12 @@ impl EguiRender {
             );
         }
 
+        // points to target pixels
+        let target_width = self.target_extent.width as f32;
+        let target_height = self.target_extent.height as f32;
+        let scale_x = self.scale_factor as f32 * target_width / self.physical_width as f32;
+        let scale_y = self.scale_factor as f32 * target_height / self.physical_height as f32;
+
         // render meshes
         let mut vertex_base = 0;
         let mut index_base = 0;
@@ -525,21 +544,21 @@ impl EguiRender {
             unsafe {
                 let min = rect.min;
                 let min = egui::Pos2 {
-                    x: min.x * self.scale_factor as f32,
-                    y: min.y * self.scale_factor as f32,
+                    x: min.x * scale_x,
+                    y: min.y * scale_y,
                 };
                 let min = egui::Pos2 {
-                    x: f32::clamp(min.x, 0.0, self.physical_width as f32),
-                    y: f32::clamp(min.y, 0.0, self.physical_height as f32),
+                    x: f32::clamp(min.x, 0.0, target_width),
+                    y: f32::clamp(min.y, 0.0, target_height),
                 };
                 let max = rect.max;
                 let max = egui::Pos2 {
-                    x: max.x * self.scale_factor as f32,
-                    y: max.y * self.scale_factor as f32,
+                    x: max.x * scale_x,
+                    y: max.y * scale_y,
                 };
                 let max = egui::Pos2 {
-                    x: f32::clamp(max.x, min.x, self.physical_width as f32),
-                    y: f32::clamp(max.y, min.y, self.physical_height as f32),
+                    x: f32::clamp(max.x, min.x, target_width),
+                    y: f32::clamp(max.y, min.y, target_height),
                 };
                 context.device.cmd_set_scissor(
                     command_buffer,
@@ -602,7 +621,8 @@ impl EguiRender {
 
 
         // create font image
-        let image = Texture::create_from_rgba(context, command_buffer, dimensions.0 as _, dimensions.1 as _, &data);
+        let image = Texture::create_from_rgba(context, command_buffer, dimensions.0 as _, dimensions.1 as _, &data)
+            .expect("Failed to create font image.");
         let image_view = unsafe {
             context.device.create_image_view(
                 &vk::ImageViewCreateInfo::builder()
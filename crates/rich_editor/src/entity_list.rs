@@ -3,7 +3,7 @@ use egui::{Align2, Color32, Direction, Painter, Shape, Ui};
 use egui::WidgetType::Button;
 use crate::EditorState;
 use rich_engine::prelude::*;
-use rich_engine::{Camera, DisplayName, RenderCamera, RenderRunner};
+use rich_engine::{Camera, DisplayName, Picking, RenderCamera, RenderRunner};
 use crate::egui_integrate::egui::{Align, ScrollArea};
 use crate::egui_integrate::EguiContext;
 use crate::event::EditorEvent;
@@ -53,6 +53,28 @@ pub fn draw_entity_list(mut state: ResMut<EditorState>
     }
 }
 
+/// Left click in the viewport selects the model under the cursor.
+pub fn select_entity_in_viewport(mut state: ResMut<EditorState>
+                                 , egui_context: Option<Res<EguiContext>>
+                                 , mouse_button_input: Res<Input<MouseButton>>
+                                 , windows: Res<Windows>
+                                 , picking: Picking) {
+    if !mouse_button_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if let Some(ctx) = &egui_context {
+        if ctx.ctx().is_pointer_over_area() {
+            return;
+        }
+    }
+
+    if let Some(window) = windows.get_primary() {
+        if let Some(hit) = picking.pick_cursor(window) {
+            state.current_select_entity = Some(hit.entity);
+        }
+    }
+}
+
 fn draw_and_edit_float(ui: &mut Ui, data: &mut f32) -> bool {
     ui.add(egui::DragValue::new(data).speed(0.1f32)).changed()
 }
@@ -122,7 +144,7 @@ pub fn draw_entity_property(mut state: ResMut<EditorState>
                     if let Some(transform) = select_transform {
                         let render_runner = render_runner.unwrap();
                         let window_size = Vec2::new(render_runner.context.window_width as _, render_runner.context.window_height as _);
-                        let ui_pos = Camera::compute_world_2_ui_position(camera, camera_transform, transform.translation, window_size);
+                        let ui_pos = camera.world_to_viewport(camera_transform, transform.translation, window_size);
                         if ui_pos.z > 0f32 {
                             const DRAW_SIZE: f32 = 50f32;
                             let draw_rect = egui::Rect::from_min_size(to_egui_pos(ui_pos.xy() - Vec2::new(DRAW_SIZE, DRAW_SIZE)),
@@ -164,7 +186,7 @@ fn rotate_translation(transform: &Transform, delta: Vec3) -> Vec3 {
 fn compute_line(camera: &Camera, camera_transform: &Transform,
                 target_world_pos: Vec3, window_size: Vec2, base_ui_pos: Vec3, length: f32, color: egui::Color32) -> egui::Shape {
     let base_ui_pos = base_ui_pos.xy();
-    let t = Camera::compute_world_2_ui_position(camera, camera_transform, target_world_pos, window_size);
+    let t = camera.world_to_viewport(camera_transform, target_world_pos, window_size);
     let mut target = t.xy();
     target = base_ui_pos + (target - base_ui_pos).normalize() * length;
     let line = [to_egui_pos(base_ui_pos), to_egui_pos(target)];
@@ -3,6 +3,7 @@ mod egui_render;
 mod file_selector;
 mod event;
 mod entity_list;
+mod render_settings_panel;
 
 use std::cell::{Cell, RefCell};
 use rich_engine::prelude::*;
@@ -40,10 +41,20 @@ impl Plugin for EditorPlugin {
 
         app.add_system(entity_list::draw_entity_list.system());
         app.add_system(entity_list::draw_entity_property.system());
+        app.add_system(entity_list::select_entity_in_viewport.system());
+        app.add_system(render_settings_panel::draw_render_settings.system());
 
         app.add_system(process_editor_events.system());
 
         app.add_system_to_stage(CoreStage::PreUpdate, enable_fly_camera.system().after(InputSystem));
+        app.add_startup_system(watch_assets.system());
+    }
+}
+
+/// Models and particle effects edited on disk are reloaded while the editor runs.
+fn watch_assets(asset_server: Res<AssetServer>) {
+    if let Err(e) = asset_server.watch_for_changes() {
+        warn!("assets are not reloaded when they change: {}", e);
     }
 }
 
@@ -111,8 +122,6 @@ fn process(mut state: ResMut<EditorState>
                     // for i in 0..co
                     // ui.label(format!("Fps: {}", average_fps));
                 });
-
-                ui.checkbox(&mut rr.grass.enable_draw, "draw grass");
             }
         });
 
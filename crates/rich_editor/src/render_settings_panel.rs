@@ -0,0 +1,89 @@
+use egui::Align2;
+use rich_engine::prelude::*;
+use rich_engine::{AmbientOcclusion, DebugView, RenderPath, PresentMode, RenderCapabilities, RenderSettings, ShadowQuality, RENDER_SETTINGS_PATH};
+use crate::egui_integrate::EguiContext;
+
+pub fn draw_render_settings(egui_context: Option<Res<EguiContext>>, capabilities: Option<Res<RenderCapabilities>>,
+                            mut settings: ResMut<RenderSettings>) {
+    if let Some(ctx) = &egui_context {
+        // edit a copy so the resource only reports a change when a value really changed
+        let mut edit = settings.clone();
+        egui::Window::new("Render Settings").anchor(Align2::RIGHT_BOTTOM, egui::Vec2::new(0.0, 0.0)).show(ctx.ctx(), |ui| {
+            if let Some(capabilities) = &capabilities {
+                ui.label(format!("device: {}", capabilities.device_name));
+            }
+
+            egui::ComboBox::from_label("msaa")
+                .selected_text(format!("{}x", edit.msaa_samples))
+                .show_ui(ui, |ui| {
+                    for samples in [1u32, 2, 4, 8] {
+                        ui.selectable_value(&mut edit.msaa_samples, samples, format!("{}x", samples));
+                    }
+                });
+
+            egui::ComboBox::from_label("present mode")
+                .selected_text(format!("{:?}", edit.present_mode))
+                .show_ui(ui, |ui| {
+                    for mode in [PresentMode::Fifo, PresentMode::Mailbox, PresentMode::Immediate] {
+                        ui.selectable_value(&mut edit.present_mode, mode, format!("{:?}", mode));
+                    }
+                });
+
+            egui::ComboBox::from_label("shadow quality")
+                .selected_text(format!("{:?}", edit.shadow_quality))
+                .show_ui(ui, |ui| {
+                    for quality in [ShadowQuality::Off, ShadowQuality::Low, ShadowQuality::Medium, ShadowQuality::High] {
+                        ui.selectable_value(&mut edit.shadow_quality, quality, format!("{:?}", quality));
+                    }
+                });
+
+            egui::ComboBox::from_label("ambient occlusion")
+                .selected_text(format!("{:?}", edit.ambient_occlusion))
+                .show_ui(ui, |ui| {
+                    for quality in AmbientOcclusion::ALL {
+                        ui.selectable_value(&mut edit.ambient_occlusion, quality, format!("{:?}", quality));
+                    }
+                });
+
+            egui::ComboBox::from_label("render path (next start)")
+                .selected_text(format!("{:?}", edit.render_path))
+                .show_ui(ui, |ui| {
+                    for path in [RenderPath::Forward, RenderPath::Deferred] {
+                        ui.selectable_value(&mut edit.render_path, path, format!("{:?}", path));
+                    }
+                });
+
+            ui.add(egui::Slider::new(&mut edit.render_scale,
+                                     RenderSettings::MIN_RENDER_SCALE..=RenderSettings::MAX_RENDER_SCALE)
+                .text("render scale"));
+            let max_anisotropy = capabilities.as_ref().map_or(16.0, |c| c.max_sampler_anisotropy.max(1.0));
+            ui.add(egui::Slider::new(&mut edit.anisotropy, 1.0..=max_anisotropy).text("anisotropy"));
+            ui.checkbox(&mut edit.temporal_aa, "temporal anti-aliasing");
+            ui.checkbox(&mut edit.grass, "draw grass");
+            ui.checkbox(&mut edit.reverse_z, "reverse z");
+            ui.checkbox(&mut edit.debug_object_names, "debug object names");
+
+            egui::ComboBox::from_label("debug view")
+                .selected_text(format!("{:?}", edit.debug_view))
+                .show_ui(ui, |ui| {
+                    for view in DebugView::ALL {
+                        if view == DebugView::Wireframe && capabilities.as_ref().map_or(false, |c| !c.fill_mode_non_solid) {
+                            continue;
+                        }
+                        ui.selectable_value(&mut edit.debug_view, view, format!("{:?}", view));
+                    }
+                });
+            ui.checkbox(&mut edit.debug_overlay, "shadow and depth overlay");
+
+            if ui.button("save").clicked() {
+                if let Err(e) = edit.save(RENDER_SETTINGS_PATH) {
+                    error!("failed to save render settings: {}", e);
+                }
+            }
+        });
+
+        if edit != *settings {
+            *settings = edit;
+        }
+    }
+}
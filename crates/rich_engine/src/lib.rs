@@ -44,13 +44,28 @@ pub use crate::render::RenderContext;
 pub use crate::render::ForwardRenderPass;
 pub use crate::render::Buffer;
 pub use crate::render::RenderRunner;
-pub use crate::render::RenderInitEvent;
+pub use crate::render::{RenderInitEvent, RenderInitFailed, RenderError, RenderResult};
 pub use crate::render::FlyCamera;
 pub use crate::render::AnimCommands;
 pub use crate::render::AnimCommand;
+pub use crate::render::FadeCurve;
 pub use crate::render::gltf_asset_loader::GltfAsset;
 pub use crate::render::Camera;
+pub use crate::render::{CameraViewport, CameraTarget, Projection, Ray, Fog, FogColor};
+pub use crate::render::{Environment, EnvironmentMap};
 pub use crate::render::RenderCamera;
+pub use crate::render::MorphWeights;
+pub use crate::render::{Picking, PickHit};
+pub use crate::render::{RenderSettings, PresentMode, ShadowQuality, RenderTargetsRecreatedEvent, RENDER_SETTINGS_PATH};
+pub use crate::render::{DeviceSelection, DeviceType, DebugView, RenderPath, AmbientOcclusion, RenderCapabilities, RENDER_DEVICE_ENV};
+pub use crate::render::DeferredDestroy;
+pub use crate::render::{ModelLoadState, ModelUploads};
+pub use crate::render::PointLight;
+pub use crate::render::Decal;
+pub use crate::render::{ParticleEffect, ParticleEmitter, Curve, EmitterShape, ParticleRender, ParticleBlend, ParticleCollision};
+pub use crate::render::{Billboard, BillboardAlignment, HealthBar, BillboardText, GlyphAtlas};
+pub use crate::render::{ShaderReflection, ReflectedBinding, ReflectedInput};
+pub use crate::render::{HudNode, HudAnchor, HudSprite, HudPanel, HudText, HudTextAlign, HudAtlas};
 use crate::vfx::VfxPlugin;
 
 
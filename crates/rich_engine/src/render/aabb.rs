@@ -93,6 +93,20 @@ impl Aabb {
         let two = Vec3::new(2f32, 2f32, 2f32);
         self.min + (self.max - self.min) / two
     }
+
+    /// Distance along `direction` where the ray enters the AABB, 0 when the origin is inside.
+    pub fn ray_distance(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let inv_dir = direction.recip();
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+        let t_near = t0.min(t1).max_element();
+        let t_far = t0.max(t1).min_element();
+        if t_near > t_far || t_far < 0.0 {
+            None
+        } else {
+            Some(t_near.max(0.0))
+        }
+    }
 }
 
 
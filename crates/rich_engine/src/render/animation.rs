@@ -11,6 +11,7 @@ use gltf::{
 };
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use bevy::prelude::{Mat4, Quat, Vec3};
 use bevy::math::Vec4Swizzles;
 
@@ -51,6 +52,31 @@ impl Interpolate for Vec3 {
     }
 }
 
+impl Interpolate for f32 {
+    fn linear(self, other: Self, amount: f32) -> Self {
+        self + (other - self) * amount
+    }
+
+    fn cubic_spline(
+        source: [Self; 3],
+        source_time: f32,
+        target: [Self; 3],
+        target_time: f32,
+        amount: f32,
+    ) -> Self {
+        let t = amount;
+        let p0 = source[1];
+        let m0 = (target_time - source_time) * source[2];
+        let p1 = target[1];
+        let m1 = (target_time - source_time) * target[0];
+
+        (2.0 * t * t * t - 3.0 * t * t + 1.0) * p0
+            + (t * t * t - 2.0 * t * t + t) * m0
+            + (-2.0 * t * t * t + 3.0 * t * t) * p1
+            + (t * t * t - t * t) * m1
+    }
+}
+
 impl Interpolate for Quat {
     fn linear(self, other: Self, amount: f32) -> Self {
         self.slerp(other, amount)
@@ -96,22 +122,32 @@ impl<T> Sampler<T> where T: Clone {
     fn get_max_time(&self) -> f32 {
         self.times.last().copied().unwrap_or(0.0)
     }
+
+    fn find_key_index(&self, t: f32) -> Option<usize> {
+        let mut index = None;
+        for i in 0..(self.times.len().max(1) - 1) {
+            let previous = self.times[i];
+            let next = self.times[i + 1];
+            if t >= previous && t < next {
+                index = Some(i);
+                break;
+            }
+        }
+        index
+    }
 }
 
 impl<T: Interpolate> Sampler<T> {
+    /// Value of the first key frame, the reference pose of additive clips.
+    fn first_value(&self) -> Option<T> {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.values.get(1).copied(),
+            _ => self.values.first().copied(),
+        }
+    }
+
     fn sample(&self, t: f32) -> Option<T> {
-        let index = {
-            let mut index = None;
-            for i in 0..(self.times.len() - 1) {
-                let previous = self.times[i];
-                let next = self.times[i + 1];
-                if t >= previous && t < next {
-                    index = Some(i);
-                    break;
-                }
-            }
-            index
-        };
+        let index = self.find_key_index(t);
 
         index.map(|i| {
             let previous_time = self.times[i];
@@ -152,6 +188,57 @@ impl<T: Interpolate> Sampler<T> {
     }
 }
 
+impl Sampler<f32> {
+    fn first_weights(&self, target_count: usize) -> Option<Vec<f32>> {
+        let start = match self.interpolation {
+            Interpolation::CubicSpline => target_count,
+            _ => 0,
+        };
+        self.values.get(start..start + target_count).map(|w| w.to_vec())
+    }
+
+    /// Morph target weights keep `target_count` values per key frame
+    /// (three groups of them for cubic spline: in tangent, value, out tangent).
+    fn sample_weights(&self, t: f32, target_count: usize) -> Option<Vec<f32>> {
+        let index = self.find_key_index(t);
+
+        index.map(|i| {
+            let previous_time = self.times[i];
+            let next_time = self.times[i + 1];
+            let factor = (t - previous_time) / (next_time - previous_time);
+            let n = target_count;
+
+            (0..n).map(|k| {
+                match self.interpolation {
+                    Interpolation::Step => self.values[i * n + k],
+                    Interpolation::Linear => {
+                        self.values[i * n + k].linear(self.values[(i + 1) * n + k], factor)
+                    }
+                    Interpolation::CubicSpline => {
+                        let previous_values = [
+                            self.values[(i * 3) * n + k],
+                            self.values[(i * 3 + 1) * n + k],
+                            self.values[(i * 3 + 2) * n + k],
+                        ];
+                        let next_values = [
+                            self.values[(i * 3 + 3) * n + k],
+                            self.values[(i * 3 + 4) * n + k],
+                            self.values[(i * 3 + 5) * n + k],
+                        ];
+                        Interpolate::cubic_spline(
+                            previous_values,
+                            previous_time,
+                            next_values,
+                            next_time,
+                            factor,
+                        )
+                    }
+                }
+            }).collect()
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Channel<T> where T: Clone {
     sampler: Sampler<T>,
@@ -170,15 +257,34 @@ impl<T: Interpolate> Channel<T> {
     }
 }
 
+#[derive(Debug, Clone)]
+struct WeightsChannel {
+    sampler: Sampler<f32>,
+    node_index: usize,
+    target_count: usize,
+}
+
+impl WeightsChannel {
+    fn get_max_time(&self) -> f32 {
+        self.sampler.get_max_time()
+    }
+
+    fn sample(&self, t: f32) -> Option<(usize, Vec<f32>)> {
+        self.sampler.sample_weights(t, self.target_count).map(|s| (self.node_index, s))
+    }
+}
+
+/// translations, rotations, scales and morph target weights of the animated nodes
 pub struct NodesKeyFrame(
     pub Vec<(usize, Vec3)>,
     pub Vec<(usize, Quat)>,
     pub Vec<(usize, Vec3)>,
+    pub Vec<(usize, Vec<f32>)>,
 );
 
 impl NodesKeyFrame {
     pub fn is_not_empty(&self) -> bool {
-        !self.0.is_empty() || !self.1.is_empty() || !self.2.is_empty()
+        !self.0.is_empty() || !self.1.is_empty() || !self.2.is_empty() || !self.3.is_empty()
     }
 }
 
@@ -188,55 +294,287 @@ pub struct Animations {
     playback_state: PlaybackState,
 }
 
+/// How a fading weight moves from its start to its target over the fade duration.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FadeCurve {
+    Linear,
+    SmoothStep,
+    EaseIn,
+    EaseOut,
+}
+
+impl FadeCurve {
+    /// Maps the fade progress `t` in 0..1 to the blend factor.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::SmoothStep => t * t * (3.0 - 2.0 * t),
+            FadeCurve::EaseIn => t * t,
+            FadeCurve::EaseOut => t * (2.0 - t),
+        }
+    }
+}
+
+/// How a clip contributes to the pose.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BlendMode {
+    /// weighted with the other override clips, the rest pose fills missing weight
+    Override,
+    /// the difference to its first key frame is added on top of the override clips
+    Additive,
+}
+
 #[derive(Debug, Copy, Clone)]
-pub struct PlaybackState {
-    pub current: usize,
+struct Fade {
+    from: f32,
+    to: f32,
+    elapsed: f32,
+    duration: f32,
+    curve: FadeCurve,
+}
+
+/// A clip being played and its blend weight.
+#[derive(Debug, Copy, Clone)]
+pub struct ClipState {
+    pub index: usize,
     pub time: f32,
     pub total_time: f32,
+    pub playback_mode: PlaybackMode,
+    pub blend_mode: BlendMode,
+    pub weight: f32,
+    fade: Option<Fade>,
+}
+
+impl ClipState {
+    fn new(index: usize, animation: &Animation, playback_mode: PlaybackMode, blend_mode: BlendMode) -> Self {
+        Self {
+            index,
+            time: 0.0,
+            total_time: animation.total_time,
+            playback_mode,
+            blend_mode,
+            weight: 0.0,
+            fade: None,
+        }
+    }
+
+    fn advance(&mut self, delta_time: f32) {
+        if self.total_time > 0.0 {
+            self.time = match self.playback_mode {
+                PlaybackMode::Loop => (self.time + delta_time) % self.total_time,
+                PlaybackMode::Once => f32::min(self.time + delta_time, self.total_time),
+            };
+        }
+
+        if let Some(fade) = &mut self.fade {
+            fade.elapsed += delta_time;
+            let t = if fade.duration > 0.0 { fade.elapsed / fade.duration } else { 1.0 };
+            self.weight = fade.from + (fade.to - fade.from) * fade.curve.apply(t);
+            if t >= 1.0 {
+                self.fade = None;
+            }
+        }
+    }
+
+    /// Moves the weight to `weight` over `duration` seconds, at once when it is 0.
+    fn fade_to(&mut self, weight: f32, duration: f32, curve: FadeCurve) {
+        if duration <= 0.0 {
+            self.weight = weight;
+            self.fade = None;
+        } else {
+            self.fade = Some(Fade { from: self.weight, to: weight, elapsed: 0.0, duration, curve });
+        }
+    }
+
+    pub fn target_weight(&self) -> f32 {
+        self.fade.map_or(self.weight, |fade| fade.to)
+    }
+
+    fn is_faded_out(&self) -> bool {
+        self.fade.is_none() && self.weight <= 0.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaybackState {
+    /// the clips playing, in the order they were started
+    pub clips: Vec<ClipState>,
     pub paused: bool,
+    /// mode of the clips started from now on
     pub playback_mode: PlaybackMode,
+    blend: BlendBuffers,
 }
 
 impl PlaybackState {
-    fn advance(&mut self, delta_time: f32) {
-        self.time = match self.playback_mode {
-            PlaybackMode::Loop => (self.time + delta_time) % self.total_time,
-            PlaybackMode::Once => f32::min(self.time + delta_time, self.total_time),
-        };
+    /// The override clip started last, the one cross-fades go to.
+    pub fn current(&self) -> Option<&ClipState> {
+        self.clips.iter().rev().find(|c| c.blend_mode == BlendMode::Override)
     }
 
-    fn set_current(&mut self, index: usize, animation: &Animation) {
-        if self.current != index {
-            self.time = 0.0;
-            self.total_time = animation.total_time;
-            self.current = index;
+    fn clip_mut(&mut self, index: usize, blend_mode: BlendMode) -> Option<&mut ClipState> {
+        self.clips.iter_mut().find(|c| c.index == index && c.blend_mode == blend_mode)
+    }
+
+    /// The clip `index` in `blend_mode`, started at weight 0 when it is not playing.
+    fn start(&mut self, index: usize, animation: &Animation, blend_mode: BlendMode) -> &mut ClipState {
+        match self.clips.iter().position(|c| c.index == index && c.blend_mode == blend_mode) {
+            Some(position) => &mut self.clips[position],
+            None => {
+                self.clips.push(ClipState::new(index, animation, self.playback_mode, blend_mode));
+                self.clips.last_mut().unwrap()
+            }
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PlaybackMode {
     Loop,
     Once,
 }
 
+/// Weighted sum of the override clips of one node property, then its blended value.
+#[derive(Debug, Clone)]
+struct Accumulator<T> {
+    sum: T,
+    weight: f32,
+}
+
+/// Per node accumulators of `Animations::update`, kept between frames so blending
+/// does not allocate them again. Empty outside of `update`.
+#[derive(Debug, Clone, Default)]
+struct BlendBuffers {
+    translations: HashMap<usize, Accumulator<Vec3>>,
+    rotations: HashMap<usize, Accumulator<Quat>>,
+    scales: HashMap<usize, Accumulator<Vec3>>,
+    weights: HashMap<usize, Accumulator<Vec<f32>>>,
+}
+
 impl Animations {
-    pub fn update(&mut self, delta_time: f32) -> Option<NodesKeyFrame> {
+    /// Loops the first of `animations`, which must not be empty.
+    fn new(animations: Vec<Animation>) -> Self {
+        let mut first = ClipState::new(0, &animations[0], PlaybackMode::Loop, BlendMode::Override);
+        first.weight = 1.0;
+
+        Animations {
+            animations,
+            playback_state: PlaybackState {
+                clips: vec![first],
+                paused: false,
+                playback_mode: PlaybackMode::Loop,
+                blend: BlendBuffers::default(),
+            },
+        }
+    }
+
+    /// Advances the clips and blends them into the local pose of the animated nodes.
+    /// `rest_pose` gives the translation, rotation and scale of a node, used where the
+    /// override weights of its property sum to less than 1.
+    pub fn update(&mut self, delta_time: f32, rest_pose: impl Fn(usize) -> (Vec3, Quat, Vec3)) -> Option<NodesKeyFrame> {
         if self.playback_state.paused {
             return None;
         }
 
-        match self.animations.get_mut(self.playback_state.current) {
-            Some(animation) => {
-                self.playback_state.advance(delta_time);
-                let ret = animation.sample(self.playback_state.time);
-                if ret.is_not_empty() {
-                    Some(ret)
-                } else {
-                    None
+        let PlaybackState { clips, blend, .. } = &mut self.playback_state;
+        for clip in clips.iter_mut() {
+            clip.advance(delta_time);
+        }
+        clips.retain(|c| !c.is_faded_out());
+
+        let BlendBuffers { translations, rotations, scales, weights } = blend;
+        for clip in clips.iter().filter(|c| c.blend_mode == BlendMode::Override && c.weight > 0.0) {
+            let NodesKeyFrame(t, r, s, w) = self.animations[clip.index].sample(clip.time);
+            let weight = clip.weight;
+            for (node_index, translation) in t {
+                let acc = translations.entry(node_index).or_insert(Accumulator { sum: Vec3::ZERO, weight: 0.0 });
+                acc.sum += translation * weight;
+                acc.weight += weight;
+            }
+            for (node_index, rotation) in r {
+                let acc = rotations.entry(node_index).or_insert(Accumulator { sum: Quat::from_xyzw(0.0, 0.0, 0.0, 0.0), weight: 0.0 });
+                // keep every rotation in the hemisphere of the sum so they don't cancel out
+                let rotation = if acc.sum.dot(rotation) < 0.0 { -rotation } else { rotation };
+                acc.sum = acc.sum + rotation * weight;
+                acc.weight += weight;
+            }
+            for (node_index, scale) in s {
+                let acc = scales.entry(node_index).or_insert(Accumulator { sum: Vec3::ZERO, weight: 0.0 });
+                acc.sum += scale * weight;
+                acc.weight += weight;
+            }
+            for (node_index, values) in w {
+                let acc = weights.entry(node_index).or_insert(Accumulator { sum: vec![0.0; values.len()], weight: 0.0 });
+                for (sum, value) in acc.sum.iter_mut().zip(values) {
+                    *sum += value * weight;
+                }
+                acc.weight += weight;
+            }
+        }
+
+        // the sums become the blended values in place
+        for (node_index, acc) in translations.iter_mut() {
+            let rest = rest_pose(*node_index).0;
+            acc.sum = if acc.weight >= 1.0 { acc.sum / acc.weight } else { acc.sum + rest * (1.0 - acc.weight) };
+        }
+        for (node_index, acc) in rotations.iter_mut() {
+            let rest = rest_pose(*node_index).1;
+            let sum = if acc.weight >= 1.0 {
+                acc.sum
+            } else {
+                let rest = if acc.sum.dot(rest) < 0.0 { -rest } else { rest };
+                acc.sum + rest * (1.0 - acc.weight)
+            };
+            acc.sum = sum.normalize();
+        }
+        for (node_index, acc) in scales.iter_mut() {
+            let rest = rest_pose(*node_index).2;
+            acc.sum = if acc.weight >= 1.0 { acc.sum / acc.weight } else { acc.sum + rest * (1.0 - acc.weight) };
+        }
+        // morph weights have no rest pose here, only the clips animating them count
+        for acc in weights.values_mut() {
+            let weight = acc.weight;
+            acc.sum.iter_mut().for_each(|w| *w /= weight);
+        }
+
+        for clip in clips.iter().filter(|c| c.blend_mode == BlendMode::Additive && c.weight > 0.0) {
+            let NodesKeyFrame(t, r, s, w) = self.animations[clip.index].sample_relative(clip.time);
+            let weight = clip.weight;
+            for (node_index, offset) in t {
+                let base = translations.entry(node_index)
+                    .or_insert_with(|| Accumulator { sum: rest_pose(node_index).0, weight: 1.0 });
+                base.sum += offset * weight;
+            }
+            for (node_index, delta) in r {
+                let base = rotations.entry(node_index)
+                    .or_insert_with(|| Accumulator { sum: rest_pose(node_index).1, weight: 1.0 });
+                base.sum = (Quat::IDENTITY.slerp(delta, weight) * base.sum).normalize();
+            }
+            for (node_index, ratio) in s {
+                let base = scales.entry(node_index)
+                    .or_insert_with(|| Accumulator { sum: rest_pose(node_index).2, weight: 1.0 });
+                base.sum *= Vec3::ONE.lerp(ratio, weight);
+            }
+            for (node_index, offsets) in w {
+                let base = weights.entry(node_index)
+                    .or_insert_with(|| Accumulator { sum: vec![0.0; offsets.len()], weight: 1.0 });
+                for (b, offset) in base.sum.iter_mut().zip(offsets) {
+                    *b += offset * weight;
                 }
             }
-            _ => None,
+        }
+
+        // draining keeps the capacity of the buffers for the next frame
+        let ret = NodesKeyFrame(
+            translations.drain().map(|(node_index, acc)| (node_index, acc.sum)).collect(),
+            rotations.drain().map(|(node_index, acc)| (node_index, acc.sum)).collect(),
+            scales.drain().map(|(node_index, acc)| (node_index, acc.sum)).collect(),
+            weights.drain().map(|(node_index, acc)| (node_index, acc.sum)).collect(),
+        );
+        if ret.is_not_empty() {
+            Some(ret)
+        } else {
+            None
         }
     }
 
@@ -244,10 +582,57 @@ impl Animations {
         &self.playback_state
     }
 
+    /// Plays `index` alone from its start, without blending.
     pub fn set_current(&mut self, index: usize) {
-        if index < self.animations.len() {
-            if let Some(animation) = self.animations.get(index) {
-                self.playback_state.set_current(index, animation);
+        if let Some(animation) = self.animations.get(index) {
+            let state = &mut self.playback_state;
+            if state.current().map(|c| c.index) == Some(index) && state.clips.len() == 1 {
+                return;
+            }
+            state.clips.clear();
+            let clip = state.start(index, animation, BlendMode::Override);
+            clip.weight = 1.0;
+        }
+    }
+
+    /// Fades the override clips playing out and `index` in over `duration` seconds. A
+    /// clip already playing keeps its time.
+    pub fn cross_fade(&mut self, index: usize, duration: f32, curve: FadeCurve) {
+        if let Some(animation) = self.animations.get(index) {
+            let state = &mut self.playback_state;
+            for clip in state.clips.iter_mut().filter(|c| c.blend_mode == BlendMode::Override && c.index != index) {
+                clip.fade_to(0.0, duration, curve);
+            }
+            let clip = state.start(index, animation, BlendMode::Override);
+            clip.fade_to(1.0, duration, curve);
+            // the target goes last so it is the current clip
+            if let Some(position) = state.clips.iter().position(|c| c.index == index && c.blend_mode == BlendMode::Override) {
+                let clip = state.clips.remove(position);
+                state.clips.push(clip);
+            }
+        }
+    }
+
+    /// Moves the weight of the override clip `index` to `weight`, leaving the other
+    /// clips alone, to mix clips like walk and run.
+    pub fn blend(&mut self, index: usize, weight: f32, duration: f32, curve: FadeCurve) {
+        if let Some(animation) = self.animations.get(index) {
+            self.playback_state.start(index, animation, BlendMode::Override).fade_to(weight, duration, curve);
+        }
+    }
+
+    /// Plays `index` as an additive layer on top of the blended pose.
+    pub fn play_additive(&mut self, index: usize, weight: f32, duration: f32, curve: FadeCurve) {
+        if let Some(animation) = self.animations.get(index) {
+            self.playback_state.start(index, animation, BlendMode::Additive).fade_to(weight, duration, curve);
+        }
+    }
+
+    /// Fades the clip `index` out, override or additive, and removes it once at 0.
+    pub fn fade_out(&mut self, index: usize, duration: f32, curve: FadeCurve) {
+        for blend_mode in [BlendMode::Override, BlendMode::Additive].iter() {
+            if let Some(clip) = self.playback_state.clip_mut(index, *blend_mode) {
+                clip.fade_to(0.0, duration, curve);
             }
         }
     }
@@ -256,6 +641,7 @@ impl Animations {
         self.playback_state.paused = false;
     }
 
+    /// Mode of the clips started from now on.
     pub fn set_playback_mode(&mut self, playback_mode: PlaybackMode) {
         self.playback_state.playback_mode = playback_mode;
     }
@@ -270,7 +656,9 @@ impl Animations {
     }
 
     pub fn reset(&mut self) {
-        self.playback_state.time = 0.0;
+        for clip in &mut self.playback_state.clips {
+            clip.time = 0.0;
+        }
     }
 
     pub fn animations(&self) -> &[Animation] {
@@ -284,6 +672,7 @@ pub struct Animation {
     translation_channels: Vec<Channel<Vec3>>,
     rotation_channels: Vec<Channel<Quat>>,
     scale_channels: Vec<Channel<Vec3>>,
+    weights_channels: Vec<WeightsChannel>,
 }
 
 impl Animation {
@@ -291,7 +680,7 @@ impl Animation {
     ///
     /// Returns true if any nodes was updated.
     pub fn animate(&mut self, nodes: &mut Nodes, time: f32) -> bool {
-        let NodesKeyFrame(translations, rotations, scale) = self.sample(time);
+        let NodesKeyFrame(translations, rotations, scale, _weights) = self.sample(time);
         translations.iter().for_each(|(node_index, translation)| {
             nodes.nodes_mut()[*node_index].set_local_position(*translation);
         });
@@ -305,6 +694,27 @@ impl Animation {
         !translations.is_empty() || !rotations.is_empty() || !scale.is_empty()
     }
 
+    /// Change of every channel at `t` from its first key frame, for additive clips:
+    /// translation and weight offsets, rotations and scale ratios to apply.
+    fn sample_relative(&self, t: f32) -> NodesKeyFrame {
+        NodesKeyFrame(
+            self.translation_channels.iter().filter_map(|c| {
+                Some((c.node_index, c.sampler.sample(t)? - c.sampler.first_value()?))
+            }).collect(),
+            self.rotation_channels.iter().filter_map(|c| {
+                Some((c.node_index, c.sampler.sample(t)? * c.sampler.first_value()?.inverse()))
+            }).collect(),
+            self.scale_channels.iter().filter_map(|c| {
+                Some((c.node_index, c.sampler.sample(t)? / c.sampler.first_value()?.max(Vec3::splat(f32::EPSILON))))
+            }).collect(),
+            self.weights_channels.iter().filter_map(|c| {
+                let values = c.sampler.sample_weights(t, c.target_count)?;
+                let reference = c.sampler.first_weights(c.target_count)?;
+                Some((c.node_index, values.iter().zip(reference).map(|(v, r)| v - r).collect()))
+            }).collect(),
+        )
+    }
+
     fn sample(&self, t: f32) -> NodesKeyFrame {
         NodesKeyFrame(
             self.translation_channels
@@ -319,6 +729,10 @@ impl Animation {
                 .iter()
                 .filter_map(|tc| tc.sample(t))
                 .collect::<Vec<_>>(),
+            self.weights_channels
+                .iter()
+                .filter_map(|wc| wc.sample(t))
+                .collect::<Vec<_>>(),
         )
     }
 }
@@ -331,24 +745,14 @@ pub fn load_animations(gltf_animations: GltfAnimations, data: &[buffer::Data]) -
     let animations = gltf_animations
         .map(|a| map_animation(&a, data))
         .collect::<Vec<_>>();
-    let total_time = animations.get(0).map_or(0.0, |a| a.total_time);
-
-    Some(Animations {
-        animations,
-        playback_state: PlaybackState {
-            current: 0,
-            time: 0.0,
-            total_time,
-            paused: false,
-            playback_mode: PlaybackMode::Loop,
-        },
-    })
+    Some(Animations::new(animations))
 }
 
 fn map_animation(gltf_animation: &GltfAnimation, data: &[buffer::Data]) -> Animation {
     let translation_channels = map_translation_channels(gltf_animation.channels(), data);
     let rotation_channels = map_rotation_channels(gltf_animation.channels(), data);
     let scale_channels = map_scale_channels(gltf_animation.channels(), data);
+    let weights_channels = map_weights_channels(gltf_animation.channels(), data);
 
     let max_translation_time = translation_channels
         .iter()
@@ -366,7 +770,13 @@ fn map_animation(gltf_animation: &GltfAnimation, data: &[buffer::Data]) -> Anima
         .max_by(|c0, c1| c0.partial_cmp(&c1).unwrap_or(Ordering::Equal))
         .unwrap_or(0.0);
 
-    let total_time = *[max_translation_time, max_rotation_time, max_scale_time]
+    let max_weights_time = weights_channels
+        .iter()
+        .map(WeightsChannel::get_max_time)
+        .max_by(|c0, c1| c0.partial_cmp(&c1).unwrap_or(Ordering::Equal))
+        .unwrap_or(0.0);
+
+    let total_time = *[max_translation_time, max_rotation_time, max_scale_time, max_weights_time]
         .iter()
         .max_by(|c0, c1| c0.partial_cmp(&c1).unwrap_or(Ordering::Equal))
         .unwrap_or(&0.0);
@@ -376,6 +786,7 @@ fn map_animation(gltf_animation: &GltfAnimation, data: &[buffer::Data]) -> Anima
         translation_channels,
         rotation_channels,
         scale_channels,
+        weights_channels,
     }
 }
 
@@ -469,6 +880,43 @@ fn map_scale_channel(gltf_channel: &GltfChannel, data: &[buffer::Data]) -> Optio
     }
 }
 
+fn map_weights_channels(gltf_channels: Channels, data: &[buffer::Data]) -> Vec<WeightsChannel> {
+    gltf_channels
+        .filter(|c| c.target().property() == Property::MorphTargetWeights)
+        .filter_map(|c| map_weights_channel(&c, data))
+        .collect::<Vec<_>>()
+}
+
+fn map_weights_channel(gltf_channel: &GltfChannel, data: &[buffer::Data]) -> Option<WeightsChannel> {
+    let gltf_sampler = gltf_channel.sampler();
+    if let Property::MorphTargetWeights = gltf_channel.target().property() {
+        map_interpolation(gltf_sampler.interpolation()).and_then(|i| {
+            let reader = gltf_channel.reader(|buffer| Some(&data[buffer.index()]));
+            let times = read_times(&reader);
+            let output = read_weights(&reader);
+            let values_per_key = match i {
+                Interpolation::CubicSpline => times.len() * 3,
+                _ => times.len(),
+            };
+            if values_per_key == 0 || output.len() % values_per_key != 0 {
+                return None;
+            }
+
+            Some(WeightsChannel {
+                target_count: output.len() / values_per_key,
+                sampler: Sampler {
+                    interpolation: i,
+                    times,
+                    values: output,
+                },
+                node_index: gltf_channel.target().node().index(),
+            })
+        })
+    } else {
+        None
+    }
+}
+
 fn map_interpolation(gltf_interpolation: GltfInterpolation) -> Option<Interpolation> {
     match gltf_interpolation {
         GltfInterpolation::Linear => Some(Interpolation::Linear),
@@ -516,3 +964,159 @@ fn read_rotations<'a, 's, F>(reader: &Reader<'a, 's, F>) -> Vec<Quat> where F: C
             _ => vec![],
         })
 }
+
+fn read_weights<'a, 's, F>(reader: &Reader<'a, 's, F>) -> Vec<f32> where F: Clone + Fn(buffer::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    reader
+        .read_outputs()
+        .map_or(vec![], |outputs| match outputs {
+            ReadOutputs::MorphTargetWeights(weights) => weights.into_f32().collect(),
+            _ => vec![],
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear<T: Clone>(values: Vec<T>) -> Sampler<T> {
+        Sampler { interpolation: Interpolation::Linear, times: vec![0.0, 1.0], values }
+    }
+
+    fn clip(translation_channels: Vec<Channel<Vec3>>) -> Animation {
+        Animation {
+            total_time: 1.0,
+            translation_channels,
+            rotation_channels: vec![],
+            scale_channels: vec![],
+            weights_channels: vec![],
+        }
+    }
+
+    fn translation(node_index: usize, from: Vec3, to: Vec3) -> Channel<Vec3> {
+        Channel { sampler: linear(vec![from, to]), node_index }
+    }
+
+    fn rest_pose(_: usize) -> (Vec3, Quat, Vec3) {
+        (Vec3::splat(10.0), Quat::IDENTITY, Vec3::ONE)
+    }
+
+    fn translations(frame: &NodesKeyFrame) -> Vec<(usize, Vec3)> {
+        let mut translations = frame.0.clone();
+        translations.sort_by_key(|(node_index, _)| *node_index);
+        translations
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).abs().max_element() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn fade_curves() {
+        for curve in [FadeCurve::Linear, FadeCurve::SmoothStep, FadeCurve::EaseIn, FadeCurve::EaseOut].iter() {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert_eq!(curve.apply(1.0), 1.0);
+            assert_eq!(curve.apply(-1.0), 0.0);
+            assert_eq!(curve.apply(2.0), 1.0);
+        }
+        assert_eq!(FadeCurve::Linear.apply(0.5), 0.5);
+        assert_eq!(FadeCurve::SmoothStep.apply(0.5), 0.5);
+        assert_eq!(FadeCurve::SmoothStep.apply(0.25), 0.15625);
+        assert_eq!(FadeCurve::EaseIn.apply(0.5), 0.25);
+        assert_eq!(FadeCurve::EaseOut.apply(0.5), 0.75);
+    }
+
+    #[test]
+    fn sample_relative_to_the_first_key_frame() {
+        let animation = Animation {
+            total_time: 1.0,
+            translation_channels: vec![translation(0, Vec3::X, Vec3::X * 3.0)],
+            rotation_channels: vec![Channel {
+                sampler: linear(vec![Quat::from_rotation_y(0.2), Quat::from_rotation_y(1.2)]),
+                node_index: 1,
+            }],
+            scale_channels: vec![Channel { sampler: linear(vec![Vec3::splat(2.0), Vec3::splat(4.0)]), node_index: 2 }],
+            weights_channels: vec![WeightsChannel { sampler: linear(vec![0.0, 1.0, 1.0, 0.0]), node_index: 3, target_count: 2 }],
+        };
+
+        let NodesKeyFrame(t, r, s, w) = animation.sample_relative(0.5);
+        assert_eq!(t, vec![(0, Vec3::X)]);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].0, 1);
+        assert!(r[0].1.abs_diff_eq(Quat::from_rotation_y(0.5), 1e-5), "{:?}", r[0].1);
+        assert_eq!(s, vec![(2, Vec3::splat(1.5))]);
+        assert_eq!(w, vec![(3, vec![0.5, -0.5])]);
+
+        // nothing changed yet at the first key frame
+        let NodesKeyFrame(t, r, s, w) = animation.sample_relative(0.0);
+        assert_eq!(t, vec![(0, Vec3::ZERO)]);
+        assert!(r[0].1.abs_diff_eq(Quat::IDENTITY, 1e-5));
+        assert_eq!(s, vec![(2, Vec3::ONE)]);
+        assert_eq!(w, vec![(3, vec![0.0, 0.0])]);
+    }
+
+    #[test]
+    fn update_loops_the_current_clip() {
+        let mut animations = Animations::new(vec![clip(vec![translation(0, Vec3::ZERO, Vec3::X * 2.0)])]);
+
+        let frame = animations.update(0.5, rest_pose).unwrap();
+        assert_eq!(translations(&frame), vec![(0, Vec3::X)]);
+        let frame = animations.update(0.75, rest_pose).unwrap();
+        assert_eq!(translations(&frame), vec![(0, Vec3::X * 0.5)]);
+
+        animations.toggle();
+        assert!(animations.update(0.5, rest_pose).is_none());
+    }
+
+    #[test]
+    fn update_fills_missing_weight_with_the_rest_pose() {
+        let mut animations = Animations::new(vec![clip(vec![translation(0, Vec3::ZERO, Vec3::X * 2.0)])]);
+        animations.blend(0, 0.25, 0.0, FadeCurve::Linear);
+
+        let frame = animations.update(0.5, rest_pose).unwrap();
+        assert_near(translations(&frame)[0].1, Vec3::X * 0.25 + Vec3::splat(10.0) * 0.75);
+    }
+
+    #[test]
+    fn update_cross_fades() {
+        let mut animations = Animations::new(vec![
+            clip(vec![translation(0, Vec3::ZERO, Vec3::X * 2.0)]),
+            clip(vec![translation(0, Vec3::Y, Vec3::Y)]),
+        ]);
+        animations.cross_fade(1, 1.0, FadeCurve::Linear);
+        assert_eq!(animations.get_playback_state().current().unwrap().index, 1);
+
+        let frame = animations.update(0.5, rest_pose).unwrap();
+        assert_near(translations(&frame)[0].1, Vec3::new(0.5, 0.5, 0.0));
+
+        // the faded out clip is dropped
+        let frame = animations.update(0.5, rest_pose).unwrap();
+        assert_near(translations(&frame)[0].1, Vec3::Y);
+        assert_eq!(animations.get_playback_state().clips.len(), 1);
+    }
+
+    #[test]
+    fn update_adds_additive_clips() {
+        let mut animations = Animations::new(vec![
+            clip(vec![translation(0, Vec3::ZERO, Vec3::X * 2.0)]),
+            clip(vec![translation(0, Vec3::Z, Vec3::Z * 3.0), translation(1, Vec3::ZERO, Vec3::Y)]),
+        ]);
+        animations.play_additive(1, 0.5, 0.0, FadeCurve::Linear);
+
+        let frame = animations.update(0.5, rest_pose).unwrap();
+        let t = translations(&frame);
+        assert_near(t[0].1, Vec3::X + Vec3::Z * 0.5);
+        // a node only the additive clip animates starts from its rest pose
+        assert_near(t[1].1, Vec3::splat(10.0) + Vec3::Y * 0.25);
+    }
+
+    #[test]
+    fn update_reuses_its_buffers() {
+        let mut animations = Animations::new(vec![clip(vec![translation(0, Vec3::ZERO, Vec3::X)])]);
+        animations.update(0.5, rest_pose).unwrap();
+
+        let blend = &animations.get_playback_state().blend;
+        assert!(blend.translations.is_empty());
+        assert!(blend.translations.capacity() > 0);
+    }
+}
@@ -1,13 +1,22 @@
 use bevy::prelude::*;
 use bevy::tasks::ComputeTaskPool;
 use crate::{Buffer, RenderContext};
-use crate::render::animation::Animations;
+use crate::render::animation::{Animations, FadeCurve};
 use crate::render::node::Nodes;
 use crate::render::skin::Skin;
 use ash::vk;
 
 pub enum AnimCommand {
+    /// snaps to the clip, dropping the ones playing
     Play { index: u32 },
+    /// fades the clips playing out and `index` in over `duration` seconds
+    CrossFade { index: u32, duration: f32, curve: FadeCurve },
+    /// fades the weight of `index` to `weight`, mixed with the clips playing
+    Blend { index: u32, weight: f32, duration: f32, curve: FadeCurve },
+    /// fades `index` in as an additive layer, relative to its first key frame
+    PlayAdditive { index: u32, weight: f32, duration: f32, curve: FadeCurve },
+    /// fades `index` out and drops it
+    FadeOut { index: u32, duration: f32, curve: FadeCurve },
     Stop,
 }
 
@@ -0,0 +1,423 @@
+use std::mem::size_of;
+use std::ops::DerefMut;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::buffer::Buffer;
+use crate::render::forward_render::ForwardRenderPass;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo};
+use crate::render::render_context::RenderContext;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_plugin::RenderCamera;
+use crate::render::render_runner::RenderRunner;
+use crate::render::texture_cache::TextureCache;
+
+/// Quads drawn per frame over all billboards, the farthest ones are dropped.
+pub const MAX_BILLBOARD_QUADS: usize = 16384;
+
+/// vertices of a quad, generated by the vertex shader
+const QUAD_VERTEX_COUNT: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BillboardAlignment {
+    /// faces the camera with the camera up
+    Screen,
+    /// lies in the XY plane of the entity rotation
+    World,
+}
+
+/// A textured quad following the entity.
+#[derive(Debug, Clone)]
+pub struct Billboard {
+    /// image file relative to `assets/`, white when empty
+    pub texture: String,
+    /// width and height in meters
+    pub size: Vec2,
+    /// from the entity position in world space, unaffected by its rotation
+    pub offset: Vec3,
+    pub color: Vec4,
+    /// part of the texture drawn, xy top left and zw bottom right in 0..1
+    pub uv_rect: Vec4,
+    pub alignment: BillboardAlignment,
+}
+
+impl Default for Billboard {
+    fn default() -> Self {
+        Self {
+            texture: String::new(),
+            size: Vec2::ONE,
+            offset: Vec3::ZERO,
+            color: Vec4::ONE,
+            uv_rect: Vec4::new(0.0, 0.0, 1.0, 1.0),
+            alignment: BillboardAlignment::Screen,
+        }
+    }
+}
+
+/// A bar facing the camera, filled from the left by `fraction`.
+#[derive(Debug, Clone)]
+pub struct HealthBar {
+    /// 0 empty to 1 full
+    pub fraction: f32,
+    /// width and height in meters
+    pub size: Vec2,
+    /// from the entity position in world space, unaffected by its rotation
+    pub offset: Vec3,
+    pub fill_color: Vec4,
+    pub background_color: Vec4,
+}
+
+impl Default for HealthBar {
+    fn default() -> Self {
+        Self {
+            fraction: 1.0,
+            size: Vec2::new(1.0, 0.12),
+            offset: Vec3::new(0.0, 2.0, 0.0),
+            fill_color: Vec4::new(0.1, 0.9, 0.2, 1.0),
+            background_color: Vec4::new(0.0, 0.0, 0.0, 0.6),
+        }
+    }
+}
+
+/// Text following the entity, drawn with the glyph atlas of a font. Lines are centered
+/// on the offset horizontally and stacked upwards from it.
+#[derive(Debug, Clone)]
+pub struct BillboardText {
+    pub text: String,
+    /// `*.font.ron` file relative to `assets/`, `DEFAULT_FONT` when empty
+    pub font: String,
+    /// line height in meters
+    pub size: f32,
+    /// from the entity position in world space, unaffected by its rotation
+    pub offset: Vec3,
+    pub color: Vec4,
+    pub alignment: BillboardAlignment,
+}
+
+impl BillboardText {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for BillboardText {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            font: String::new(),
+            size: 0.25,
+            offset: Vec3::new(0.0, 2.2, 0.0),
+            color: Vec4::ONE,
+            alignment: BillboardAlignment::Screen,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuQuad {
+    /// xyz world position, w 1 when facing the camera
+    anchor: Vec4,
+    /// world axes of the quad plane when not facing the camera
+    right: Vec4,
+    up: Vec4,
+    /// quad in the plane around the anchor in meters, xy bottom left and zw top right
+    rect: Vec4,
+    /// xy top left and zw bottom right
+    uv_rect: Vec4,
+    color: Vec4,
+}
+
+/// Consecutive quads sharing a texture, `None` is white.
+struct BillboardBatch {
+    texture: Option<String>,
+    first_instance: u32,
+    instance_count: u32,
+}
+
+/// Draws `Billboard`, `HealthBar` and `BillboardText` entities inside the scene pass
+/// of every view. They are depth tested against the scene without writing the depth,
+/// and sorted back to front for the main camera.
+pub struct BillboardRenderer {
+    pipeline: GraphicPipeline,
+    instance_set_layout: vk::DescriptorSetLayout,
+    instance_set: vk::DescriptorSet,
+    /// owned by the texture cache
+    texture_set_layout: vk::DescriptorSetLayout,
+    instance_buffer: Buffer,
+    batches: Vec<BillboardBatch>,
+}
+
+impl BillboardRenderer {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.pipeline.destroy(context);
+        self.instance_buffer.destroy(context);
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.instance_set]);
+            context.device.destroy_descriptor_set_layout(self.instance_set_layout, None);
+        }
+    }
+
+    /// `texture_set_layout` is the layout of the texture cache sets.
+    pub fn create(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                  texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<Self> {
+        let bindings = Self::instance_set_bindings();
+        let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+        let instance_set_layout = unsafe { context.device.create_descriptor_set_layout(&ci, None) }
+            .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?;
+        let pipeline = match Self::create_pipeline(context, forward_render, instance_set_layout, texture_set_layout) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe { context.device.destroy_descriptor_set_layout(instance_set_layout, None); }
+                return Err(e);
+            }
+        };
+
+        let instance_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                                           (MAX_BILLBOARD_QUADS * size_of::<GpuQuad>()) as _);
+
+        let mut renderer = Self {
+            pipeline,
+            instance_set_layout,
+            instance_set: vk::DescriptorSet::null(),
+            texture_set_layout,
+            instance_buffer,
+            batches: vec![],
+        };
+        // freeing a null set is a no-op, so a failed allocation releases everything
+        if let Err(e) = renderer.create_instance_set(context) {
+            renderer.destroy(context);
+            return Err(e);
+        }
+
+        Ok(renderer)
+    }
+
+    /// The quads read by the vertex stage, checked against the billboard shaders.
+    fn instance_set_bindings() -> [vk::DescriptorSetLayoutBinding; 1] {
+        [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .build(),
+        ]
+    }
+
+    fn create_instance_set(&mut self, context: &RenderContext) -> RenderResult<()> {
+        let layouts = [self.instance_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        self.instance_set = unsafe { context.device.allocate_descriptor_sets(&allocate_info) }
+            .map_err(RenderError::vk("vkAllocateDescriptorSets"))?[0];
+        let instance_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(self.instance_buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(self.instance_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&instance_info)
+            .build()];
+        unsafe {
+            context.device.update_descriptor_sets(&writes, &[]);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the pipeline against new forward targets or settings.
+    pub fn recreate(&mut self, context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<()> {
+        self.pipeline.destroy(context);
+        self.pipeline = Self::create_pipeline(context, forward_render, self.instance_set_layout, self.texture_set_layout)?;
+        Ok(())
+    }
+
+    fn create_pipeline(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                       instance_set_layout: vk::DescriptorSetLayout,
+                       texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<GraphicPipeline> {
+        let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+        let set_layouts = [frame_uniform_layout, instance_set_layout, texture_set_layout];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .build();
+
+        // world aligned quads are seen from both sides
+        let vertex_input = PipelineVertexInputInfo::from(&[], &[])
+            .with_cull_mode(vk::CullModeFlags::NONE)
+            .with_alpha_blend(true);
+        let instance_bindings = Self::instance_set_bindings();
+        let texture_bindings = TextureCache::set_layout_bindings();
+        context.shader_modules.check_stages(&context.device, &["billboard_vert", "billboard_frag"], &[],
+                                            &[(1, &instance_bindings[..]), (2, &texture_bindings[..])])?;
+
+        let msaa = context.render_config.msaa;
+        GraphicPipeline::create(context, forward_render.get_native_render_pass(), &vertex_input, &pipeline_layout_ci,
+                                msaa, "billboard_vert", "billboard_frag", &[])
+    }
+
+    /// Builds and sorts the quads of every billboard for the main camera at `camera_pos`,
+    /// skipping ones whose texture or font is not loaded.
+    pub fn prepare<'a>(&mut self, context: &RenderContext, textures: &TextureCache, camera_pos: Vec3,
+                       billboards: impl Iterator<Item=(&'a Billboard, &'a GlobalTransform)>,
+                       health_bars: impl Iterator<Item=(&'a HealthBar, &'a GlobalTransform)>,
+                       texts: impl Iterator<Item=(&'a BillboardText, &'a GlobalTransform)>) {
+        // quads of one billboard, drawn in order after sorting by distance
+        struct Item<'t> {
+            distance: f32,
+            texture: Option<&'t str>,
+            quads: Vec<GpuQuad>,
+        }
+        let quad = |anchor: Vec3, transform: &GlobalTransform, alignment: BillboardAlignment, rect: Vec4, uv_rect: Vec4,
+                    color: Vec4| GpuQuad {
+            anchor: Vec4::from((anchor, if alignment == BillboardAlignment::Screen { 1.0 } else { 0.0 })),
+            right: Vec4::from((transform.rotation * Vec3::X, 0.0)),
+            up: Vec4::from((transform.rotation * Vec3::Y, 0.0)),
+            rect,
+            uv_rect,
+            color,
+        };
+        let centered = |size: Vec2| Vec4::new(-size.x * 0.5, -size.y * 0.5, size.x * 0.5, size.y * 0.5);
+        let full_uv = Vec4::new(0.0, 0.0, 1.0, 1.0);
+
+        let mut items = Vec::new();
+        for (billboard, transform) in billboards {
+            let texture = if billboard.texture.is_empty() {
+                None
+            } else if textures.texture(&billboard.texture).is_some() {
+                Some(billboard.texture.as_str())
+            } else {
+                continue;
+            };
+            let anchor = transform.translation + billboard.offset;
+            items.push(Item {
+                distance: anchor.distance(camera_pos),
+                texture,
+                quads: vec![quad(anchor, transform, billboard.alignment, centered(billboard.size), billboard.uv_rect,
+                                 billboard.color)],
+            });
+        }
+
+        for (bar, transform) in health_bars {
+            let anchor = transform.translation + bar.offset;
+            let background = centered(bar.size);
+            let mut fill = background;
+            fill.z = fill.x + bar.size.x * bar.fraction.clamp(0.0, 1.0);
+            items.push(Item {
+                distance: anchor.distance(camera_pos),
+                texture: None,
+                quads: vec![
+                    quad(anchor, transform, BillboardAlignment::Screen, background, full_uv, bar.background_color),
+                    quad(anchor, transform, BillboardAlignment::Screen, fill, full_uv, bar.fill_color),
+                ],
+            });
+        }
+
+        for (text, transform) in texts {
+            let font = textures.font(&text.font);
+            let font = match font {
+                Some(font) => font,
+                None => continue,
+            };
+            let anchor = transform.translation + text.offset;
+            let quads = font.layout(&text.text, text.size, 0.5).into_iter()
+                .map(|(rect, uv_rect)| quad(anchor, transform, text.alignment, rect, uv_rect, text.color))
+                .collect::<Vec<_>>();
+            items.push(Item {
+                distance: anchor.distance(camera_pos),
+                texture: Some(font.atlas.texture.as_str()),
+                quads,
+            });
+        }
+
+        items.sort_by(|a, b| b.distance.partial_cmp(&a.distance).unwrap_or(std::cmp::Ordering::Equal));
+        // the farthest ones are dropped when over the limit
+        let mut total = 0;
+        let skip = items.iter().rev()
+            .position(|item| {
+                total += item.quads.len();
+                total > MAX_BILLBOARD_QUADS
+            })
+            .map_or(0, |index| items.len() - index);
+
+        let mut instances = Vec::new();
+        let mut batches: Vec<BillboardBatch> = Vec::new();
+        for item in items.iter().skip(skip) {
+            let first_instance = instances.len() as u32;
+            instances.extend_from_slice(&item.quads);
+            match batches.last_mut() {
+                Some(batch) if batch.texture.as_deref() == item.texture => {
+                    batch.instance_count += item.quads.len() as u32;
+                }
+                _ => batches.push(BillboardBatch {
+                    texture: item.texture.map(str::to_string),
+                    first_instance,
+                    instance_count: item.quads.len() as u32,
+                }),
+            }
+        }
+
+        self.batches = batches;
+
+        if !instances.is_empty() {
+            self.instance_buffer.upload_data(context, &instances);
+        }
+    }
+
+    /// Draws the prepared quads inside the scene pass of a view, after its models.
+    pub fn draw(&self, context: &RenderContext, textures: &TextureCache, command_buffer: vk::CommandBuffer,
+                frame_descriptor_set: vk::DescriptorSet) {
+        if self.batches.is_empty() {
+            return;
+        }
+
+        let device = &context.device;
+        let layout = self.pipeline.get_layout();
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.get_pipeline());
+            for batch in &self.batches {
+                let texture_set = match textures.texture_set(batch.texture.as_deref()) {
+                    Some(set) => set,
+                    None => continue,
+                };
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                &[frame_descriptor_set, self.instance_set, texture_set], &[]);
+                device.cmd_draw(command_buffer, QUAD_VERTEX_COUNT, batch.instance_count, 0, batch.first_instance);
+            }
+        }
+    }
+}
+
+/// Asks the texture cache for the textures and fonts of billboards.
+pub fn request_billboard_resources_system(mut runner: Option<ResMut<RenderRunner>>,
+                                          asset_server: Res<AssetServer>,
+                                          billboard_query: Query<&Billboard>,
+                                          text_query: Query<&BillboardText>) {
+    if let Some(runner) = &mut runner {
+        for billboard in billboard_query.iter() {
+            runner.texture_cache.request_texture(&asset_server, &billboard.texture);
+        }
+        for text in text_query.iter() {
+            runner.texture_cache.request_font(&asset_server, &text.font);
+        }
+    }
+}
+
+pub fn prepare_billboards_system(mut runner: Option<ResMut<RenderRunner>>,
+                                 render_camera: Res<RenderCamera>,
+                                 camera_query: Query<&GlobalTransform>,
+                                 billboard_query: Query<(&Billboard, &GlobalTransform)>,
+                                 health_bar_query: Query<(&HealthBar, &GlobalTransform)>,
+                                 text_query: Query<(&BillboardText, &GlobalTransform)>) {
+    if let Some(runner) = &mut runner {
+        let runner: &mut RenderRunner = runner.deref_mut();
+        let camera_pos = camera_query.get(render_camera.camera).map_or(Vec3::ZERO, |transform| transform.translation);
+        runner.billboards.prepare(&runner.context, &runner.texture_cache, camera_pos, billboard_query.iter(),
+                                  health_bar_query.iter(), text_query.iter());
+    }
+}
@@ -1,5 +1,9 @@
 use bevy::prelude::*;
+use bevy::math::Vec4Swizzles;
+use bevy::window::WindowId;
+use ash::vk;
 use crate::{FlyCamera, RenderCamera};
+use crate::render::aabb::Aabb;
 
 pub enum CameraOpEvent {
     Focus(Transform, f32),
@@ -7,34 +11,266 @@ pub enum CameraOpEvent {
     ChangeRotation(Quat),
 }
 
+/// Normalized rect inside the camera target, origin at the top left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraViewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for CameraViewport {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }
+    }
+}
+
+impl CameraViewport {
+    pub fn to_rect(&self, extent: vk::Extent2D) -> vk::Rect2D {
+        let (w, h) = (extent.width as f32, extent.height as f32);
+        let x = (self.x.max(0.0).min(1.0) * w) as u32;
+        let y = (self.y.max(0.0).min(1.0) * h) as u32;
+        let width = ((self.width * w) as u32).min(extent.width - x.min(extent.width)).max(1);
+        let height = ((self.height * h) as u32).min(extent.height - y.min(extent.height)).max(1);
+        vk::Rect2D {
+            offset: vk::Offset2D { x: x as _, y: y as _ },
+            extent: vk::Extent2D { width, height },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraTarget {
+    /// a window, shared by all cameras of that window through their viewports
+    Window(WindowId),
+    /// an offscreen color texture owned by the renderer, see `RenderRunner::get_camera_target_view`
+    Texture { width: u32, height: u32 },
+}
+
+/// How view space maps to clip space. The depth convention follows the renderer,
+/// see `RenderSettings::reverse_z`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective { fov: f32, z_near: f32, z_far: f32 },
+    /// `height` is the visible vertical extent in world units, the width follows the aspect
+    Orthographic { height: f32, z_near: f32, z_far: f32 },
+    /// used as is, it has to match the depth convention of the renderer
+    Custom(Mat4),
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective {
+            fov: 45f32.to_radians(),
+            z_near: 1.0,
+            z_far: 96.0,
+        }
+    }
+}
+
+/// Where the fog of a camera takes its color from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FogColor {
+    Fixed(Vec3),
+    /// the clear color of the camera, so distant geometry fades into the background.
+    ClearColor,
+    /// the horizon color of the `Environment` map, the clear color until it is loaded
+    Environment,
+}
+
+/// Exponential distance and height fog of the camera entity, applied by every
+/// shader reading `PerFrameData`: models, grass and transparent geometry alike.
+/// Cameras without it draw no fog.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub color: FogColor,
+    /// opacity approached at an infinite distance
+    pub max_opacity: f32,
+    /// extinction per unit of view distance, 0 disables the distance fog
+    pub distance_density: f32,
+    /// extinction per unit at `base_height`, 0 disables the height fog
+    pub height_density: f32,
+    /// how fast the height fog thins out above `base_height`
+    pub height_falloff: f32,
+    pub base_height: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            color: FogColor::ClearColor,
+            max_opacity: 1.0,
+            distance_density: 0.02,
+            height_density: 0.0,
+            height_falloff: 0.5,
+            base_height: 0.0,
+        }
+    }
+}
+
+impl Fog {
+    /// Distance fog which is 99% opaque at `distance`, give it the far plane to hide
+    /// geometry popping in and out there.
+    pub fn opaque_at(distance: f32) -> Self {
+        Self {
+            distance_density: 100f32.ln() / distance.max(f32::EPSILON),
+            ..Default::default()
+        }
+    }
+
+    /// `fog_color` and `fog_params` of `PerFrameData`, zero when there is no fog.
+    pub fn frame_data(fog: Option<&Fog>, clear_color: Vec4, environment_color: Option<Vec3>) -> (Vec4, Vec4) {
+        match fog {
+            Some(fog) => {
+                let color = match fog.color {
+                    FogColor::Fixed(color) => color,
+                    FogColor::ClearColor => clear_color.truncate(),
+                    FogColor::Environment => environment_color.unwrap_or_else(|| clear_color.truncate()),
+                };
+                (color.extend(fog.max_opacity.clamp(0.0, 1.0)),
+                 Vec4::new(fog.distance_density, fog.height_density, fog.height_falloff, fog.base_height))
+            }
+            None => (Vec4::ZERO, Vec4::ZERO),
+        }
+    }
+}
+
+/// World space ray, `direction` is normalized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn get_point(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+}
+
+/// Clip planes of a view projection with the vulkan depth range, points inside have
+/// a positive distance to every plane. Works for both depth conventions.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn from_view_projection(view_proj: Mat4) -> Self {
+        let (r0, r1, r2, r3) = (view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3));
+        // an infinite far plane has a zero normal and a positive distance, it never culls
+        Self {
+            planes: [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2],
+        }
+    }
+
+    /// Conservative, a box near a corner of the frustum may pass while outside.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let corner = Vec3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// Any number of cameras can render. Texture cameras render first, then window
+/// cameras, each group in ascending priority so higher priorities end up on top.
 #[derive(Debug)]
 pub struct Camera {
-    pub fov: f32,
+    pub projection: Projection,
+    /// kept in sync with the viewport size by the renderer
     pub aspect: f32,
-    pub z_near: f32,
-    pub z_far: f32,
+    /// kept in sync with `RenderSettings::reverse_z` by the renderer, perspective
+    /// cameras then use an infinite far plane
+    pub reverse_z: bool,
+    pub priority: i32,
+    pub clear_color: Vec4,
+    pub viewport: CameraViewport,
+    pub target: CameraTarget,
 }
 
 impl Default for Camera {
     fn default() -> Self {
         Self {
-            fov: 45f32.to_radians(),
+            projection: Projection::default(),
             aspect: 1280f32 / 720f32,
-            z_near: 1.0,
-            z_far: 96.0,
+            reverse_z: false,
+            priority: 0,
+            clear_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+            viewport: CameraViewport::default(),
+            target: CameraTarget::Window(WindowId::primary()),
         }
     }
 }
 
 impl Camera {
-    pub fn compute_world_2_ui_position(camera: &Camera, camera_transform: &Transform, target_world_pos: Vec3, window_size: Vec2) -> Vec3 {
-        let proj = Mat4::perspective_rh(camera.fov, camera.aspect, camera.z_near, camera.z_far);
-        let view = camera_transform.compute_matrix().inverse();
-        let clip_pos = (proj * view).mul_vec4(Vec4::from((target_world_pos, 1f32)));
-        let mut res = Vec3::new(clip_pos.x / clip_pos.w, clip_pos.y / clip_pos.w, clip_pos.w);
-        res = res * 0.5f32 + Vec3::new(0.5f32, 0.5f32, 0.0);
-        res.y = 1f32 - res.y;
-        Vec3::new(window_size.x * (res.x), window_size.y * (res.y), res.z)
+    pub fn projection_matrix(&self) -> Mat4 {
+        match self.projection {
+            Projection::Perspective { fov, z_near, z_far } => {
+                if self.reverse_z {
+                    Mat4::perspective_infinite_reverse_rh(fov, self.aspect, z_near)
+                } else {
+                    Mat4::perspective_rh(fov, self.aspect, z_near, z_far)
+                }
+            }
+            Projection::Orthographic { height, z_near, z_far } => {
+                let half_height = height * 0.5;
+                let half_width = half_height * self.aspect;
+                // swapping the planes maps near to 1 and far to 0
+                let (near, far) = if self.reverse_z { (z_far, z_near) } else { (z_near, z_far) };
+                Mat4::orthographic_rh(-half_width, half_width, -half_height, half_height, near, far)
+            }
+            Projection::Custom(proj) => proj,
+        }
+    }
+
+    pub fn view_projection(&self, camera_transform: &Transform) -> Mat4 {
+        self.projection_matrix() * camera_transform.compute_matrix().inverse()
+    }
+
+    /// Clip space depth of the near plane.
+    pub fn near_depth(&self) -> f32 {
+        if self.reverse_z { 1.0 } else { 0.0 }
+    }
+
+    /// Projects a world position into a viewport of `viewport_size` pixels, origin at the
+    /// top left. `z` is the view space distance, negative behind the camera.
+    pub fn world_to_viewport(&self, camera_transform: &Transform, world_pos: Vec3, viewport_size: Vec2) -> Vec3 {
+        let view_pos = camera_transform.compute_matrix().inverse().transform_point3(world_pos);
+        let clip_pos = self.projection_matrix().mul_vec4(Vec4::from((view_pos, 1f32)));
+        let ndc = clip_pos.xy() / clip_pos.w;
+        let uv = Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        Vec3::new(viewport_size.x * uv.x, viewport_size.y * uv.y, -view_pos.z)
+    }
+
+    /// Inverse of the projection, `depth` is in clip space, see `near_depth`.
+    pub fn viewport_to_world(&self, camera_transform: &Transform, viewport_pos: Vec2, depth: f32, viewport_size: Vec2) -> Vec3 {
+        let uv = viewport_pos / viewport_size;
+        let ndc = Vec3::new(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth);
+        self.view_projection(camera_transform).inverse().project_point3(ndc)
+    }
+
+    /// Ray from the near plane through a viewport position, works for every projection.
+    pub fn viewport_to_ray(&self, camera_transform: &Transform, viewport_pos: Vec2, viewport_size: Vec2) -> Ray {
+        let near_depth = self.near_depth();
+        let origin = self.viewport_to_world(camera_transform, viewport_pos, near_depth, viewport_size);
+        // halfway in clip space stays finite even with an infinite far plane
+        let far = self.viewport_to_world(camera_transform, viewport_pos, 0.5, viewport_size);
+        Ray {
+            origin,
+            direction: (far - origin).normalize(),
+        }
     }
 
     pub fn update_camera_op_event_system(
@@ -70,3 +306,135 @@ impl Camera {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewport() -> Vec2 {
+        Vec2::new(800.0, 600.0)
+    }
+
+    fn camera(projection: Projection, reverse_z: bool) -> Camera {
+        Camera {
+            projection,
+            aspect: viewport().x / viewport().y,
+            reverse_z,
+            ..Default::default()
+        }
+    }
+
+    fn perspective() -> Projection {
+        Projection::Perspective { fov: 60f32.to_radians(), z_near: 0.5, z_far: 100.0 }
+    }
+
+    fn orthographic() -> Projection {
+        Projection::Orthographic { height: 10.0, z_near: 0.5, z_far: 100.0 }
+    }
+
+    fn clip_depth(camera: &Camera, distance: f32) -> f32 {
+        let clip = camera.projection_matrix().mul_vec4(Vec4::new(0.0, 0.0, -distance, 1.0));
+        clip.z / clip.w
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).abs().max_element() < 1e-3, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn depth_range_follows_convention() {
+        for projection in [perspective(), orthographic()] {
+            let standard = camera(projection, false);
+            assert!(clip_depth(&standard, 0.5).abs() < 1e-5);
+            assert!((clip_depth(&standard, 100.0) - 1.0).abs() < 1e-5);
+
+            let reverse = camera(projection, true);
+            assert_eq!(reverse.near_depth(), 1.0);
+            assert!((clip_depth(&reverse, 0.5) - 1.0).abs() < 1e-5);
+            assert!(clip_depth(&reverse, 50.0) < clip_depth(&reverse, 10.0));
+        }
+    }
+
+    #[test]
+    fn reverse_perspective_has_infinite_far_plane() {
+        let reverse = camera(perspective(), true);
+        let depth = clip_depth(&reverse, 1.0e6);
+        assert!(depth > 0.0 && depth < 1.0e-5);
+    }
+
+    #[test]
+    fn viewport_round_trip() {
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0);
+        let world_pos = Vec3::new(2.0, 1.0, -7.0);
+        for projection in [perspective(), orthographic()] {
+            for reverse_z in [false, true] {
+                let camera = camera(projection, reverse_z);
+                let view = camera.world_to_viewport(&transform, world_pos, viewport());
+                assert!((view.z - 10.0).abs() < 1e-4);
+
+                let clip = camera.view_projection(&transform).project_point3(world_pos);
+                let back = camera.viewport_to_world(&transform, view.truncate(), clip.z, viewport());
+                assert_near(back, world_pos);
+            }
+        }
+    }
+
+    #[test]
+    fn viewport_origin_is_top_left() {
+        let camera = camera(orthographic(), false);
+        let view = camera.world_to_viewport(&Transform::identity(), Vec3::new(0.0, 5.0, -1.0), viewport());
+        assert_near(view, Vec3::new(400.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn rays_start_at_the_near_plane() {
+        for reverse_z in [false, true] {
+            let camera = camera(perspective(), reverse_z);
+            let ray = camera.viewport_to_ray(&Transform::identity(), viewport() * 0.5, viewport());
+            assert_near(ray.origin, Vec3::new(0.0, 0.0, -0.5));
+            assert_near(ray.direction, Vec3::new(0.0, 0.0, -1.0));
+
+            let corner = camera.viewport_to_ray(&Transform::identity(), Vec2::ZERO, viewport());
+            assert!(corner.direction.x < 0.0 && corner.direction.y > 0.0);
+        }
+
+        // orthographic rays are parallel
+        let camera = camera(orthographic(), true);
+        let ray = camera.viewport_to_ray(&Transform::identity(), Vec2::ZERO, viewport());
+        assert_near(ray.direction, Vec3::new(0.0, 0.0, -1.0));
+        assert_near(ray.origin, Vec3::new(-5.0 * camera.aspect, 5.0, -0.5));
+    }
+
+    #[test]
+    fn frustum_culls_boxes_outside() {
+        let inside = Aabb::new(Vec3::new(-1.0, -1.0, -11.0), Vec3::new(1.0, 1.0, -9.0));
+        let behind = Aabb::new(Vec3::new(-1.0, -1.0, 9.0), Vec3::new(1.0, 1.0, 11.0));
+        let beside = Aabb::new(Vec3::new(100.0, -1.0, -11.0), Vec3::new(102.0, 1.0, -9.0));
+        let far = Aabb::new(Vec3::new(-1.0, -1.0, -1001.0), Vec3::new(1.0, 1.0, -999.0));
+        for reverse_z in [false, true] {
+            let camera = camera(perspective(), reverse_z);
+            let frustum = Frustum::from_view_projection(camera.view_projection(&Transform::identity()));
+            assert!(frustum.intersects_aabb(&inside));
+            assert!(!frustum.intersects_aabb(&behind));
+            assert!(!frustum.intersects_aabb(&beside));
+            // only the standard depth has a far plane
+            assert_eq!(frustum.intersects_aabb(&far), reverse_z);
+        }
+    }
+
+    #[test]
+    fn fog_color_sources() {
+        let clear = Vec4::new(0.1, 0.2, 0.3, 1.0);
+        let env = Vec3::new(0.5, 0.6, 0.7);
+        let color = |c: FogColor, env: Option<Vec3>| {
+            let fog = Fog { color: c, max_opacity: 0.5, ..Default::default() };
+            Fog::frame_data(Some(&fog), clear, env).0
+        };
+        assert_eq!(color(FogColor::Fixed(Vec3::ONE), Some(env)), Vec4::new(1.0, 1.0, 1.0, 0.5));
+        assert_eq!(color(FogColor::ClearColor, Some(env)), Vec4::new(0.1, 0.2, 0.3, 0.5));
+        assert_eq!(color(FogColor::Environment, Some(env)), env.extend(0.5));
+        // the clear color stands in until the environment map is loaded
+        assert_eq!(color(FogColor::Environment, None), Vec4::new(0.1, 0.2, 0.3, 0.5));
+        assert_eq!(Fog::frame_data(None, clear, Some(env)), (Vec4::ZERO, Vec4::ZERO));
+    }
+}
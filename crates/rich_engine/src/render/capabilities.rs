@@ -0,0 +1,126 @@
+use std::ffi::CStr;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::render_settings::DeviceType;
+
+/// Properties and optional features of the device the renderer runs on, logged at
+/// startup and inserted as a resource.
+#[derive(Clone, Debug)]
+pub struct RenderCapabilities {
+    pub device_name: String,
+    /// `None` for devices of unknown type
+    pub device_type: Option<DeviceType>,
+    /// index in `available_devices`
+    pub device_index: usize,
+    /// (major, minor, patch)
+    pub api_version: (u32, u32, u32),
+    pub driver_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub device_local_memory: u64,
+    pub max_image_dimension_2d: u32,
+    /// at least 128 bytes
+    pub max_push_constants_size: u32,
+    pub supported_msaa: vk::SampleCountFlags,
+    pub sampler_anisotropy: bool,
+    /// 1.0 when anisotropic filtering is not supported
+    pub max_sampler_anisotropy: f32,
+    pub pipeline_statistics: bool,
+    pub wide_lines: bool,
+    pub line_width_range: [f32; 2],
+    pub texture_compression_bc: bool,
+    /// polygon modes other than fill, used by the wireframe debug view
+    pub fill_mode_non_solid: bool,
+    /// names of every physical device, unsuitable ones included
+    pub available_devices: Vec<String>,
+}
+
+impl RenderCapabilities {
+    pub fn device_name(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> String {
+        let props = unsafe { instance.get_physical_device_properties(physical_device) };
+        unsafe { CStr::from_ptr(props.device_name.as_ptr()) }.to_string_lossy().into_owned()
+    }
+
+    pub fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice,
+                 device_index: usize, available_devices: Vec<String>) -> Self {
+        let props = unsafe { instance.get_physical_device_properties(physical_device) };
+        let features = unsafe { instance.get_physical_device_features(physical_device) };
+        let memory = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+
+        let device_local_memory = memory.memory_heaps[..memory.memory_heap_count as usize].iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+
+        let sampler_anisotropy = features.sampler_anisotropy == vk::TRUE;
+        Self {
+            device_name: unsafe { CStr::from_ptr(props.device_name.as_ptr()) }.to_string_lossy().into_owned(),
+            device_type: DeviceType::from_vk(props.device_type),
+            device_index,
+            api_version: (vk::api_version_major(props.api_version),
+                          vk::api_version_minor(props.api_version),
+                          vk::api_version_patch(props.api_version)),
+            driver_version: props.driver_version,
+            vendor_id: props.vendor_id,
+            device_id: props.device_id,
+            device_local_memory,
+            max_image_dimension_2d: props.limits.max_image_dimension2_d,
+            max_push_constants_size: props.limits.max_push_constants_size,
+            supported_msaa: props.limits.framebuffer_color_sample_counts & props.limits.framebuffer_depth_sample_counts,
+            sampler_anisotropy,
+            max_sampler_anisotropy: if sampler_anisotropy { props.limits.max_sampler_anisotropy } else { 1.0 },
+            pipeline_statistics: features.pipeline_statistics_query == vk::TRUE,
+            wide_lines: features.wide_lines == vk::TRUE,
+            line_width_range: props.limits.line_width_range,
+            texture_compression_bc: features.texture_compression_bc == vk::TRUE,
+            fill_mode_non_solid: features.fill_mode_non_solid == vk::TRUE,
+            available_devices,
+        }
+    }
+
+    pub fn log(&self) {
+        info!("render device [{}] {} ({:?}), vulkan {}.{}.{}, driver {:#x}, vendor {:#06x}, device {:#06x}",
+              self.device_index, self.device_name, self.device_type,
+              self.api_version.0, self.api_version.1, self.api_version.2,
+              self.driver_version, self.vendor_id, self.device_id);
+        info!("  device local memory: {} MiB, max image 2d: {}, push constants: {} bytes, msaa: {:?}",
+              self.device_local_memory / (1024 * 1024), self.max_image_dimension_2d, self.max_push_constants_size,
+              self.supported_msaa);
+        info!("  anisotropy: {} (max {}), pipeline statistics: {}, wide lines: {} ({:?}), bc compression: {}, wireframe: {}",
+              self.sampler_anisotropy, self.max_sampler_anisotropy, self.pipeline_statistics,
+              self.wide_lines, self.line_width_range, self.texture_compression_bc, self.fill_mode_non_solid);
+        for (index, name) in self.available_devices.iter().enumerate() {
+            info!("  device [{}] {}", index, name);
+        }
+    }
+}
+
+/// Format queries that work off the render thread, used when textures are decoded
+/// on the async compute pool.
+#[derive(Clone)]
+pub struct FormatSupport {
+    instance: ash::Instance,
+    physical_device: vk::PhysicalDevice,
+}
+
+impl FormatSupport {
+    pub fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Self {
+        Self { instance: instance.clone(), physical_device }
+    }
+
+    /// Whether optimal tiled images of this format can be sampled with linear filtering.
+    pub fn is_sampleable(&self, format: vk::Format) -> bool {
+        let props = unsafe {
+            self.instance.get_physical_device_format_properties(self.physical_device, format)
+        };
+        props.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    }
+
+    /// Whether vertex attributes can be fetched from buffers in this format.
+    pub fn is_vertex_format(&self, format: vk::Format) -> bool {
+        let props = unsafe {
+            self.instance.get_physical_device_format_properties(self.physical_device, format)
+        };
+        props.buffer_features.contains(vk::FormatFeatureFlags::VERTEX_BUFFER)
+    }
+}
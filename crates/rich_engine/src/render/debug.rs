@@ -1,106 +1,311 @@
-/*use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo};
+use std::mem::size_of;
 use ash::vk;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo, set_flipped_viewport_rect};
 use crate::render::render_context::RenderContext;
-use crate::render::swapchain_mgr::SwapChainMgr;
 use crate::render::forward_render::ForwardRenderPass;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::util;
 
-pub struct DebugDraw
-{
+const OVERLAY_MARGIN: u32 = 16;
+
+const SOURCE_SHADOW_MAP: u32 = 0;
+const SOURCE_SCENE_DEPTH: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OverlayConstant {
+    /// 0 samples the shadow map, 1 the scene depth
+    source: u32,
+    /// flips the scene depth so near stays dark with reverse z
+    reverse_z: u32,
+}
+
+/// Pictures of the shadow map and the scene depth drawn over the bottom left of the
+/// primary window after the forward pass, see `RenderSettings::debug_overlay`.
+pub struct DebugOverlay {
+    pub enable_draw: bool,
+    render_pass: vk::RenderPass,
+    frame_buffer: vk::Framebuffer,
+    sampler: vk::Sampler,
     descriptor_set_layout: vk::DescriptorSetLayout,
     descriptor_set: vk::DescriptorSet,
     pipeline: GraphicPipeline,
 }
 
-impl DebugDraw {
-    pub fn create(context: &mut RenderContext, swap_mgr: &SwapChainMgr, forward_render: &ForwardRenderPass) -> Self {
-        let (descriptor_set_layout, descriptor_set) = {
-            let bindings = [
-                vk::DescriptorSetLayoutBinding::builder()
-                    .binding(1)
-                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .descriptor_count(1)
-                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                    .build(),
-            ];
-
-            let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
-
-            let set_layout = unsafe {
-                context.device
-                    .create_descriptor_set_layout(&layout_info, None)
-                    .unwrap()
-            };
-
-            let layouts = [set_layout];
-
-            let allocate_info = vk::DescriptorSetAllocateInfo::builder()
-                .descriptor_pool(context.descriptor_pool)
-                .set_layouts(&layouts);
-            let set = unsafe {
-                context
-                    .device
-                    .allocate_descriptor_sets(&allocate_info)
-                    .unwrap()[0]
-            };
-
-
-            let shadow_info = {
-                let shadow = forward_render.get_shadow();
-                let (view, sampler) = (shadow.shadow_view, shadow.sampler);
-
-                [vk::DescriptorImageInfo::builder()
-                    .image_layout(vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL)
-                    .image_view(view)
-                    .sampler(sampler)
-                    .build()]
-            };
-
-            let descriptor_writes = [
-                vk::WriteDescriptorSet::builder()
-                    .dst_set(set)
-                    .dst_binding(0)
-                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .image_info(&shadow_info)
-                    .build(),
-            ];
-
-            unsafe {
-                context
-                    .device
-                    .update_descriptor_sets(&descriptor_writes, &[])
-            }
+impl DebugOverlay {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.pipeline.destroy(context);
+        let device = &context.device;
+        unsafe {
+            device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_framebuffer(self.frame_buffer, None);
+            device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+
+    pub fn create(context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<Self> {
+        let render_pass = Self::create_render_pass(context)?;
+
+        let extent = forward_render.get_extent();
+        let views = [forward_render.get_final_render_image_view()];
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1)
+            .width(extent.width).height(extent.height).attachments(&views).build();
+        let frame_buffer = unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) }
+            .map_err(RenderError::vk("vkCreateFramebuffer"))?;
 
-            (set_layout, set)
+        let sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
         };
 
-        let all_layout = [descriptor_set_layout];
-        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&all_layout).build();
+        let (descriptor_set_layout, descriptor_set) = match Self::create_descriptors(context, forward_render, sampler) {
+            Ok(descriptors) => descriptors,
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_sampler(sampler, None);
+                    context.device.destroy_framebuffer(frame_buffer, None);
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                return Err(e);
+            }
+        };
 
+        let set_layouts = [descriptor_set_layout];
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<OverlayConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT).build(),
+        ];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&constant_ranges)
+            .build();
 
-        let vi = PipelineVertexInputInfo::none();
-        let pipeline = GraphicPipeline::create(context,
-                                               swap_mgr,
-                                               forward_render.get_native_render_pass(),
-                                               &vi,
-                                               &pipeline_layout_ci,
-                                               vk::SampleCountFlags::TYPE_1,
-                                               "", "", &[]);
+        // the scene depth is sampled per sample when it is multisampled
+        let mut defines = vec![];
+        if context.render_config.msaa != vk::SampleCountFlags::TYPE_1 {
+            defines.push("MSAA_DEPTH");
+        }
+
+        let vertex_input = PipelineVertexInputInfo::from(&[], &[]).with_cull_mode(vk::CullModeFlags::NONE);
+        let bindings = Self::set_layout_bindings();
+        let pipeline = context.shader_modules
+            .check_stages(&context.device, &["debug_overlay_vert", "debug_overlay_frag"], &defines,
+                          &[(0, &bindings[..])])
+            .and_then(|_| GraphicPipeline::create(context, render_pass, &vertex_input, &pipeline_layout_ci,
+                                                  vk::SampleCountFlags::TYPE_1,
+                                                  "debug_overlay_vert", "debug_overlay_frag", &defines));
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe {
+                    context.device.free_descriptor_sets(context.descriptor_pool, &[descriptor_set]);
+                    context.device.destroy_descriptor_set_layout(descriptor_set_layout, None);
+                    context.device.destroy_sampler(sampler, None);
+                    context.device.destroy_framebuffer(frame_buffer, None);
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                return Err(e);
+            }
+        };
 
-        Self {
+        Ok(Self {
+            enable_draw: false,
+            render_pass,
+            frame_buffer,
+            sampler,
             descriptor_set_layout,
             descriptor_set,
             pipeline,
+        })
+    }
+
+    /// Rebuilds against new forward targets, the overlay stays enabled or disabled.
+    pub fn recreate(&mut self, context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<()> {
+        let enable_draw = self.enable_draw;
+        self.destroy(context);
+        *self = Self::create(context, forward_render)?;
+        self.enable_draw = enable_draw;
+        Ok(())
+    }
+
+    /// Loads the final color image, drawn after the forward pass ended.
+    fn create_render_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: context.render_config.color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// Two images read by the fragment stage, checked against the overlay shaders.
+    fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 2] {
+        let binding = |binding: u32| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build();
+        [binding(0), binding(1)]
+    }
+
+    fn create_descriptors(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                          sampler: vk::Sampler) -> RenderResult<(vk::DescriptorSetLayout, vk::DescriptorSet)> {
+        let bindings = Self::set_layout_bindings();
+
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+
+        let set_layout = unsafe {
+            context.device
+                .create_descriptor_set_layout(&layout_info, None)
+                .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?
+        };
+
+        let layouts = [set_layout];
+
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = match unsafe { context.device.allocate_descriptor_sets(&allocate_info) } {
+            Ok(sets) => sets[0],
+            Err(e) => {
+                unsafe { context.device.destroy_descriptor_set_layout(set_layout, None); }
+                return Err(RenderError::vk("vkAllocateDescriptorSets")(e));
+            }
+        };
+
+        let shadow_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .image_view(forward_render.get_shadow().shadow_view)
+            .sampler(sampler)
+            .build()];
+
+        let depth_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .image_view(forward_render.get_depth_view())
+            .sampler(sampler)
+            .build()];
+
+        let descriptor_writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&shadow_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&depth_info)
+                .build(),
+        ];
+
+        unsafe {
+            context
+                .device
+                .update_descriptor_sets(&descriptor_writes, &[])
         }
+
+        Ok((set_layout, set))
     }
 
-    pub fn draw(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
-        let device = context.device;
+    /// Draws both pictures, call after the forward pass of the primary window ended.
+    pub fn draw(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, forward_render: &ForwardRenderPass) {
+        if !self.enable_draw {
+            return;
+        }
+
+        forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER, true);
+
+        let extent = forward_render.get_extent();
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(self.frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .build();
+
+        // the shadow map is square, the depth picture keeps the aspect of the scene
+        let height = extent.height / 4;
+        let depth_width = (height as u64 * extent.width as u64 / extent.height.max(1) as u64) as u32;
+        let top = extent.height.saturating_sub(height + OVERLAY_MARGIN) as i32;
+        let pictures = [
+            (SOURCE_SHADOW_MAP, OVERLAY_MARGIN, height),
+            (SOURCE_SCENE_DEPTH, height + 2 * OVERLAY_MARGIN, depth_width),
+        ];
+
+        let device = &context.device;
         unsafe {
+            device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
             device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.get_pipeline());
             device.cmd_bind_descriptor_sets(command_buffer,
                                             vk::PipelineBindPoint::GRAPHICS, self.pipeline.get_layout(),
-                                            0, &[self.descriptor_set], &[0]);
-            device.cmd_draw(command_buffer, 3, 1, 0, 0);
+                                            0, &[self.descriptor_set], &[]);
+            for (source, left, width) in pictures {
+                let constant = OverlayConstant {
+                    source,
+                    reverse_z: context.render_config.reverse_z as u32,
+                };
+                device.cmd_push_constants(command_buffer, self.pipeline.get_layout(),
+                                          vk::ShaderStageFlags::FRAGMENT, 0, util::any_as_u8_slice(&constant));
+                set_flipped_viewport_rect(context, command_buffer, vk::Rect2D {
+                    offset: vk::Offset2D { x: left as i32, y: top },
+                    extent: vk::Extent2D { width, height },
+                });
+                // a single triangle covering the viewport
+                device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            }
+            device.cmd_end_render_pass(command_buffer);
         }
+
+        forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER, false);
     }
-}*/
\ No newline at end of file
+}
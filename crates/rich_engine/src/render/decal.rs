@@ -0,0 +1,523 @@
+use std::mem::size_of;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::buffer::Buffer;
+use crate::render::camera::Frustum;
+use crate::render::aabb::Aabb;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo, set_flipped_viewport_rect};
+use crate::render::render_context::RenderContext;
+use crate::render::forward_render::ForwardRenderPass;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_runner::RenderRunner;
+use crate::render::render_view::RenderView;
+use crate::render::texture_cache::TextureCache;
+use crate::render::util;
+
+/// Decal instances drawn per frame over all views, the lowest sort orders are kept.
+pub const MAX_DECALS: usize = 1024;
+
+/// vertices of the box drawn per decal, generated by the vertex shader
+const BOX_VERTEX_COUNT: u32 = 36;
+
+/// Projects a texture along the local -Y axis onto everything opaque inside the unit
+/// box of the entity, scale the transform to size it. Nothing is added to the meshes
+/// it covers, the decal pass reads the scene depth of the primary window.
+#[derive(Debug, Clone)]
+pub struct Decal {
+    /// image file relative to `assets/`, shared by every decal using it
+    pub texture: String,
+    /// multiplies the texture, lower the alpha to fade the decal out
+    pub color: Vec4,
+    /// surfaces turned further than this from the projection axis are skipped, in radians
+    pub angle_cutoff: f32,
+    /// part of the box height faded out towards its top and bottom, 0 is a hard edge
+    pub edge_fade: f32,
+    /// decals with a higher order are drawn over lower ones
+    pub sort_order: i32,
+}
+
+impl Decal {
+    pub fn new(texture: impl Into<String>) -> Self {
+        Self {
+            texture: texture.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for Decal {
+    fn default() -> Self {
+        Self {
+            texture: String::new(),
+            color: Vec4::ONE,
+            angle_cutoff: 60f32.to_radians(),
+            edge_fade: 0.25,
+            sort_order: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuDecal {
+    /// unit box to world
+    model: Mat4,
+    world_to_decal: Mat4,
+    color: Vec4,
+    /// x cosine of the angle cutoff, y edge fade
+    params: Vec4,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DecalConstant {
+    /// unprojects the scene depth to world space
+    inv_view_proj: Mat4,
+    /// pixel rect of the view, xy offset and zw size
+    viewport: Vec4,
+}
+
+/// Consecutive instances of one view sharing a texture.
+struct DecalBatch {
+    view: usize,
+    texture: String,
+    first_instance: u32,
+    instance_count: u32,
+}
+
+/// Objects bound to the forward targets, rebuilt with them.
+struct DecalPass {
+    render_pass: vk::RenderPass,
+    frame_buffer: vk::Framebuffer,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_set: vk::DescriptorSet,
+    pipeline: GraphicPipeline,
+}
+
+impl DecalPass {
+    fn destroy(&mut self, context: &RenderContext) {
+        self.pipeline.destroy(context);
+        let device = &context.device;
+        unsafe {
+            device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_framebuffer(self.frame_buffer, None);
+            device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+
+    fn create(context: &mut RenderContext, forward_render: &ForwardRenderPass, depth_sampler: vk::Sampler,
+              instance_buffer: &Buffer, texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<Self> {
+        let render_pass = Self::create_render_pass(context)?;
+
+        let extent = forward_render.get_extent();
+        let views = [forward_render.get_color_view()];
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1)
+            .width(extent.width).height(extent.height).attachments(&views).build();
+        let frame_buffer = match unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) } {
+            Ok(frame_buffer) => frame_buffer,
+            Err(e) => {
+                unsafe { context.device.destroy_render_pass(render_pass, None); }
+                return Err(RenderError::vk("vkCreateFramebuffer")(e));
+            }
+        };
+
+        let (descriptor_set_layout, descriptor_set) = match Self::create_descriptors(context, forward_render,
+                                                                                     depth_sampler, instance_buffer) {
+            Ok(descriptors) => descriptors,
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_framebuffer(frame_buffer, None);
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                return Err(e);
+            }
+        };
+
+        let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+        let set_layouts = [frame_uniform_layout, descriptor_set_layout, texture_set_layout];
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<DecalConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT).build(),
+        ];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&constant_ranges)
+            .build();
+
+        let mut defines = vec![];
+        if context.render_config.msaa != vk::SampleCountFlags::TYPE_1 {
+            defines.push("MSAA_DEPTH");
+        }
+
+        // back faces keep the decal visible while the camera is inside its box
+        let vertex_input = PipelineVertexInputInfo::from(&[], &[])
+            .with_cull_mode(vk::CullModeFlags::FRONT)
+            .with_alpha_blend(true);
+        let bindings = Self::set_layout_bindings();
+        let texture_bindings = TextureCache::set_layout_bindings();
+        let pipeline = context.shader_modules
+            .check_stages(&context.device, &["decal_vert", "decal_frag"], &defines,
+                          &[(1, &bindings[..]), (2, &texture_bindings[..])])
+            .and_then(|_| GraphicPipeline::create(context, render_pass, &vertex_input, &pipeline_layout_ci,
+                                                  context.render_config.msaa, "decal_vert", "decal_frag", &defines));
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe {
+                    context.device.free_descriptor_sets(context.descriptor_pool, &[descriptor_set]);
+                    context.device.destroy_descriptor_set_layout(descriptor_set_layout, None);
+                    context.device.destroy_framebuffer(frame_buffer, None);
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            render_pass,
+            frame_buffer,
+            descriptor_set_layout,
+            descriptor_set,
+            pipeline,
+        })
+    }
+
+    /// Loads the scene color image, multisampled like the scene. The blended geometry
+    /// drawn after it resolves it.
+    fn create_render_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: context.render_config.color_format,
+                samples: context.render_config.msaa,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// The scene depth and the decals, checked against the decal shaders.
+    fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 2] {
+        [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ]
+    }
+
+    fn create_descriptors(context: &RenderContext, forward_render: &ForwardRenderPass, depth_sampler: vk::Sampler,
+                          instance_buffer: &Buffer) -> RenderResult<(vk::DescriptorSetLayout, vk::DescriptorSet)> {
+        let bindings = Self::set_layout_bindings();
+
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+
+        let set_layout = unsafe { context.device.create_descriptor_set_layout(&layout_info, None) }
+            .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?;
+
+        let layouts = [set_layout];
+
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = match unsafe { context.device.allocate_descriptor_sets(&allocate_info) } {
+            Ok(sets) => sets[0],
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                return Err(RenderError::vk("vkAllocateDescriptorSets")(e));
+            }
+        };
+
+        let depth_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .image_view(forward_render.get_depth_view())
+            .sampler(depth_sampler)
+            .build()];
+
+        let instance_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(instance_buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+
+        let descriptor_writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&depth_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&instance_info)
+                .build(),
+        ];
+
+        unsafe {
+            context
+                .device
+                .update_descriptor_sets(&descriptor_writes, &[])
+        }
+
+        Ok((set_layout, set))
+    }
+}
+
+/// Draws `Decal` entities into the primary window between the opaque models and the
+/// blended ones, grass, particles and billboards drawn after them are not covered.
+/// Decals outside the frustum of a view are skipped for that view.
+pub struct DecalRenderer {
+    pass: DecalPass,
+    depth_sampler: vk::Sampler,
+    /// owned by the texture cache
+    texture_set_layout: vk::DescriptorSetLayout,
+    instance_buffer: Buffer,
+    batches: Vec<DecalBatch>,
+}
+
+impl DecalRenderer {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.pass.destroy(context);
+        self.instance_buffer.destroy(context);
+        unsafe {
+            context.device.destroy_sampler(self.depth_sampler, None);
+        }
+    }
+
+    /// `texture_set_layout` is the layout of the texture cache sets.
+    pub fn create(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                  texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<Self> {
+        let depth_sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+
+        let instance_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                                           (MAX_DECALS * size_of::<GpuDecal>()) as _);
+
+        let pass = match DecalPass::create(context, forward_render, depth_sampler, &instance_buffer, texture_set_layout) {
+            Ok(pass) => pass,
+            Err(e) => {
+                let mut instance_buffer = instance_buffer;
+                instance_buffer.destroy(context);
+                unsafe {
+                    context.device.destroy_sampler(depth_sampler, None);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            pass,
+            depth_sampler,
+            texture_set_layout,
+            instance_buffer,
+            batches: vec![],
+        })
+    }
+
+    /// Rebuilds against new forward targets.
+    pub fn recreate(&mut self, context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<()> {
+        self.pass.destroy(context);
+        self.pass = DecalPass::create(context, forward_render, self.depth_sampler, &self.instance_buffer,
+                                      self.texture_set_layout)?;
+        Ok(())
+    }
+
+    /// World space bounds of the unit box under `transform`.
+    fn world_aabb(transform: &Mat4) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 { -0.5 } else { 0.5 },
+                if i & 2 == 0 { -0.5 } else { 0.5 },
+                if i & 4 == 0 { -0.5 } else { 0.5 },
+            );
+            let p = transform.transform_point3(corner);
+            min = min.min(p);
+            max = max.max(p);
+        }
+        Aabb::new(min, max)
+    }
+
+    /// Culls and sorts the decals for each of `views` and uploads the instances drawn
+    /// by `draw`. Decals whose texture is not loaded yet are skipped.
+    pub fn prepare<'a>(&mut self, context: &RenderContext, textures: &TextureCache, views: &[&RenderView],
+                       decals: impl Iterator<Item=(&'a Decal, &'a GlobalTransform)>) {
+        self.batches.clear();
+
+        let mut decals = decals
+            .filter(|(decal, _)| textures.texture(&decal.texture).is_some())
+            .map(|(decal, transform)| {
+                let model = transform.compute_matrix();
+                (decal, model, Self::world_aabb(&model))
+            })
+            .collect::<Vec<_>>();
+        if decals.is_empty() {
+            return;
+        }
+        // textures are grouped within an order to draw them in fewer batches
+        decals.sort_by(|a, b| (a.0.sort_order, &a.0.texture).cmp(&(b.0.sort_order, &b.0.texture)));
+
+        let mut instances = Vec::new();
+        for (view_index, view) in views.iter().enumerate() {
+            let frustum = Frustum::from_view_projection(view.view_proj);
+            for (decal, model, aabb) in &decals {
+                if instances.len() == MAX_DECALS {
+                    break;
+                }
+                if !frustum.intersects_aabb(aabb) {
+                    continue;
+                }
+
+                let first_instance = instances.len() as u32;
+                instances.push(GpuDecal {
+                    model: *model,
+                    world_to_decal: model.inverse(),
+                    color: decal.color,
+                    params: Vec4::new(decal.angle_cutoff.cos(), decal.edge_fade.clamp(0.0, 1.0), 0.0, 0.0),
+                });
+                match self.batches.last_mut() {
+                    Some(batch) if batch.view == view_index && batch.texture == decal.texture => {
+                        batch.instance_count += 1;
+                    }
+                    _ => self.batches.push(DecalBatch {
+                        view: view_index,
+                        texture: decal.texture.clone(),
+                        first_instance,
+                        instance_count: 1,
+                    }),
+                }
+            }
+        }
+
+        if !instances.is_empty() {
+            self.instance_buffer.upload_data(context, &instances);
+        }
+    }
+
+    /// Draws the prepared decals, `views` are the ones given to `prepare`. Call between
+    /// the opaque and the blended geometry of the primary window. `depth_readable` when
+    /// the scene depth is already in the read-only layout, as the G-buffer pass leaves
+    /// it, otherwise it is moved there and back to the attachment layout.
+    pub fn draw(&self, context: &RenderContext, textures: &TextureCache, command_buffer: vk::CommandBuffer,
+                forward_render: &ForwardRenderPass, views: &[&RenderView], depth_readable: bool) {
+        if self.batches.is_empty() {
+            return;
+        }
+
+        if !depth_readable {
+            forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER, true);
+        }
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.pass.render_pass)
+            .framebuffer(self.pass.frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: forward_render.get_extent(),
+            })
+            .build();
+
+        let device = &context.device;
+        let layout = self.pass.pipeline.get_layout();
+        unsafe {
+            device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pass.pipeline.get_pipeline());
+            let mut current_view = None;
+            for batch in &self.batches {
+                let view = views[batch.view];
+                if current_view != Some(batch.view) {
+                    current_view = Some(batch.view);
+                    let rect = view.rect;
+                    let constant = DecalConstant {
+                        inv_view_proj: view.view_proj.inverse(),
+                        viewport: Vec4::new(rect.offset.x as f32, rect.offset.y as f32,
+                                            rect.extent.width as f32, rect.extent.height as f32),
+                    };
+                    device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                              util::any_as_u8_slice(&constant));
+                    set_flipped_viewport_rect(context, command_buffer, rect);
+                }
+
+                let texture_set = match textures.texture_set(Some(&batch.texture)) {
+                    Some(set) => set,
+                    None => continue,
+                };
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                &[view.frame_descriptor_set, self.pass.descriptor_set, texture_set], &[]);
+                device.cmd_draw(command_buffer, BOX_VERTEX_COUNT, batch.instance_count, 0, batch.first_instance);
+            }
+            device.cmd_end_render_pass(command_buffer);
+        }
+
+        if !depth_readable {
+            forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER, false);
+        }
+    }
+}
+
+/// Asks the texture cache for the textures of decals.
+pub fn request_decal_textures_system(mut runner: Option<ResMut<RenderRunner>>,
+                                     asset_server: Res<AssetServer>,
+                                     query: Query<&Decal>) {
+    if let Some(runner) = &mut runner {
+        for decal in query.iter() {
+            runner.texture_cache.request_texture(&asset_server, &decal.texture);
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use std::mem::size_of;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::buffer::Buffer;
+use crate::render::texture::Texture;
+use crate::render::render_context::RenderContext;
+use crate::render::forward_render::{SceneTarget, ShadowPass};
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo, set_flipped_viewport_rect};
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::util;
+
+/// Lights beyond this count are not shaded.
+pub const MAX_POINT_LIGHTS: usize = 256;
+
+const ALBEDO_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const NORMAL_FORMAT: vk::Format = vk::Format::A2B10G10R10_UNORM_PACK32;
+/// metallic, roughness, occlusion and emissive strength
+const MATERIAL_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// Number of color attachments written by G-buffer pipelines.
+pub const GBUFFER_COLOR_ATTACHMENTS: usize = 3;
+
+/// A light at the position of the entity, shaded by the deferred path only. The
+/// forward path shades the main light alone.
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub color: Vec3,
+    pub intensity: f32,
+    /// distance at which the light fades out
+    pub range: f32,
+}
+
+impl Default for PointLight {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ONE,
+            intensity: 1.0,
+            range: 10.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpuPointLight {
+    position_range: Vec4,
+    color_intensity: Vec4,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LightingConstant {
+    /// written where the G-buffer was not drawn
+    clear_color: Vec4,
+    light_count: u32,
+    reverse_z: u32,
+}
+
+/// Render passes of the deferred path. Opaque primitives write the G-buffer and the
+/// scene depth, the lighting pass shades them into the scene color, the decals are
+/// projected on it, then transparent primitives, grass and effects are drawn forward
+/// over the result.
+///
+/// Owned by `ForwardRenderPass` when `RenderConfig::render_path` is deferred, it shares
+/// the scene target and the shadow map. Offscreen cameras and other windows are always
+/// rendered forward.
+pub struct DeferredRenderPass {
+    albedo_texture: Texture,
+    albedo_view: vk::ImageView,
+    normal_texture: Texture,
+    normal_view: vk::ImageView,
+    material_texture: Texture,
+    material_view: vk::ImageView,
+    gbuffer_pass: vk::RenderPass,
+    gbuffer_frame_buffer: vk::Framebuffer,
+    lighting_pass: vk::RenderPass,
+    lighting_frame_buffer: vk::Framebuffer,
+    /// compatible with the forward pass, loads the lit color and the G-buffer depth
+    transparent_pass: vk::RenderPass,
+    sampler: vk::Sampler,
+    light_buffer: Buffer,
+    light_count: u32,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_set: vk::DescriptorSet,
+    lighting_pipeline: Option<GraphicPipeline>,
+    extent: vk::Extent2D,
+}
+
+impl DeferredRenderPass {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        if let Some(pipeline) = self.lighting_pipeline.as_mut() {
+            pipeline.destroy(context);
+        }
+        self.light_buffer.destroy(context);
+        self.albedo_texture.destroy(context);
+        self.normal_texture.destroy(context);
+        self.material_texture.destroy(context);
+        let device = &context.device;
+        unsafe {
+            device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_image_view(self.albedo_view, None);
+            device.destroy_image_view(self.normal_view, None);
+            device.destroy_image_view(self.material_view, None);
+            device.destroy_framebuffer(self.gbuffer_frame_buffer, None);
+            device.destroy_framebuffer(self.lighting_frame_buffer, None);
+            device.destroy_render_pass(self.gbuffer_pass, None);
+            device.destroy_render_pass(self.lighting_pass, None);
+            device.destroy_render_pass(self.transparent_pass, None);
+        }
+    }
+
+    /// `target` must be single sampled, MSAA is disabled on the deferred path.
+    pub fn create(context: &mut RenderContext, target: &SceneTarget, shadow: &ShadowPass) -> RenderResult<Self> {
+        let extent = target.get_extent();
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+        let create_gbuffer_texture = |context: &RenderContext, format, name| {
+            Texture::create_as_render_target(context, extent.width, extent.height, format,
+                                             vk::SampleCountFlags::TYPE_1, usage, name,
+                                             vk::ImageCreateFlags::empty())
+        };
+        let albedo_texture = create_gbuffer_texture(context, ALBEDO_FORMAT, "gbuffer_albedo")?;
+        let normal_texture = create_gbuffer_texture(context, NORMAL_FORMAT, "gbuffer_normal")?;
+        let material_texture = create_gbuffer_texture(context, MATERIAL_FORMAT, "gbuffer_material")?;
+        let albedo_view = albedo_texture.create_color_view(context)?;
+        let normal_view = normal_texture.create_color_view(context)?;
+        let material_view = material_texture.create_color_view(context)?;
+
+        let gbuffer_pass = Self::create_gbuffer_pass(context)?;
+        let lighting_pass = Self::create_lighting_pass(context)?;
+        let transparent_pass = Self::create_transparent_pass(context)?;
+
+        let gbuffer_views = [albedo_view, normal_view, material_view, target.get_depth_view()];
+        let gbuffer_frame_buffer = Self::create_frame_buffer(context, gbuffer_pass, &gbuffer_views, extent)?;
+        let lighting_views = [target.get_color_view()];
+        let lighting_frame_buffer = Self::create_frame_buffer(context, lighting_pass, &lighting_views, extent)?;
+
+        let sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+
+        let light_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                                        (MAX_POINT_LIGHTS * size_of::<GpuPointLight>()) as _);
+
+        let mut deferred = Self {
+            albedo_texture,
+            albedo_view,
+            normal_texture,
+            normal_view,
+            material_texture,
+            material_view,
+            gbuffer_pass,
+            gbuffer_frame_buffer,
+            lighting_pass,
+            lighting_frame_buffer,
+            transparent_pass,
+            sampler,
+            light_buffer,
+            light_count: 0,
+            descriptor_set_layout: vk::DescriptorSetLayout::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+            lighting_pipeline: None,
+            extent,
+        };
+
+        match deferred.create_descriptors(context, target, shadow) {
+            Ok((descriptor_set_layout, descriptor_set)) => {
+                deferred.descriptor_set_layout = descriptor_set_layout;
+                deferred.descriptor_set = descriptor_set;
+            }
+            Err(e) => {
+                deferred.destroy(context);
+                return Err(e);
+            }
+        }
+
+        match deferred.create_lighting_pipeline(context) {
+            Ok(pipeline) => deferred.lighting_pipeline = Some(pipeline),
+            Err(e) => {
+                deferred.destroy(context);
+                return Err(e);
+            }
+        }
+
+        Ok(deferred)
+    }
+
+    fn create_frame_buffer(context: &RenderContext, render_pass: vk::RenderPass, views: &[vk::ImageView],
+                           extent: vk::Extent2D) -> RenderResult<vk::Framebuffer> {
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1)
+            .width(extent.width).height(extent.height).attachments(views).build();
+        unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) }
+            .map_err(RenderError::vk("vkCreateFramebuffer"))
+    }
+
+    fn create_gbuffer_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let gbuffer_attachment = |format| vk::AttachmentDescription {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..Default::default()
+        };
+        let attachments = [
+            gbuffer_attachment(ALBEDO_FORMAT),
+            gbuffer_attachment(NORMAL_FORMAT),
+            gbuffer_attachment(MATERIAL_FORMAT),
+            // the scene depth, read by the lighting pass and tested by the transparent pass
+            vk::AttachmentDescription {
+                format: context.render_config.depth_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = (0..GBUFFER_COLOR_ATTACHMENTS as u32).map(|attachment| vk::AttachmentReference {
+            attachment,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }).collect::<Vec<_>>();
+        let depth_attachment_ref = vk::AttachmentReference {
+            attachment: GBUFFER_COLOR_ATTACHMENTS as u32,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: vk::AccessFlags::SHADER_READ,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+            vk::SubpassDependency {
+                src_subpass: 0,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    fn create_lighting_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: context.render_config.color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// Same attachments and subpass as the single sampled forward pass so forward
+    /// pipelines and the scene framebuffer can be used with it.
+    fn create_transparent_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let render_config = &context.render_config;
+        let attachments = [
+            vk::AttachmentDescription {
+                format: render_config.color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+            vk::AttachmentDescription {
+                format: render_config.depth_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_attachment_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE |
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// The G-buffer, the scene depth, the shadow map and the lights, checked against the
+    /// lighting shaders.
+    fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 6] {
+        let image_binding = |binding| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build();
+        [
+            image_binding(0),
+            image_binding(1),
+            image_binding(2),
+            image_binding(3),
+            image_binding(4),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(5)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ]
+    }
+
+    fn create_descriptors(&self, context: &mut RenderContext, target: &SceneTarget,
+                          shadow: &ShadowPass) -> RenderResult<(vk::DescriptorSetLayout, vk::DescriptorSet)> {
+        let bindings = Self::set_layout_bindings();
+
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+
+        let set_layout = unsafe { context.device.create_descriptor_set_layout(&layout_info, None) }
+            .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?;
+
+        let layouts = [set_layout];
+
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = match unsafe { context.device.allocate_descriptor_sets(&allocate_info) } {
+            Ok(sets) => sets[0],
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                return Err(RenderError::vk("vkAllocateDescriptorSets")(e));
+            }
+        };
+
+        let image_info = |view, sampler, layout| [vk::DescriptorImageInfo::builder()
+            .image_layout(layout)
+            .image_view(view)
+            .sampler(sampler)
+            .build()];
+        let albedo_info = image_info(self.albedo_view, self.sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let normal_info = image_info(self.normal_view, self.sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let material_info = image_info(self.material_view, self.sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let depth_info = image_info(target.get_depth_view(), self.sampler, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        let shadow_info = image_info(shadow.shadow_view, shadow.sampler, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        let light_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(self.light_buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+
+        let image_write = |binding, info: &[vk::DescriptorImageInfo]| vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(binding)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(info)
+            .build();
+        let descriptor_writes = [
+            image_write(0, &albedo_info),
+            image_write(1, &normal_info),
+            image_write(2, &material_info),
+            image_write(3, &depth_info),
+            image_write(4, &shadow_info),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(5)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_info)
+                .build(),
+        ];
+
+        unsafe {
+            context
+                .device
+                .update_descriptor_sets(&descriptor_writes, &[])
+        }
+
+        Ok((set_layout, set))
+    }
+
+    fn create_lighting_pipeline(&self, context: &mut RenderContext) -> RenderResult<GraphicPipeline> {
+        let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+        let set_layouts = [frame_uniform_layout, self.descriptor_set_layout];
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<LightingConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT).build(),
+        ];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&constant_ranges)
+            .build();
+
+        let bindings = Self::set_layout_bindings();
+        context.shader_modules.check_stages(&context.device, &["deferred_lighting_vert", "deferred_lighting_frag"], &[],
+                                            &[(1, &bindings[..])])?;
+
+        let vertex_input = PipelineVertexInputInfo::from(&[], &[]).with_cull_mode(vk::CullModeFlags::NONE);
+        GraphicPipeline::create(context, self.lighting_pass, &vertex_input, &pipeline_layout_ci,
+                                vk::SampleCountFlags::TYPE_1,
+                                "deferred_lighting_vert", "deferred_lighting_frag", &[])
+    }
+
+    /// Render pass of the G-buffer pipelines, which write `GBUFFER_COLOR_ATTACHMENTS` colors.
+    pub fn get_gbuffer_render_pass(&self) -> vk::RenderPass {
+        self.gbuffer_pass
+    }
+
+    /// Copies the lights for the lighting passes of this frame, at most `MAX_POINT_LIGHTS`.
+    pub fn set_lights<'a>(&mut self, context: &RenderContext, lights: impl Iterator<Item=(&'a PointLight, &'a GlobalTransform)>) {
+        let lights = lights.take(MAX_POINT_LIGHTS).map(|(light, transform)| GpuPointLight {
+            position_range: transform.translation.extend(light.range),
+            color_intensity: light.color.extend(light.intensity),
+        }).collect::<Vec<_>>();
+        self.light_count = lights.len() as u32;
+        if !lights.is_empty() {
+            self.light_buffer.upload_data(context, &lights);
+        }
+    }
+
+    pub fn begin_gbuffer_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let clear_values = [
+            vk::ClearValue { color: vk::ClearColorValue { float32: [0.0; 4] } },
+            vk::ClearValue { color: vk::ClearColorValue { float32: [0.0; 4] } },
+            vk::ClearValue { color: vk::ClearColorValue { float32: [0.0; 4] } },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: context.render_config.clear_depth(),
+                    stencil: 0,
+                },
+            },
+        ];
+        self.begin_pass(context, command_buffer, self.gbuffer_pass, self.gbuffer_frame_buffer, &clear_values);
+    }
+
+    /// Clears the G-buffer and depth inside `rect` and restricts drawing to it.
+    pub fn clear_view(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, rect: vk::Rect2D) {
+        let mut attachments = (0..GBUFFER_COLOR_ATTACHMENTS as u32).map(|color_attachment| vk::ClearAttachment {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            color_attachment,
+            clear_value: vk::ClearValue { color: vk::ClearColorValue { float32: [0.0; 4] } },
+        }).collect::<Vec<_>>();
+        attachments.push(vk::ClearAttachment {
+            aspect_mask: vk::ImageAspectFlags::DEPTH,
+            color_attachment: 0,
+            clear_value: vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: context.render_config.clear_depth(),
+                    stencil: 0,
+                },
+            },
+        });
+        let rects = [vk::ClearRect {
+            rect,
+            base_array_layer: 0,
+            layer_count: 1,
+        }];
+
+        unsafe {
+            context.device.cmd_clear_attachments(command_buffer, &attachments, &rects);
+        }
+        set_flipped_viewport_rect(context, command_buffer, rect);
+    }
+
+    pub fn begin_lighting_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let clear_values = [vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] } }];
+        self.begin_pass(context, command_buffer, self.lighting_pass, self.lighting_frame_buffer, &clear_values);
+    }
+
+    /// Shades the G-buffer inside `rect` with the main light and the point lights.
+    /// `frame_descriptor_set` holds the `PerFrameData` of the camera that drew the G-buffer.
+    pub fn draw_lighting(&self, context: &RenderContext, command_buffer: vk::CommandBuffer,
+                         frame_descriptor_set: vk::DescriptorSet, rect: vk::Rect2D, clear_color: Vec4) {
+        let pipeline = match &self.lighting_pipeline {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        let constant = LightingConstant {
+            clear_color,
+            light_count: self.light_count,
+            reverse_z: context.render_config.reverse_z as u32,
+        };
+
+        set_flipped_viewport_rect(context, command_buffer, rect);
+        let device = &context.device;
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_pipeline());
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_layout(),
+                                            0, &[frame_descriptor_set, self.descriptor_set], &[]);
+            device.cmd_push_constants(command_buffer, pipeline.get_layout(), vk::ShaderStageFlags::FRAGMENT, 0,
+                                      util::any_as_u8_slice(&constant));
+            // a single triangle covering the viewport
+            device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+    }
+
+    /// Begins the forward pass over the lit scene of `target`, nothing is cleared.
+    pub fn begin_transparent_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, target: &SceneTarget) {
+        self.begin_pass(context, command_buffer, self.transparent_pass, target.get_frame_buffer(), &[]);
+    }
+
+    pub fn end_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            context.device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    fn begin_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass,
+                  frame_buffer: vk::Framebuffer, clear_values: &[vk::ClearValue]) {
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .clear_values(clear_values)
+            .build();
+
+        unsafe {
+            context.device.cmd_begin_render_pass(
+                command_buffer,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            )
+        };
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use ash::vk;
+use ash::vk::Handle;
+use crate::render::render_context::RenderContext;
+use crate::render::buffer::Buffer;
+use crate::render::texture::Texture;
+use crate::render::graphic_pipeline::GraphicPipeline;
+use crate::render::mesh::Meshes;
+use crate::render::model::{Model, ModelTextures};
+
+/// GPU objects handed to `RenderContext::defer_destroy`, destroyed once every frame
+/// that may have recorded them has retired.
+pub trait DeferredDestroy: 'static + Send + Sync {
+    fn destroy_deferred(&mut self, context: &mut RenderContext);
+
+    /// Handles renamed after the label when `debug_object_names` is on, so the
+    /// validation layer names a retired object that is still used.
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        vec![]
+    }
+}
+
+impl DeferredDestroy for Buffer {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        vec![(vk::ObjectType::BUFFER, self.buffer.as_raw())]
+    }
+}
+
+impl DeferredDestroy for Texture {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        vec![(vk::ObjectType::IMAGE, self.get_image().as_raw())]
+    }
+}
+
+impl DeferredDestroy for GraphicPipeline {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        vec![(vk::ObjectType::PIPELINE, self.get_pipeline().as_raw())]
+    }
+}
+
+impl DeferredDestroy for Meshes {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        self.buffer.debug_objects()
+    }
+}
+
+impl DeferredDestroy for ModelTextures {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        self.textures.iter().flat_map(|t| t.texture.debug_objects()).collect()
+    }
+}
+
+impl DeferredDestroy for Model {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        let mut objects = self.get_buffer().debug_objects();
+        objects.extend(self.get_textures().iter().flat_map(|t| t.texture.debug_objects()));
+        objects
+    }
+}
+
+struct Retired {
+    frame: u64,
+    label: String,
+    object: Box<dyn DeferredDestroy>,
+}
+
+/// Objects retired by the renderer, ordered by the frame they were retired in.
+#[derive(Default)]
+pub struct DeletionQueue {
+    frame: u64,
+    pending: VecDeque<Retired>,
+}
+
+impl DeletionQueue {
+    /// Number of frames begun so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn push(&mut self, label: String, object: Box<dyn DeferredDestroy>) {
+        self.pending.push_back(Retired { frame: self.frame, label, object });
+    }
+
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Removes the objects retired at least `frames_in_flight` frames ago.
+    pub fn take_retired(&mut self, frames_in_flight: u64) -> Vec<(String, Box<dyn DeferredDestroy>)> {
+        let mut retired = vec![];
+        while let Some(front) = self.pending.front() {
+            if front.frame + frames_in_flight > self.frame {
+                break;
+            }
+            let r = self.pending.pop_front().unwrap();
+            retired.push((r.label, r.object));
+        }
+        retired
+    }
+
+    /// Removes every object, the device must be idle.
+    pub fn take_all(&mut self) -> Vec<(String, Box<dyn DeferredDestroy>)> {
+        self.pending.drain(..).map(|r| (r.label, r.object)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dummy;
+
+    impl DeferredDestroy for Dummy {
+        fn destroy_deferred(&mut self, _context: &mut RenderContext) {}
+    }
+
+    fn labels(retired: Vec<(String, Box<dyn DeferredDestroy>)>) -> Vec<String> {
+        retired.into_iter().map(|(label, _)| label).collect()
+    }
+
+    #[test]
+    fn retired_after_frames_in_flight() {
+        let mut queue = DeletionQueue::default();
+        queue.push("a".to_string(), Box::new(Dummy));
+
+        queue.next_frame();
+        assert!(queue.take_retired(2).is_empty());
+        assert_eq!(queue.len(), 1);
+
+        queue.next_frame();
+        assert_eq!(labels(queue.take_retired(2)), vec!["a"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn kept_in_the_frame_it_was_retired() {
+        let mut queue = DeletionQueue::default();
+        queue.push("a".to_string(), Box::new(Dummy));
+        assert!(queue.take_retired(1).is_empty());
+
+        queue.next_frame();
+        assert_eq!(labels(queue.take_retired(1)), vec!["a"]);
+    }
+
+    #[test]
+    fn taken_in_retire_order() {
+        let mut queue = DeletionQueue::default();
+        queue.push("a".to_string(), Box::new(Dummy));
+        queue.push("b".to_string(), Box::new(Dummy));
+        queue.next_frame();
+        queue.push("c".to_string(), Box::new(Dummy));
+        assert_eq!(queue.frame(), 1);
+
+        queue.next_frame();
+        assert_eq!(labels(queue.take_retired(2)), vec!["a", "b"]);
+        assert_eq!(queue.len(), 1);
+
+        queue.next_frame();
+        assert_eq!(labels(queue.take_retired(2)), vec!["c"]);
+    }
+
+    #[test]
+    fn take_all_ignores_frames() {
+        let mut queue = DeletionQueue::default();
+        queue.push("a".to_string(), Box::new(Dummy));
+        queue.next_frame();
+        queue.push("b".to_string(), Box::new(Dummy));
+
+        assert_eq!(labels(queue.take_all()), vec!["a", "b"]);
+        assert!(queue.is_empty());
+        assert!(queue.take_retired(0).is_empty());
+    }
+}
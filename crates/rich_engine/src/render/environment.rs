@@ -0,0 +1,115 @@
+use bevy::{
+    prelude::*,
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+
+/// Latitude above and below the horizon averaged into `EnvironmentMap::horizon_color`.
+const HORIZON_BAND_DEGREES: f32 = 10.0;
+
+/// An equirectangular image around the scene, loaded from `*.env.png` and `*.env.jpg`
+/// files. Only the colors derived from it are kept.
+#[derive(Debug, Clone, PartialEq, TypeUuid)]
+#[uuid = "4de5de33-00e6-4ccf-abd3-f5874a2bd18f"]
+pub struct EnvironmentMap {
+    /// linear average of the band around the horizon, where fog is seen against the sky
+    pub horizon_color: Vec3,
+}
+
+impl EnvironmentMap {
+    pub fn from_image(image: &image::RgbaImage) -> Self {
+        Self { horizon_color: horizon_color(image) }
+    }
+}
+
+/// Environment of the scene, cameras with `FogColor::Environment` take their fog
+/// color from it.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub map: Option<Handle<EnvironmentMap>>,
+}
+
+impl Environment {
+    /// `None` until the map is loaded.
+    pub fn horizon_color(&self, maps: &Assets<EnvironmentMap>) -> Option<Vec3> {
+        self.map.as_ref().and_then(|h| maps.get(h)).map(|m| m.horizon_color)
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn horizon_color(image: &image::RgbaImage) -> Vec3 {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Vec3::ZERO;
+    }
+
+    // rows span 180 degrees of latitude, the horizon is the middle row
+    let half_band = (height as f32 * HORIZON_BAND_DEGREES / 180.0) as u32;
+    let first = (height / 2).saturating_sub(half_band);
+    let last = (height / 2 + half_band).min(height - 1);
+
+    let mut sum = Vec3::ZERO;
+    for y in first..=last {
+        for x in 0..width {
+            let p = image.get_pixel(x, y);
+            sum += Vec3::new(srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2]));
+        }
+    }
+    sum / ((last - first + 1) * width) as f32
+}
+
+#[derive(Default)]
+pub struct EnvironmentMapLoader;
+
+impl AssetLoader for EnvironmentMapLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let image = image::load_from_memory(bytes)?.to_rgba8();
+            load_context.set_default_asset(LoadedAsset::new(EnvironmentMap::from_image(&image)));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["env.png", "env.jpg", "env.jpeg"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_endpoints() {
+        assert_eq!(srgb_to_linear(0), 0.0);
+        assert!((srgb_to_linear(255) - 1.0).abs() < 1e-6);
+        assert!((srgb_to_linear(188) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn horizon_ignores_sky_and_ground() {
+        // sky above, white horizon band, black ground below
+        let image = image::RgbaImage::from_fn(8, 18, |_, y| match y {
+            0..=7 => image::Rgba([0, 0, 255, 255]),
+            8..=10 => image::Rgba([255, 255, 255, 255]),
+            _ => image::Rgba([0, 0, 0, 255]),
+        });
+        let color = horizon_color(&image);
+        assert!((color - Vec3::ONE).abs().max_element() < 1e-6, "{:?}", color);
+    }
+
+    #[test]
+    fn thin_image_uses_the_middle_row() {
+        let image = image::RgbaImage::from_fn(4, 2, |_, y| if y == 1 {
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            image::Rgba([0, 255, 0, 255])
+        });
+        assert_eq!(horizon_color(&image), Vec3::new(1.0, 0.0, 0.0));
+    }
+}
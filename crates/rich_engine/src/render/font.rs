@@ -0,0 +1,187 @@
+use bevy::{
+    prelude::*,
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+/// Font used for an empty font path, relative to `assets/`.
+pub const DEFAULT_FONT: &str = "fonts/default.font.ron";
+
+/// A bitmap font, loaded from `*.font.ron` files. The image holds the glyphs in a grid
+/// of equal cells, row by row in character order from `first_char`. Characters
+/// outside the grid advance a full cell without drawing.
+#[derive(Debug, Clone, Deserialize, Serialize, TypeUuid)]
+#[uuid = "c29a885d-911c-47e6-80c3-045b2fa93acf"]
+pub struct GlyphAtlas {
+    /// image file relative to `assets/`
+    pub texture: String,
+    pub columns: u32,
+    pub rows: u32,
+    pub first_char: char,
+    /// advance of each glyph from `first_char` as a part of the cell width, glyphs
+    /// past the end advance a full cell
+    #[serde(default)]
+    pub advances: Vec<f32>,
+}
+
+impl GlyphAtlas {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let atlas: GlyphAtlas = ron::de::from_bytes(bytes)?;
+        if atlas.columns == 0 || atlas.rows == 0 {
+            anyhow::bail!("empty glyph grid");
+        }
+        Ok(atlas)
+    }
+}
+
+#[derive(Default)]
+pub struct GlyphAtlasLoader;
+
+impl AssetLoader for GlyphAtlasLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(GlyphAtlas::from_bytes(bytes)?));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["font.ron"]
+    }
+}
+
+/// `DEFAULT_FONT` for an empty path.
+pub(crate) fn font_path(path: &str) -> &str {
+    if path.is_empty() { DEFAULT_FONT } else { path }
+}
+
+/// A glyph atlas with the size of its loaded texture.
+pub(crate) struct Font {
+    pub atlas: GlyphAtlas,
+    /// cell width over cell height
+    cell_aspect: f32,
+}
+
+impl Font {
+    pub fn new(atlas: GlyphAtlas, (width, height): (u32, u32)) -> Self {
+        let cell_aspect = (width as f32 / atlas.columns as f32) / (height as f32 / atlas.rows as f32);
+        Self { atlas, cell_aspect }
+    }
+
+    /// Quads of the glyphs as xy bottom left and zw top right with y up, and their uv
+    /// rect. The last line sits on 0, `align` places each line from its left at 0 to
+    /// its right at 0.
+    pub fn layout(&self, text: &str, size: f32, align: f32) -> Vec<(Vec4, Vec4)> {
+        let atlas = &self.atlas;
+        let cell_width = size * self.cell_aspect;
+        let glyph_count = atlas.columns * atlas.rows;
+        let lines = text.lines().collect::<Vec<_>>();
+        let advance = |c: char| {
+            let index = (c as u32).wrapping_sub(atlas.first_char as u32);
+            let part = atlas.advances.get(index as usize).copied().unwrap_or(1.0);
+            (index, part * cell_width)
+        };
+
+        let mut glyphs = Vec::new();
+        for (line_index, line) in lines.iter().enumerate() {
+            let width = line.chars().map(|c| advance(c).1).sum::<f32>();
+            let bottom = (lines.len() - 1 - line_index) as f32 * size;
+
+            let mut pen = -width * align;
+            for c in line.chars() {
+                let (index, advance) = advance(c);
+                if index < glyph_count {
+                    let column = (index % atlas.columns) as f32;
+                    let row = (index / atlas.columns) as f32;
+                    glyphs.push((
+                        Vec4::new(pen, bottom, pen + cell_width, bottom + size),
+                        Vec4::new(column / atlas.columns as f32, row / atlas.rows as f32,
+                                  (column + 1.0) / atlas.columns as f32, (row + 1.0) / atlas.rows as f32),
+                    ));
+                }
+                pen += advance;
+            }
+        }
+        glyphs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 by 2 cells of 8 by 16 pixels from 'A', 'B' half as wide as a cell
+    fn font() -> Font {
+        let atlas = GlyphAtlas {
+            texture: String::new(),
+            columns: 4,
+            rows: 2,
+            first_char: 'A',
+            advances: vec![1.0, 0.5],
+        };
+        Font::new(atlas, (32, 32))
+    }
+
+    #[test]
+    fn glyphs_advance_along_the_line() {
+        let glyphs = font().layout("ABA", 10.0, 0.0);
+        let rects = glyphs.iter().map(|(rect, _)| *rect).collect::<Vec<_>>();
+        // cells are half as wide as high
+        assert_eq!(rects, vec![
+            Vec4::new(0.0, 0.0, 5.0, 10.0),
+            Vec4::new(5.0, 0.0, 10.0, 10.0),
+            Vec4::new(7.5, 0.0, 12.5, 10.0),
+        ]);
+    }
+
+    #[test]
+    fn uv_rects_follow_the_grid() {
+        let glyphs = font().layout("AF", 10.0, 0.0);
+        assert_eq!(glyphs[0].1, Vec4::new(0.0, 0.0, 0.25, 0.5));
+        // 'F' is the second glyph of the second row
+        assert_eq!(glyphs[1].1, Vec4::new(0.25, 0.5, 0.5, 1.0));
+    }
+
+    #[test]
+    fn align_moves_each_line() {
+        let font = font();
+        let left = |text: &str, align: f32| font.layout(text, 10.0, align).iter()
+            .map(|(rect, _)| rect.x).collect::<Vec<_>>();
+        assert_eq!(left("AA", 0.5), vec![-5.0, 0.0]);
+        assert_eq!(left("AA", 1.0), vec![-10.0, -5.0]);
+        // lines are aligned on their own width
+        assert_eq!(left("AA\nA", 1.0), vec![-10.0, -5.0, -5.0]);
+    }
+
+    #[test]
+    fn last_line_sits_on_zero() {
+        let glyphs = font().layout("A\nA\nA", 10.0, 0.0);
+        let bottoms = glyphs.iter().map(|(rect, _)| rect.y).collect::<Vec<_>>();
+        assert_eq!(bottoms, vec![20.0, 10.0, 0.0]);
+    }
+
+    #[test]
+    fn characters_outside_the_grid_only_advance() {
+        // ' ' is before 'A' and 'Z' past the 8 glyphs
+        let glyphs = font().layout(" A Z A", 10.0, 0.0);
+        let rects = glyphs.iter().map(|(rect, _)| rect.x).collect::<Vec<_>>();
+        assert_eq!(rects, vec![5.0, 25.0]);
+    }
+
+    #[test]
+    fn advances_default_to_a_full_cell() {
+        let atlas: GlyphAtlas = ron::de::from_str("(texture: \"font.png\", columns: 16, rows: 6, first_char: ' ')").unwrap();
+        assert!(atlas.advances.is_empty());
+        let font = Font::new(atlas, (256, 96));
+        let glyphs = font.layout("!!", 16.0, 0.0);
+        assert_eq!(glyphs[1].0.x, 16.0);
+    }
+
+    #[test]
+    fn empty_grid_is_rejected() {
+        assert!(GlyphAtlas::from_bytes(b"(texture: \"font.png\", columns: 0, rows: 6, first_char: ' ')").is_err());
+        assert!(GlyphAtlas::from_bytes(b"(texture: \"font.png\", columns: 16, rows: 6, first_char: ' ')").is_ok());
+    }
+}
@@ -2,20 +2,129 @@
 use ash::vk;
 use crate::render::render_context::{RenderContext, RenderConfig};
 use crate::render::swapchain_mgr::SwapChainMgr;
-use ash::vk::{ImageLayout, ImageView};
+use ash::vk::{Handle, ImageLayout, ImageView};
 use crate::render::model_renderer::ModelRenderer;
 use crate::render::command_buffer_list::CommandBufferList;
-
-pub struct ForwardRenderPass {
+use crate::render::graphic_pipeline::{set_flipped_viewport, set_flipped_viewport_rect};
+use bevy::math::Vec4;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_settings::{RenderPath, AmbientOcclusion};
+use crate::render::deferred_render::DeferredRenderPass;
+use crate::render::ssao::SsaoPass;
+use crate::render::taa::TaaPass;
+use crate::render::deletion_queue::DeferredDestroy;
+
+/// Color, depth and the optional MSAA resolve image of one forward pass framebuffer.
+pub struct SceneTarget {
     color_texture: Texture,
     color_view: vk::ImageView,
     depth_texture: Texture,
     depth_view: vk::ImageView,
     resolve_texture: Option<Texture>,
     resolve_view: Option<vk::ImageView>,
-    render_pass: vk::RenderPass,
     frame_buffer: vk::Framebuffer,
+    extent: vk::Extent2D,
+}
+
+impl SceneTarget {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        let device = &context.device;
+        if let Some(rt) = self.resolve_texture.as_mut() {
+            rt.destroy(context);
+            unsafe {
+                device.destroy_image_view(self.resolve_view.unwrap(), None);
+            }
+        }
+
+        self.color_texture.destroy(context);
+        self.depth_texture.destroy(context);
+
+        unsafe {
+            device.destroy_image_view(self.color_view, None);
+            device.destroy_image_view(self.depth_view, None);
+            device.destroy_framebuffer(self.frame_buffer, None);
+        }
+    }
+
+    pub fn get_extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Makes the final image readable by shaders after the pass ended.
+    pub fn cmd_barrier_for_sampling(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let image_barriers = [
+            vk::ImageMemoryBarrier::builder().image(self.get_final_render_image())
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE).dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                }).build(),
+        ];
+
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::DependencyFlags::empty(), &[], &[],
+                                                &image_barriers);
+        }
+    }
+
+    pub fn get_final_render_image_view(&self) -> vk::ImageView {
+        self.resolve_view.unwrap_or(self.color_view)
+    }
+
+    pub fn get_color_view(&self) -> vk::ImageView {
+        self.color_view
+    }
+
+    pub fn get_depth_view(&self) -> vk::ImageView {
+        self.depth_view
+    }
+
+    pub fn get_frame_buffer(&self) -> vk::Framebuffer {
+        self.frame_buffer
+    }
+
+    pub fn get_final_render_image(&self) -> vk::Image {
+        match &self.resolve_texture {
+            Some(rt) => rt.get_image(),
+            _ => self.color_texture.get_image(),
+        }
+    }
+}
+
+impl DeferredDestroy for SceneTarget {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        let mut objects = self.color_texture.debug_objects();
+        objects.extend(self.depth_texture.debug_objects());
+        objects.push((vk::ObjectType::FRAMEBUFFER, self.frame_buffer.as_raw()));
+        objects
+    }
+}
+
+pub struct ForwardRenderPass {
+    /// window target, scene resolution is the window size scaled by the render scale
+    target: SceneTarget,
+    render_pass: vk::RenderPass,
+    /// compatible with `render_pass`, loads the window target to draw blended geometry
+    /// over the decals
+    transparent_pass: vk::RenderPass,
     shadow: ShadowPass,
+    /// G-buffer and lighting passes drawing the window target on the deferred path
+    deferred: Option<DeferredRenderPass>,
+    /// ambient occlusion of the main camera, `None` when off
+    ssao: Option<SsaoPass>,
+    /// temporal anti-aliasing of the window target, `None` when off
+    taa: Option<TaaPass>,
 }
 
 pub struct ShadowPass {
@@ -41,53 +150,30 @@ impl ShadowPass {
 
 impl ForwardRenderPass {
     pub fn destroy(&mut self, context: &RenderContext) {
-        let device = &context.device;
-        if let Some(rt) = self.resolve_texture.as_mut() {
-            rt.destroy(context);
-            unsafe {
-                device.destroy_image_view(self.resolve_view.unwrap(), None);
-            }
+        if let Some(deferred) = self.deferred.as_mut() {
+            deferred.destroy(context);
         }
-
-        self.color_texture.destroy(context);
-        unsafe {
-            device.destroy_image_view(self.color_view, None);
+        if let Some(ssao) = self.ssao.as_mut() {
+            ssao.destroy(context);
         }
-
-        self.depth_texture.destroy(context);
-
+        if let Some(taa) = self.taa.as_mut() {
+            taa.destroy(context);
+        }
+        self.target.destroy(context);
         unsafe {
-            device.destroy_image_view(self.depth_view, None);
-            device.destroy_framebuffer(self.frame_buffer, None);
-            device.destroy_render_pass(self.render_pass, None);
+            context.device.destroy_render_pass(self.render_pass, None);
+            context.device.destroy_render_pass(self.transparent_pass, None);
         }
 
         self.shadow.destroy(context);
     }
 
-    pub fn create(context: &mut RenderContext, swap_chain_mgr: &SwapChainMgr, command_list: &CommandBufferList) -> Self {
+    pub fn create(context: &mut RenderContext, swap_chain_mgr: &SwapChainMgr, command_list: &CommandBufferList) -> RenderResult<Self> {
         unsafe {
             let render_config = &context.render_config;
             let msaa_on = render_config.msaa != vk::SampleCountFlags::TYPE_1;
             let msaa = render_config.msaa;
 
-            let color_texture =
-                Texture::create_as_render_target(context, context.window_width,
-                                                 context.window_height, render_config.color_format,
-                                                 msaa,
-                                                 vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE |
-                                                     vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::SAMPLED,
-                                                 "color_render_texture", vk::ImageCreateFlags::empty());
-
-            let color_view = color_texture.create_color_view(context);
-
-            let depth_texture =
-                Texture::create_as_depth_stencil(context, context.window_width,
-                                                 context.window_height, render_config.depth_format,
-                                                 vk::SampleCountFlags::TYPE_1,
-                                                 "color_render_texture");
-            let depth_view = depth_texture.create_depth_view(context);
-
             let mut renderpass_attachment = vec![
                 // render target
                 vk::AttachmentDescription {
@@ -120,7 +206,7 @@ impl ForwardRenderPass {
                 renderpass_attachment.push(
                     vk::AttachmentDescription {
                         flags: Default::default(),
-                        format: swap_chain_mgr.format,
+                        format: render_config.color_format,
                         samples: vk::SampleCountFlags::TYPE_1,
                         load_op: vk::AttachmentLoadOp::DONT_CARE,
                         store_op: vk::AttachmentStoreOp::STORE,
@@ -170,62 +256,245 @@ impl ForwardRenderPass {
 
             let renderpass_create_info = vk::RenderPassCreateInfo::builder()
                 .attachments(&renderpass_attachment).subpasses(&subpasses).dependencies(&dependencies).build();
-            let render_pass = context.device.create_render_pass(&renderpass_create_info, None).unwrap();
+            let render_pass = context.device.create_render_pass(&renderpass_create_info, None)
+                .map_err(RenderError::vk("vkCreateRenderPass"))?;
+            let transparent_pass = match Self::create_transparent_pass(context) {
+                Ok(transparent_pass) => transparent_pass,
+                Err(e) => {
+                    context.device.destroy_render_pass(render_pass, None);
+                    return Err(e);
+                }
+            };
 
-            let mut frame_buffer_views = vec![
-                color_view,
-                depth_view,
-            ];
+            let mut target = Self::create_scene_target(context, render_pass, context.render_extent(), "scene")?;
+            let mut shadow = Self::create_shadow(context)?;
+            let deferred = match context.render_config.render_path {
+                RenderPath::Deferred => match DeferredRenderPass::create(context, &target, &shadow) {
+                    Ok(deferred) => Some(deferred),
+                    Err(e) => {
+                        target.destroy(context);
+                        shadow.destroy(context);
+                        context.device.destroy_render_pass(render_pass, None);
+                        context.device.destroy_render_pass(transparent_pass, None);
+                        return Err(e);
+                    }
+                },
+                RenderPath::Forward => None,
+            };
+            let quality = context.render_config.ambient_occlusion;
+            let ssao = match quality {
+                AmbientOcclusion::Off => None,
+                _ => match SsaoPass::create(context, quality, target.get_extent()) {
+                    Ok(ssao) => Some(ssao),
+                    Err(e) => {
+                        if let Some(mut deferred) = deferred {
+                            deferred.destroy(context);
+                        }
+                        target.destroy(context);
+                        shadow.destroy(context);
+                        context.device.destroy_render_pass(render_pass, None);
+                        context.device.destroy_render_pass(transparent_pass, None);
+                        return Err(e);
+                    }
+                },
+            };
+            let taa = match context.render_config.temporal_aa {
+                false => None,
+                true => match TaaPass::create(context, &target) {
+                    Ok(taa) => Some(taa),
+                    Err(e) => {
+                        if let Some(mut ssao) = ssao {
+                            ssao.destroy(context);
+                        }
+                        if let Some(mut deferred) = deferred {
+                            deferred.destroy(context);
+                        }
+                        target.destroy(context);
+                        shadow.destroy(context);
+                        context.device.destroy_render_pass(render_pass, None);
+                        context.device.destroy_render_pass(transparent_pass, None);
+                        return Err(e);
+                    }
+                },
+            };
 
-            let mut resolve_texture: Option<Texture> = None;
+            Ok(ForwardRenderPass {
+                target,
+                render_pass,
+                transparent_pass,
+                shadow,
+                deferred,
+                ssao,
+                taa,
+            })
+        }
+    }
 
-            let mut resolve_view: Option<vk::ImageView> = None;
+    /// Same attachments and subpass as the forward pass, color and depth are loaded
+    /// and multisampled color is resolved again at the end.
+    fn create_transparent_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let render_config = &context.render_config;
+        let msaa_on = render_config.msaa != vk::SampleCountFlags::TYPE_1;
+        let msaa = render_config.msaa;
 
-            if msaa_on {
-                let l_resolve_texture =
-                    Texture::create_as_render_target(context, context.window_width,
-                                                     context.window_height, render_config.color_format,
-                                                     vk::SampleCountFlags::TYPE_1,
-                                                     vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE,
-                                                     "resolve_texture", vk::ImageCreateFlags::empty());
+        let mut attachments = vec![
+            vk::AttachmentDescription {
+                format: render_config.color_format,
+                samples: msaa,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+            vk::AttachmentDescription {
+                format: render_config.depth_format,
+                samples: msaa,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+        if msaa_on {
+            attachments.push(vk::AttachmentDescription {
+                format: render_config.color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::DONT_CARE,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            });
+        }
 
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_attachment_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        let resolve_attachment_refs = [vk::AttachmentReference {
+            attachment: 2,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+
+        // the decals write the color and sample the depth in between
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE |
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
 
-                let l_resolve_view = l_resolve_texture.create_color_view(context);
-                frame_buffer_views.push(l_resolve_view);
+        let mut subpass_builder = vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+        if msaa_on {
+            subpass_builder = subpass_builder.resolve_attachments(&resolve_attachment_refs);
+        }
+        let subpasses = [subpass_builder.build()];
 
-                resolve_texture = Some(l_resolve_texture);
-                resolve_view = Some(l_resolve_view);
-            }
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
 
-            let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1).
-                width(context.window_width).height(context.window_height).attachments(&frame_buffer_views).build();
+    /// Creates a framebuffer compatible with the forward pass, used for the window
+    /// and for cameras rendering to textures.
+    pub fn create_target(&self, context: &RenderContext, extent: vk::Extent2D, name: &str) -> RenderResult<SceneTarget> {
+        Self::create_scene_target(context, self.render_pass, extent, name)
+    }
 
-            let frame_buffer = context.device.create_framebuffer(&frame_buffer_ci, None).unwrap();
+    fn create_scene_target(context: &RenderContext, render_pass: vk::RenderPass, extent: vk::Extent2D, name: &str) -> RenderResult<SceneTarget> {
+        let render_config = &context.render_config;
+        let msaa = render_config.msaa;
+
+        let color_texture =
+            Texture::create_as_render_target(context, extent.width,
+                                             extent.height, render_config.color_format,
+                                             msaa,
+                                             vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE |
+                                                 vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST |
+                                                 vk::ImageUsageFlags::SAMPLED,
+                                             &format!("{}_color", name), vk::ImageCreateFlags::empty())?;
+        let color_view = color_texture.create_color_view(context)?;
+
+        let depth_texture =
+            Texture::create_as_depth_stencil(context, extent.width,
+                                             extent.height, render_config.depth_format,
+                                             msaa,
+                                             &format!("{}_depth", name))?;
+        let depth_view = depth_texture.create_depth_view(context)?;
+
+        let mut frame_buffer_views = vec![
+            color_view,
+            depth_view,
+        ];
 
-            let shadow = Self::create_shadow(context, msaa);
+        let mut resolve_texture: Option<Texture> = None;
+        let mut resolve_view: Option<vk::ImageView> = None;
 
-            ForwardRenderPass {
-                depth_texture,
-                depth_view,
-                color_texture,
-                color_view,
-                render_pass,
-                frame_buffer,
-                resolve_texture,
-                resolve_view,
-                shadow,
-            }
+        if msaa != vk::SampleCountFlags::TYPE_1 {
+            let l_resolve_texture =
+                Texture::create_as_render_target(context, extent.width,
+                                                 extent.height, render_config.color_format,
+                                                 vk::SampleCountFlags::TYPE_1,
+                                                 vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE |
+                                                     vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST |
+                                                     vk::ImageUsageFlags::SAMPLED,
+                                                 &format!("{}_resolve", name), vk::ImageCreateFlags::empty())?;
+
+            let l_resolve_view = l_resolve_texture.create_color_view(context)?;
+            frame_buffer_views.push(l_resolve_view);
+
+            resolve_texture = Some(l_resolve_texture);
+            resolve_view = Some(l_resolve_view);
         }
+
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1).
+            width(extent.width).height(extent.height).attachments(&frame_buffer_views).build();
+
+        let frame_buffer = unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) }
+            .map_err(RenderError::vk("vkCreateFramebuffer"))?;
+
+        Ok(SceneTarget {
+            color_texture,
+            color_view,
+            depth_texture,
+            depth_view,
+            resolve_texture,
+            resolve_view,
+            frame_buffer,
+            extent,
+        })
     }
 
-    fn create_shadow(context: &RenderContext, msaa: vk::SampleCountFlags) -> ShadowPass {
+    fn create_shadow(context: &RenderContext) -> RenderResult<ShadowPass> {
         let shadow_format = context.render_config.depth_format;
         let sd = context.render_config.shadow_map_dim;
         let shadow_texture = Texture::create_as_depth_stencil(context,
                                                               sd as _, sd as _,
-                                                              shadow_format, msaa, "shadow map");
+                                                              shadow_format, vk::SampleCountFlags::TYPE_1, "shadow map")?;
 
-        let shadow_view = shadow_texture.create_depth_view(context);
+        let shadow_view = shadow_texture.create_depth_view(context)?;
 
         let sampler = {
             let sampler_info = vk::SamplerCreateInfo::builder()
@@ -249,7 +518,7 @@ impl ForwardRenderPass {
                 context
                     .device
                     .create_sampler(&sampler_info, None)
-                    .expect("Failed to create sampler")
+                    .map_err(RenderError::vk("vkCreateSampler"))?
             }
         };
 
@@ -257,7 +526,7 @@ impl ForwardRenderPass {
         let shadow_attachments = [
             vk::AttachmentDescription {
                 format: shadow_format,
-                samples: msaa,
+                samples: vk::SampleCountFlags::TYPE_1,
                 initial_layout: vk::ImageLayout::UNDEFINED,
                 final_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
                 load_op: vk::AttachmentLoadOp::CLEAR,
@@ -286,7 +555,8 @@ impl ForwardRenderPass {
         let render_pass_ci = vk::RenderPassCreateInfo::builder().attachments(&shadow_attachments)
             .subpasses(&subpasses).dependencies(&dependence).build();
 
-        let shadow_pass = unsafe { context.device.create_render_pass(&render_pass_ci, None).expect("failed to create shadow render pass") };
+        let shadow_pass = unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))?;
 
         let views = [shadow_view];
         let frame_buffer_ci = vk::FramebufferCreateInfo::builder()
@@ -297,33 +567,75 @@ impl ForwardRenderPass {
             .height(sd as _).build();
 
         let shadow_buffer = unsafe {
-            context.device.create_framebuffer(&frame_buffer_ci, None).expect("failed to create shadow frame buffer")
-        };
+            context.device.create_framebuffer(&frame_buffer_ci, None)
+        }.map_err(RenderError::vk("vkCreateFramebuffer"))?;
 
 
-        ShadowPass {
+        Ok(ShadowPass {
             shadow_texture,
             shadow_view,
             shadow_buffer,
             shadow_pass,
             sampler,
-        }
+        })
     }
 
     pub fn get_color_view(&self) -> vk::ImageView {
-        self.color_view
+        self.target.color_view
     }
 
     pub fn get_color_texture(&self) -> &Texture {
-        &self.color_texture
+        &self.target.color_texture
     }
 
     pub fn get_depth_view(&self) -> vk::ImageView {
-        self.depth_view
+        self.target.depth_view
     }
 
     pub fn get_depth_texture(&self) -> &Texture {
-        &self.depth_texture
+        &self.target.depth_texture
+    }
+
+    /// Moves the scene depth between the attachment layout the scene passes leave it in
+    /// and a read-only layout sampled from `shader_stage`. Move it back before the frame
+    /// ends, the next frame starts the scene passes from the attachment layout.
+    pub fn cmd_depth_barrier(&self, context: &RenderContext, command_buffer: vk::CommandBuffer,
+                             shader_stage: vk::PipelineStageFlags, to_sampling: bool) {
+        let (old_layout, new_layout) = if to_sampling {
+            (vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+        } else {
+            (vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        };
+        let image_barriers = [
+            vk::ImageMemoryBarrier::builder().image(self.target.depth_texture.get_image())
+                .src_access_mask(if to_sampling { vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE } else { vk::AccessFlags::SHADER_READ })
+                .dst_access_mask(if to_sampling { vk::AccessFlags::SHADER_READ } else { vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE })
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                }).build(),
+        ];
+
+        let fragment_tests = vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        let (src_stage, dst_stage) = if to_sampling {
+            (fragment_tests, shader_stage)
+        } else {
+            (shader_stage, fragment_tests)
+        };
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, src_stage, dst_stage,
+                                                vk::DependencyFlags::empty(), &[], &[],
+                                                &image_barriers);
+        }
+    }
+
+    pub fn get_extent(&self) -> vk::Extent2D {
+        self.target.extent
     }
 
     pub fn get_native_render_pass(&self) -> vk::RenderPass {
@@ -338,6 +650,24 @@ impl ForwardRenderPass {
         &self.shadow
     }
 
+    /// `Some` when the window target is drawn by the deferred path.
+    pub fn get_deferred(&self) -> Option<&DeferredRenderPass> {
+        self.deferred.as_ref()
+    }
+
+    pub fn get_deferred_mut(&mut self) -> Option<&mut DeferredRenderPass> {
+        self.deferred.as_mut()
+    }
+
+    /// `Some` when ambient occlusion is on.
+    pub fn get_ssao(&self) -> Option<&SsaoPass> {
+        self.ssao.as_ref()
+    }
+
+    pub fn get_taa(&self) -> Option<&TaaPass> {
+        self.taa.as_ref()
+    }
+
     pub fn begin_shadow_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
         let clear_values = [
             vk::ClearValue {
@@ -349,16 +679,17 @@ impl ForwardRenderPass {
         ];
 
         let sd = context.render_config.shadow_map_dim;
+        let extent = vk::Extent2D {
+            width: sd as _,
+            height: sd as _,
+        };
 
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
             .render_pass(self.shadow.shadow_pass)
             .framebuffer(self.shadow.shadow_buffer)
             .render_area(vk::Rect2D {
                 offset: vk::Offset2D { x: 0, y: 0 },
-                extent: vk::Extent2D {
-                    width: sd as _,
-                    height: sd as _,
-                },
+                extent,
             })
             .clear_values(&clear_values)
             .build();
@@ -370,6 +701,7 @@ impl ForwardRenderPass {
                 vk::SubpassContents::INLINE,
             )
         };
+        set_flipped_viewport(context, command_buffer, extent);
     }
 
     pub fn end_shadow_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
@@ -378,16 +710,48 @@ impl ForwardRenderPass {
         }
     }
 
+    pub fn get_target(&self) -> &SceneTarget {
+        &self.target
+    }
+
+    /// Begins the pass on the window target cleared to black, cameras clear their own
+    /// viewports with `clear_view`.
     pub fn begin_render_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        self.begin_target_pass(context, command_buffer, &self.target, Vec4::new(0.0, 0.0, 0.0, 1.0));
+    }
+
+    /// Continues drawing on the window target after `end_render_pass`, nothing is cleared.
+    pub fn begin_transparent_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.transparent_pass)
+            .framebuffer(self.target.frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.target.extent,
+            })
+            .build();
+
+        unsafe {
+            context.device.cmd_begin_render_pass(
+                command_buffer,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            )
+        };
+        set_flipped_viewport(context, command_buffer, self.target.extent);
+    }
+
+    pub fn begin_target_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer,
+                             target: &SceneTarget, clear_color: Vec4) {
         let clear_values = [
             vk::ClearValue {
                 color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
+                    float32: clear_color.into(),
                 },
             },
             vk::ClearValue {
                 depth_stencil: vk::ClearDepthStencilValue {
-                    depth: 1.0,
+                    depth: context.render_config.clear_depth(),
                     stencil: 0,
                 },
             },
@@ -395,13 +759,10 @@ impl ForwardRenderPass {
 
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
             .render_pass(self.render_pass)
-            .framebuffer(self.frame_buffer)
+            .framebuffer(target.frame_buffer)
             .render_area(vk::Rect2D {
                 offset: vk::Offset2D { x: 0, y: 0 },
-                extent: vk::Extent2D {
-                    width: context.window_width,
-                    height: context.window_height,
-                },
+                extent: target.extent,
             })
             .clear_values(&clear_values)
             .build();
@@ -413,6 +774,43 @@ impl ForwardRenderPass {
                 vk::SubpassContents::INLINE,
             )
         };
+        set_flipped_viewport(context, command_buffer, target.extent);
+    }
+
+    /// Clears color and depth inside `rect` and restricts drawing to it, used when
+    /// several cameras share one target.
+    pub fn clear_view(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, rect: vk::Rect2D, clear_color: Vec4) {
+        let attachments = [
+            vk::ClearAttachment {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                color_attachment: 0,
+                clear_value: vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: clear_color.into(),
+                    },
+                },
+            },
+            vk::ClearAttachment {
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                color_attachment: 0,
+                clear_value: vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: context.render_config.clear_depth(),
+                        stencil: 0,
+                    },
+                },
+            },
+        ];
+        let rects = [vk::ClearRect {
+            rect,
+            base_array_layer: 0,
+            layer_count: 1,
+        }];
+
+        unsafe {
+            context.device.cmd_clear_attachments(command_buffer, &attachments, &rects);
+        }
+        set_flipped_viewport_rect(context, command_buffer, rect);
     }
 
     pub fn end_render_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
@@ -422,24 +820,10 @@ impl ForwardRenderPass {
     }
 
     pub fn get_final_render_image_view(&self) -> vk::ImageView {
-        match &self.resolve_texture {
-            Some(rt) => {
-                self.resolve_view.unwrap()
-            }
-            _ => {
-                self.color_view
-            }
-        }
+        self.target.get_final_render_image_view()
     }
 
     pub fn get_final_render_image(&self) -> vk::Image {
-        match &self.resolve_texture {
-            Some(rt) => {
-                rt.get_image()
-            }
-            _ => {
-                self.color_texture.get_image()
-            }
-        }
+        self.target.get_final_render_image()
     }
-}
\ No newline at end of file
+}
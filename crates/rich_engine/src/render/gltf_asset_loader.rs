@@ -9,6 +9,26 @@ use crate::render::node::Nodes;
 use crate::render::skin::Skin;
 use crate::{Buffer, RenderContext};
 use ash::vk;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::anyhow;
+
+const KHR_TEXTURE_BASISU: &str = "KHR_texture_basisu";
+const KTX2_IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+
+#[derive(Debug)]
+pub enum ImageData {
+    /// 8 bits RGBA pixels
+    Rgba {
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+    },
+    /// KTX2 container, transcoded at upload time since the target format
+    /// depends on what the device supports
+    Ktx2(Vec<u8>),
+}
 
 #[derive(Debug)]
 pub enum GltfData {
@@ -16,37 +36,163 @@ pub enum GltfData {
     Raw {
         document: gltf::Document,
         buffers: Vec<gltf::buffer::Data>,
-        images: Vec<gltf::image::Data>,
+        images: Vec<ImageData>,
+        /// texture index -> KHR_texture_basisu image index
+        basisu_sources: HashMap<usize, usize>,
     },
 }
 
 
 
-#[derive(Debug, TypeUuid)]
+/// Cloning shares the parsed data, models are decoded from a clone on the async compute pool.
+#[derive(Debug, Clone, TypeUuid)]
 #[uuid = "f779f9ea-41cd-48ad-a553-0894d84a4be7"]
 pub struct GltfAsset {
-    data: GltfData,
+    data: Arc<GltfData>,
 }
 
 impl GltfAsset {
     pub fn export(&self) -> (
         &gltf::Document,
         &Vec<gltf::buffer::Data>,
-        &Vec<gltf::image::Data>,
+        &Vec<ImageData>,
     )
     {
-        if let GltfData::Raw { document, buffers, images } = &self.data {
+        if let GltfData::Raw { document, buffers, images, .. } = self.data.as_ref() {
             return (&document, &buffers, &images);
         }
 
         panic!("not raw")
     }
 
+    pub fn get_basisu_source(&self, texture_index: usize) -> Option<usize> {
+        if let GltfData::Raw { basisu_sources, .. } = self.data.as_ref() {
+            return basisu_sources.get(&texture_index).copied();
+        }
+
+        panic!("not raw")
+    }
+
     pub fn set_parsed(&mut self) {
-        self.data = GltfData::Parsed {}
+        self.data = Arc::new(GltfData::Parsed {})
+    }
+}
+
+/// gltf-json drops unknown texture extensions and requires `source`, which is
+/// optional when KHR_texture_basisu is used. The basisu sources are collected
+/// here and patched in as `source` for textures without a fallback image.
+fn extract_basisu_sources(json: &mut gltf::json::Value) -> HashMap<usize, usize> {
+    let mut basisu_sources = HashMap::new();
+    let textures = match json.get_mut("textures").and_then(|t| t.as_array_mut()) {
+        Some(textures) => textures,
+        None => return basisu_sources,
+    };
+
+    for (index, texture) in textures.iter_mut().enumerate() {
+        let source = texture.get("extensions")
+            .and_then(|e| e.get(KHR_TEXTURE_BASISU))
+            .and_then(|e| e.get("source"))
+            .and_then(|s| s.as_u64());
+        if let Some(source) = source {
+            basisu_sources.insert(index, source as usize);
+            if let Some(texture) = texture.as_object_mut() {
+                texture.entry("source").or_insert(gltf::json::Value::from(source));
+            }
+        }
+    }
+
+    basisu_sources
+}
+
+fn parse_document(bytes: &[u8]) -> anyhow::Result<(gltf::Document, Option<Vec<u8>>, HashMap<usize, usize>)> {
+    let (json, blob) = if bytes.starts_with(b"glTF") {
+        let mut glb = gltf::Glb::from_slice(bytes)?;
+        let json: gltf::json::Value = gltf::json::deserialize::from_slice(&glb.json)?;
+        (json, glb.bin.take().map(|x| x.into_owned()))
+    } else {
+        let json: gltf::json::Value = gltf::json::deserialize::from_slice(bytes)?;
+        (json, None)
+    };
+
+    let mut json = json;
+    let basisu_sources = extract_basisu_sources(&mut json);
+    let root: gltf::json::Root = gltf::json::deserialize::from_value(json)?;
+    let document = gltf::Document::from_json(root)?;
+
+    Ok((document, blob, basisu_sources))
+}
+
+fn decode_data_uri(uri: &str) -> anyhow::Result<Vec<u8>> {
+    let data = uri.splitn(2, ";base64,").nth(1).ok_or(anyhow!("unsupported data uri"))?;
+    Ok(base64::decode(data)?)
+}
+
+async fn load_uri(uri: &str, load_context: &LoadContext<'_>) -> anyhow::Result<Vec<u8>> {
+    if uri.starts_with("data:") {
+        decode_data_uri(uri)
+    } else {
+        let path = load_context.path().parent().unwrap_or(Path::new("")).join(uri);
+        Ok(load_context.read_asset_bytes(path).await?)
     }
 }
 
+async fn load_buffers(document: &gltf::Document, blob: Option<Vec<u8>>, load_context: &LoadContext<'_>) -> anyhow::Result<Vec<gltf::buffer::Data>> {
+    let mut blob = blob;
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let mut data = match buffer.source() {
+            gltf::buffer::Source::Uri(uri) => load_uri(uri, load_context).await?,
+            gltf::buffer::Source::Bin => blob.take().ok_or(anyhow!("missing glb blob"))?,
+        };
+        if data.len() < buffer.length() {
+            return Err(anyhow!("buffer {} is shorter than declared", buffer.index()));
+        }
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        buffers.push(gltf::buffer::Data(data));
+    }
+    Ok(buffers)
+}
+
+fn decode_image(bytes: Vec<u8>, mime_type: Option<&str>) -> anyhow::Result<ImageData> {
+    if bytes.starts_with(&KTX2_IDENTIFIER) || mime_type == Some("image/ktx2") {
+        return Ok(ImageData::Ktx2(bytes));
+    }
+
+    let image = match mime_type {
+        Some("image/png") => image::load_from_memory_with_format(&bytes, image::ImageFormat::Png)?,
+        Some("image/jpeg") => image::load_from_memory_with_format(&bytes, image::ImageFormat::Jpeg)?,
+        _ => image::load_from_memory(&bytes)?,
+    };
+    let image = image.to_rgba8();
+    Ok(ImageData::Rgba {
+        width: image.width(),
+        height: image.height(),
+        pixels: image.into_raw(),
+    })
+}
+
+async fn load_images(document: &gltf::Document, buffers: &[gltf::buffer::Data], load_context: &LoadContext<'_>) -> anyhow::Result<Vec<ImageData>> {
+    let mut images = Vec::new();
+    for image in document.images() {
+        let data = match image.source() {
+            gltf::image::Source::View { view, mime_type } => {
+                let start = view.offset();
+                let end = start + view.length();
+                let bytes = buffers[view.buffer().index()].0[start..end].to_vec();
+                decode_image(bytes, Some(mime_type))?
+            }
+            gltf::image::Source::Uri { uri, mime_type } => {
+                let bytes = load_uri(uri, load_context).await?;
+                decode_image(bytes, mime_type)?
+            }
+        };
+        images.push(data);
+    }
+    Ok(images)
+}
+
 #[derive(Default)]
 pub struct GltfAssetLoader;
 
@@ -54,8 +200,10 @@ impl AssetLoader for GltfAssetLoader {
     fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
         Box::pin(async move {
             info!("start parse gltf");
-            let (document, buffers, images) = gltf::import_slice(bytes)?;
-            let data = GltfAsset { data: GltfData::Raw { document, buffers, images } };
+            let (document, blob, basisu_sources) = parse_document(bytes)?;
+            let buffers = load_buffers(&document, blob, load_context).await?;
+            let images = load_images(&document, &buffers, load_context).await?;
+            let data = GltfAsset { data: Arc::new(GltfData::Raw { document, buffers, images, basisu_sources }) };
             load_context.set_default_asset(LoadedAsset::new(data));
             info!("parse complete");
             Ok(())
@@ -66,4 +214,3 @@ impl AssetLoader for GltfAssetLoader {
         &["gltf", "glb"]
     }
 }
-
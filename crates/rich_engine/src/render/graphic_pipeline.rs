@@ -1,19 +1,74 @@
 use ash::vk;
 use crate::render::render_context::RenderContext;
-use crate::render::swapchain_mgr::SwapChainMgr;
 use std::ffi::CString;
 use std::path::Path;
 use std::io::Cursor;
 use ash::vk::DeviceSize;
+use crate::render::render_error::{RenderError, RenderResult};
 
-fn read_shader_data_from_file(context: &mut RenderContext, path: &str, defines: &[&str]) -> vk::ShaderModule {
+fn read_shader_data_from_file(context: &mut RenderContext, path: &str, defines: &[&str]) -> RenderResult<vk::ShaderModule> {
     context.shader_modules.create_shader(&context.device, path, defines)
 }
 
+/// Creates the pipeline, destroying `layout` when it fails.
+fn create_pipeline_with_layout(device: &ash::Device, pipeline_infos: &[vk::GraphicsPipelineCreateInfo],
+                               layout: vk::PipelineLayout) -> RenderResult<GraphicPipeline> {
+    match unsafe { device.create_graphics_pipelines(vk::PipelineCache::null(), pipeline_infos, None) } {
+        Ok(pipelines) => Ok(GraphicPipeline {
+            pipeline: pipelines[0],
+            pipeline_layout: layout,
+        }),
+        Err((_, result)) => {
+            unsafe { device.destroy_pipeline_layout(layout, None); }
+            Err(RenderError::vk("vkCreateGraphicsPipelines")(result))
+        }
+    }
+}
+
+/// Viewport and scissor are dynamic so pipelines survive render target resizes,
+/// see `set_flipped_viewport`.
+fn dynamic_viewport_state() -> vk::PipelineViewportStateCreateInfo {
+    vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1)
+        .build()
+}
+
+/// Sets a y-up viewport and a scissor covering the whole extent.
+pub fn set_flipped_viewport(context: &RenderContext, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
+    set_flipped_viewport_rect(context, command_buffer, vk::Rect2D {
+        offset: vk::Offset2D { x: 0, y: 0 },
+        extent,
+    });
+}
+
+/// Sets a y-up viewport and a scissor covering `rect`, given from the top left.
+pub fn set_flipped_viewport_rect(context: &RenderContext, command_buffer: vk::CommandBuffer, rect: vk::Rect2D) {
+    let viewport = vk::Viewport {
+        x: rect.offset.x as _,
+        y: (rect.offset.y + rect.extent.height as i32) as _,
+        width: rect.extent.width as _,
+        height: -(rect.extent.height as f32),
+        min_depth: 0.0,
+        max_depth: 1.0,
+    };
+    unsafe {
+        context.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+        context.device.cmd_set_scissor(command_buffer, 0, &[rect]);
+    }
+}
+
 pub struct PipelineVertexInputInfo {
     ci: Option<vk::PipelineVertexInputStateCreateInfo>,
     primitive: vk::PrimitiveTopology,
     cull_mode: vk::CullModeFlags,
+    polygon_mode: vk::PolygonMode,
+    additive_blend: bool,
+    alpha_blend: bool,
+    depth_test: bool,
+    /// `None` compares with the op of the current config, see `RenderConfig::depth_compare_op`
+    depth_compare_op: Option<vk::CompareOp>,
+    color_attachment_count: usize,
 }
 
 impl PipelineVertexInputInfo {
@@ -25,6 +80,12 @@ impl PipelineVertexInputInfo {
                 .build()),
             primitive: primitive,
             cull_mode: cull,
+            polygon_mode: vk::PolygonMode::FILL,
+            additive_blend: false,
+            alpha_blend: false,
+            depth_test: true,
+            depth_compare_op: None,
+            color_attachment_count: 1,
         }
     }
 
@@ -35,6 +96,12 @@ impl PipelineVertexInputInfo {
                 .build()),
             primitive: vk::PrimitiveTopology::TRIANGLE_LIST,
             cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            additive_blend: false,
+            alpha_blend: false,
+            depth_test: true,
+            depth_compare_op: None,
+            color_attachment_count: 1,
         }
     }
 
@@ -43,9 +110,59 @@ impl PipelineVertexInputInfo {
             ci: None,
             primitive: vk::PrimitiveTopology::TRIANGLE_LIST,
             cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            additive_blend: false,
+            alpha_blend: false,
+            depth_test: true,
+            depth_compare_op: None,
+            color_attachment_count: 1,
         }
     }
 
+    pub fn with_cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    /// `LINE` needs the `fill_mode_non_solid` feature, see `RenderConfig::supports_wireframe`.
+    pub fn with_polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    /// Adds every fragment to the target with depth test and write disabled, call
+    /// `with_depth_test` afterwards to keep the test.
+    pub fn with_additive_blend(mut self, additive_blend: bool) -> Self {
+        self.additive_blend = additive_blend;
+        if additive_blend {
+            self.depth_test = false;
+        }
+        self
+    }
+
+    /// Depth test on by default, blended pipelines never write the depth.
+    pub fn with_depth_test(mut self, depth_test: bool) -> Self {
+        self.depth_test = depth_test;
+        self
+    }
+
+    pub fn with_depth_compare_op(mut self, depth_compare_op: vk::CompareOp) -> Self {
+        self.depth_compare_op = Some(depth_compare_op);
+        self
+    }
+
+    /// Blends by the source alpha over the target, depth write is disabled.
+    pub fn with_alpha_blend(mut self, alpha_blend: bool) -> Self {
+        self.alpha_blend = alpha_blend;
+        self
+    }
+
+    /// Attachments of the subpass written with the same blend state, 1 by default.
+    pub fn with_color_attachments(mut self, count: usize) -> Self {
+        self.color_attachment_count = count;
+        self
+    }
+
     pub fn get_ci(&self) -> &Option<vk::PipelineVertexInputStateCreateInfo> {
         &self.ci
     }
@@ -82,10 +199,10 @@ impl ShaderStages {
                             stage_flags: vk::ShaderStageFlags,
                             defines: &[&str],
                             res: &mut Vec<vk::PipelineShaderStageCreateInfo>,
-                            entry_point_name: &CString)
+                            entry_point_name: &CString) -> RenderResult<()>
     {
         if let Some(vt) = file_path {
-            let module = read_shader_data_from_file(context, vt, defines);
+            let module = read_shader_data_from_file(context, vt, defines)?;
             let shader_state_info = vk::PipelineShaderStageCreateInfo::builder()
                 .stage(stage_flags)
                 .module(module)
@@ -94,21 +211,23 @@ impl ShaderStages {
 
             res.push(shader_state_info);
         }
+        Ok(())
     }
 
 
     pub fn to_shader_stage_create_info_array(&self, context: &mut RenderContext, defines: &[&str], entry_point_name: &CString) ->
-    Vec<vk::PipelineShaderStageCreateInfo>
+    RenderResult<Vec<vk::PipelineShaderStageCreateInfo>>
     {
         let mut res = Vec::new();
-        Self::file_to_shader_stage(context, self.vert, vk::ShaderStageFlags::VERTEX, defines, &mut res, entry_point_name);
-        Self::file_to_shader_stage(context, self.frag, vk::ShaderStageFlags::FRAGMENT, defines, &mut res, entry_point_name);
-        Self::file_to_shader_stage(context, self.tesc, vk::ShaderStageFlags::TESSELLATION_CONTROL, defines, &mut res, entry_point_name);
-        Self::file_to_shader_stage(context, self.tese, vk::ShaderStageFlags::TESSELLATION_EVALUATION, defines, &mut res, entry_point_name);
-        res
+        Self::file_to_shader_stage(context, self.vert, vk::ShaderStageFlags::VERTEX, defines, &mut res, entry_point_name)?;
+        Self::file_to_shader_stage(context, self.frag, vk::ShaderStageFlags::FRAGMENT, defines, &mut res, entry_point_name)?;
+        Self::file_to_shader_stage(context, self.tesc, vk::ShaderStageFlags::TESSELLATION_CONTROL, defines, &mut res, entry_point_name)?;
+        Self::file_to_shader_stage(context, self.tese, vk::ShaderStageFlags::TESSELLATION_EVALUATION, defines, &mut res, entry_point_name)?;
+        Ok(res)
     }
 }
 
+#[derive(Default)]
 pub struct GraphicPipeline {
     pipeline: vk::Pipeline,
     pipeline_layout: vk::PipelineLayout,
@@ -123,43 +242,27 @@ impl GraphicPipeline {
     }
 
     pub fn create_with_info(device_mgr: &mut RenderContext,
-                            swapchain_mgr: &SwapChainMgr,
                             render_pass: vk::RenderPass,
                             vertex_input: &PipelineVertexInputInfo,
                             pipeline_layout_ci: &vk::PipelineLayoutCreateInfo,
                             msaa: vk::SampleCountFlags,
-                            pipeline_stage_shader_create_info_array: &[vk::PipelineShaderStageCreateInfo]) -> Self {
+                            pipeline_stage_shader_create_info_array: &[vk::PipelineShaderStageCreateInfo]) -> RenderResult<Self> {
+        let depth_compare_op = vertex_input.depth_compare_op
+            .unwrap_or_else(|| device_mgr.render_config.depth_compare_op());
         let device = &mut device_mgr.device;
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
             .topology(vertex_input.primitive)
             .primitive_restart_enable(false)
             .build();
 
-        let surface_resolution = swapchain_mgr.surface_resolution;
-
-        let viewport = vk::Viewport {
-            x: 0.0,
-            y: surface_resolution.height as _,
-            width: surface_resolution.width as _,
-            height: -(surface_resolution.height as f32),
-            min_depth: 0.0,
-            max_depth: 1.0,
-        };
-        let viewports = [viewport];
-        let scissor = vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: surface_resolution,
-        };
-        let scissors = [scissor];
-        let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors)
-            .build();
+        let viewport_info = dynamic_viewport_state();
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_info = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states).build();
 
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
             .depth_clamp_enable(false)
             .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
+            .polygon_mode(vertex_input.polygon_mode)
             .line_width(1.0)
             .cull_mode(vertex_input.cull_mode)
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
@@ -185,27 +288,35 @@ impl GraphicPipeline {
             compare_op: vk::CompareOp::ALWAYS,
             ..Default::default()
         };
+        let blend = vertex_input.additive_blend || vertex_input.alpha_blend;
         let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo {
-            depth_test_enable: 1,
-            depth_write_enable: 1,
-            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            depth_test_enable: vertex_input.depth_test as vk::Bool32,
+            depth_write_enable: (vertex_input.depth_test && !blend) as vk::Bool32,
+            depth_compare_op,
             front: noop_stencil_state,
             back: noop_stencil_state,
             max_depth_bounds: 1.0,
             ..Default::default()
         };
 
+        let (src_color_factor, dst_factor) = if vertex_input.additive_blend {
+            (vk::BlendFactor::ONE, vk::BlendFactor::ONE)
+        } else if vertex_input.alpha_blend {
+            (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        } else {
+            (vk::BlendFactor::ONE, vk::BlendFactor::ZERO)
+        };
         let color_blend_attachment = vk::PipelineColorBlendAttachmentState::builder()
             .color_write_mask(vk::ColorComponentFlags::all())
-            .blend_enable(false)
-            .src_color_blend_factor(vk::BlendFactor::ONE)
-            .dst_color_blend_factor(vk::BlendFactor::ZERO)
+            .blend_enable(blend)
+            .src_color_blend_factor(src_color_factor)
+            .dst_color_blend_factor(dst_factor)
             .color_blend_op(vk::BlendOp::ADD)
             .src_alpha_blend_factor(vk::BlendFactor::ONE)
-            .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(dst_factor)
             .alpha_blend_op(vk::BlendOp::ADD)
             .build();
-        let color_blend_attachments = [color_blend_attachment];
+        let color_blend_attachments = vec![color_blend_attachment; vertex_input.color_attachment_count];
 
         let color_blending_info = vk::PipelineColorBlendStateCreateInfo::builder()
             .logic_op_enable(false)
@@ -215,7 +326,7 @@ impl GraphicPipeline {
             .build();
 
         let layout = {
-            unsafe { device.create_pipeline_layout(pipeline_layout_ci, None).unwrap() }
+            unsafe { device.create_pipeline_layout(pipeline_layout_ci, None) }.map_err(RenderError::vk("vkCreatePipelineLayout"))?
         };
 
 
@@ -231,7 +342,7 @@ impl GraphicPipeline {
                 .multisample_state(&multisampling_info)
                 .depth_stencil_state(&depth_stencil_info)
                 .color_blend_state(&color_blending_info)
-                // .dynamic_state() null since don't have any dynamic states
+                .dynamic_state(&dynamic_info)
                 .layout(layout)
                 .render_pass(render_pass)
                 .subpass(0);
@@ -253,29 +364,19 @@ impl GraphicPipeline {
         };
         let pipeline_infos = [pipeline_info];
 
-        let pipeline = unsafe {
-            device
-                .create_graphics_pipelines(vk::PipelineCache::null(), &pipeline_infos, None)
-                .unwrap()[0]
-        };
-
-        GraphicPipeline {
-            pipeline,
-            pipeline_layout: layout,
-        }
+        create_pipeline_with_layout(device, &pipeline_infos, layout)
     }
 
     pub fn create(device_mgr: &mut RenderContext,
-                  swapchain_mgr: &SwapChainMgr,
                   render_pass: vk::RenderPass,
                   vertex_input: &PipelineVertexInputInfo,
                   pipeline_layout_ci: &vk::PipelineLayoutCreateInfo,
                   msaa: vk::SampleCountFlags,
                   vert_spv_path: &str,
                   frag_spv_path: &str,
-                  defines: &[&str]) -> Self {
-        let vertex_shader_module = read_shader_data_from_file(device_mgr, vert_spv_path, defines);
-        let fragment_shader_module = read_shader_data_from_file(device_mgr, frag_spv_path, defines);
+                  defines: &[&str]) -> RenderResult<Self> {
+        let vertex_shader_module = read_shader_data_from_file(device_mgr, vert_spv_path, defines)?;
+        let fragment_shader_module = read_shader_data_from_file(device_mgr, frag_spv_path, defines)?;
 
         let entry_point_name = CString::new("main").unwrap();
         let vertex_shader_state_info = vk::PipelineShaderStageCreateInfo::builder()
@@ -290,19 +391,18 @@ impl GraphicPipeline {
             .build();
         let shader_states_infos = [vertex_shader_state_info, fragment_shader_state_info];
 
-        Self::create_with_info(device_mgr, swapchain_mgr, render_pass, vertex_input,
+        Self::create_with_info(device_mgr, render_pass, vertex_input,
                                pipeline_layout_ci, msaa, &shader_states_infos)
     }
 
     pub fn create_vert_only(device_mgr: &mut RenderContext,
-                            swapchain_mgr: &SwapChainMgr,
                             render_pass: vk::RenderPass,
                             vertex_input: &PipelineVertexInputInfo,
                             pipeline_layout_ci: &vk::PipelineLayoutCreateInfo,
                             msaa: vk::SampleCountFlags,
                             vert_spv_path: &str,
-                            defines: &[&str]) -> Self {
-        let vertex_shader_module = read_shader_data_from_file(device_mgr, vert_spv_path, defines);
+                            defines: &[&str]) -> RenderResult<Self> {
+        let vertex_shader_module = read_shader_data_from_file(device_mgr, vert_spv_path, defines)?;
 
         let device = &device_mgr.device;
         let entry_point_name = CString::new("main").unwrap();
@@ -319,30 +419,9 @@ impl GraphicPipeline {
             .primitive_restart_enable(false)
             .build();
 
-
-        let sd = device_mgr.render_config.shadow_map_dim;
-
-        let mut surface_resolution = swapchain_mgr.surface_resolution;
-        surface_resolution.width = sd as _;
-        surface_resolution.height = sd as _;
-        let viewport = vk::Viewport {
-            x: 0.0,
-            y: sd,
-            width: sd,
-            height: -sd,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        };
-        let viewports = [viewport];
-        let scissor = vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: surface_resolution,
-        };
-        let scissors = [scissor];
-        let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors)
-            .build();
+        let viewport_info = dynamic_viewport_state();
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_info = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states).build();
 
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
             .depth_clamp_enable(false)
@@ -384,7 +463,7 @@ impl GraphicPipeline {
         };
 
         let layout = {
-            unsafe { device.create_pipeline_layout(pipeline_layout_ci, None).unwrap() }
+            unsafe { device.create_pipeline_layout(pipeline_layout_ci, None) }.map_err(RenderError::vk("vkCreatePipelineLayout"))?
         };
 
         let pipeline_info = {
@@ -395,6 +474,7 @@ impl GraphicPipeline {
                 .rasterization_state(&rasterizer_info)
                 .multisample_state(&multisampling_info)
                 .depth_stencil_state(&depth_stencil_info)
+                .dynamic_state(&dynamic_info)
                 .layout(layout)
                 .render_pass(render_pass)
                 .subpass(0);
@@ -407,16 +487,7 @@ impl GraphicPipeline {
         };
         let pipeline_infos = [pipeline_info];
 
-        let pipeline = unsafe {
-            device
-                .create_graphics_pipelines(vk::PipelineCache::null(), &pipeline_infos, None)
-                .unwrap()[0]
-        };
-
-        GraphicPipeline {
-            pipeline,
-            pipeline_layout: layout,
-        }
+        create_pipeline_with_layout(device, &pipeline_infos, layout)
     }
 
     pub fn get_pipeline(&self) -> vk::Pipeline {
@@ -7,6 +7,9 @@ use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo,
 use crate::{Buffer, ForwardRenderPass, RenderContext};
 use crate::render::swapchain_mgr::SwapChainMgr;
 use crate::render::util;
+use crate::render::render_error::{RenderError, RenderResult};
+
+const GRASS_STAGES: [&str; 4] = ["grass_vert", "grass_tesc", "grass_tese", "grass_frag"];
 
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
@@ -39,17 +42,14 @@ impl GrassGenerateCompute {
         }
     }
 
-    pub fn create(context: &mut RenderContext, swap_chain_mgr: &SwapChainMgr, grass_blade_buffer: &Buffer, grid: &GrassGridData) -> Self {
-        let descriptor_layout = {
-            let descriptor_set_bindings = [
-                vk::DescriptorSetLayoutBinding::builder()
-                    .binding(0)
-                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count(1)
-                    .build()
-            ];
+    pub fn create(context: &mut RenderContext, swap_chain_mgr: &SwapChainMgr, grass_blade_buffer: &Buffer, grid: &GrassGridData) -> RenderResult<Self> {
+        // the blades buffer and the grid constants, as declared by the shader
+        let (descriptor_set_bindings, constant_ranges) = {
+            let reflection = context.shader_modules.reflect(&context.device, "grass_generate_comp", &[])?;
+            (reflection.set_layout_bindings(0), reflection.push_constant_ranges())
+        };
 
+        let descriptor_layout = {
             unsafe {
                 let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&descriptor_set_bindings).build();
                 context.device.create_descriptor_set_layout(&ci, None).expect("failed to create set layout")
@@ -80,10 +80,6 @@ impl GrassGenerateCompute {
             }
         }
 
-        let constant_ranges = [
-            vk::PushConstantRange::builder().offset(0).size(size_of::<GrassGridData>() as _).stage_flags(vk::ShaderStageFlags::COMPUTE).build()
-        ];
-
         let pipeline_layout = {
             let descriptor_layouts = [descriptor_layout];
             let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_layouts)
@@ -95,7 +91,7 @@ impl GrassGenerateCompute {
 
         let pipeline = {
             let stage = context.shader_modules.create_shader_stage(&context.device, "grass_generate_comp", &[],
-                                                                   vk::ShaderStageFlags::COMPUTE);
+                                                                   vk::ShaderStageFlags::COMPUTE)?;
 
             let ci = vk::ComputePipelineCreateInfo::builder().stage(stage).layout(pipeline_layout).build();
             unsafe {
@@ -110,13 +106,13 @@ impl GrassGenerateCompute {
             }
         };
 
-        GrassGenerateCompute {
+        Ok(GrassGenerateCompute {
             pipeline_layout,
             pipeline,
             descriptor_set,
             descriptor_set_layout: descriptor_layout,
             working_semaphore,
-        }
+        })
     }
 
     pub fn compute(&mut self, context: &RenderContext, command_buffer: vk::CommandBuffer, grid: &GrassGridData) {
@@ -174,7 +170,13 @@ impl GrassUpdateCompute {
 
     pub fn create(context: &mut RenderContext, swap_chain_mgr: &SwapChainMgr, upload_command_buffer: vk::CommandBuffer
                   , grass_blade_buffer: &Buffer,
-                  visible_grass: &Buffer) -> Self {
+                  visible_grass: &Buffer) -> RenderResult<Self> {
+        // all, visible and the count of blades after the frame data, as declared by the shader
+        let (descriptor_set_bindings, constant_ranges) = {
+            let reflection = context.shader_modules.reflect(&context.device, "grass_update_comp", &[])?;
+            (reflection.set_layout_bindings(1), reflection.push_constant_ranges())
+        };
+
         let num_blades = NumBlades { first_vertex: 0, first_instance: 0, instance_count: 1, vertex_count: 0 };
         let num_blades_buffer = Buffer::create_device_local_buffer(context, upload_command_buffer,
                                                                    vk::BufferUsageFlags::UNIFORM_BUFFER |
@@ -184,30 +186,6 @@ impl GrassUpdateCompute {
                                                                    &[num_blades]);
 
         let descriptor_layout = {
-            let descriptor_set_bindings = [
-                //all blades
-                vk::DescriptorSetLayoutBinding::builder()
-                    .binding(0)
-                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count(1)
-                    .build(),
-                //cull blades
-                vk::DescriptorSetLayoutBinding::builder()
-                    .binding(1)
-                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count(1)
-                    .build(),
-                //num blades
-                vk::DescriptorSetLayoutBinding::builder()
-                    .binding(2)
-                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count(1)
-                    .build(),
-            ];
-
             unsafe {
                 let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&descriptor_set_bindings).build();
                 context.device.create_descriptor_set_layout(&ci, None).expect("failed to create set layout")
@@ -252,10 +230,6 @@ impl GrassUpdateCompute {
 
         let uni = context.per_frame_uniform.as_ref().unwrap();
 
-        let constant_ranges = [
-            vk::PushConstantRange::builder().offset(0).size(size_of::<GrassGridData>() as _).stage_flags(vk::ShaderStageFlags::COMPUTE).build()
-        ];
-
         let pipeline_layout = {
             let descriptor_layouts = [uni.descriptor_set_layout, descriptor_layout];
             let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_layouts).push_constant_ranges
@@ -268,7 +242,7 @@ impl GrassUpdateCompute {
 
         let pipeline = {
             let stage = context.shader_modules.create_shader_stage(&context.device, "grass_update_comp", &[],
-                                                                   vk::ShaderStageFlags::COMPUTE);
+                                                                   vk::ShaderStageFlags::COMPUTE)?;
 
             let ci = vk::ComputePipelineCreateInfo::builder().stage(stage).layout(pipeline_layout).build();
             unsafe {
@@ -283,14 +257,14 @@ impl GrassUpdateCompute {
             }
         };
 
-        GrassUpdateCompute {
+        Ok(GrassUpdateCompute {
             pipeline_layout,
             pipeline,
             descriptor_set,
             descriptor_set_layout: descriptor_layout,
             working_semaphore,
             num_blades_buffer,
-        }
+        })
     }
 
     pub fn compute(&mut self, context: &RenderContext, command_buffer: vk::CommandBuffer, grid: &GrassGridData,
@@ -339,6 +313,8 @@ pub struct GrassMgr {
     all_grass_blade_buffer: Buffer,
     visible_grass_blade_buffer: Buffer,
     pipeline: GraphicPipeline,
+    /// blade motion for temporal anti-aliasing, `None` when off
+    velocity_pipeline: Option<GraphicPipeline>,
     compute_command_pool: vk::CommandPool,
     generate_command_buffer: vk::CommandBuffer,
     update_command_buffer: vk::CommandBuffer,
@@ -354,6 +330,9 @@ pub struct GrassMgr {
 impl GrassMgr {
     pub fn destroy(&mut self, context: &RenderContext) {
         self.pipeline.destroy(context);
+        if let Some(pipeline) = self.velocity_pipeline.as_mut() {
+            pipeline.destroy(context);
+        }
         self.all_grass_blade_buffer.destroy(context);
         self.visible_grass_blade_buffer.destroy(context);
         self.gen_compute.destroy(context);
@@ -365,59 +344,18 @@ impl GrassMgr {
     }
 
     pub fn create(context: &mut RenderContext, swap_mgr: &SwapChainMgr, render_pass: &ForwardRenderPass,
-                  upload_command_buffer: vk::CommandBuffer) -> Self {
-        let vb = [vk::VertexInputBindingDescription::builder()
-            .binding(0)
-            .stride(std::mem::size_of::<GrassBlade>() as _)
-            .input_rate(vk::VertexInputRate::VERTEX).build()];
-
-        let va = [
-            //v0
-            vk::VertexInputAttributeDescription::builder()
-                .binding(0)
-                .location(0)
-                .format(vk::Format::R32G32B32A32_SFLOAT)
-                .offset(0).build(),
-
-            //v1
-            vk::VertexInputAttributeDescription::builder()
-                .binding(0)
-                .location(1)
-                .format(vk::Format::R32G32B32A32_SFLOAT)
-                .offset(4 * 4).build(),
-
-            //v2
-            vk::VertexInputAttributeDescription::builder()
-                .binding(0)
-                .location(2)
-                .format(vk::Format::R32G32B32A32_SFLOAT)
-                .offset(4 * 4 * 2).build(),
-
-            //up
-            vk::VertexInputAttributeDescription::builder()
-                .binding(0)
-                .location(3)
-                .format(vk::Format::R32G32B32A32_SFLOAT)
-                .offset(4 * 4 * 3).build(),
-        ];
-
-        let (draw_descriptor_layout, draw_descriptor_set) = Self::create_descriptors(context, render_pass);
-
-        let vi = PipelineVertexInputInfo::from_bap(&vb, &va, vk::PrimitiveTopology::PATCH_LIST, vk::CullModeFlags::NONE);
-        let uni = context.per_frame_uniform.as_ref().unwrap();
-        let pipe_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&[uni.descriptor_set_layout, draw_descriptor_layout])
-            .build();
-
-        let entry_point_name = CString::new("main").unwrap();
-        let shaders = ShaderStages {
-            vert: Some("grass_vert"),
-            frag: Some("grass_frag"),
-            tesc: Some("grass_tesc"),
-            tese: Some("grass_tese"),
-        }.to_shader_stage_create_info_array(context, &[], &entry_point_name);
-
-        let pipeline = GraphicPipeline::create_with_info(context, swap_mgr, render_pass.get_native_render_pass(),
-                                                         &vi, &pipe_ci, vk::SampleCountFlags::TYPE_1, &shaders);
+                  upload_command_buffer: vk::CommandBuffer) -> RenderResult<Self> {
+        let (draw_descriptor_layout, draw_descriptor_set) = Self::create_descriptors(context, render_pass)?;
+        let (pipeline, velocity_pipeline) = match Self::create_pipelines(context, render_pass, draw_descriptor_layout) {
+            Ok(pipelines) => pipelines,
+            Err(e) => {
+                unsafe {
+                    context.device.free_descriptor_sets(context.descriptor_pool, &[draw_descriptor_set]);
+                    context.device.destroy_descriptor_set_layout(draw_descriptor_layout, None);
+                }
+                return Err(e);
+            }
+        };
 
         let compute_command_pool = {
             let pool_ci = vk::CommandPoolCreateInfo {
@@ -474,15 +412,16 @@ impl GrassMgr {
                                                                                           vk::BufferUsageFlags::STORAGE_BUFFER,
                                                                                       all_blade_size);
 
-        let gen_compute = GrassGenerateCompute::create(context, swap_mgr, &all_grass_blade_buffer, &grid_data);
+        let gen_compute = GrassGenerateCompute::create(context, swap_mgr, &all_grass_blade_buffer, &grid_data)?;
         let update_compute = GrassUpdateCompute::create(context, swap_mgr,
-                                                        upload_command_buffer, &all_grass_blade_buffer, &visible_grass_blade_buffer);
+                                                        upload_command_buffer, &all_grass_blade_buffer, &visible_grass_blade_buffer)?;
 
-        Self {
+        Ok(Self {
             compute_command_pool,
             generate_command_buffer,
             update_command_buffer,
             pipeline,
+            velocity_pipeline,
             all_grass_blade_buffer,
             visible_grass_blade_buffer,
             has_gen_grass: false,
@@ -493,20 +432,123 @@ impl GrassMgr {
 
             draw_descriptor_layout,
             draw_descriptor_set,
-        }
+        })
     }
 
-    fn create_descriptors(context: &mut RenderContext,
-                          render_pass: &ForwardRenderPass) -> (vk::DescriptorSetLayout, vk::DescriptorSet) {
-        let bindings = [
-            vk::DescriptorSetLayoutBinding::builder()
+    /// The forward pipeline and the velocity pipeline when temporal anti-aliasing is on.
+    fn create_pipelines(context: &mut RenderContext, render_pass: &ForwardRenderPass,
+                        draw_descriptor_layout: vk::DescriptorSetLayout) -> RenderResult<(GraphicPipeline, Option<GraphicPipeline>)> {
+        let msaa = context.render_config.msaa;
+        let mut pipeline = Self::create_pipeline(context, render_pass.get_native_render_pass(), msaa, &[],
+                                                 draw_descriptor_layout)?;
+        let velocity_pipeline = match render_pass.get_taa() {
+            Some(taa) => match Self::create_pipeline(context, taa.get_velocity_render_pass(), vk::SampleCountFlags::TYPE_1,
+                                                     &["VELOCITY"], draw_descriptor_layout) {
+                Ok(pipeline) => Some(pipeline),
+                Err(e) => {
+                    pipeline.destroy(context);
+                    return Err(e);
+                }
+            },
+            None => None,
+        };
+        Ok((pipeline, velocity_pipeline))
+    }
+
+    fn create_pipeline(context: &mut RenderContext, render_pass: vk::RenderPass, msaa: vk::SampleCountFlags,
+                       defines: &[&str], draw_descriptor_layout: vk::DescriptorSetLayout) -> RenderResult<GraphicPipeline> {
+        let vb = [vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(std::mem::size_of::<GrassBlade>() as _)
+            .input_rate(vk::VertexInputRate::VERTEX).build()];
+
+        let va = [
+            //v0
+            vk::VertexInputAttributeDescription::builder()
                 .binding(0)
-                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                .build(),
+                .location(0)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(0).build(),
+
+            //v1
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(1)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(4 * 4).build(),
+
+            //v2
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(2)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(4 * 4 * 2).build(),
+
+            //up
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(3)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(4 * 4 * 3).build(),
         ];
 
+        // the draw set comes from the forward permutation, the others must fit in it
+        let draw_bindings = context.shader_modules.reflect_stages(&context.device, &GRASS_STAGES, &[])?.set_layout_bindings(1);
+        let reflection = context.shader_modules.reflect_stages(&context.device, &GRASS_STAGES, defines)?;
+        let interface_error = |message| RenderError::ShaderInterface { name: "grass".to_string(), message };
+        reflection.check_vertex_attributes(&va).map_err(interface_error)?;
+        reflection.check_set_layout(1, &draw_bindings).map_err(interface_error)?;
+        let push_constant_ranges = reflection.push_constant_ranges();
+
+        let vi = PipelineVertexInputInfo::from_bap(&vb, &va, vk::PrimitiveTopology::PATCH_LIST, vk::CullModeFlags::NONE);
+        let uni = context.per_frame_uniform.as_ref().unwrap();
+        let pipe_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&[uni.descriptor_set_layout, draw_descriptor_layout])
+            .push_constant_ranges(&push_constant_ranges)
+            .build();
+
+        let entry_point_name = CString::new("main").unwrap();
+        let shaders = ShaderStages {
+            vert: Some("grass_vert"),
+            frag: Some("grass_frag"),
+            tesc: Some("grass_tesc"),
+            tese: Some("grass_tese"),
+        }.to_shader_stage_create_info_array(context, defines, &entry_point_name)?;
+
+        GraphicPipeline::create_with_info(context, render_pass, &vi, &pipe_ci, msaa, &shaders)
+    }
+
+    /// Rebuilds what depends on the forward render targets, the blade buffers are kept.
+    pub fn recreate_pipeline(&mut self, context: &mut RenderContext, render_pass: &ForwardRenderPass) -> RenderResult<()> {
+        self.pipeline.destroy(context);
+        if let Some(mut pipeline) = self.velocity_pipeline.take() {
+            pipeline.destroy(context);
+        }
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.draw_descriptor_set]);
+            context.device.destroy_descriptor_set_layout(self.draw_descriptor_layout, None);
+        }
+
+        let (draw_descriptor_layout, draw_descriptor_set) = Self::create_descriptors(context, render_pass)?;
+        self.draw_descriptor_layout = draw_descriptor_layout;
+        self.draw_descriptor_set = draw_descriptor_set;
+        let (pipeline, velocity_pipeline) = Self::create_pipelines(context, render_pass, draw_descriptor_layout)?;
+        self.pipeline = pipeline;
+        self.velocity_pipeline = velocity_pipeline;
+        Ok(())
+    }
+
+    /// The draw set as the grass shaders declare it, only the shadow map can be bound.
+    fn create_descriptors(context: &mut RenderContext,
+                          render_pass: &ForwardRenderPass) -> RenderResult<(vk::DescriptorSetLayout, vk::DescriptorSet)> {
+        let reflection = context.shader_modules.reflect_stages(&context.device, &GRASS_STAGES, &[])?;
+        let bindings = reflection.set_layout_bindings(1);
+        if let Some(binding) = bindings.iter().find(|b| b.binding != 0 || b.descriptor_type != vk::DescriptorType::COMBINED_IMAGE_SAMPLER) {
+            return Err(RenderError::ShaderInterface {
+                name: "grass".to_string(),
+                message: format!("draw binding {} ({:?}) is not provided", binding.binding, binding.descriptor_type),
+            });
+        }
+
         let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
 
         let set_layout = unsafe {
@@ -538,7 +580,7 @@ impl GrassMgr {
                 .build()]
         };
 
-        let descriptor_writes = [
+        let mut descriptor_writes = vec![
             vk::WriteDescriptorSet::builder()
                 .dst_set(set)
                 .dst_binding(0)
@@ -546,6 +588,7 @@ impl GrassMgr {
                 .image_info(&shadow_info)
                 .build(),
         ];
+        descriptor_writes.retain(|w| bindings.iter().any(|b| b.binding == w.dst_binding));
 
         unsafe {
             context
@@ -554,7 +597,7 @@ impl GrassMgr {
         }
 
 
-        (set_layout, set)
+        Ok((set_layout, set))
     }
 
     pub fn compute_grass_data(&mut self, context: &RenderContext) {
@@ -613,4 +656,21 @@ impl GrassMgr {
             context.device.cmd_draw_indirect(command_buffer, self.update_compute.num_blades_buffer.buffer, 0, 1, 0);
         }
     }
-}
\ No newline at end of file
+
+    /// Writes the blade motion into the velocity pass of temporal anti-aliasing.
+    pub fn draw_velocity(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let pipeline = match &self.velocity_pipeline {
+            Some(pipeline) if self.enable_draw => pipeline,
+            _ => return,
+        };
+
+        let uni = context.per_frame_uniform.as_ref().unwrap();
+        unsafe {
+            context.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_pipeline());
+            context.device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.visible_grass_blade_buffer.buffer], &[0]);
+            context.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                    pipeline.get_layout(), 0, &[uni.descriptor_set, self.draw_descriptor_set], &[]);
+            context.device.cmd_draw_indirect(command_buffer, self.update_compute.num_blades_buffer.buffer, 0, 1, 0);
+        }
+    }
+}
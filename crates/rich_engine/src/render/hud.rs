@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ops::DerefMut;
+use ash::vk;
+use bevy::{
+    prelude::*,
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use crate::render::buffer::Buffer;
+use crate::render::forward_render::ForwardRenderPass;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo};
+use crate::render::render_context::RenderContext;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_runner::RenderRunner;
+use crate::render::texture_cache::TextureCache;
+use crate::render::util;
+
+/// Rectangles drawn per frame over all HUD nodes, the top layers are dropped when over.
+pub const MAX_HUD_RECTS: usize = 8192;
+
+/// vertices of a rectangle, generated by the vertex shader
+const QUAD_VERTEX_COUNT: u32 = 6;
+
+/// A point of the window and the matching point of a node placed on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HudAnchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl HudAnchor {
+    /// Position of the point in a rectangle, 0 at the top left and 1 at the bottom right.
+    fn factor(self) -> Vec2 {
+        match self {
+            HudAnchor::TopLeft => Vec2::new(0.0, 0.0),
+            HudAnchor::Top => Vec2::new(0.5, 0.0),
+            HudAnchor::TopRight => Vec2::new(1.0, 0.0),
+            HudAnchor::Left => Vec2::new(0.0, 0.5),
+            HudAnchor::Center => Vec2::new(0.5, 0.5),
+            HudAnchor::Right => Vec2::new(1.0, 0.5),
+            HudAnchor::BottomLeft => Vec2::new(0.0, 1.0),
+            HudAnchor::Bottom => Vec2::new(0.5, 1.0),
+            HudAnchor::BottomRight => Vec2::new(1.0, 1.0),
+        }
+    }
+}
+
+/// Places an entity on the 2D layer of the primary window, drawn over the scene after
+/// post-processing. Sizes and offsets are in pixels of the render target with y down.
+/// Add a `HudPanel`, `HudSprite` or `HudText` to draw something, drawn in that order.
+#[derive(Debug, Clone)]
+pub struct HudNode {
+    pub anchor: HudAnchor,
+    /// from the anchor of the window to the anchor of the node
+    pub offset: Vec2,
+    pub size: Vec2,
+    /// higher layers are drawn over lower ones
+    pub layer: i32,
+    pub visible: bool,
+}
+
+impl HudNode {
+    pub fn new(anchor: HudAnchor, offset: Vec2, size: Vec2) -> Self {
+        Self {
+            anchor,
+            offset,
+            size,
+            layer: 0,
+            visible: true,
+        }
+    }
+
+    pub fn with_layer(mut self, layer: i32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    /// Top left and bottom right in pixels on a target of `extent`.
+    pub fn rect(&self, extent: Vec2) -> (Vec2, Vec2) {
+        let factor = self.anchor.factor();
+        let min = extent * factor + self.offset - self.size * factor;
+        (min, min + self.size)
+    }
+}
+
+/// An image stretched over the node.
+#[derive(Debug, Clone)]
+pub struct HudSprite {
+    /// image file or `*.atlas.ron` texture atlas relative to `assets/`, white when empty
+    pub texture: String,
+    /// region of the atlas, the whole image when empty
+    pub region: String,
+    pub color: Vec4,
+}
+
+impl HudSprite {
+    pub fn new(texture: impl Into<String>) -> Self {
+        Self {
+            texture: texture.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for HudSprite {
+    fn default() -> Self {
+        Self {
+            texture: String::new(),
+            region: String::new(),
+            color: Vec4::ONE,
+        }
+    }
+}
+
+/// A nine-slice image over the node, its corners keep their size and its edges and
+/// center stretch.
+#[derive(Debug, Clone)]
+pub struct HudPanel {
+    /// image file or `*.atlas.ron` texture atlas relative to `assets/`, white when empty
+    pub texture: String,
+    /// region of the atlas, the whole image when empty
+    pub region: String,
+    pub color: Vec4,
+    /// left, top, right and bottom border in pixels of the image
+    pub border: Vec4,
+    /// pixels on screen per pixel of the border
+    pub border_scale: f32,
+}
+
+impl HudPanel {
+    pub fn new(texture: impl Into<String>, border: f32) -> Self {
+        Self {
+            texture: texture.into(),
+            border: Vec4::splat(border),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for HudPanel {
+    fn default() -> Self {
+        Self {
+            texture: String::new(),
+            region: String::new(),
+            color: Vec4::ONE,
+            border: Vec4::ZERO,
+            border_scale: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HudTextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/// Text inside the node, aligned horizontally and centered vertically. Lines are not
+/// wrapped.
+#[derive(Debug, Clone)]
+pub struct HudText {
+    pub text: String,
+    /// `*.font.ron` file relative to `assets/`, `DEFAULT_FONT` when empty
+    pub font: String,
+    /// line height in pixels
+    pub size: f32,
+    pub color: Vec4,
+    pub align: HudTextAlign,
+}
+
+impl HudText {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for HudText {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            font: String::new(),
+            size: 24.0,
+            color: Vec4::ONE,
+            align: HudTextAlign::Left,
+        }
+    }
+}
+
+/// Named regions of one image, loaded from `*.atlas.ron` files.
+#[derive(Debug, Clone, Deserialize, Serialize, TypeUuid)]
+#[uuid = "f79edc79-5537-42f0-be55-3ebcc1fde51a"]
+pub struct HudAtlas {
+    /// image file relative to `assets/`
+    pub texture: String,
+    /// x, y, width and height in pixels from the top left of the image
+    pub regions: HashMap<String, (u32, u32, u32, u32)>,
+}
+
+#[derive(Default)]
+pub struct HudAtlasLoader;
+
+impl AssetLoader for HudAtlasLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let atlas: HudAtlas = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(atlas));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["atlas.ron"]
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuRect {
+    /// xy top left and zw bottom right in pixels
+    rect: Vec4,
+    /// xy top left and zw bottom right
+    uv_rect: Vec4,
+    color: Vec4,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct HudConstant {
+    extent: Vec2,
+}
+
+/// Part of a loaded image drawn by a sprite or panel.
+struct ImageRegion {
+    /// `None` is white
+    texture: Option<String>,
+    uv_rect: Vec4,
+    /// in pixels of the image
+    size: Vec2,
+}
+
+/// Consecutive rectangles sharing a texture, `None` is white.
+struct HudBatch {
+    texture: Option<String>,
+    first_instance: u32,
+    instance_count: u32,
+}
+
+/// Draws the `HudNode` entities over the final image of the primary window, recorded
+/// last before it is presented so the scene, the debug overlay and effects are below.
+pub struct HudRenderer {
+    render_pass: vk::RenderPass,
+    frame_buffer: vk::Framebuffer,
+    pipeline: GraphicPipeline,
+    instance_set_layout: vk::DescriptorSetLayout,
+    instance_set: vk::DescriptorSet,
+    /// owned by the texture cache
+    texture_set_layout: vk::DescriptorSetLayout,
+    /// requested atlases keyed by their path, the handles keep them loaded
+    atlases: HashMap<String, Handle<HudAtlas>>,
+    instance_buffer: Buffer,
+    batches: Vec<HudBatch>,
+}
+
+impl HudRenderer {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.destroy_pass(context);
+        self.instance_buffer.destroy(context);
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.instance_set]);
+            context.device.destroy_descriptor_set_layout(self.instance_set_layout, None);
+        }
+    }
+
+    fn destroy_pass(&mut self, context: &RenderContext) {
+        self.pipeline.destroy(context);
+        unsafe {
+            context.device.destroy_framebuffer(self.frame_buffer, None);
+            context.device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+
+    /// `texture_set_layout` is the layout of the texture cache sets.
+    pub fn create(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                  texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<Self> {
+        let bindings = Self::instance_set_bindings();
+        let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+        let instance_set_layout = unsafe { context.device.create_descriptor_set_layout(&ci, None) }
+            .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?;
+        let (render_pass, frame_buffer, pipeline) =
+            match Self::create_pass(context, forward_render, instance_set_layout, texture_set_layout) {
+                Ok(pass) => pass,
+                Err(e) => {
+                    unsafe { context.device.destroy_descriptor_set_layout(instance_set_layout, None); }
+                    return Err(e);
+                }
+            };
+
+        let instance_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                                           (MAX_HUD_RECTS * size_of::<GpuRect>()) as _);
+
+        let mut renderer = Self {
+            render_pass,
+            frame_buffer,
+            pipeline,
+            instance_set_layout,
+            instance_set: vk::DescriptorSet::null(),
+            texture_set_layout,
+            atlases: HashMap::new(),
+            instance_buffer,
+            batches: vec![],
+        };
+        // freeing a null set is a no-op, so a failed allocation releases everything
+        if let Err(e) = renderer.create_instance_set(context) {
+            renderer.destroy(context);
+            return Err(e);
+        }
+
+        Ok(renderer)
+    }
+
+    /// The rects read by the vertex stage, checked against the HUD shaders.
+    fn instance_set_bindings() -> [vk::DescriptorSetLayoutBinding; 1] {
+        [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .build(),
+        ]
+    }
+
+    fn create_instance_set(&mut self, context: &RenderContext) -> RenderResult<()> {
+        let layouts = [self.instance_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        self.instance_set = unsafe { context.device.allocate_descriptor_sets(&allocate_info) }
+            .map_err(RenderError::vk("vkAllocateDescriptorSets"))?[0];
+        let instance_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(self.instance_buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(self.instance_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&instance_info)
+            .build()];
+        unsafe {
+            context.device.update_descriptor_sets(&writes, &[]);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds against new forward targets.
+    pub fn recreate(&mut self, context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<()> {
+        self.destroy_pass(context);
+        let (render_pass, frame_buffer, pipeline) =
+            Self::create_pass(context, forward_render, self.instance_set_layout, self.texture_set_layout)?;
+        self.render_pass = render_pass;
+        self.frame_buffer = frame_buffer;
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    fn create_pass(context: &mut RenderContext, forward_render: &ForwardRenderPass,
+                   instance_set_layout: vk::DescriptorSetLayout,
+                   texture_set_layout: vk::DescriptorSetLayout) -> RenderResult<(vk::RenderPass, vk::Framebuffer, GraphicPipeline)> {
+        let render_pass = Self::create_render_pass(context)?;
+
+        let extent = forward_render.get_extent();
+        let views = [forward_render.get_final_render_image_view()];
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(render_pass).layers(1)
+            .width(extent.width).height(extent.height).attachments(&views).build();
+        let frame_buffer = match unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) } {
+            Ok(frame_buffer) => frame_buffer,
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                return Err(RenderError::vk("vkCreateFramebuffer")(e));
+            }
+        };
+
+        let set_layouts = [instance_set_layout, texture_set_layout];
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<HudConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::VERTEX).build(),
+        ];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&constant_ranges)
+            .build();
+
+        let vertex_input = PipelineVertexInputInfo::from(&[], &[])
+            .with_cull_mode(vk::CullModeFlags::NONE)
+            .with_alpha_blend(true)
+            .with_depth_test(false);
+        let instance_bindings = Self::instance_set_bindings();
+        let texture_bindings = TextureCache::set_layout_bindings();
+        let pipeline = context.shader_modules
+            .check_stages(&context.device, &["hud_vert", "hud_frag"], &[],
+                          &[(0, &instance_bindings[..]), (1, &texture_bindings[..])])
+            .and_then(|_| GraphicPipeline::create(context, render_pass, &vertex_input, &pipeline_layout_ci,
+                                                  vk::SampleCountFlags::TYPE_1, "hud_vert", "hud_frag", &[]));
+        match pipeline {
+            Ok(pipeline) => Ok((render_pass, frame_buffer, pipeline)),
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_framebuffer(frame_buffer, None);
+                    context.device.destroy_render_pass(render_pass, None);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Loads the final color image, drawn after everything else of the frame.
+    fn create_render_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: context.render_config.color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::LOAD,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments).subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// Starts loading a `*.atlas.ron` atlas the first time it is asked for, then its image
+    /// once it is loaded. Other paths are images.
+    fn request_texture(&mut self, textures: &mut TextureCache, asset_server: &AssetServer,
+                       atlases: &Assets<HudAtlas>, path: &str) {
+        if !path.ends_with(".atlas.ron") {
+            textures.request_texture(asset_server, path);
+            return;
+        }
+
+        let handle = self.atlases.entry(path.to_string()).or_insert_with(|| asset_server.load(path));
+        if let Some(atlas) = atlases.get(&*handle) {
+            textures.request_texture(asset_server, &atlas.texture);
+        }
+    }
+
+    /// The drawn part of `texture`, `None` until it is loaded or when the region is missing.
+    fn resolve(&self, textures: &TextureCache, atlases: &Assets<HudAtlas>, texture: &str,
+               region: &str) -> Option<ImageRegion> {
+        if texture.is_empty() {
+            return Some(ImageRegion {
+                texture: None,
+                uv_rect: Vec4::new(0.0, 0.0, 1.0, 1.0),
+                size: Vec2::ONE,
+            });
+        }
+
+        let (image, region) = match self.atlases.get(texture) {
+            Some(handle) => {
+                let atlas = atlases.get(handle)?;
+                let region = if region.is_empty() { None } else { Some(*atlas.regions.get(region)?) };
+                (atlas.texture.as_str(), region)
+            }
+            None => (texture, None),
+        };
+        let (width, height) = textures.texture(image)?.texture.texture.get_size();
+        let image_size = Vec2::new(width as f32, height as f32);
+        let (x, y, w, h) = region.unwrap_or((0, 0, width, height));
+        let min = Vec2::new(x as f32, y as f32) / image_size;
+        let max = Vec2::new((x + w) as f32, (y + h) as f32) / image_size;
+        Some(ImageRegion {
+            texture: Some(image.to_string()),
+            uv_rect: Vec4::new(min.x, min.y, max.x, max.y),
+            size: Vec2::new(w as f32, h as f32),
+        })
+    }
+
+    /// Nine rectangles of a panel over `min`..`max`, empty ones are skipped.
+    fn nine_slice(panel: &HudPanel, image: &ImageRegion, min: Vec2, max: Vec2, rects: &mut Vec<GpuRect>) {
+        let size = max - min;
+        let mut left = panel.border.x * panel.border_scale;
+        let mut right = panel.border.z * panel.border_scale;
+        let mut top = panel.border.y * panel.border_scale;
+        let mut bottom = panel.border.w * panel.border_scale;
+        // borders wider than the node shrink together
+        if left + right > size.x {
+            let scale = size.x / (left + right);
+            left *= scale;
+            right *= scale;
+        }
+        if top + bottom > size.y {
+            let scale = size.y / (top + bottom);
+            top *= scale;
+            bottom *= scale;
+        }
+
+        let uv = image.uv_rect;
+        let uv_size = Vec2::new(uv.z - uv.x, uv.w - uv.y) / image.size;
+        let xs = [min.x, min.x + left, max.x - right, max.x];
+        let ys = [min.y, min.y + top, max.y - bottom, max.y];
+        let us = [uv.x, uv.x + panel.border.x * uv_size.x, uv.z - panel.border.z * uv_size.x, uv.z];
+        let vs = [uv.y, uv.y + panel.border.y * uv_size.y, uv.w - panel.border.w * uv_size.y, uv.w];
+        for row in 0..3 {
+            for column in 0..3 {
+                if xs[column + 1] <= xs[column] || ys[row + 1] <= ys[row] {
+                    continue;
+                }
+                rects.push(GpuRect {
+                    rect: Vec4::new(xs[column], ys[row], xs[column + 1], ys[row + 1]),
+                    uv_rect: Vec4::new(us[column], vs[row], us[column + 1], vs[row + 1]),
+                    color: panel.color,
+                });
+            }
+        }
+    }
+
+    /// Lays out the visible nodes on a target of `extent` by layer and uploads their
+    /// rectangles, skipping parts whose image or font is not loaded.
+    pub fn prepare<'a>(&mut self, context: &RenderContext, textures: &TextureCache, atlases: &Assets<HudAtlas>,
+                       extent: vk::Extent2D,
+                       nodes: impl Iterator<Item=(&'a HudNode, Option<&'a HudPanel>, Option<&'a HudSprite>, Option<&'a HudText>)>) {
+        let extent = Vec2::new(extent.width as f32, extent.height as f32);
+        let mut nodes = nodes.filter(|(node, ..)| node.visible).collect::<Vec<_>>();
+        nodes.sort_by_key(|(node, ..)| node.layer);
+
+        let mut instances = Vec::new();
+        let mut batches: Vec<HudBatch> = Vec::new();
+        let mut push = |texture: Option<String>, rects: &[GpuRect]| {
+            if rects.is_empty() || instances.len() + rects.len() > MAX_HUD_RECTS {
+                return;
+            }
+            let first_instance = instances.len() as u32;
+            instances.extend_from_slice(rects);
+            match batches.last_mut() {
+                Some(batch) if batch.texture == texture => batch.instance_count += rects.len() as u32,
+                _ => batches.push(HudBatch {
+                    texture,
+                    first_instance,
+                    instance_count: rects.len() as u32,
+                }),
+            }
+        };
+
+        let mut rects = Vec::new();
+        for (node, panel, sprite, text) in nodes {
+            let (min, max) = node.rect(extent);
+
+            if let Some(panel) = panel {
+                if let Some(image) = self.resolve(textures, atlases, &panel.texture, &panel.region) {
+                    rects.clear();
+                    Self::nine_slice(panel, &image, min, max, &mut rects);
+                    push(image.texture, &rects);
+                }
+            }
+
+            if let Some(sprite) = sprite {
+                if let Some(image) = self.resolve(textures, atlases, &sprite.texture, &sprite.region) {
+                    push(image.texture, &[GpuRect {
+                        rect: Vec4::new(min.x, min.y, max.x, max.y),
+                        uv_rect: image.uv_rect,
+                        color: sprite.color,
+                    }]);
+                }
+            }
+
+            if let Some(text) = text {
+                if let Some(font) = textures.font(&text.font) {
+                    let (origin, align) = match text.align {
+                        HudTextAlign::Left => (min.x, 0.0),
+                        HudTextAlign::Center => ((min.x + max.x) * 0.5, 0.5),
+                        HudTextAlign::Right => (max.x, 1.0),
+                    };
+                    // the layout is y up with the last line on 0
+                    let height = text.text.lines().count().max(1) as f32 * text.size;
+                    let bottom = (min.y + max.y + height) * 0.5;
+                    rects.clear();
+                    rects.extend(font.layout(&text.text, text.size, align).into_iter().map(|(rect, uv_rect)| GpuRect {
+                        rect: Vec4::new(origin + rect.x, bottom - rect.w, origin + rect.z, bottom - rect.y),
+                        uv_rect,
+                        color: text.color,
+                    }));
+                    push(Some(font.atlas.texture.clone()), &rects);
+                }
+            }
+        }
+
+        if !instances.is_empty() {
+            self.instance_buffer.upload_data(context, &instances);
+        }
+        self.batches = batches;
+    }
+
+    /// Draws the prepared rectangles, call after the last pass writing the final image.
+    pub fn draw(&self, context: &RenderContext, textures: &TextureCache, command_buffer: vk::CommandBuffer,
+                forward_render: &ForwardRenderPass) {
+        if self.batches.is_empty() {
+            return;
+        }
+
+        let extent = forward_render.get_extent();
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(self.frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .build();
+        // y down, matching the pixel coordinates of the nodes
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: extent.width as _,
+            height: extent.height as _,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+        let constant = HudConstant {
+            extent: Vec2::new(extent.width as f32, extent.height as f32),
+        };
+
+        let device = &context.device;
+        let layout = self.pipeline.get_layout();
+        unsafe {
+            device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
+            device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+            device.cmd_set_scissor(command_buffer, 0, &[render_pass_begin_info.render_area]);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.get_pipeline());
+            device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::VERTEX, 0,
+                                      util::any_as_u8_slice(&constant));
+            for batch in &self.batches {
+                let texture_set = match textures.texture_set(batch.texture.as_deref()) {
+                    Some(set) => set,
+                    None => continue,
+                };
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                &[self.instance_set, texture_set], &[]);
+                device.cmd_draw(command_buffer, QUAD_VERTEX_COUNT, batch.instance_count, 0, batch.first_instance);
+            }
+            device.cmd_end_render_pass(command_buffer);
+        }
+    }
+}
+
+/// Asks the texture cache for the images, atlas images and fonts of HUD nodes.
+pub fn request_hud_resources_system(mut runner: Option<ResMut<RenderRunner>>,
+                                    asset_server: Res<AssetServer>,
+                                    atlases: Res<Assets<HudAtlas>>,
+                                    sprite_query: Query<&HudSprite>,
+                                    panel_query: Query<&HudPanel>,
+                                    text_query: Query<&HudText>) {
+    if let Some(runner) = &mut runner {
+        let runner: &mut RenderRunner = runner.deref_mut();
+        let textures = &mut runner.texture_cache;
+        for sprite in sprite_query.iter() {
+            runner.hud.request_texture(textures, &asset_server, &atlases, &sprite.texture);
+        }
+        for panel in panel_query.iter() {
+            runner.hud.request_texture(textures, &asset_server, &atlases, &panel.texture);
+        }
+        for text in text_query.iter() {
+            textures.request_font(&asset_server, &text.font);
+        }
+    }
+}
+
+pub fn prepare_hud_system(mut runner: Option<ResMut<RenderRunner>>,
+                          atlases: Res<Assets<HudAtlas>>,
+                          node_query: Query<(&HudNode, Option<&HudPanel>, Option<&HudSprite>, Option<&HudText>)>) {
+    if let Some(runner) = &mut runner {
+        let runner: &mut RenderRunner = runner.deref_mut();
+        let extent = runner.forward_render_pass.get_extent();
+        runner.hud.prepare(&runner.context, &runner.texture_cache, &atlases, extent, node_query.iter());
+    }
+}
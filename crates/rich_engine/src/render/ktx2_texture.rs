@@ -0,0 +1,436 @@
+use std::io::Read;
+use std::sync::Once;
+use anyhow::anyhow;
+use ash::vk;
+use basis_universal::{DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscodeParameters, Transcoder,
+                       TranscoderBlockFormat, TranscoderTextureFormat};
+use ktx2::{ColorModel, SupercompressionScheme};
+use crate::render::render_context::RenderContext;
+use crate::render::texture::Texture;
+use crate::render::capabilities::FormatSupport;
+use crate::render::render_error::RenderResult;
+
+static TRANSCODER_INIT: Once = Once::new();
+
+/// channel ids of the UASTC data format descriptor
+const UASTC_CHANNEL_RGBA: u32 = 3;
+const UASTC_CHANNEL_RRRG: u32 = 5;
+const UASTC_CHANNEL_RG: u32 = 6;
+/// channel ids of the second ETC1S slice
+const ETC1S_CHANNEL_GGG: u32 = 4;
+
+/// version and header sizes of the `.basis` container, see `basis_file.h`
+const BASIS_SIGNATURE: u64 = ((b'B' as u64) << 8) | b's' as u64;
+const BASIS_VERSION: u64 = 0x13;
+const BASIS_HEADER_SIZE: usize = 77;
+const BASIS_SLICE_DESC_SIZE: usize = 23;
+const BASIS_HEADER_FLAG_ETC1S: u64 = 1;
+const BASIS_HEADER_FLAG_HAS_ALPHA_SLICES: u64 = 4;
+const BASIS_HEADER_FLAG_SRGB: u64 = 16;
+const BASIS_SLICE_FLAG_ALPHA: u64 = 1;
+
+/// Mip chain ready to be uploaded as is.
+pub struct Ktx2Levels {
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl Ktx2Levels {
+    pub fn byte_size(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+}
+
+/// Channels stored in a Basis Universal texture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Channels {
+    Rgb,
+    Rgba,
+    /// two channel data, X in the color channels and Y in alpha, the
+    /// layout `basisu` uses for normal maps, or X and Y in red and green
+    Rg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TranscodeTarget {
+    Bc1,
+    Bc3,
+    Bc5,
+    Bc7,
+    Rgba32,
+}
+
+impl TranscodeTarget {
+    fn vk_format(self, srgb: bool) -> vk::Format {
+        match (self, srgb) {
+            (TranscodeTarget::Bc1, false) => vk::Format::BC1_RGB_UNORM_BLOCK,
+            (TranscodeTarget::Bc1, true) => vk::Format::BC1_RGB_SRGB_BLOCK,
+            (TranscodeTarget::Bc3, false) => vk::Format::BC3_UNORM_BLOCK,
+            (TranscodeTarget::Bc3, true) => vk::Format::BC3_SRGB_BLOCK,
+            // two channel data is never color
+            (TranscodeTarget::Bc5, _) => vk::Format::BC5_UNORM_BLOCK,
+            (TranscodeTarget::Bc7, false) => vk::Format::BC7_UNORM_BLOCK,
+            (TranscodeTarget::Bc7, true) => vk::Format::BC7_SRGB_BLOCK,
+            (TranscodeTarget::Rgba32, false) => vk::Format::R8G8B8A8_UNORM,
+            (TranscodeTarget::Rgba32, true) => vk::Format::R8G8B8A8_SRGB,
+        }
+    }
+
+    fn block_format(self) -> TranscoderBlockFormat {
+        match self {
+            TranscodeTarget::Bc1 => TranscoderBlockFormat::BC1,
+            TranscodeTarget::Bc3 => TranscoderBlockFormat::BC3,
+            TranscodeTarget::Bc5 => TranscoderBlockFormat::BC5,
+            TranscodeTarget::Bc7 => TranscoderBlockFormat::BC7,
+            TranscodeTarget::Rgba32 => TranscoderBlockFormat::RGBA32,
+        }
+    }
+
+    fn texture_format(self) -> TranscoderTextureFormat {
+        match self {
+            TranscodeTarget::Bc1 => TranscoderTextureFormat::BC1_RGB,
+            TranscodeTarget::Bc3 => TranscoderTextureFormat::BC3_RGBA,
+            TranscodeTarget::Bc5 => TranscoderTextureFormat::BC5_RG,
+            TranscodeTarget::Bc7 => TranscoderTextureFormat::BC7_RGBA,
+            TranscodeTarget::Rgba32 => TranscoderTextureFormat::RGBA32,
+        }
+    }
+}
+
+/// ETC1S converts to BC1 and BC3 almost losslessly, UASTC keeps its quality in BC7.
+/// Uncompressed RGBA works everywhere.
+fn select_target(formats: &FormatSupport, channels: Channels, etc1s: bool, srgb: bool) -> TranscodeTarget {
+    let candidates: &[TranscodeTarget] = match (channels, etc1s) {
+        (Channels::Rg, _) => &[TranscodeTarget::Bc5],
+        (Channels::Rgb, true) => &[TranscodeTarget::Bc1, TranscodeTarget::Bc7],
+        (Channels::Rgba, true) => &[TranscodeTarget::Bc3, TranscodeTarget::Bc7],
+        (Channels::Rgb, false) => &[TranscodeTarget::Bc7, TranscodeTarget::Bc1],
+        (Channels::Rgba, false) => &[TranscodeTarget::Bc7, TranscodeTarget::Bc3],
+    };
+    candidates.iter().copied()
+        .find(|target| formats.is_sampleable(target.vk_format(srgb)))
+        .unwrap_or(TranscodeTarget::Rgba32)
+}
+
+fn uastc_channels(dfd: &ktx2::BasicDataFormatDescriptor) -> Channels {
+    uastc_channel_type(dfd.sample_information().next().map(|s| s.channel_type))
+}
+
+/// RG and RRRG are both transcoded to BC5, the shaders rebuild Z of the normal maps.
+fn uastc_channel_type(channel_type: Option<u32>) -> Channels {
+    match channel_type {
+        Some(UASTC_CHANNEL_RGBA) => Channels::Rgba,
+        Some(UASTC_CHANNEL_RRRG) | Some(UASTC_CHANNEL_RG) => Channels::Rg,
+        _ => Channels::Rgb,
+    }
+}
+
+/// ETC1S stores a second slice for alpha or the Y channel of two channel data.
+fn etc1s_channels(dfd: &ktx2::BasicDataFormatDescriptor) -> Channels {
+    match dfd.sample_information().nth(1).map(|s| s.channel_type) {
+        None => Channels::Rgb,
+        Some(ETC1S_CHANNEL_GGG) => Channels::Rg,
+        Some(_) => Channels::Rgba,
+    }
+}
+
+fn decompress_level(scheme: Option<SupercompressionScheme>, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match scheme {
+        None => Ok(data.to_vec()),
+        Some(SupercompressionScheme::Zstandard) => {
+            let mut cursor = std::io::Cursor::new(data);
+            let mut decoder = ruzstd::StreamingDecoder::new(&mut cursor).map_err(|e| anyhow!(e))?;
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        Some(scheme) => Err(anyhow!("unsupported ktx2 supercompression {:?}", scheme)),
+    }
+}
+
+fn transcode_uastc(formats: &FormatSupport, reader: &ktx2::Reader<&[u8]>, channels: Channels, srgb: bool) -> anyhow::Result<Ktx2Levels> {
+    TRANSCODER_INIT.call_once(basis_universal::transcoder_init);
+
+    let header = reader.header();
+    let target = select_target(formats, channels, false, srgb);
+    let transcoder = LowLevelUastcTranscoder::new();
+
+    let mut levels = Vec::new();
+    for (level, data) in reader.levels().enumerate() {
+        let width = (header.pixel_width >> level).max(1);
+        let height = (header.pixel_height >> level).max(1);
+        let data = decompress_level(header.supercompression_scheme, data)?;
+        let transcoded = transcoder.transcode_slice(
+            &data,
+            SliceParametersUastc {
+                num_blocks_x: (width + 3) / 4,
+                num_blocks_y: (height + 3) / 4,
+                has_alpha: channels != Channels::Rgb,
+                original_width: width,
+                original_height: height,
+            },
+            DecodeFlags::HIGH_QUALITY,
+            target.block_format(),
+        ).map_err(|e| anyhow!("failed to transcode mip {} to {:?}: {:?}", level, target, e))?;
+        levels.push(transcoded);
+    }
+
+    Ok(Ktx2Levels {
+        width: header.pixel_width,
+        height: header.pixel_height,
+        format: target.vk_format(srgb),
+        levels,
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("truncated BasisLZ global data"))
+}
+
+fn put_uint(out: &mut Vec<u8>, value: u64, size: usize) {
+    out.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/// CRC-16 used by the `.basis` container
+fn basis_crc16(data: &[u8]) -> u16 {
+    let mut crc = !0u16;
+    for &b in data {
+        let q = b as u16 ^ (crc >> 8);
+        let k = (q >> 4) ^ q;
+        crc = (((crc << 8) ^ k) ^ (k << 5)) ^ (k << 12);
+    }
+    !crc
+}
+
+/// A BasisLZ payload is split between the supercompression global data, which holds the
+/// codebooks and per-level slice ranges, and the mip levels. The transcoder only reads ETC1S
+/// from `.basis` files, so the payload is repacked into one with a single image.
+fn etc1s_to_basis(reader: &ktx2::Reader<&[u8]>, has_alpha: bool, srgb: bool) -> anyhow::Result<Vec<u8>> {
+    let header = reader.header();
+    let sgd = reader.supercompression_global_data();
+    if sgd.len() < 20 {
+        return Err(anyhow!("truncated BasisLZ global data"));
+    }
+    let endpoint_count = u16::from_le_bytes([sgd[0], sgd[1]]) as u64;
+    let selector_count = u16::from_le_bytes([sgd[2], sgd[3]]) as u64;
+    let endpoints_len = read_u32(sgd, 4)? as usize;
+    let selectors_len = read_u32(sgd, 8)? as usize;
+    let tables_len = read_u32(sgd, 12)? as usize;
+
+    const IMAGE_DESC_SIZE: usize = 20;
+    let level_count = reader.levels().len();
+    let codebooks_start = 20 + level_count * IMAGE_DESC_SIZE;
+    let codebooks = sgd.get(codebooks_start..codebooks_start + endpoints_len + selectors_len + tables_len)
+        .ok_or_else(|| anyhow!("truncated BasisLZ global data"))?;
+
+    // (level, alpha, data) in the order the transcoder expects
+    let mut slices = Vec::new();
+    for (level, data) in reader.levels().enumerate() {
+        let desc = 20 + level * IMAGE_DESC_SIZE;
+        let (rgb_offset, rgb_len) = (read_u32(sgd, desc + 4)? as usize, read_u32(sgd, desc + 8)? as usize);
+        let (alpha_offset, alpha_len) = (read_u32(sgd, desc + 12)? as usize, read_u32(sgd, desc + 16)? as usize);
+        let rgb = data.get(rgb_offset..rgb_offset + rgb_len).ok_or_else(|| anyhow!("BasisLZ slice out of mip {}", level))?;
+        slices.push((level, false, rgb));
+        if has_alpha {
+            let alpha = data.get(alpha_offset..alpha_offset + alpha_len).ok_or_else(|| anyhow!("BasisLZ slice out of mip {}", level))?;
+            slices.push((level, true, alpha));
+        }
+    }
+
+    let slice_descs_start = BASIS_HEADER_SIZE;
+    let endpoints_start = slice_descs_start + slices.len() * BASIS_SLICE_DESC_SIZE;
+    let selectors_start = endpoints_start + endpoints_len;
+    let tables_start = selectors_start + selectors_len;
+    let mut slice_start = tables_start + tables_len;
+
+    let mut body = Vec::new();
+    for (level, alpha, data) in slices.iter() {
+        let width = (header.pixel_width >> level).max(1) as u64;
+        let height = (header.pixel_height >> level).max(1) as u64;
+        put_uint(&mut body, 0, 3);
+        put_uint(&mut body, *level as u64, 1);
+        put_uint(&mut body, if *alpha { BASIS_SLICE_FLAG_ALPHA } else { 0 }, 1);
+        put_uint(&mut body, width, 2);
+        put_uint(&mut body, height, 2);
+        put_uint(&mut body, (width + 3) / 4, 2);
+        put_uint(&mut body, (height + 3) / 4, 2);
+        put_uint(&mut body, slice_start as u64, 4);
+        put_uint(&mut body, data.len() as u64, 4);
+        put_uint(&mut body, basis_crc16(data) as u64, 2);
+        slice_start += data.len();
+    }
+    body.extend_from_slice(codebooks);
+    for (_, _, data) in slices.iter() {
+        body.extend_from_slice(data);
+    }
+
+    let mut flags = BASIS_HEADER_FLAG_ETC1S;
+    if has_alpha {
+        flags |= BASIS_HEADER_FLAG_HAS_ALPHA_SLICES;
+    }
+    if srgb {
+        flags |= BASIS_HEADER_FLAG_SRGB;
+    }
+
+    // fields after the header crc, which covers them
+    let mut fields = Vec::with_capacity(BASIS_HEADER_SIZE - 8);
+    put_uint(&mut fields, body.len() as u64, 4);
+    put_uint(&mut fields, basis_crc16(&body) as u64, 2);
+    put_uint(&mut fields, slices.len() as u64, 3);
+    put_uint(&mut fields, 1, 3);
+    // ETC1S texture format, 2D texture type
+    put_uint(&mut fields, 0, 1);
+    put_uint(&mut fields, flags, 2);
+    put_uint(&mut fields, 0, 1);
+    // us per frame, reserved and user data
+    put_uint(&mut fields, 0, 3);
+    put_uint(&mut fields, 0, 4);
+    put_uint(&mut fields, 0, 4);
+    put_uint(&mut fields, 0, 4);
+    put_uint(&mut fields, endpoint_count, 2);
+    put_uint(&mut fields, endpoints_start as u64, 4);
+    put_uint(&mut fields, endpoints_len as u64, 3);
+    put_uint(&mut fields, selector_count, 2);
+    put_uint(&mut fields, selectors_start as u64, 4);
+    put_uint(&mut fields, selectors_len as u64, 3);
+    put_uint(&mut fields, tables_start as u64, 4);
+    put_uint(&mut fields, tables_len as u64, 4);
+    put_uint(&mut fields, slice_descs_start as u64, 4);
+    // no extended data
+    put_uint(&mut fields, 0, 4);
+    put_uint(&mut fields, 0, 4);
+
+    let mut basis = Vec::with_capacity(BASIS_HEADER_SIZE + body.len());
+    put_uint(&mut basis, BASIS_SIGNATURE, 2);
+    put_uint(&mut basis, BASIS_VERSION, 2);
+    put_uint(&mut basis, BASIS_HEADER_SIZE as u64, 2);
+    put_uint(&mut basis, basis_crc16(&fields) as u64, 2);
+    basis.extend(fields);
+    basis.extend(body);
+    Ok(basis)
+}
+
+fn transcode_etc1s(formats: &FormatSupport, reader: &ktx2::Reader<&[u8]>, channels: Channels, srgb: bool) -> anyhow::Result<Ktx2Levels> {
+    TRANSCODER_INIT.call_once(basis_universal::transcoder_init);
+
+    let header = reader.header();
+    if header.supercompression_scheme != Some(SupercompressionScheme::BasisLZ) {
+        return Err(anyhow!("ETC1S ktx2 without BasisLZ supercompression"));
+    }
+    let target = select_target(formats, channels, true, srgb);
+    let basis = etc1s_to_basis(reader, channels != Channels::Rgb, srgb)?;
+
+    let mut transcoder = Transcoder::new();
+    transcoder.prepare_transcoding(&basis).map_err(|_| anyhow!("invalid BasisLZ data"))?;
+    let mut levels = Vec::new();
+    for level in 0..reader.levels().len() as u32 {
+        let transcoded = transcoder.transcode_image_level(&basis, target.texture_format(), TranscodeParameters {
+            image_index: 0,
+            level_index: level,
+            decode_flags: Some(DecodeFlags::HIGH_QUALITY),
+            output_row_pitch_in_blocks_or_pixels: None,
+            output_rows_in_pixels: None,
+        });
+        match transcoded {
+            Ok(data) => levels.push(data),
+            Err(e) => {
+                transcoder.end_transcoding();
+                return Err(anyhow!("failed to transcode mip {} to {:?}: {:?}", level, target, e));
+            }
+        }
+    }
+    transcoder.end_transcoding();
+
+    Ok(Ktx2Levels {
+        width: header.pixel_width,
+        height: header.pixel_height,
+        format: target.vk_format(srgb),
+        levels,
+    })
+}
+
+/// Reads the stored mip chain of a 2D KTX2 texture. UASTC and BasisLZ/ETC1S payloads
+/// are transcoded to a format the device can sample, other formats are used as stored.
+/// `srgb` only applies to transcoded data, stored formats carry their own color space.
+/// Runs on any thread.
+pub fn read_ktx2(formats: &FormatSupport, bytes: &[u8], srgb: bool) -> anyhow::Result<Ktx2Levels> {
+    let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("invalid ktx2: {:?}", e))?;
+    let header = reader.header();
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+        return Err(anyhow!("only 2D ktx2 textures are supported"));
+    }
+
+    let dfd = reader.data_format_descriptors().next().ok_or(anyhow!("ktx2 without data format descriptor"))?;
+    let basic_dfd = ktx2::BasicDataFormatDescriptor::parse(dfd.data).map_err(|e| anyhow!("invalid ktx2 dfd: {:?}", e))?;
+
+    match (header.format, basic_dfd.color_model) {
+        (None, Some(ColorModel::UASTC)) => transcode_uastc(formats, &reader, uastc_channels(&basic_dfd), srgb),
+        (None, Some(ColorModel::ETC1S)) => transcode_etc1s(formats, &reader, etc1s_channels(&basic_dfd), srgb),
+        (None, model) => Err(anyhow!("unsupported ktx2 color model {:?}", model)),
+        (Some(format), _) => {
+            let format = vk::Format::from_raw(format.0.get() as i32);
+            if !formats.is_sampleable(format) {
+                return Err(anyhow!("ktx2 format {:?} is not supported by the device", format));
+            }
+            let levels = reader.levels()
+                .map(|data| decompress_level(header.supercompression_scheme, data))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(Ktx2Levels {
+                width: header.pixel_width,
+                height: header.pixel_height,
+                format,
+                levels,
+            })
+        }
+    }
+}
+
+pub fn create_texture_by_ktx2(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                              ktx2: &Ktx2Levels) -> RenderResult<Texture> {
+    let image_ci = vk::ImageCreateInfo::builder()
+        .extent(vk::Extent3D { width: ktx2.width, height: ktx2.height.max(1), depth: 1 })
+        .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .mip_levels(ktx2.levels.len().max(1) as u32)
+        .array_layers(1)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .format(ktx2.format)
+        .flags(vk::ImageCreateFlags::empty())
+        .image_type(vk::ImageType::TYPE_2D)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .build();
+
+    Texture::create_from_levels(context, upload_command_buffer, &image_ci, &ktx2.levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basis_crc16_check_value() {
+        assert_eq!(basis_crc16(b"123456789"), 0xd64e);
+        assert_eq!(basis_crc16(&[]), 0);
+    }
+
+    #[test]
+    fn put_uint_writes_little_endian() {
+        let mut out = vec![];
+        put_uint(&mut out, 0x123456, 3);
+        put_uint(&mut out, BASIS_SIGNATURE, 2);
+        assert_eq!(out, vec![0x56, 0x34, 0x12, b's', b'B']);
+    }
+
+    #[test]
+    fn uastc_two_channel_ids_are_rg() {
+        assert_eq!(uastc_channel_type(Some(UASTC_CHANNEL_RG)), Channels::Rg);
+        assert_eq!(uastc_channel_type(Some(UASTC_CHANNEL_RRRG)), Channels::Rg);
+        assert_eq!(uastc_channel_type(Some(UASTC_CHANNEL_RGBA)), Channels::Rgba);
+        assert_eq!(uastc_channel_type(None), Channels::Rgb);
+    }
+}
@@ -10,23 +10,28 @@ use crate::render::vertex_layout::VertexLayout;
 use std::io::Write;
 use bevy::prelude::*;
 use crate::render::shader_const::*;
+use crate::render::tangent::TangentGenerator;
+use crate::render::util;
+use gltf::accessor::sparse::{IndexType, Sparse};
+use crate::render::mesh_bvh::MeshBvh;
+use crate::render::capabilities::FormatSupport;
+use gltf::accessor::DataType;
 
 
 pub struct Mesh {
     primitives: Vec<Primitive>,
     aabb: Aabb,
+    weights: Vec<f32>,
+    bvh: Option<MeshBvh>,
 }
 
 impl Mesh {
-    fn new(primitives: Vec<Primitive>) -> Self {
+    fn new(primitives: Vec<Primitive>, weights: Vec<f32>, bvh: Option<MeshBvh>) -> Self {
         let aabbs = primitives.iter().map(|p| p.aabb()).collect::<Vec<_>>();
         let aabb = Aabb::union(&aabbs).unwrap_or(Aabb::new(Vec3::ZERO, Vec3::ZERO));
-        Mesh { primitives, aabb }
+        Mesh { primitives, aabb, weights, bvh }
     }
 
-    pub fn get_primitives(&self) -> &Vec<Primitive> {
-        &self.primitives
-    }
 }
 
 impl Mesh {
@@ -41,6 +46,20 @@ impl Mesh {
     pub fn aabb(&self) -> Aabb {
         self.aabb
     }
+
+    /// default morph target weights
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn has_morph_targets(&self) -> bool {
+        self.primitives.iter().any(|p| p.morph_targets.is_some())
+    }
+
+    /// triangle BVH used for picking
+    pub fn bvh(&self) -> Option<&MeshBvh> {
+        self.bvh.as_ref()
+    }
 }
 
 pub struct Meshes {
@@ -55,19 +74,50 @@ impl Meshes {
         }
     }
 
-    pub fn from_gltf(context: &mut RenderContext,
-                     upload_command_buffer: vk::CommandBuffer,
-                     document: &gltf::Document,
-                     buffers: &Vec<gltf::buffer::Data>) -> Meshes {
-        load_meshes(context, upload_command_buffer, document, buffers)
+    /// Reads every mesh and packs indices, vertices and morph targets into one
+    /// buffer data, CPU only so it can run on a worker thread.
+    /// Attributes in formats `formats` can't fetch from vertex buffers are widened.
+    pub fn prepare_gltf(document: &gltf::Document, buffers: &Vec<gltf::buffer::Data>,
+                        formats: &FormatSupport) -> (Vec<Mesh>, Vec<u8>) {
+        prepare_meshes(document, buffers, &|format| formats.is_vertex_format(format))
+    }
+
+    /// Copies the data packed by `prepare_gltf` to a device local buffer.
+    pub fn upload(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                  meshes: Vec<Mesh>, data: &[u8]) -> Meshes {
+        let buffer = Buffer::create_device_local_buffer(context, upload_command_buffer,
+                                                        vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                        data);
+        Meshes {
+            meshes,
+            buffer,
+        }
     }
 }
 
+pub const MORPH_ATTRIBUTE_POSITION: u32 = 1;
+pub const MORPH_ATTRIBUTE_NORMAL: u32 = 1 << 1;
+pub const MORPH_ATTRIBUTE_TANGENT: u32 = 1 << 2;
+
+const MORPH_DELTA_SIZE: usize = size_of::<[f32; 4]>();
+/// max value of `minStorageBufferOffsetAlignment` allowed by the spec
+const MORPH_BUFFER_ALIGNMENT: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MorphTargets {
+    pub offset: usize,
+    pub size: usize,
+    pub target_count: u32,
+    pub vertex_count: u32,
+    pub attributes: u32,
+}
+
 pub struct Primitive {
     index: usize,
     material: Material,
     aabb: Aabb,
     vertex_layout: VertexLayout,
+    morph_targets: Option<MorphTargets>,
 }
 
 impl Primitive {
@@ -94,6 +144,10 @@ impl Primitive {
     pub fn get_vertex_layout(&self) -> &VertexLayout {
         &self.vertex_layout
     }
+
+    pub fn get_morph_targets(&self) -> Option<&MorphTargets> {
+        self.morph_targets.as_ref()
+    }
 }
 
 
@@ -117,96 +171,339 @@ fn buffer_view_slice<'a, 's>(
     &buffer[start..end]
 }
 
-fn read_no_sparse_buffer(accessor: &gltf::accessor::Accessor, datas: &Vec<gltf::buffer::Data>, element_count: u32) -> (Vec<u8>, usize, usize) {
-    assert!(accessor.sparse().is_none(), "load sparse data is not supported");
-    let view = accessor.view().expect("not view found");
+fn read_sparse_index(indices: &[u8], index_type: &IndexType, i: usize) -> usize {
+    match index_type {
+        IndexType::U8 => indices[i] as usize,
+        IndexType::U16 => u16::from_le_bytes([indices[i * 2], indices[i * 2 + 1]]) as usize,
+        IndexType::U32 => u32::from_le_bytes([indices[i * 4], indices[i * 4 + 1], indices[i * 4 + 2], indices[i * 4 + 3]]) as usize,
+    }
+}
+
+/// Overwrite the elements listed by the sparse accessor, `stride` is the packed element size
+fn apply_sparse(data: &mut [u8], sparse: &Sparse, stride: usize, datas: &Vec<gltf::buffer::Data>) {
+    let indices = sparse.indices();
+    let index_type = indices.index_type();
+    let indices_slice = &buffer_view_slice(indices.view(), datas)[indices.offset() as usize..];
+
+    let values = sparse.values();
+    let values_slice = &buffer_view_slice(values.view(), datas)[values.offset() as usize..];
+
+    for i in 0..sparse.count() as usize {
+        let dst = read_sparse_index(indices_slice, &index_type, i) * stride;
+        let src = i * stride;
+        data[dst..dst + stride].copy_from_slice(&values_slice[src..src + stride]);
+    }
+}
+
+/// Read the accessor as tightly packed elements, returns (data, count, stride)
+fn read_accessor_data(accessor: &gltf::accessor::Accessor, datas: &Vec<gltf::buffer::Data>) -> (Vec<u8>, usize, usize) {
+    let element_count = accessor.dimensions().multiplicity() as u32;
     let stride = VertexLayout::calculate_stride(accessor.data_type(), element_count) as usize;
-    let v_stride = if let Some(v) = view.stride() {
-        //assert_eq!(stride, v_stride as u32, "error stride");
-        v
-    } else {
-        stride
+    let count = accessor.count();
+
+    let mut ret = match accessor.view() {
+        Some(view) => {
+            let v_stride = view.stride().unwrap_or(stride);
+            let view_slice = buffer_view_slice(view, datas);
+            let start = accessor.offset();
+
+            if stride == v_stride {
+                (&view_slice[start..start + stride * count]).to_vec()
+            } else {
+                let mut ret = Vec::with_capacity(stride * count);
+                for i in 0..count {
+                    let si = start + i * v_stride;
+                    ret.extend_from_slice(&view_slice[si..si + stride]);
+                }
+                ret
+            }
+        }
+        // sparse accessor without buffer view, the base data is all zeros
+        None => vec![0; stride * count],
     };
 
-    let view_slice = buffer_view_slice(view, datas);
-    let start = accessor.offset();
-    let count = accessor.count();
+    if let Some(sparse) = accessor.sparse() {
+        apply_sparse(&mut ret, &sparse, stride, datas);
+    }
+
+    (ret, count, stride)
+}
+
+/// vertex attributes and indices must start at a 4 bytes boundary
+fn align_data(output_data: &mut Vec<u8>) {
+    const MOD: usize = 4;
+    let m = output_data.len() % MOD;
+    if m != 0 {
+        output_data.extend(vec![0; MOD - m]);
+    }
+}
 
-    if stride == v_stride {
-        let end = start + (stride as usize) * count;
-        return ((&view_slice[start..end]).to_vec(), count, v_stride);
+fn read_indices(accessor: &gltf::accessor::Accessor, datas: &Vec<gltf::buffer::Data>) -> (Vec<u8>, usize) {
+    let (data, count, stride) = read_accessor_data(accessor, datas);
+    if accessor.data_type() != gltf::accessor::DataType::U8 {
+        return (data, count);
     }
 
-    let mut ret = vec![];
-    let data_len = view_slice.len();
+    // vulkan has no 8 bit index type without extensions, widen to u16
+    let mut ret = Vec::with_capacity(count * 2);
     for i in 0..count {
-        let si = start + i * v_stride;
-        let mut ei = si + v_stride;
-        if ei > data_len {
-            ei = data_len;
-            assert_eq!(i, count - 1, "error data len");
+        ret.extend_from_slice(&(data[i * stride] as u16).to_ne_bytes());
+    }
+    (ret, count)
+}
+
+/// An attribute packed as `element_count` components of `data_type`.
+struct VertexData {
+    data: Vec<u8>,
+    data_type: DataType,
+    element_count: u32,
+    normalized: bool,
+}
+
+impl VertexData {
+    fn stride(&self) -> u32 {
+        VertexLayout::calculate_stride(self.data_type, self.element_count)
+    }
+
+    /// Repacks the data in a format the device can fetch when its own is not supported.
+    /// Many drivers lack the 3 component 8 and 16 bit formats, those get a 4th component
+    /// of one, the value vulkan would have filled in. Anything else falls back to f32, or
+    /// to u32 for integer attributes, which every device supports.
+    fn widen(self, integer: bool, supported: &dyn Fn(vk::Format) -> bool) -> Self {
+        let format = VertexLayout::gltf_data_type_2_vk_format(self.data_type, self.element_count, self.normalized, integer);
+        if supported(format) {
+            return self;
         }
 
-        ret.extend_from_slice(&view_slice[si..ei]);
+        let size = self.data_type.size();
+        let stride = self.stride() as usize;
+        if self.element_count == 3 && size < 4
+            && supported(VertexLayout::gltf_data_type_2_vk_format(self.data_type, 4, self.normalized, integer)) {
+            let one = component_one(self.data_type, self.normalized);
+            let mut data = Vec::with_capacity(self.data.len() / 3 * 4);
+            for element in self.data.chunks_exact(stride) {
+                data.extend_from_slice(element);
+                data.extend_from_slice(&one[..size]);
+            }
+            return VertexData { data, element_count: 4, ..self };
+        }
+
+        let mut data = Vec::with_capacity(self.data.len() / size * 4);
+        for component in self.data.chunks_exact(size) {
+            if integer {
+                let value = match self.data_type {
+                    DataType::U8 => component[0] as u32,
+                    DataType::U16 => u16::from_le_bytes([component[0], component[1]]) as u32,
+                    DataType::I8 => component[0] as i8 as u32,
+                    DataType::I16 => i16::from_le_bytes([component[0], component[1]]) as u32,
+                    _ => u32::from_le_bytes([component[0], component[1], component[2], component[3]]),
+                };
+                data.extend_from_slice(&value.to_le_bytes());
+            } else {
+                let value = VertexLayout::read_float_component(component, self.data_type, self.normalized);
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let data_type = if integer { DataType::U32 } else { DataType::F32 };
+        VertexData { data, data_type, element_count: self.element_count, normalized: false }
     }
+}
 
-    (ret, count, v_stride)
+/// Little endian one in the format of a component, the 4th component vulkan reads
+/// for attributes with 3.
+fn component_one(data_type: DataType, normalized: bool) -> [u8; 2] {
+    match (data_type, normalized) {
+        (DataType::U8, true) => [u8::MAX, 0],
+        (DataType::I8, true) => [i8::MAX as u8, 0],
+        (DataType::U16, true) => u16::MAX.to_le_bytes(),
+        (DataType::I16, true) => i16::MAX.to_le_bytes(),
+        (DataType::U8, _) | (DataType::I8, _) => [1, 0],
+        _ => 1u16.to_le_bytes(),
+    }
 }
 
-fn read_no_sparse_vertex_data(primitive: &gltf::mesh::Primitive,
-                              data_type: &gltf::Semantic,
-                              datas: &Vec<gltf::buffer::Data>,
-                              element_count: u32,
-                              output_data: &mut Vec<u8>,
-                              layout: &mut VertexLayout,
-                              location: u32) {
+fn read_vertex_data(primitive: &gltf::mesh::Primitive,
+                    data_type: &gltf::Semantic,
+                    datas: &Vec<gltf::buffer::Data>,
+                    output_data: &mut Vec<u8>,
+                    layout: &mut VertexLayout,
+                    location: u32,
+                    supported: &dyn Fn(vk::Format) -> bool) {
     if let Some(accessor) = &primitive.get(data_type) {
-        let (data, count, stride) = read_no_sparse_buffer(accessor, datas, element_count);
-        let mut offset = output_data.len();
-        output_data.extend(data);
-        const MOD: usize = 4;
-        let m = output_data.len() % MOD;
-        if m != 0 {
-            let left = MOD - m;
-            let ar = vec![0; left];
-            output_data.extend(ar);
-            offset += left;
+        let (data, _count, _stride) = read_accessor_data(accessor, datas);
+        let vertex_data = VertexData {
+            data,
+            data_type: accessor.data_type(),
+            element_count: accessor.dimensions().multiplicity() as u32,
+            normalized: accessor.normalized(),
+        }.widen(is_integer_location(location), supported);
+
+        align_data(output_data);
+        let offset = output_data.len();
+        output_data.extend_from_slice(&vertex_data.data);
+        layout.push_meta(vertex_data.data_type, vertex_data.element_count, vertex_data.normalized, offset, location,
+                         vertex_data.stride());
+    }
+}
+
+/// Morph target deltas are stored as `vec4` in the mesh buffer and read by the
+/// vertex shader as a storage buffer:
+/// `deltas[(target * attribute_count + attribute) * vertex_count + vertex]`
+fn read_morph_targets(primitive: &gltf::mesh::Primitive, datas: &Vec<gltf::buffer::Data>,
+                      output_data: &mut Vec<u8>) -> Option<MorphTargets> {
+    let reader = primitive.reader(|buffer| Some(&datas[buffer.index()]));
+    let targets = reader.read_morph_targets().collect::<Vec<_>>();
+    let vertex_count = primitive.get(&gltf::Semantic::Positions)?.count();
+    let (has_positions, has_normals, has_tangents) = match targets.first() {
+        Some((p, n, t)) => (p.is_some(), n.is_some(), t.is_some()),
+        None => return None,
+    };
+
+    let mut attributes = 0;
+    if has_positions {
+        attributes |= MORPH_ATTRIBUTE_POSITION;
+    }
+    if has_normals {
+        attributes |= MORPH_ATTRIBUTE_NORMAL;
+    }
+    if has_tangents {
+        attributes |= MORPH_ATTRIBUTE_TANGENT;
+    }
+
+    let m = output_data.len() % MORPH_BUFFER_ALIGNMENT;
+    if m != 0 {
+        output_data.extend(vec![0; MORPH_BUFFER_ALIGNMENT - m]);
+    }
+    let offset = output_data.len();
+
+    let push_deltas = |deltas: Option<gltf::accessor::Iter<[f32; 3]>>, output_data: &mut Vec<u8>| {
+        let mut count = 0;
+        if let Some(deltas) = deltas {
+            for d in deltas.take(vertex_count) {
+                for v in [d[0], d[1], d[2], 0f32].iter() {
+                    output_data.extend_from_slice(&v.to_ne_bytes());
+                }
+                count += 1;
+            }
         }
+        output_data.extend(vec![0; (vertex_count - count) * MORPH_DELTA_SIZE]);
+    };
 
-        layout.push_meta(accessor.data_type(), element_count, offset, location, stride as _);
+    let target_count = targets.len();
+    for (positions, normals, tangents) in targets {
+        if has_positions {
+            push_deltas(positions, output_data);
+        }
+        if has_normals {
+            push_deltas(normals, output_data);
+        }
+        if has_tangents {
+            push_deltas(tangents, output_data);
+        }
     }
+
+    Some(MorphTargets {
+        offset,
+        size: output_data.len() - offset,
+        target_count: target_count as u32,
+        vertex_count: vertex_count as u32,
+        attributes,
+    })
 }
 
+/// MikkTSpace tangents for primitives that have a normal map but no TANGENT attribute
+fn generate_tangents(primitive: &gltf::mesh::Primitive, datas: &Vec<gltf::buffer::Data>, tex_coord_channel: u32) -> Option<Vec<[f32; 4]>> {
+    let reader = primitive.reader(|buffer| Some(&datas[buffer.index()]));
+    let positions = reader.read_positions()?.collect::<Vec<_>>();
+    let normals = reader.read_normals()?.collect::<Vec<_>>();
+    let tex_coords = reader.read_tex_coords(tex_coord_channel)?.into_f32().collect::<Vec<_>>();
+    let indices = reader.read_indices()?.into_u32().collect::<Vec<_>>();
+
+    TangentGenerator::new(positions, normals, tex_coords, indices).generate()
+}
 
-fn load_meshes(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
-               document: &gltf::Document, buffers: &Vec<gltf::buffer::Data>) -> Meshes {
+/// Positions and indices on the CPU side, used to build the picking BVH
+fn read_triangles(primitive: &gltf::mesh::Primitive, indices_accessor: &gltf::accessor::Accessor,
+                  datas: &Vec<gltf::buffer::Data>) -> (Vec<Vec3>, Vec<u32>) {
+    let positions = match primitive.get(&gltf::Semantic::Positions) {
+        Some(accessor) => {
+            // KHR_mesh_quantization allows integer positions
+            let (data_type, normalized) = (accessor.data_type(), accessor.normalized());
+            let size = data_type.size();
+            let (data, _count, stride) = read_accessor_data(&accessor, datas);
+            data.chunks_exact(stride)
+                .map(|v| Vec3::new(VertexLayout::read_float_component(v, data_type, normalized),
+                                   VertexLayout::read_float_component(&v[size..], data_type, normalized),
+                                   VertexLayout::read_float_component(&v[size * 2..], data_type, normalized)))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    let (data, count, _stride) = read_accessor_data(indices_accessor, datas);
+    let indices = (0..count).map(|i| match indices_accessor.data_type() {
+        gltf::accessor::DataType::U8 => data[i] as u32,
+        gltf::accessor::DataType::U16 => u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as u32,
+        _ => u32::from_le_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]),
+    }).collect();
+
+    (positions, indices)
+}
+
+fn prepare_meshes(document: &gltf::Document, buffers: &Vec<gltf::buffer::Data>,
+                  supported: &dyn Fn(vk::Format) -> bool) -> (Vec<Mesh>, Vec<u8>) {
     let mut primitive_count = 0;
     let mut all_data = Vec::<u8>::new();
     let mut meshes = Vec::<Mesh>::new();
 
     for mesh in document.meshes() {
         let mut primitives_buffers = Vec::<Primitive>::new();
+        let mut triangles = Vec::new();
         for primitive in mesh.primitives() {
             assert_eq!(primitive.mode(), gltf::mesh::Mode::Triangles, "error mode");
 
             let mut vertex_layout = VertexLayout::create();
-            let indices_offset = all_data.len();
 
             let indices_accessor = &primitive.indices().expect("no indices");
-            let (indices, indices_count, stride) = read_no_sparse_buffer(indices_accessor, buffers, 1);
+            triangles.push(read_triangles(&primitive, indices_accessor, buffers));
+            let (indices, indices_count) = read_indices(indices_accessor, buffers);
+            align_data(&mut all_data);
+            let indices_offset = all_data.len();
             all_data.extend(indices);
 
-
             vertex_layout.set_indices(indices_offset, indices_count, indices_accessor.data_type());
-            read_no_sparse_vertex_data(&primitive, &gltf::Semantic::Positions, buffers, 3, &mut all_data, &mut vertex_layout, LOCATION_IN_POS);
-            read_no_sparse_vertex_data(&primitive, &gltf::Semantic::Normals, buffers, 3, &mut all_data, &mut vertex_layout, LOCATION_IN_NORMAL);
-            read_no_sparse_vertex_data(&primitive, &gltf::Semantic::TexCoords(0), buffers, 2, &mut all_data, &mut vertex_layout, LOCATION_IN_TEX_COORD);
-            read_no_sparse_vertex_data(&primitive, &gltf::Semantic::Weights(0), buffers, 4, &mut all_data, &mut vertex_layout, LOCATION_IN_WEIGHTS);
-            read_no_sparse_vertex_data(&primitive, &gltf::Semantic::Joints(0), buffers, 4, &mut all_data, &mut vertex_layout, LOCATION_IN_JOINTS);
+            read_vertex_data(&primitive, &gltf::Semantic::Positions, buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_POS, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::Normals, buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_NORMAL, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::Tangents, buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_TANGENT, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::TexCoords(0), buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_TEX_COORD, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::TexCoords(1), buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_TEX_COORD1, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::Colors(0), buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_COLOR, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::Weights(0), buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_WEIGHTS, supported);
+            read_vertex_data(&primitive, &gltf::Semantic::Joints(0), buffers, &mut all_data, &mut vertex_layout, LOCATION_IN_JOINTS, supported);
+
+            let material: Material = primitive.material().into();
+            if let Some(normals_texture) = material.get_normals_texture() {
+                if !vertex_layout.has_location(LOCATION_IN_TANGENT) {
+                    match generate_tangents(&primitive, buffers, normals_texture.get_channel()) {
+                        Some(tangents) => {
+                            align_data(&mut all_data);
+                            let offset = all_data.len();
+                            all_data.extend_from_slice(unsafe { util::slice_as_u8_slice(&tangents) });
+                            vertex_layout.push_meta(gltf::accessor::DataType::F32, 4, false, offset,
+                                                    LOCATION_IN_TANGENT, size_of::<[f32; 4]>() as _);
+                        }
+                        None => {
+                            warn!("can not generate tangents for mesh {:?} primitive {}, normal map ignored",
+                                  mesh.name(), primitive.index());
+                        }
+                    }
+                }
+            }
 
             vertex_layout.refresh_buffer_offsets();
 
-            let material = primitive.material().into();
+            let morph_targets = read_morph_targets(&primitive, buffers, &mut all_data);
+
             let primitive_index = primitive_count;
             primitive_count += 1;
 
@@ -217,19 +514,164 @@ fn load_meshes(context: &mut RenderContext, upload_command_buffer: vk::CommandBu
                 vertex_layout,
                 material,
                 aabb,
+                morph_targets,
             });
         }
 
-        meshes.push(Mesh::new(primitives_buffers))
+        let weights = mesh.weights().map_or_else(Vec::new, |w| w.to_vec());
+        meshes.push(Mesh::new(primitives_buffers, weights, MeshBvh::build(&triangles)))
     }
 
+    (meshes, all_data)
+}
 
-    let buffer =
-        Buffer::create_device_local_buffer(context, upload_command_buffer, vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER, &all_data);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [{ "byteLength": 52 }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 2 },
+            { "buffer": 0, "byteOffset": 40, "byteLength": 12 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "sparse": { "count": 1, "indices": { "bufferView": 1, "componentType": 5123 },
+                          "values": { "bufferView": 2 } } },
+            { "componentType": 5126, "count": 3, "type": "VEC3",
+              "sparse": { "count": 1, "indices": { "bufferView": 1, "componentType": 5123 },
+                          "values": { "bufferView": 2 } } }
+        ],
+        "meshes": [{
+            "primitives": [{ "attributes": { "POSITION": 0 }, "targets": [{ "POSITION": 2 }] }],
+            "weights": [0.5]
+        }]
+    }"#;
+
+    fn load() -> (gltf::Document, Vec<gltf::buffer::Data>) {
+        let mut bytes = vec![];
+        for v in [0f32, 0., 0., 1., 0., 0., 0., 1., 0.].iter() {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        for v in [5f32, 6., 7.].iter() {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        let gltf = gltf::Gltf::from_slice(GLTF.as_bytes()).unwrap();
+        (gltf.document, vec![gltf::buffer::Data(bytes)])
+    }
+
+    fn floats(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+    }
+
+    #[test]
+    fn read_dense_accessor() {
+        let (document, buffers) = load();
+        let accessor = document.accessors().next().unwrap();
+        let (data, count, stride) = read_accessor_data(&accessor, &buffers);
+        assert_eq!((count, stride), (3, 12));
+        assert_eq!(floats(&data), vec![0., 0., 0., 1., 0., 0., 0., 1., 0.]);
+    }
+
+    #[test]
+    fn sparse_values_replace_base_data() {
+        let (document, buffers) = load();
+        let accessor = document.accessors().nth(1).unwrap();
+        let (data, count, _) = read_accessor_data(&accessor, &buffers);
+        assert_eq!(count, 3);
+        assert_eq!(floats(&data), vec![0., 0., 0., 1., 0., 0., 5., 6., 7.]);
+    }
+
+    #[test]
+    fn sparse_without_view_starts_from_zeros() {
+        let (document, buffers) = load();
+        let accessor = document.accessors().nth(2).unwrap();
+        let (data, _, _) = read_accessor_data(&accessor, &buffers);
+        assert_eq!(floats(&data), vec![0., 0., 0., 0., 0., 0., 5., 6., 7.]);
+    }
+
+    #[test]
+    fn sparse_index_types() {
+        let indices = [1u8, 0, 2, 0, 3, 0, 0, 0];
+        assert_eq!(read_sparse_index(&indices, &IndexType::U8, 2), 2);
+        assert_eq!(read_sparse_index(&indices, &IndexType::U16, 1), 2);
+        assert_eq!(read_sparse_index(&indices, &IndexType::U32, 1), 3);
+    }
 
+    #[test]
+    fn quantized_components_read_as_floats() {
+        use gltf::accessor::DataType;
+        assert_eq!(VertexLayout::read_float_component(&[255], DataType::U8, true), 1.0);
+        assert_eq!(VertexLayout::read_float_component(&[200], DataType::U8, false), 200.0);
+        assert_eq!(VertexLayout::read_float_component(&(-32768i16).to_le_bytes(), DataType::I16, true), -1.0);
+        assert_eq!(VertexLayout::read_float_component(&(-300i16).to_le_bytes(), DataType::I16, false), -300.0);
+        assert_eq!(VertexLayout::read_float_component(&1.5f32.to_le_bytes(), DataType::F32, false), 1.5);
+    }
+
+    fn rgb_u8(normalized: bool) -> VertexData {
+        VertexData { data: vec![1, 2, 3, 4, 5, 6], data_type: DataType::U8, element_count: 3, normalized }
+    }
+
+    #[test]
+    fn supported_vertex_formats_are_kept() {
+        let widened = rgb_u8(true).widen(false, &|_| true);
+        assert_eq!((widened.data_type, widened.element_count, widened.stride()), (DataType::U8, 3, 3));
+        assert_eq!(widened.data, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn three_components_widen_to_four() {
+        let no_rgb = |format: vk::Format| format != vk::Format::R8G8B8_UNORM && format != vk::Format::R8G8B8_USCALED;
+        let widened = rgb_u8(true).widen(false, &no_rgb);
+        assert_eq!((widened.data_type, widened.element_count, widened.stride()), (DataType::U8, 4, 4));
+        assert_eq!(widened.data, vec![1, 2, 3, 255, 4, 5, 6, 255]);
 
-    Meshes {
-        meshes,
-        buffer,
+        let widened = rgb_u8(false).widen(false, &no_rgb);
+        assert_eq!(widened.data, vec![1, 2, 3, 1, 4, 5, 6, 1]);
+
+        let rgb_i16 = VertexData { data: vec![0; 6], data_type: DataType::I16, element_count: 3, normalized: true };
+        let widened = rgb_i16.widen(false, &|format| format != vk::Format::R16G16B16_SNORM);
+        assert_eq!(widened.data, vec![0, 0, 0, 0, 0, 0, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn unsupported_formats_fall_back_to_32_bits() {
+        let only_32_bits = |format: vk::Format| matches!(format, vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32_UINT);
+        let widened = rgb_u8(true).widen(false, &only_32_bits);
+        assert_eq!((widened.data_type, widened.element_count, widened.normalized), (DataType::F32, 3, false));
+        assert_eq!(widened.stride(), 12);
+        assert_eq!(floats(&widened.data), vec![1.0 / 255.0, 2.0 / 255.0, 3.0 / 255.0, 4.0 / 255.0, 5.0 / 255.0, 6.0 / 255.0]);
+
+        let widened = rgb_u8(false).widen(true, &only_32_bits);
+        assert_eq!((widened.data_type, widened.element_count), (DataType::U32, 3));
+        let values = widened.data.chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn morph_deltas_are_aligned_vec4() {
+        let (document, buffers) = load();
+        let primitive = document.meshes().next().unwrap().primitives().next().unwrap();
+        let mut output = vec![0u8; 10];
+        let targets = read_morph_targets(&primitive, &buffers, &mut output).unwrap();
+
+        assert_eq!(targets.offset, MORPH_BUFFER_ALIGNMENT);
+        assert_eq!(targets.target_count, 1);
+        assert_eq!(targets.vertex_count, 3);
+        assert_eq!(targets.attributes, MORPH_ATTRIBUTE_POSITION);
+        assert_eq!(targets.size, 3 * MORPH_DELTA_SIZE);
+        let deltas = output[targets.offset..].chunks_exact(4)
+            .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(deltas, vec![0., 0., 0., 0., 0., 0., 0., 0., 5., 6., 7., 0.]);
+    }
+}
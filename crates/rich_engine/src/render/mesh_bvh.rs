@@ -0,0 +1,107 @@
+use bevy::prelude::*;
+use bvh::aabb::{AABB, Bounded};
+use bvh::bounding_hierarchy::BHShape;
+use bvh::bvh::BVH;
+
+// the bvh crate uses its own glam version
+fn to_bvh_vec(v: Vec3) -> bvh::Vector3 {
+    bvh::Vector3::new(v.x, v.y, v.z)
+}
+
+struct Triangle {
+    vertices: [bvh::Point3; 3],
+    primitive: usize,
+    index: usize,
+    node_index: usize,
+}
+
+impl Bounded for Triangle {
+    fn aabb(&self) -> AABB {
+        let [a, b, c] = &self.vertices;
+        AABB::with_bounds(*a, *a).grow(b).grow(c)
+    }
+}
+
+impl BHShape for Triangle {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+}
+
+/// Closest ray hit in mesh space.
+#[derive(Debug, Clone, Copy)]
+pub struct MeshHit {
+    pub distance: f32,
+    pub position: Vec3,
+    /// primitive index inside the mesh
+    pub primitive: usize,
+    pub triangle: usize,
+}
+
+/// Triangle BVH of all primitives of a mesh, built from the base positions so
+/// skinning and morph targets are not taken into account.
+pub struct MeshBvh {
+    triangles: Vec<Triangle>,
+    bvh: BVH,
+}
+
+impl MeshBvh {
+    /// `primitives` holds the positions and triangle list indices of each primitive,
+    /// returns `None` when there is no triangle.
+    pub fn build(primitives: &[(Vec<Vec3>, Vec<u32>)]) -> Option<Self> {
+        let mut triangles = Vec::new();
+        for (primitive, (positions, indices)) in primitives.iter().enumerate() {
+            for (index, tri) in indices.chunks_exact(3).enumerate() {
+                let vertex = |i: u32| positions.get(i as usize).copied().map(to_bvh_vec);
+                if let (Some(a), Some(b), Some(c)) = (vertex(tri[0]), vertex(tri[1]), vertex(tri[2])) {
+                    triangles.push(Triangle {
+                        vertices: [a, b, c],
+                        primitive,
+                        index,
+                        node_index: 0,
+                    });
+                }
+            }
+        }
+
+        if triangles.is_empty() {
+            return None;
+        }
+
+        let bvh = BVH::build(&mut triangles);
+        Some(Self { triangles, bvh })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    /// `direction` does not need to be normalized, the hit distance is measured in mesh space.
+    pub fn intersect(&self, origin: Vec3, direction: Vec3) -> Option<MeshHit> {
+        let ray = bvh::ray::Ray::new(to_bvh_vec(origin), to_bvh_vec(direction));
+        let mut closest: Option<MeshHit> = None;
+        for triangle in self.bvh.traverse(&ray, &self.triangles) {
+            let [a, b, c] = &triangle.vertices;
+            // the test culls back faces, picking should not depend on the winding
+            let mut intersection = ray.intersects_triangle(a, b, c);
+            if intersection.distance.is_infinite() {
+                intersection = ray.intersects_triangle(a, c, b);
+            }
+
+            let distance = intersection.distance;
+            if distance.is_finite() && closest.map_or(true, |h| distance < h.distance) {
+                closest = Some(MeshHit {
+                    distance,
+                    position: origin + direction.normalize() * distance,
+                    primitive: triangle.primitive,
+                    triangle: triangle.index,
+                });
+            }
+        }
+        closest
+    }
+}
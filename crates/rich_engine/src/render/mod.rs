@@ -34,6 +34,31 @@ mod animation;
 mod skin;
 mod animation_system;
 mod model_runtime;
+mod tangent;
+mod sampler_cache;
+mod ktx2_texture;
+mod render_settings;
+mod render_view;
+mod mesh_bvh;
+mod picking;
+mod window_surface;
+mod capabilities;
+mod render_error;
+mod deletion_queue;
+mod model_upload;
+mod deferred_render;
+mod ssao;
+mod decal;
+mod particle_effect;
+mod particles;
+mod font;
+mod billboard;
+mod hud;
+mod texture_cache;
+mod taa;
+mod pipeline_cache;
+mod shader_reflect;
+mod environment;
 
 use bevy::prelude::*;
 pub use render_plugin::RenderPlugin;
@@ -43,13 +68,33 @@ pub use render_context::RenderContext;
 pub use forward_render::ForwardRenderPass;
 pub use buffer::Buffer;
 pub use render_runner::RenderRunner;
-pub use render_plugin::RenderInitEvent;
+pub use render_plugin::{RenderInitEvent, RenderInitFailed};
+pub use render_error::{RenderError, RenderResult};
 pub use fly_camera::FlyCamera;
 pub use animation_system::*;
+pub use animation::FadeCurve;
 pub use camera::Camera;
 pub use camera::CameraOpEvent;
+pub use camera::{CameraViewport, CameraTarget, Projection, Ray, Fog, FogColor};
+pub use environment::{Environment, EnvironmentMap};
 pub use render_plugin::RenderCamera;
 pub use command_buffer_list::CommandBufferList;
+pub use model_runtime::MorphWeights;
+pub use picking::{Picking, PickHit};
+pub use render_settings::{RenderSettings, PresentMode, ShadowQuality, RenderTargetsRecreatedEvent, RENDER_SETTINGS_PATH};
+pub use render_settings::{DeviceSelection, DeviceType, DebugView, RenderPath, AmbientOcclusion, RENDER_DEVICE_ENV};
+pub use capabilities::RenderCapabilities;
+pub use deletion_queue::DeferredDestroy;
+pub use model_upload::{ModelLoadState, ModelUploads, DEFAULT_UPLOAD_BYTES_PER_FRAME};
+pub use deferred_render::{PointLight, MAX_POINT_LIGHTS};
+pub use decal::{Decal, MAX_DECALS};
+pub use particle_effect::{ParticleEffect, Curve, EmitterShape, ParticleRender, ParticleBlend, ParticleCollision, CURVE_SAMPLES};
+pub use shader_reflect::{ShaderReflection, ReflectedBinding, ReflectedInput};
+pub use particles::{ParticleEmitter, MAX_PARTICLE_EMITTERS, MAX_PARTICLES_PER_EMITTER};
+pub use font::{GlyphAtlas, DEFAULT_FONT};
+pub use texture_cache::ImageAsset;
+pub use billboard::{Billboard, BillboardAlignment, HealthBar, BillboardText, MAX_BILLBOARD_QUADS};
+pub use hud::{HudNode, HudAnchor, HudSprite, HudPanel, HudText, HudTextAlign, HudAtlas, MAX_HUD_RECTS};
 
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
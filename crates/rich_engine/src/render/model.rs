@@ -1,9 +1,10 @@
 use gltf;
 use gltf::Gltf;
-use std::error::Error;
 use crate::render::aabb::Aabb;
 use std::mem::size_of;
-use crate::render::material::Material;
+use crate::render::material::{Material, Workflow};
+use crate::render::sampler_cache::SamplerDesc;
+use std::collections::HashSet;
 use crate::render::render_context::RenderContext;
 use crate::render::texture::Texture;
 use crate::render::util;
@@ -12,8 +13,10 @@ use ash::vk;
 use crate::render::mesh::{Meshes, Mesh};
 use crate::render::node::{Nodes, Node};
 use crate::render::buffer::Buffer;
-use crate::render::vertex_layout::VertexLayout;
-use crate::render::gltf_asset_loader::GltfAsset;
+use crate::render::gltf_asset_loader::{GltfAsset, ImageData};
+use crate::render::ktx2_texture::{create_texture_by_ktx2, read_ktx2, Ktx2Levels};
+use crate::render::render_error::RenderResult;
+use crate::render::capabilities::FormatSupport;
 
 use bevy::prelude::*;
 use crate::render::animation::{Animations, load_animations};
@@ -35,44 +38,8 @@ impl Model {
         self.textures.destroy(context);
     }
 
-    pub fn from_gltf(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, asset: &GltfAsset) -> Result<Model, Box<dyn Error>> {
-        let (document, buffers, images) = asset.export();
-        let meshes = Meshes::from_gltf(context, upload_command_buffer, &document, &buffers);
-        let textures = ModelTextures::from_gltf(context, upload_command_buffer, document.textures(), &images);
-        let nodes = Nodes::from_gltf(document.nodes(), &document.default_scene().unwrap());
-        let animations = load_animations(document.animations(), &buffers);
-        info!("animations is some {}", animations.is_some());
-
-        let mut skins = create_skins_from_gltf(document.skins(), &buffers);
-
-        nodes.get_skins_transform()
-            .iter()
-            .for_each(|(index, transform)| {
-                let skin = &mut skins[*index];
-                skin.compute_joints_matrices(*transform, &nodes.nodes());
-            });
-
-        let aabbs = nodes
-            .nodes()
-            .iter()
-            .filter(|n| n.mesh_index().is_some())
-            .map(|n| {
-                let mesh = &meshes.meshes[n.mesh_index().unwrap()];
-                mesh.aabb() * n.transform()
-            })
-            .collect::<Vec<_>>();
-
-        let aabb = Aabb::union(&aabbs).unwrap();
-
-        Ok(
-            Model {
-                aabb,
-                nodes,
-                textures,
-                meshes,
-                animations,
-                skins,
-            })
+    pub fn refresh_samplers(&mut self, context: &mut RenderContext) -> RenderResult<()> {
+        self.textures.refresh_samplers(context)
     }
 
     pub fn primitive_count(&self) -> usize {
@@ -95,10 +62,6 @@ impl Model {
         self.aabb
     }
 
-    pub fn get_vertex_layout(&self) -> &VertexLayout {
-        &self.meshes.meshes[0].primitives()[0].get_vertex_layout()
-    }
-
     pub fn get_buffer(&self) -> &Buffer {
         &self.meshes.buffer
     }
@@ -126,54 +89,213 @@ impl Model {
     }
 }
 
+/// CPU side of a model: packed mesh data, decoded textures, nodes, skins and
+/// animations. Built on a worker thread, `ModelUpload` turns it into a `Model`.
+pub struct PreparedModel {
+    meshes: Vec<Mesh>,
+    mesh_data: Vec<u8>,
+    textures: Vec<PreparedTexture>,
+    nodes: Nodes,
+    animations: Option<Animations>,
+    skins: Vec<Skin>,
+    aabb: Aabb,
+}
+
+impl PreparedModel {
+    pub fn from_gltf(asset: &GltfAsset, formats: &FormatSupport) -> PreparedModel {
+        let (document, buffers, _) = asset.export();
+        let (meshes, mesh_data) = Meshes::prepare_gltf(&document, &buffers, formats);
+        let textures = prepare_textures(asset, formats);
+        let nodes = Nodes::from_gltf(document.nodes(), &document.default_scene().unwrap());
+        let animations = load_animations(document.animations(), &buffers);
+        info!("animations is some {}", animations.is_some());
+
+        let mut skins = create_skins_from_gltf(document.skins(), &buffers);
+
+        nodes.get_skins_transform()
+            .iter()
+            .for_each(|(index, transform)| {
+                let skin = &mut skins[*index];
+                skin.compute_joints_matrices(*transform, &nodes.nodes());
+            });
+
+        let aabbs = nodes
+            .nodes()
+            .iter()
+            .filter(|n| n.mesh_index().is_some())
+            .map(|n| {
+                let mesh = &meshes[n.mesh_index().unwrap()];
+                mesh.aabb() * n.transform()
+            })
+            .collect::<Vec<_>>();
+
+        let aabb = Aabb::union(&aabbs).unwrap();
+
+        PreparedModel {
+            meshes,
+            mesh_data,
+            textures,
+            nodes,
+            animations,
+            skins,
+            aabb,
+        }
+    }
+}
+
+/// Uploads a prepared model one resource per step, the mesh buffer first and
+/// then every texture, so a large model can be spread over several frames.
+pub struct ModelUpload {
+    asset: GltfAsset,
+    prepared: PreparedModel,
+    /// bytes of the mesh buffer followed by those of every texture
+    step_sizes: Vec<u64>,
+    meshes: Option<Meshes>,
+    textures: Vec<ModelTexture>,
+}
+
+impl ModelUpload {
+    /// `asset` is the one the model was prepared from, RGBA images are read from it.
+    pub fn new(asset: GltfAsset, prepared: PreparedModel) -> Self {
+        let (_, _, images) = asset.export();
+        let mut step_sizes = vec![prepared.mesh_data.len() as u64];
+        step_sizes.extend(prepared.textures.iter().map(|t| t.byte_size(images) as u64));
+
+        Self {
+            asset,
+            prepared,
+            step_sizes,
+            meshes: None,
+            textures: vec![],
+        }
+    }
+
+    fn uploaded_steps(&self) -> usize {
+        if self.meshes.is_some() { 1 + self.textures.len() } else { 0 }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.step_sizes.iter().sum()
+    }
+
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.step_sizes[..self.uploaded_steps()].iter().sum()
+    }
+
+    /// Bytes recorded by the next `upload_next`, `None` once everything is on the device.
+    pub fn next_step_size(&self) -> Option<u64> {
+        self.step_sizes.get(self.uploaded_steps()).copied()
+    }
+
+    /// Records the copies of the next resource, the staging data is freed.
+    pub fn upload_next(&mut self, context: &mut RenderContext, command_buffer: vk::CommandBuffer) -> RenderResult<()> {
+        if self.meshes.is_none() {
+            let meshes = std::mem::take(&mut self.prepared.meshes);
+            let data = std::mem::take(&mut self.prepared.mesh_data);
+            self.meshes = Some(Meshes::upload(context, command_buffer, meshes, &data));
+            return Ok(());
+        }
+
+        let prepared = match self.prepared.textures.get_mut(self.textures.len()) {
+            Some(prepared) => prepared,
+            None => return Ok(()),
+        };
+        let (_, _, images) = self.asset.export();
+        let texture = match &mut prepared.source {
+            TextureSource::Rgba(index) => match &images[*index] {
+                ImageData::Rgba { width, height, pixels } => {
+                    create_texture_by_rgba(context, command_buffer, *width, *height, pixels, prepared.srgb)?
+                }
+                ImageData::Ktx2(_) => unreachable!("image {} is not decoded", index),
+            },
+            TextureSource::Ktx2(ktx2) => {
+                let texture = create_texture_by_ktx2(context, command_buffer, ktx2)?;
+                ktx2.levels = vec![];
+                texture
+            }
+            TextureSource::White => {
+                create_texture_by_rgba(context, command_buffer, 1, 1, &[255, 255, 255, 255], prepared.srgb)?
+            }
+        };
+        let sampler = prepared.sampler;
+        self.textures.push(ModelTexture::from(context, texture, &sampler)?);
+        Ok(())
+    }
+
+    /// The uploaded model, only valid once `next_step_size` returns `None`.
+    pub fn finish(self) -> Model {
+        let prepared = self.prepared;
+        Model {
+            meshes: self.meshes.expect("the model upload is not complete"),
+            nodes: prepared.nodes,
+            textures: ModelTextures { textures: self.textures },
+            animations: prepared.animations,
+            skins: prepared.skins,
+            aabb: prepared.aabb,
+        }
+    }
+
+    /// Releases the resources uploaded so far, they may be used by recorded copies.
+    pub fn cancel(self, context: &mut RenderContext) {
+        if let Some(meshes) = self.meshes {
+            context.defer_destroy("cancelled model meshes", meshes);
+        }
+        context.defer_destroy("cancelled model textures", ModelTextures { textures: self.textures });
+    }
+}
+
 
 pub struct ModelTexture {
     pub texture: Texture,
     pub view: vk::ImageView,
+    /// owned by the context sampler cache
     pub sampler: vk::Sampler,
+    pub sampler_desc: SamplerDesc,
 }
 
 impl ModelTexture {
     pub fn destroy(&mut self, context: &RenderContext) {
         unsafe {
             context.device.destroy_image_view(self.view, None);
-            context.device.destroy_sampler(self.sampler, None);
         }
         self.texture.destroy(context);
     }
 
-    pub fn from(context: &RenderContext, texture: Texture) -> Self
+    /// The texture may still be uploading, it is retired when its view can not be created.
+    pub fn from(context: &mut RenderContext, texture: Texture, sampler_desc: &SamplerDesc) -> RenderResult<Self>
     {
-        let view = texture.create_color_view(context);
-
-        let sampler = {
-            let sampler_info = vk::SamplerCreateInfo::builder()
-                .mag_filter(vk::Filter::LINEAR)
-                .min_filter(vk::Filter::LINEAR)
-                .address_mode_u(vk::SamplerAddressMode::REPEAT)
-                .address_mode_v(vk::SamplerAddressMode::REPEAT)
-                .address_mode_w(vk::SamplerAddressMode::REPEAT)
-                .anisotropy_enable(false)
-                .max_anisotropy(16.0)
-                .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
-                .unnormalized_coordinates(false)
-                .compare_enable(false)
-                .compare_op(vk::CompareOp::ALWAYS)
-                .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-                .mip_lod_bias(0.0)
-                .min_lod(0.0)
-                .max_lod(0.25);
-
-            unsafe {
-                context
-                    .device
-                    .create_sampler(&sampler_info, None)
-                    .expect("Failed to create sampler")
+        let view_and_sampler = texture.create_color_view(context).and_then(|view| {
+            match context.sampler_cache.get(&context.device, sampler_desc) {
+                Ok(sampler) => Ok((view, sampler)),
+                Err(e) => {
+                    unsafe { context.device.destroy_image_view(view, None); }
+                    Err(e)
+                }
+            }
+        });
+        let (view, sampler) = match view_and_sampler {
+            Ok(view_and_sampler) => view_and_sampler,
+            Err(e) => {
+                context.defer_destroy("texture without view", texture);
+                return Err(e);
             }
         };
 
+        Ok(Self { texture, view, sampler, sampler_desc: *sampler_desc })
+    }
+
+    /// Takes the sampler from the cache again after its anisotropy level changed.
+    pub fn refresh_sampler(&mut self, context: &mut RenderContext) -> RenderResult<()> {
+        self.sampler = context.sampler_cache.get(&context.device, &self.sampler_desc)?;
+        Ok(())
+    }
 
-        Self { texture, view, sampler }
+    /// Uploads RGBA pixels as an sRGB texture without mipmaps.
+    pub fn from_rgba(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, width: u32, height: u32,
+                     pixels: &[u8], sampler_desc: &SamplerDesc) -> RenderResult<Self> {
+        let texture = Texture::create_from_data_with_format(context, upload_command_buffer, width, height,
+                                                            vk::Format::R8G8B8A8_SRGB, pixels)?;
+        Self::from(context, texture, sampler_desc)
     }
 }
 
@@ -182,58 +304,16 @@ pub struct ModelTextures {
     pub textures: Vec<ModelTexture>,
 }
 
-fn get_next_rgba(pixels: &[u8], format: gltf::image::Format, index: usize) -> [u8; 4] {
-    use gltf::image::Format::*;
-    match format {
-        R8 => [pixels[index], 0, 0, std::u8::MAX],
-        R8G8 => [pixels[index * 2], pixels[index * 2 + 1], 0, std::u8::MAX],
-        R8G8B8 => [
-            pixels[index * 3],
-            pixels[index * 3 + 1],
-            pixels[index * 3 + 2],
-            std::u8::MAX,
-        ],
-        B8G8R8 => [
-            pixels[index * 3 + 2],
-            pixels[index * 3 + 1],
-            pixels[index * 3],
-            std::u8::MAX,
-        ],
-        R8G8B8A8 => [
-            pixels[index * 4],
-            pixels[index * 4 + 1],
-            pixels[index * 4 + 2],
-            pixels[index * 4 + 3],
-        ],
-        B8G8R8A8 => [
-            pixels[index * 4 + 2],
-            pixels[index * 4 + 1],
-            pixels[index * 4],
-            pixels[index * 4 + 3],
-        ],
-        R16 | R16G16 | R16G16B16 | R16G16B16A16 => {
-            panic!("16 bits colors are not supported for model textures")
-        }
-    }
-}
-
-fn build_rgba_buffer(image: &gltf::image::Data) -> Vec<u8> {
-    let mut buffer = Vec::new();
-    let size = image.width * image.height;
-    for index in 0..size {
-        let rgba = get_next_rgba(&image.pixels, image.format, index as usize);
-        buffer.extend_from_slice(&rgba);
-    }
-    buffer
-}
-
-fn create_texture_by_gltf_image_data(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, image: &gltf::image::Data) -> Texture {
-    let max_mip_levels = ((image.width.min(image.height) as f32).log2().floor() + 1.0) as u32;
-    //todo better way convert image
-    let vk_format = vk::Format::R8G8B8A8_UNORM;
-    //let vk_format = util::Gltf2VkConvertor::format(image.format);
+fn create_texture_by_rgba(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                          width: u32, height: u32, pixels: &[u8], srgb: bool) -> RenderResult<Texture> {
+    let max_mip_levels = ((width.max(height) as f32).log2().floor() + 1.0) as u32;
+    let vk_format = if srgb {
+        vk::Format::R8G8B8A8_SRGB
+    } else {
+        vk::Format::R8G8B8A8_UNORM
+    };
     let image_ci = vk::ImageCreateInfo::builder()
-        .extent(vk::Extent3D { width: image.width, height: image.height, depth: 1 })
+        .extent(vk::Extent3D { width, height, depth: 1 })
         .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
         .sharing_mode(vk::SharingMode::EXCLUSIVE)
         .mip_levels(max_mip_levels)
@@ -246,11 +326,48 @@ fn create_texture_by_gltf_image_data(context: &mut RenderContext, upload_command
         .tiling(vk::ImageTiling::OPTIMAL)
         .build();
 
-    let rgba = build_rgba_buffer(image);
+    // create_from_data leaves every level in SHADER_READ_ONLY_OPTIMAL
+    Texture::create_from_data(context, upload_command_buffer, &image_ci, pixels)
+}
+
+/// Textures holding colors (base color, emissive, specular) are stored as sRGB,
+/// every other texture holds linear data.
+fn collect_srgb_textures(document: &gltf::Document) -> HashSet<usize> {
+    let mut srgb = HashSet::new();
+    for material in document.materials() {
+        let material: Material = material.into();
+        if let Some(idx) = material.get_color_texture_index() {
+            srgb.insert(idx);
+        }
+        if let Some(idx) = material.get_emissive_texture_index() {
+            srgb.insert(idx);
+        }
+        if let Workflow::SpecularGlossiness(workflow) = material.get_workflow() {
+            if let Some(idx) = workflow.get_specular_glossiness_texture_index() {
+                srgb.insert(idx);
+            }
+        }
+    }
+    srgb
+}
 
-    let texture = Texture::create_from_data(context, upload_command_buffer, &image_ci, &rgba);
-    texture.cmd_transition_image_layout(context, upload_command_buffer, vk::ImageLayout::UNDEFINED, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
-    texture
+fn collect_linear_textures(document: &gltf::Document) -> HashSet<usize> {
+    let mut linear = HashSet::new();
+    for material in document.materials() {
+        let material: Material = material.into();
+        if let Some(idx) = material.get_normals_texture_index() {
+            linear.insert(idx);
+        }
+        if let Some(idx) = material.get_occlusion_texture_index() {
+            linear.insert(idx);
+        }
+        if let Workflow::MetallicRoughness(workflow) = material.get_workflow() {
+            if let Some(idx) = workflow.get_metallic_roughness_texture_index() {
+                linear.insert(idx);
+            }
+        }
+    }
+    linear
 }
 
 impl ModelTextures {
@@ -260,17 +377,79 @@ impl ModelTextures {
         }
     }
 
-    pub fn from_gltf(context: &mut RenderContext, command_buffer: vk::CommandBuffer,
-                     gltf_textures: gltf::iter::Textures, gltf_image_datas: &[gltf::image::Data]) -> ModelTextures {
-        let model_textures = gltf_textures.map(|t| {
-            let texture = create_texture_by_gltf_image_data(context, command_buffer, &gltf_image_datas[t.source().index()]);
-            let view = texture.create_color_view(context);
-            let sampler = util::Gltf2VkConvertor::sampler(context, &texture, t.sampler());
-            ModelTexture { texture, view, sampler }
-        }).collect::<Vec<_>>();
-
-        ModelTextures {
-            textures: model_textures
+    pub fn refresh_samplers(&mut self, context: &mut RenderContext) -> RenderResult<()> {
+        for t in self.textures.iter_mut() {
+            t.refresh_sampler(context)?;
+        }
+        Ok(())
+    }
+}
+
+enum TextureSource {
+    /// index of an RGBA image of the asset
+    Rgba(usize),
+    Ktx2(Ktx2Levels),
+    /// 1x1 white, no source could be decoded
+    White,
+}
+
+struct PreparedTexture {
+    source: TextureSource,
+    srgb: bool,
+    sampler: SamplerDesc,
+}
+
+impl PreparedTexture {
+    fn byte_size(&self, images: &[ImageData]) -> usize {
+        match &self.source {
+            TextureSource::Rgba(index) => match &images[*index] {
+                ImageData::Rgba { pixels, .. } => pixels.len(),
+                ImageData::Ktx2(_) => unreachable!("image {} is not decoded", index),
+            },
+            TextureSource::Ktx2(ktx2) => ktx2.byte_size(),
+            TextureSource::White => 4,
         }
     }
 }
+
+/// KHR_texture_basisu sources are preferred, the regular source is the
+/// fallback when the device or the transcoder can't handle them.
+fn prepare_textures(asset: &GltfAsset, formats: &FormatSupport) -> Vec<PreparedTexture> {
+    let (document, _, images) = asset.export();
+    let srgb_textures = collect_srgb_textures(document);
+    let linear_textures = collect_linear_textures(document);
+
+    document.textures().map(|t| {
+        let srgb = srgb_textures.contains(&t.index());
+        if srgb && linear_textures.contains(&t.index()) {
+            warn!("texture {} is used as color and data, load it as sRGB", t.index());
+        }
+
+        let mut sources = Vec::new();
+        if let Some(basisu) = asset.get_basisu_source(t.index()) {
+            sources.push(basisu);
+        }
+        if !sources.contains(&t.source().index()) {
+            sources.push(t.source().index());
+        }
+
+        let source = sources.iter().find_map(|source| {
+            match &images[*source] {
+                ImageData::Rgba { .. } => Some(TextureSource::Rgba(*source)),
+                ImageData::Ktx2(bytes) => match read_ktx2(formats, bytes, srgb) {
+                    Ok(ktx2) => Some(TextureSource::Ktx2(ktx2)),
+                    Err(e) => {
+                        warn!("failed to load image {} of texture {}: {}", source, t.index(), e);
+                        None
+                    }
+                },
+            }
+        }).unwrap_or(TextureSource::White);
+
+        PreparedTexture {
+            source,
+            srgb,
+            sampler: SamplerDesc::from_gltf(&t.sampler()),
+        }
+    }).collect()
+}
@@ -2,8 +2,8 @@ use crate::render::model::{Model, ModelTexture};
 use ash::vk;
 use crate::render::buffer::Buffer;
 use crate::render::render_context::{RenderContext, PerFrameData, DummyResources};
-use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo, PipelineLayoutInfo};
-use crate::render::swapchain_mgr::SwapChainMgr;
+use crate::render::graphic_pipeline::PipelineLayoutInfo;
+use crate::render::pipeline_cache::{PipelineCache, PipelineKey, CachedPipeline, RenderState};
 use crate::render::{vertex, util};
 use std::mem::size_of;
 use crate::render::texture::Texture;
@@ -12,14 +12,23 @@ use crate::render::uniform::UniformObject;
 use crate::render::aabb::Aabb;
 use crate::render::animation::Animations;
 use crate::render::gltf_asset_loader::{GltfAsset};
-use crate::render::material::{Material, TextureInfo};
+use crate::render::material::{Material, TextureInfo, Workflow};
+use crate::render::render_settings::DebugView;
 use crate::render::vertex_layout::VertexLayout;
-use crate::render::mesh::Primitive;
+use crate::render::mesh::{Primitive, MorphTargets};
 use crate::render::forward_render::ForwardRenderPass;
-use crate::render::model_runtime::{ModelRuntime, ModelSkins};
+use crate::render::model_runtime::{ModelRuntime, ModelSkins, MorphWeights};
 use crate::render::node::{Node, Nodes};
+use crate::render::shader_const::LOCATION_IN_TANGENT;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::shader_reflect::ShaderReflection;
+use crate::render::deletion_queue::DeferredDestroy;
+use crate::render::deferred_render::GBUFFER_COLOR_ATTACHMENTS;
+use crate::render::taa::MotionHistory;
+use ash::vk::Handle;
 
 
+#[derive(Clone, Copy)]
 pub struct ShadeNames {
     pub vertex: &'static str,
     pub frag: &'static str,
@@ -34,6 +43,65 @@ pub struct ModelData {
     pub transform: Mat4,
 }
 
+/// pushed to the vertex stage of the velocity pipelines instead of the regular constants
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+struct VelocityModelData {
+    transform: Mat4,
+    prev_transform: Mat4,
+}
+
+/// max morph targets blended per draw, the most weighted ones are picked
+pub const MAX_MORPH_WEIGHTS: usize = 8;
+
+/// pushed to the vertex stage right after `PrimitiveFragConstant`
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+struct MorphConstant {
+    weights: [f32; MAX_MORPH_WEIGHTS],
+    /// four u8 target indices per u32
+    target_indices: [u32; MAX_MORPH_WEIGHTS / 4],
+    vertex_count: u32,
+    attributes: u32,
+}
+
+impl MorphConstant {
+    fn new(targets: &MorphTargets, weights: &[f32]) -> Self {
+        let mut active = weights.iter().copied().enumerate()
+            .take(targets.target_count as usize)
+            .filter(|(_, w)| *w != 0.0)
+            .collect::<Vec<_>>();
+        active.sort_by(|a, b| b.1.abs().partial_cmp(&a.1.abs()).unwrap_or(std::cmp::Ordering::Equal));
+        active.truncate(MAX_MORPH_WEIGHTS);
+
+        let mut constant = MorphConstant {
+            vertex_count: targets.vertex_count,
+            attributes: targets.attributes,
+            ..Default::default()
+        };
+        for (i, (target, weight)) in active.into_iter().enumerate() {
+            constant.weights[i] = weight;
+            constant.target_indices[i / 4] |= (target as u32 & 0xff) << ((i % 4) * 8);
+        }
+        constant
+    }
+}
+
+/// Primitives drawn by `ModelRenderer::draw` and the pipelines they are drawn with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawPass {
+    /// every primitive, shaded forward
+    Forward,
+    /// primitives that are not blended, shaded forward before the decals
+    Opaque,
+    /// opaque primitives writing the G-buffer of the deferred path
+    GBuffer,
+    /// blended primitives, drawn forward after the decals
+    Transparent,
+    /// opaque primitives writing the depth the ambient occlusion is computed from
+    DepthPrepass,
+}
+
 impl Default for ModelData {
     fn default() -> Self {
         Self {
@@ -46,40 +114,97 @@ impl Default for ModelData {
 pub struct ModelRenderer {
     model: Model,
     primitive_renders: Vec<PrimitiveRender>,
+    shader_names: ShadeNames,
+}
+
+impl DeferredDestroy for ModelRenderer {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        let mut objects = self.model.debug_objects();
+        // pipelines are shared through the cache and outlive the model
+        for r in &self.primitive_renders {
+            objects.push((vk::ObjectType::DESCRIPTOR_SET, r.descriptor_set.as_raw()));
+            if let Some(set) = r.shadow_morph_set {
+                objects.push((vk::ObjectType::DESCRIPTOR_SET, set.as_raw()));
+            }
+        }
+        objects
+    }
 }
 
 impl ModelRenderer {
     pub fn destroy(&mut self, context: &mut RenderContext) {
+        self.release_pipelines(context);
+        self.model.destroy(context);
+    }
+
+    /// Releases the cached pipelines and descriptors, the model draws nothing until
+    /// `recreate_pipelines`.
+    pub fn release_pipelines(&mut self, context: &mut RenderContext) {
         let mut rs = std::mem::take(&mut self.primitive_renders);
         for r in &mut rs {
             r.destroy(context);
         }
-        self.model.destroy(context);
     }
 
-    pub fn create(context: &mut RenderContext, swapchain_mgr: &SwapChainMgr, render_pass: &ForwardRenderPass,
-                  command_buffer: vk::CommandBuffer, gltf_asset: &GltfAsset, shader_names: &ShadeNames) -> ModelRenderer {
-        let model = Model::from_gltf(context, command_buffer, gltf_asset).expect("load error");
+    /// Builds the pipelines and descriptors of an uploaded model.
+    pub fn create(context: &mut RenderContext, render_pass: &ForwardRenderPass,
+                  mut model: Model, shader_names: &ShadeNames) -> RenderResult<ModelRenderer> {
+        // the anisotropy may have changed while the textures were uploaded
+        let primitive_renders = match model.refresh_samplers(context)
+            .and_then(|_| Self::create_primitive_renders(context, render_pass, &model, shader_names)) {
+            Ok(renders) => renders,
+            Err(e) => {
+                // the upload of the model may not have been submitted yet
+                context.defer_destroy("model without pipelines", model);
+                return Err(e);
+            }
+        };
 
+        Ok(ModelRenderer {
+            primitive_renders,
+            model,
+            shader_names: *shader_names,
+        })
+    }
+
+    fn create_primitive_renders(context: &mut RenderContext, render_pass: &ForwardRenderPass,
+                                model: &Model, shader_names: &ShadeNames) -> RenderResult<Vec<PrimitiveRender>> {
         let mut primitive_renders = Vec::new();
         for node in model.get_nodes() {
             if let Some(mesh_idx) = node.mesh_index() {
                 let mesh = &model.get_meshes()[mesh_idx];
                 for primitive in mesh.primitives() {
-                    let r = PrimitiveRender::create(context, swapchain_mgr, render_pass, primitive, &model, shader_names);
-                    primitive_renders.push(r);
+                    match PrimitiveRender::create(context, render_pass, primitive, model, shader_names) {
+                        Ok(r) => primitive_renders.push(r),
+                        Err(e) => {
+                            for r in &mut primitive_renders {
+                                r.destroy(context);
+                            }
+                            return Err(e);
+                        }
+                    }
                 }
             }
         }
+        Ok(primitive_renders)
+    }
 
-        ModelRenderer {
-            primitive_renders,
-            model,
-        }
+    /// Pipelines depend on the forward render pass and descriptors on its shadow and occlusion maps,
+    /// both are rebuilt when the render targets change. Device data is kept.
+    pub fn recreate_pipelines(&mut self, context: &mut RenderContext, render_pass: &ForwardRenderPass) -> RenderResult<()> {
+        self.release_pipelines(context);
+        self.model.refresh_samplers(context)?;
+        self.primitive_renders = Self::create_primitive_renders(context, render_pass, &self.model, &self.shader_names)?;
+        Ok(())
     }
 
     pub fn draw_shadow(&self, context: &RenderContext, command_buffer: vk::CommandBuffer,
-                       runtime: &ModelRuntime, skins: Option<&ModelSkins>, transform_query: &Query<&GlobalTransform>) {
+                       runtime: &ModelRuntime, skins: Option<&ModelSkins>,
+                       transform_query: &Query<&GlobalTransform>, morph_query: &Query<&MorphWeights>) {
         let mut primitive_idx = 0;
         let uniform = context.per_frame_uniform.as_ref().unwrap();
         for model_node in runtime.get_nodes() {
@@ -93,6 +218,9 @@ impl ModelRenderer {
                 let model_data_bytes: &[u8] = unsafe { util::any_as_u8_slice(&m_data) };
 
                 let mesh = &self.model.get_meshes()[mesh_idx];
+                let morph_weights = morph_query.get(model_node.entity)
+                    .map_or(mesh.weights(), |w| w.weights.as_slice());
+
                 for primitive in mesh.primitives() {
                     let render = &self.primitive_renders[primitive_idx];
                     let vertex_layout = &primitive.get_vertex_layout();
@@ -101,7 +229,15 @@ impl ModelRenderer {
                         context.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, render.shadow_pipeline.get_pipeline());
 
                         context.device.cmd_push_constants(command_buffer, render.shadow_pipeline.get_layout(),
-                                                          vk::ShaderStageFlags::VERTEX, 0, model_data_bytes);
+                                                          render.shadow_constant_stages, 0, model_data_bytes);
+
+                        // right after `ModelData`, the shadow pass has no fragment constants
+                        if let Some(targets) = &render.morph_targets {
+                            let morph_constant = MorphConstant::new(targets, morph_weights);
+                            context.device.cmd_push_constants(command_buffer, render.shadow_pipeline.get_layout(),
+                                                              render.shadow_morph_stages, model_data_bytes.len() as _,
+                                                              util::any_as_u8_slice(&morph_constant));
+                        }
 
                         context.device.cmd_bind_vertex_buffers(command_buffer,
                                                                0,
@@ -117,6 +253,7 @@ impl ModelRenderer {
                         if let Some(skins) = skins {
                             descriptor_sets.push(skins.skin_descriptor_set);
                         }
+                        descriptor_sets.extend(render.shadow_morph_set);
 
                         context.device.cmd_bind_descriptor_sets(command_buffer,
                                                                 vk::PipelineBindPoint::GRAPHICS,
@@ -131,10 +268,11 @@ impl ModelRenderer {
         }
     }
 
-    pub fn draw(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, runtime: &ModelRuntime, skins: Option<&ModelSkins>,
-                transform_query: &Query<&GlobalTransform>) {
+    /// `frame_descriptor_set` holds the `PerFrameData` of the camera being drawn.
+    pub fn draw(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, frame_descriptor_set: vk::DescriptorSet,
+                runtime: &ModelRuntime, skins: Option<&ModelSkins>,
+                transform_query: &Query<&GlobalTransform>, morph_query: &Query<&MorphWeights>, pass: DrawPass) {
         let mut primitive_idx = 0;
-        let uniform = context.per_frame_uniform.as_ref().unwrap();
 
         for model_node in runtime.get_nodes() {
             if let Some(mesh_idx) = model_node.node.mesh_index() {
@@ -147,6 +285,9 @@ impl ModelRenderer {
                 let model_data_bytes: &[u8] = unsafe { util::any_as_u8_slice(&m_data) };
 
                 let mesh = &self.model.get_meshes()[mesh_idx];
+                let morph_weights = morph_query.get(model_node.entity)
+                    .map_or(mesh.weights(), |w| w.weights.as_slice());
+
                 for primitive in mesh.primitives() {
                     let render = &self.primitive_renders[primitive_idx];
                     let vertex_layout = &primitive.get_vertex_layout();
@@ -155,16 +296,37 @@ impl ModelRenderer {
 
 
                     primitive_idx += 1;
+                    let pipeline = match (pass, &render.gbuffer_pipeline) {
+                        (DrawPass::Forward, _) => &render.graphic_pipeline,
+                        (DrawPass::Opaque, _) if !render.transparent => &render.graphic_pipeline,
+                        (DrawPass::Transparent, _) if render.transparent => &render.graphic_pipeline,
+                        (DrawPass::GBuffer, Some(pipeline)) => pipeline,
+                        (DrawPass::DepthPrepass, _) => match &render.prepass_pipeline {
+                            Some(pipeline) => pipeline,
+                            None => continue,
+                        },
+                        _ => continue,
+                    };
                     let set = render.descriptor_set;
                     unsafe {
-                        context.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, render.graphic_pipeline.get_pipeline());
+                        context.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_pipeline());
 
-                        context.device.cmd_push_constants(command_buffer, render.graphic_pipeline.get_layout(),
-                                                          vk::ShaderStageFlags::VERTEX, 0, model_data_bytes);
+                        context.device.cmd_push_constants(command_buffer, pipeline.get_layout(),
+                                                          render.model_constant_stages, 0, model_data_bytes);
 
-                        context.device.cmd_push_constants(command_buffer, render.graphic_pipeline.get_layout(),
-                                                          vk::ShaderStageFlags::FRAGMENT, model_data_bytes.len() as _,
-                                                          primitive_constant_bytes);
+                        if !render.frag_constant_stages.is_empty() {
+                            context.device.cmd_push_constants(command_buffer, pipeline.get_layout(),
+                                                              render.frag_constant_stages, model_data_bytes.len() as _,
+                                                              primitive_constant_bytes);
+                        }
+
+                        if let Some(targets) = &render.morph_targets {
+                            let morph_constant = MorphConstant::new(targets, morph_weights);
+                            context.device.cmd_push_constants(command_buffer, pipeline.get_layout(),
+                                                              render.morph_constant_stages,
+                                                              (model_data_bytes.len() + primitive_constant_bytes.len()) as _,
+                                                              util::any_as_u8_slice(&morph_constant));
+                        }
 
                         context.device.cmd_bind_vertex_buffers(command_buffer,
                                                                0,
@@ -175,14 +337,88 @@ impl ModelRenderer {
                                                              vertex_layout.indices.index as _,
                                                              vertex_layout.indices_type);
 
-                        let mut descriptor_sets = vec![uniform.descriptor_set, set];
+                        let mut descriptor_sets = vec![frame_descriptor_set, set];
                         if let Some(skins) = skins {
                             descriptor_sets.push(skins.skin_descriptor_set);
                         }
 
                         context.device.cmd_bind_descriptor_sets(command_buffer,
                                                                 vk::PipelineBindPoint::GRAPHICS,
-                                                                render.graphic_pipeline.get_layout(),
+                                                                pipeline.get_layout(),
+                                                                0,
+                                                                &descriptor_sets, &[]);
+
+                        context.device.cmd_draw_indexed(command_buffer, vertex_layout.indices.count as _, 1, 0, 0, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the screen motion of opaque primitives into the velocity pass of temporal
+    /// anti-aliasing, `frame_descriptor_set` holds the `PerFrameData` of the main camera.
+    pub fn draw_velocity(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, frame_descriptor_set: vk::DescriptorSet,
+                         runtime: &ModelRuntime, skins: Option<&ModelSkins>, transform_query: &Query<&GlobalTransform>,
+                         morph_query: &Query<&MorphWeights>, history: &mut MotionHistory) {
+        let mut primitive_idx = 0;
+
+        for model_node in runtime.get_nodes() {
+            if let Some(mesh_idx) = model_node.node.mesh_index() {
+                let transform = match transform_query.get(model_node.entity) {
+                    Ok(transform) => transform.compute_matrix(),
+                    Err(_) => continue,
+                };
+                let m_data = VelocityModelData {
+                    transform,
+                    prev_transform: history.record(model_node.entity, transform),
+                };
+                let model_data_bytes: &[u8] = unsafe { util::any_as_u8_slice(&m_data) };
+
+                let mesh = &self.model.get_meshes()[mesh_idx];
+                let morph_weights = morph_query.get(model_node.entity)
+                    .map_or(mesh.weights(), |w| w.weights.as_slice());
+
+                for primitive in mesh.primitives() {
+                    let render = &self.primitive_renders[primitive_idx];
+                    let vertex_layout = &primitive.get_vertex_layout();
+                    primitive_idx += 1;
+                    let pipeline = match &render.velocity_pipeline {
+                        Some(pipeline) => pipeline,
+                        None => continue,
+                    };
+                    unsafe {
+                        context.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_pipeline());
+
+                        context.device.cmd_push_constants(command_buffer, pipeline.get_layout(),
+                                                          render.velocity_constant_stages, 0, model_data_bytes);
+
+                        // the previous position is morphed with the same weights, only the
+                        // motion of the node and the joints is written
+                        if let (Some(targets), false) = (&render.morph_targets, render.velocity_morph_stages.is_empty()) {
+                            let morph_constant = MorphConstant::new(targets, morph_weights);
+                            context.device.cmd_push_constants(command_buffer, pipeline.get_layout(),
+                                                              render.velocity_morph_stages, model_data_bytes.len() as _,
+                                                              util::any_as_u8_slice(&morph_constant));
+                        }
+
+                        context.device.cmd_bind_vertex_buffers(command_buffer,
+                                                               0,
+                                                               &render.buffers_ref_for_draw,
+                                                               &vertex_layout.buffers_ref_offsets);
+                        context.device.cmd_bind_index_buffer(command_buffer,
+                                                             self.model.get_buffer().buffer,
+                                                             vertex_layout.indices.index as _,
+                                                             vertex_layout.indices_type);
+
+                        let mut descriptor_sets = vec![frame_descriptor_set, render.descriptor_set];
+                        if let Some(skins) = skins {
+                            descriptor_sets.push(skins.skin_descriptor_set);
+                            descriptor_sets.push(skins.prev_skin_descriptor_set);
+                        }
+
+                        context.device.cmd_bind_descriptor_sets(command_buffer,
+                                                                vk::PipelineBindPoint::GRAPHICS,
+                                                                pipeline.get_layout(),
                                                                 0,
                                                                 &descriptor_sets, &[]);
 
@@ -205,56 +441,68 @@ struct PrimitiveFragConstant {
 }
 
 
+/// Pipelines come from the `PipelineCache` and are shared with identical primitives.
 struct PrimitiveRender {
+    /// shared by the primitives with the same bindings, owned by the pipeline cache
     pub descriptor_set_layout: vk::DescriptorSetLayout,
     pub descriptor_set: vk::DescriptorSet,
-    pub graphic_pipeline: GraphicPipeline,
-    pub shadow_pipeline: GraphicPipeline,
+    /// morph deltas of the shadow pass, bound after the joint matrices
+    pub shadow_morph_set: Option<vk::DescriptorSet>,
+    pub graphic_pipeline: CachedPipeline,
+    pub shadow_pipeline: CachedPipeline,
+    /// opaque primitives on the deferred path, same layout as `graphic_pipeline`
+    pub gbuffer_pipeline: Option<CachedPipeline>,
+    /// opaque primitives when ambient occlusion is on, same layout as `graphic_pipeline`
+    pub prepass_pipeline: Option<CachedPipeline>,
+    /// opaque primitives when temporal anti-aliasing is on, with the previous joint matrices
+    /// as an extra set and `VelocityModelData` followed by `MorphConstant` as push constants
+    pub velocity_pipeline: Option<CachedPipeline>,
+    /// blended material, drawn by `DrawPass::Transparent` instead of the opaque passes
+    pub transparent: bool,
+    /// released to the cache on destroy
+    pub pipeline_keys: Vec<PipelineKey>,
     pub buffers_ref_for_draw: Vec<vk::Buffer>,
     pub frag_constant: PrimitiveFragConstant,
+    /// stages reading `ModelData`, `PrimitiveFragConstant` and `MorphConstant`, from the
+    /// push constant ranges of the forward shaders
+    pub model_constant_stages: vk::ShaderStageFlags,
+    /// empty when the fragment shader doesn't read the tilling
+    pub frag_constant_stages: vk::ShaderStageFlags,
+    pub morph_constant_stages: vk::ShaderStageFlags,
+    pub shadow_constant_stages: vk::ShaderStageFlags,
+    pub shadow_morph_stages: vk::ShaderStageFlags,
+    pub velocity_constant_stages: vk::ShaderStageFlags,
+    /// empty when the velocity permutation is not morphed
+    pub velocity_morph_stages: vk::ShaderStageFlags,
+    pub morph_targets: Option<MorphTargets>,
 }
 
 impl PrimitiveRender {
+    /// Reflects the stages of a permutation and checks them against the vertex layout of
+    /// the primitive and the set layouts they are bound with.
+    fn reflect(context: &mut RenderContext, stages: &[&str], defines: &[&str], vertex_layout: &VertexLayout,
+               sets: &[(u32, &[vk::DescriptorSetLayoutBinding])]) -> RenderResult<ShaderReflection> {
+        let reflection = context.shader_modules.reflect_stages(&context.device, stages, defines)?;
+        let interface_error = |message: String| RenderError::ShaderInterface { name: stages.join(" / "), message };
+        reflection.check_vertex_layout(vertex_layout).map_err(&interface_error)?;
+        for (set, bindings) in sets {
+            reflection.check_set_layout(*set, bindings).map_err(&interface_error)?;
+        }
+        Ok(reflection)
+    }
+
+    /// `bindings` is the material set reflected from the shader `shader_name`, each of
+    /// them must be one of the descriptors written here. `color_texture` is bound as the
+    /// albedo, white when `None`.
     fn create_descriptors(context: &mut RenderContext,
                           render_pass: &ForwardRenderPass,
-                          material: &Material, model: &Model) -> (vk::DescriptorSetLayout, vk::DescriptorSet) {
-        let bindings = vec![
-            vk::DescriptorSetLayoutBinding::builder()
-                .binding(0)
-                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                .build(),
-            vk::DescriptorSetLayoutBinding::builder()
-                .binding(1)
-                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                .build(),
-        ];
-
-        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
-
-        let set_layout = unsafe {
-            context.device
-                .create_descriptor_set_layout(&layout_info, None)
-                .unwrap()
-        };
-
-        let layouts = [set_layout];
-
-        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(context.descriptor_pool)
-            .set_layouts(&layouts);
-        let set = unsafe {
-            context
-                .device
-                .allocate_descriptor_sets(&allocate_info)
-                .unwrap()[0]
-        };
-
+                          material: &Material, color_texture: Option<usize>, model: &Model,
+                          morph_targets: Option<&MorphTargets>,
+                          shader_name: &str,
+                          bindings: &[vk::DescriptorSetLayoutBinding],
+                          set_layout: vk::DescriptorSetLayout) -> RenderResult<vk::DescriptorSet> {
         let textures = model.get_textures();
-        let texture = material.get_color_texture_index().map_or_else(|| {
+        let texture = color_texture.map_or_else(|| {
             let dr = context.get_resource::<DummyResources>();
             &dr.white_texture
         }, |idx| &textures[idx]);
@@ -269,6 +517,17 @@ impl PrimitiveRender {
                 .build()]
         };
 
+        let normal_texture = material.get_normals_texture_index().map_or_else(|| {
+            let dr = context.get_resource::<DummyResources>();
+            &dr.normal_texture
+        }, |idx| &textures[idx]);
+
+        let normal_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(normal_texture.view)
+            .sampler(normal_texture.sampler)
+            .build()];
+
         let shadow_info = {
             let shadow = render_pass.get_shadow();
             let (view, sampler) = (shadow.shadow_view, shadow.sampler);
@@ -280,20 +539,86 @@ impl PrimitiveRender {
                 .build()]
         };
 
-        let descriptor_writes = [
+        // white when off, `PerFrameData::ao_strength` is 0 for views without occlusion
+        let ao_info = {
+            let (view, sampler) = match render_pass.get_ssao() {
+                Some(ssao) => (ssao.get_ao_view(), ssao.get_sampler()),
+                None => {
+                    let dr = context.get_resource::<DummyResources>();
+                    (dr.white_texture.view, dr.white_texture.sampler)
+                }
+            };
+
+            [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(view)
+                .sampler(sampler)
+                .build()]
+        };
+
+        let morph_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(model.get_buffer().buffer)
+            .offset(morph_targets.map_or(0, |t| t.offset as vk::DeviceSize))
+            .range(morph_targets.map_or(vk::WHOLE_SIZE, |t| t.size as vk::DeviceSize))
+            .build()];
+
+        let mut descriptor_writes = vec![
             vk::WriteDescriptorSet::builder()
-                .dst_set(set)
                 .dst_binding(0)
                 .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
                 .image_info(&albedo_info)
                 .build(),
             vk::WriteDescriptorSet::builder()
-                .dst_set(set)
                 .dst_binding(1)
                 .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
                 .image_info(&shadow_info)
                 .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&normal_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_binding(4)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&ao_info)
+                .build(),
         ];
+        if morph_targets.is_some() {
+            descriptor_writes.push(vk::WriteDescriptorSet::builder()
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&morph_info)
+                .build());
+        }
+
+        for binding in bindings {
+            let written = descriptor_writes.iter()
+                .any(|w| w.dst_binding == binding.binding && w.descriptor_type == binding.descriptor_type);
+            if !written {
+                return Err(RenderError::ShaderInterface {
+                    name: shader_name.to_string(),
+                    message: format!("material binding {} ({:?}) is not provided", binding.binding, binding.descriptor_type),
+                });
+            }
+        }
+        // only what the permutation declares
+        descriptor_writes.retain(|w| bindings.iter().any(|b| b.binding == w.dst_binding));
+
+        let layouts = [set_layout];
+
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = unsafe {
+            context
+                .device
+                .allocate_descriptor_sets(&allocate_info)
+                .map_err(RenderError::vk("vkAllocateDescriptorSets"))?[0]
+        };
+        for write in &mut descriptor_writes {
+            write.dst_set = set;
+        }
 
         unsafe {
             context
@@ -302,34 +627,80 @@ impl PrimitiveRender {
         }
 
 
-        (set_layout, set)
+        Ok(set)
+    }
+
+    /// The deltas read by the morphed shadow permutation, alone in their set.
+    fn create_shadow_morph_set(context: &mut RenderContext, model: &Model, targets: &MorphTargets, shader_name: &str,
+                               bindings: &[vk::DescriptorSetLayoutBinding],
+                               set_layout: vk::DescriptorSetLayout) -> RenderResult<vk::DescriptorSet> {
+        let deltas = bindings.iter().find(|b| b.binding == 0);
+        if !matches!(deltas, Some(b) if b.descriptor_type == vk::DescriptorType::STORAGE_BUFFER) || bindings.len() != 1 {
+            return Err(RenderError::ShaderInterface {
+                name: shader_name.to_string(),
+                message: "the morph deltas are not a storage buffer alone at binding 0".to_string(),
+            });
+        }
+
+        let layouts = [set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = unsafe {
+            context
+                .device
+                .allocate_descriptor_sets(&allocate_info)
+                .map_err(RenderError::vk("vkAllocateDescriptorSets"))?[0]
+        };
+
+        let morph_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(model.get_buffer().buffer)
+            .offset(targets.offset as vk::DeviceSize)
+            .range(targets.size as vk::DeviceSize)
+            .build()];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&morph_info)
+            .build();
+        unsafe {
+            context.device.update_descriptor_sets(&[write], &[]);
+        }
+        Ok(set)
     }
 
     pub fn destroy(self: &mut Self, context: &mut RenderContext)
     {
-        self.graphic_pipeline.destroy(context);
-        self.shadow_pipeline.destroy(context);
+        for key in self.pipeline_keys.drain(..) {
+            PipelineCache::release(context, &key);
+        }
+        let mut sets = vec![self.descriptor_set];
+        sets.extend(self.shadow_morph_set.take());
         unsafe {
-            context.device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
-            context.device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            context.device.free_descriptor_sets(context.descriptor_pool, &sets);
         }
     }
 
     pub fn create(context: &mut RenderContext,
-                  swapchain_mgr: &SwapChainMgr,
                   render_pass: &ForwardRenderPass,
                   primitive: &Primitive,
                   model: &Model,
                   shader_names: &ShadeNames,
-    ) -> Self {
+    ) -> RenderResult<Self> {
         let vertex_layout = primitive.get_vertex_layout();
         let material = primitive.get_material();
-        let color_tex_tilling = {
-            if let Some(t) = material.get_color_texture() {
-                t.get_tilling()
-            } else {
-                TextureInfo::get_default_tilling()
+        let debug_view = context.render_config.debug_view;
+        // the metallic roughness view samples its texture through the albedo binding and
+        // reads the factors instead of the tilling
+        let (color_texture, color_tex_tilling) = match (debug_view, material.get_workflow()) {
+            (DebugView::MetallicRoughness, Workflow::MetallicRoughness(workflow)) => {
+                (workflow.get_metallic_roughness_texture_index(),
+                 Vec4::new(workflow.get_metallic(), workflow.get_roughness(), 0.0, 0.0))
             }
+            (DebugView::MetallicRoughness, Workflow::SpecularGlossiness(_)) => (None, Vec4::ZERO),
+            _ => (material.get_color_texture_index(),
+                  material.get_color_texture().map_or_else(TextureInfo::get_default_tilling, |t| t.get_tilling())),
         };
 
         let frag_constant = PrimitiveFragConstant {
@@ -338,63 +709,231 @@ impl PrimitiveRender {
 
         let vertex_bindings = vertex_layout.build_vk_bindings();
         let vertex_attributes = vertex_layout.build_vk_attributes();
-        let vertex_input = PipelineVertexInputInfo::from(&vertex_bindings, &vertex_attributes);
         let mut shader_defines = vertex_layout.get_shader_defines();
         if model.has_animation() {
             shader_defines.push("SKIN");
         }
+        let morph_targets = primitive.get_morph_targets().copied();
+        if morph_targets.is_some() {
+            shader_defines.push("MORPH_TARGETS");
+        }
+        if material.get_normals_texture().is_some() && vertex_layout.has_location(LOCATION_IN_TANGENT) {
+            shader_defines.push("HAS_NORMAL_MAP");
+        }
+        if material.get_color_texture().map_or(false, |t| t.get_channel() == 1) {
+            shader_defines.push("COLOR_MAP_UV1");
+        }
+        if material.get_normals_texture().map_or(false, |t| t.get_channel() == 1) {
+            shader_defines.push("NORMAL_MAP_UV1");
+        }
+
+        // the shadow pass keeps the regular permutation
+        let mut forward_defines = shader_defines.clone();
+        forward_defines.extend(debug_view.shader_define());
+
+        // material render state, double sided primitives are not culled and blended ones
+        // are drawn over the target
+        let cull_mode = if material.is_double_sided() { vk::CullModeFlags::NONE } else { vk::CullModeFlags::BACK };
+        let msaa = context.render_config.msaa;
+        let opaque_state = RenderState::new(context, vk::SampleCountFlags::TYPE_1).with_cull_mode(cull_mode);
+        let forward_state = RenderState::new(context, msaa)
+            .with_cull_mode(cull_mode)
+            .with_polygon_mode(debug_view.polygon_mode())
+            .with_additive_blend(debug_view.is_additive())
+            .with_alpha_blend(material.is_transparent());
 
         let buffers_ref_for_draw = (0..vertex_bindings.len()).map(|_| model.get_buffer().buffer).collect::<Vec<_>>();
         let frame_uniform_layout = context.per_frame_uniform.as_mut().unwrap().descriptor_set_layout;
 
-        let (descriptor_set_layout, descriptor_set) = Self::create_descriptors(context, render_pass, &material, model);
+        // the forward permutation declares the material set, the other ones are checked against it
+        let skin_bindings = context.skin_buffer_mgr.bindings();
+        let mut skin_sets = Vec::new();
+        if model.has_animation() {
+            skin_sets.push((2, &skin_bindings[..]));
+        }
+        let forward = Self::reflect(context, &[shader_names.vertex, shader_names.frag], &forward_defines,
+                                    vertex_layout, &skin_sets)?;
+        let material_bindings = forward.set_layout_bindings(1);
+        let mut material_sets = vec![(1, material_bindings.as_slice())];
+        material_sets.extend_from_slice(&skin_sets);
+        let descriptor_set_layout = context.pipeline_cache.get_set_layout(&context.device, &material_bindings)?;
 
         let mut all_layout = vec![frame_uniform_layout, descriptor_set_layout];
-
+        let mut shadow_layout = vec![frame_uniform_layout];
+        // the velocity pass reads the previous joint matrices from an extra set
+        let mut velocity_layout = vec![frame_uniform_layout, descriptor_set_layout];
         if model.has_animation() {
-            all_layout.push(context.skin_buffer_mgr.descriptor_set_layout);
+            let skin_layout = context.skin_buffer_mgr.descriptor_set_layout;
+            all_layout.push(skin_layout);
+            shadow_layout.push(skin_layout);
+            velocity_layout.push(skin_layout);
+            velocity_layout.push(skin_layout);
         }
 
         let model_data_size = size_of::<ModelData>() as u32;
-        let constant_ranges = [
-            vk::PushConstantRange::builder().offset(0).size(model_data_size).stage_flags(vk::ShaderStageFlags::VERTEX).build(),
-            vk::PushConstantRange::builder().offset(model_data_size)
-                .size(size_of::<PrimitiveFragConstant>() as _).stage_flags(vk::ShaderStageFlags::FRAGMENT).build(),
+        let frag_constant_size = size_of::<PrimitiveFragConstant>() as u32;
+        let model_constant_stages = forward.push_constant_stages(0, model_data_size);
+        let frag_constant_stages = forward.push_constant_stages(model_data_size, frag_constant_size);
+        let morph_constant_stages = forward.push_constant_stages(model_data_size + frag_constant_size,
+                                                                 size_of::<MorphConstant>() as u32);
+        let constant_ranges = forward.push_constant_ranges();
+
+        let key = |render_pass: vk::RenderPass, fragment_shader: Option<&'static str>, defines: &[&str],
+                   state: RenderState, set_layouts: &[vk::DescriptorSetLayout], ranges: &[vk::PushConstantRange]| {
+            PipelineKey::new(render_pass, shader_names.vertex, fragment_shader, defines,
+                             &vertex_bindings, &vertex_attributes, state, set_layouts, ranges)
+        };
+
+        // the shadow pass binds the joint matrices right after the frame data, then the morph deltas
+        let mut shadow_sets = Vec::new();
+        if model.has_animation() {
+            shadow_sets.push((1, &skin_bindings[..]));
+        }
+        let shadow = Self::reflect(context, &[shader_names.shadow_vertex], &shader_defines, vertex_layout, &shadow_sets)?;
+        let shadow_constant_stages = shadow.push_constant_stages(0, model_data_size);
+        let shadow_morph_stages = shadow.push_constant_stages(model_data_size, size_of::<MorphConstant>() as u32);
+        let shadow_morph = match &morph_targets {
+            Some(targets) => {
+                let bindings = shadow.set_layout_bindings(shadow_layout.len() as u32);
+                let set_layout = context.pipeline_cache.get_set_layout(&context.device, &bindings)?;
+                shadow_layout.push(set_layout);
+                Some((targets, bindings, set_layout))
+            }
+            None => None,
+        };
+
+        let mut keys = vec![
+            key(render_pass.get_native_render_pass(), Some(shader_names.frag), &forward_defines, forward_state,
+                &all_layout, &constant_ranges),
+            PipelineKey {
+                vertex_shader: shader_names.shadow_vertex,
+                ..key(render_pass.get_shadow_render_pass(), None, &shader_defines, RenderState::shadow_caster(context),
+                      &shadow_layout, &shadow.push_constant_ranges())
+            },
         ];
 
-        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&all_layout).push_constant_ranges(&constant_ranges).build();
+        // the G-buffer permutation writes the same material data to the G-buffer instead of shading
+        let gbuffer_index = match render_pass.get_deferred() {
+            Some(deferred) if !material.is_transparent() => {
+                let mut gbuffer_defines = forward_defines.clone();
+                gbuffer_defines.push("GBUFFER");
+                Self::reflect(context, &[shader_names.vertex, shader_names.frag], &gbuffer_defines,
+                              vertex_layout, &material_sets)?;
+                let state = opaque_state
+                    .with_polygon_mode(debug_view.polygon_mode())
+                    .with_additive_blend(debug_view.is_additive())
+                    .with_color_attachments(GBUFFER_COLOR_ATTACHMENTS);
+                keys.push(key(deferred.get_gbuffer_render_pass(), Some(shader_names.frag), &gbuffer_defines, state,
+                              &all_layout, &constant_ranges));
+                Some(keys.len() - 1)
+            }
+            _ => None,
+        };
 
+        // depth only, the vertex stage of the regular permutation without a fragment stage
+        let prepass_index = match render_pass.get_ssao() {
+            Some(ssao) if !material.is_transparent() => {
+                let mut prepass_defines = shader_defines.clone();
+                prepass_defines.push("DEPTH_PREPASS");
+                Self::reflect(context, &[shader_names.vertex], &prepass_defines, vertex_layout, &material_sets)?;
+                keys.push(key(ssao.get_prepass_render_pass(), None, &prepass_defines,
+                              opaque_state.with_color_attachments(0), &all_layout, &constant_ranges));
+                Some(keys.len() - 1)
+            }
+            _ => None,
+        };
 
-        let graphic_pipeline = GraphicPipeline::create(context,
-                                                       swapchain_mgr,
-                                                       render_pass.get_native_render_pass(),
-                                                       &vertex_input, &pipeline_layout_ci, context.render_config.msaa,
-                                                       shader_names.vertex, shader_names.frag, &shader_defines);
+        // `MorphConstant` follows the previous transform, past the 128 bytes every device has
+        let mut velocity_constant_stages = vk::ShaderStageFlags::empty();
+        let mut velocity_morph_stages = vk::ShaderStageFlags::empty();
+        let velocity_index = match render_pass.get_taa() {
+            Some(taa) if !material.is_transparent() => {
+                let velocity_constant_size = size_of::<VelocityModelData>() as u32;
+                let morph_fits = velocity_constant_size + size_of::<MorphConstant>() as u32
+                    <= context.capabilities.max_push_constants_size;
+                if morph_targets.is_some() && !morph_fits {
+                    warn!("{} bytes of push constants, morph targets are left out of the velocity pass",
+                          context.capabilities.max_push_constants_size);
+                }
+                let mut velocity_defines = shader_defines.iter().copied()
+                    .filter(|define| *define != "MORPH_TARGETS" || morph_fits)
+                    .collect::<Vec<_>>();
+                velocity_defines.push("VELOCITY");
+                let mut velocity_sets = material_sets.clone();
+                if model.has_animation() {
+                    velocity_sets.push((3, &skin_bindings[..]));
+                }
+                let velocity = Self::reflect(context, &[shader_names.vertex, shader_names.frag], &velocity_defines,
+                                             vertex_layout, &velocity_sets)?;
+                velocity_constant_stages = velocity.push_constant_stages(0, velocity_constant_size);
+                if morph_targets.is_some() && morph_fits {
+                    velocity_morph_stages = velocity.push_constant_stages(velocity_constant_size,
+                                                                          size_of::<MorphConstant>() as u32);
+                }
+                keys.push(key(taa.get_velocity_render_pass(), Some(shader_names.frag), &velocity_defines,
+                              opaque_state, &velocity_layout, &velocity.push_constant_ranges()));
+                Some(keys.len() - 1)
+            }
+            _ => None,
+        };
 
-        let mut shadow_layout = vec![frame_uniform_layout];
-        if model.has_animation() {
-            shadow_layout.push(context.skin_buffer_mgr.descriptor_set_layout);
+        let descriptor_set = Self::create_descriptors(context, render_pass, &material, color_texture, model,
+                                                      morph_targets.as_ref(), shader_names.frag, &material_bindings,
+                                                      descriptor_set_layout)?;
+        let shadow_morph_set = match shadow_morph {
+            Some((targets, bindings, set_layout)) => {
+                match Self::create_shadow_morph_set(context, model, targets, shader_names.shadow_vertex, &bindings, set_layout) {
+                    Ok(set) => Some(set),
+                    Err(e) => {
+                        unsafe {
+                            context.device.free_descriptor_sets(context.descriptor_pool, &[descriptor_set]);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+        let mut descriptor_sets = vec![descriptor_set];
+        descriptor_sets.extend(shadow_morph_set);
+
+        let mut pipelines = Vec::new();
+        for key in &keys {
+            match PipelineCache::acquire(context, key) {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(e) => {
+                    for key in &keys[..pipelines.len()] {
+                        PipelineCache::release(context, key);
+                    }
+                    unsafe {
+                        context.device.free_descriptor_sets(context.descriptor_pool, &descriptor_sets);
+                    }
+                    return Err(e);
+                }
+            }
         }
-        let shadow_layout_ci = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&shadow_layout)
-            .push_constant_ranges(&constant_ranges)
-            .build();
-        let shadow_pipeline = GraphicPipeline::create_vert_only(context,
-                                                                swapchain_mgr,
-                                                                render_pass.get_shadow_render_pass(),
-                                                                &vertex_input,
-                                                                &shadow_layout_ci,
-                                                                context.render_config.msaa,
-                                                                shader_names.shadow_vertex,
-                                                                &shader_defines);
 
-        Self {
-            graphic_pipeline,
-            shadow_pipeline,
+        Ok(Self {
+            graphic_pipeline: pipelines[0],
+            shadow_pipeline: pipelines[1],
+            gbuffer_pipeline: gbuffer_index.map(|i| pipelines[i]),
+            prepass_pipeline: prepass_index.map(|i| pipelines[i]),
+            velocity_pipeline: velocity_index.map(|i| pipelines[i]),
+            transparent: material.is_transparent(),
+            pipeline_keys: keys,
             descriptor_set_layout,
             descriptor_set,
+            shadow_morph_set,
             buffers_ref_for_draw,
             frag_constant,
-        }
+            model_constant_stages,
+            frag_constant_stages,
+            morph_constant_stages,
+            shadow_constant_stages,
+            shadow_morph_stages,
+            velocity_constant_stages,
+            velocity_morph_stages,
+            morph_targets,
+        })
     }
 }
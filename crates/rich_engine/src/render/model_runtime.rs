@@ -7,6 +7,8 @@ use crate::render::node::{Node, Nodes};
 use crate::render::skin::{Joint, Skin};
 use ash::vk;
 use crate::core::destroy::Destroy;
+use crate::render::deletion_queue::DeferredDestroy;
+use ash::vk::Handle;
 
 pub const MAX_JOINTS_PER_MESH: usize = 512;
 
@@ -28,23 +30,73 @@ pub struct ModelNode {
     pub node: Node,
 }
 
+/// Morph target weights of a mesh node, written by weights animation channels
+/// and free to be changed by game code.
+#[derive(Debug, Clone, Default)]
+pub struct MorphWeights {
+    pub weights: Vec<f32>,
+}
+
+impl MorphWeights {
+    pub fn set_weight(&mut self, target: usize, weight: f32) {
+        if let Some(w) = self.weights.get_mut(target) {
+            *w = weight;
+        }
+    }
+
+    pub fn get_weight(&self, target: usize) -> f32 {
+        self.weights.get(target).copied().unwrap_or(0.0)
+    }
+}
+
 pub struct ModelSkins {
     pub skins: Vec<Skin>,
     pub skin_buffer: Buffer,
     pub skin_buffer_element_size: u32,
     pub skin_descriptor_set: vk::DescriptorSet,
+    /// joint matrices of the previous frame, read by the velocity pass of temporal anti-aliasing
+    pub prev_skin_buffer: Buffer,
+    pub prev_skin_descriptor_set: vk::DescriptorSet,
     pub valid: bool,
 }
 
 impl ModelSkins {
-    pub fn destroy(&mut self, context: &RenderContext) {
+    /// Hands the joint buffer to the deletion queue, frames in flight may still read it.
+    pub fn destroy(&mut self, context: &mut RenderContext) {
         assert!(self.valid, "the model skins has already destroy");
         self.valid = false;
-        self.skin_buffer.destroy(context);
+        let retired = RetiredSkins {
+            buffer: std::mem::take(&mut self.skin_buffer),
+            descriptor_set: std::mem::replace(&mut self.skin_descriptor_set, vk::DescriptorSet::null()),
+            prev_buffer: std::mem::take(&mut self.prev_skin_buffer),
+            prev_descriptor_set: std::mem::replace(&mut self.prev_skin_descriptor_set, vk::DescriptorSet::null()),
+        };
+        context.defer_destroy("model skins", retired);
+    }
+}
+
+struct RetiredSkins {
+    buffer: Buffer,
+    descriptor_set: vk::DescriptorSet,
+    prev_buffer: Buffer,
+    prev_descriptor_set: vk::DescriptorSet,
+}
+
+impl DeferredDestroy for RetiredSkins {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.buffer.destroy(context);
+        self.prev_buffer.destroy(context);
         unsafe {
-            context.device.free_descriptor_sets(context.descriptor_pool, &[self.skin_descriptor_set]);
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set, self.prev_descriptor_set]);
         }
     }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        vec![(vk::ObjectType::BUFFER, self.buffer.buffer.as_raw()),
+             (vk::ObjectType::DESCRIPTOR_SET, self.descriptor_set.as_raw()),
+             (vk::ObjectType::BUFFER, self.prev_buffer.buffer.as_raw()),
+             (vk::ObjectType::DESCRIPTOR_SET, self.prev_descriptor_set.as_raw())]
+    }
 }
 
 pub struct ModelJointRef {
@@ -80,16 +132,20 @@ pub fn init_model_runtime_system(
     if let Some(mut runner) = runner {
         let context = &mut runner.context;
         for (entity, handle) in query.iter_mut() {
-            let nodes_and_skins: Option<(Nodes, Vec<Skin>, Option<Animations>)> = {
+            let nodes_and_skins: Option<(Nodes, Vec<Skin>, Option<Animations>, HashMap<usize, Vec<f32>>)> = {
                 if let Some(model_renderer) = context.get_model(handle) {
                     let model = model_renderer.get_model();
-                    Some((model.nodes().clone(), model.get_skins().clone(), model.clone_animations()))
+                    let morph_weights = model.get_meshes().iter().enumerate()
+                        .filter(|(_, mesh)| mesh.has_morph_targets())
+                        .map(|(idx, mesh)| (idx, mesh.weights().to_vec()))
+                        .collect();
+                    Some((model.nodes().clone(), model.get_skins().clone(), model.clone_animations(), morph_weights))
                 } else {
                     None
                 }
             };
 
-            if let Some((nodes, skins, animations)) = nodes_and_skins {
+            if let Some((nodes, skins, animations, morph_weights)) = nodes_and_skins {
                 if skins.len() > 1 {
                     //todo 多重skin需要创建多个set
                     panic!("multi skin not supported ")
@@ -111,6 +167,12 @@ pub fn init_model_runtime_system(
                     ModelNode { entity: node_entity, node: node.clone() }
                 }).collect::<Vec<_>>();
 
+                for model_node in &model_nodes {
+                    if let Some(weights) = model_node.node.mesh_index().and_then(|idx| morph_weights.get(&idx)) {
+                        commands.entity(model_node.entity).insert(MorphWeights { weights: weights.clone() });
+                    }
+                }
+
                 //create skin
                 if skins.len() > 0 {
                     // add skin joint ref to node entity
@@ -158,58 +220,78 @@ pub fn destroy_model_skins_system(
     }
 }
 
+/// Skin buffers went away with a lost device, creates them on the new renderer.
+pub fn recreate_model_skins(world: &mut World) {
+    if !world.contains_resource::<RenderRunner>() {
+        return;
+    }
+
+    world.resource_scope(|world, mut runner: Mut<RenderRunner>| {
+        let mut query = world.query::<&mut ModelSkins>();
+        for mut model_skins in query.iter_mut(world) {
+            let skins = std::mem::take(&mut model_skins.skins);
+            *model_skins = create_model_skins(&mut runner.context, skins);
+        }
+    });
+}
+
 fn create_model_skins(context: &mut RenderContext, skins: Vec<Skin>) -> ModelSkins {
     let skin_count = skins.len();
     let elem_size = context.get_ubo_alignment::<SkinJointsUbo>();
     let buffer_size = elem_size * (skin_count as u32);
     let skin_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::UNIFORM_BUFFER,
                                                                    buffer_size as _);
-
-    let skin_descriptor_set = {
-        let layouts = [context.skin_buffer_mgr.descriptor_set_layout];
-        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(context.descriptor_pool)
-            .set_layouts(&layouts);
-        let set = unsafe {
-            context
-                .device
-                .allocate_descriptor_sets(&allocate_info)
-                .unwrap()[0]
-        };
-
-        let descriptor_buffer_info = [vk::DescriptorBufferInfo::builder()
-            .buffer(skin_buffer.buffer)
-            .offset(0)
-            .range(vk::WHOLE_SIZE)
-            .build()];
-
-        let descriptor_write_info = vk::WriteDescriptorSet::builder()
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .dst_set(set)
-            .dst_binding(0)
-            .buffer_info(&descriptor_buffer_info)
-            .build();
-
-        unsafe {
-            context.device.update_descriptor_sets(&[descriptor_write_info], &[]);
-        }
-
-        set
-    };
+    let prev_skin_buffer = Buffer::create_host_visible_buffer_with_size(context, vk::BufferUsageFlags::UNIFORM_BUFFER,
+                                                                        buffer_size as _);
 
     ModelSkins {
         skins,
+        skin_descriptor_set: create_skin_descriptor_set(context, &skin_buffer),
+        prev_skin_descriptor_set: create_skin_descriptor_set(context, &prev_skin_buffer),
         skin_buffer,
-        skin_descriptor_set,
+        prev_skin_buffer,
         skin_buffer_element_size: elem_size,
         valid: true,
     }
 }
 
+fn create_skin_descriptor_set(context: &RenderContext, skin_buffer: &Buffer) -> vk::DescriptorSet {
+    let layouts = [context.skin_buffer_mgr.descriptor_set_layout];
+    let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(context.descriptor_pool)
+        .set_layouts(&layouts);
+    let set = unsafe {
+        context
+            .device
+            .allocate_descriptor_sets(&allocate_info)
+            .unwrap()[0]
+    };
+
+    let descriptor_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(skin_buffer.buffer)
+        .offset(0)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let descriptor_write_info = vk::WriteDescriptorSet::builder()
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .dst_set(set)
+        .dst_binding(0)
+        .buffer_info(&descriptor_buffer_info)
+        .build();
+
+    unsafe {
+        context.device.update_descriptor_sets(&[descriptor_write_info], &[]);
+    }
+
+    set
+}
+
 pub fn update_model_runtime_animation(
     time: Res<Time>,
     mut runtime_query: Query<(&ModelRuntime, &mut Animations, &mut AnimCommands)>,
     mut transform_query: Query<&mut Transform>,
+    mut weights_query: Query<&mut MorphWeights>,
 )
 {
     let delta_time = time.delta_seconds();
@@ -220,6 +302,19 @@ pub fn update_model_runtime_animation(
                     animations.set_current(*index as _);
                     animations.play();
                 }
+                AnimCommand::CrossFade { index, duration, curve } => {
+                    animations.cross_fade(*index as _, *duration, *curve);
+                    animations.play();
+                }
+                AnimCommand::Blend { index, weight, duration, curve } => {
+                    animations.blend(*index as _, *weight, *duration, *curve);
+                }
+                AnimCommand::PlayAdditive { index, weight, duration, curve } => {
+                    animations.play_additive(*index as _, *weight, *duration, *curve);
+                }
+                AnimCommand::FadeOut { index, duration, curve } => {
+                    animations.fade_out(*index as _, *duration, *curve);
+                }
                 AnimCommand::Stop => {
                     animations.stop();
                 }
@@ -227,7 +322,12 @@ pub fn update_model_runtime_animation(
         }
         commands.data.clear();
 
-        if let Some(NodesKeyFrame(translations, rotations, scale)) = animations.update(delta_time) {
+        // the clips are blended over the rest pose of the nodes before the pose is written
+        let rest_pose = |node_index: usize| {
+            let node = &runtime.nodes[node_index].node;
+            (node.get_local_position(), node.get_local_rotation(), node.get_local_scale())
+        };
+        if let Some(NodesKeyFrame(translations, rotations, scale, weights)) = animations.update(delta_time, rest_pose) {
             translations.iter().for_each(|(node_index, translation)| {
                 if let Ok(mut t) = transform_query.get_mut(runtime.nodes[*node_index].entity) {
                     t.translation = *translation;
@@ -243,6 +343,11 @@ pub fn update_model_runtime_animation(
                     t.scale = *scale;
                 }
             });
+            weights.into_iter().for_each(|(node_index, weights)| {
+                if let Ok(mut w) = weights_query.get_mut(runtime.nodes[node_index].entity) {
+                    w.weights = weights;
+                }
+            });
         }
     };
 }
@@ -253,6 +358,15 @@ pub fn update_skin_joint_matrix(
     mut joint_query: Query<(Entity, &GlobalTransform, &ModelJointRef), Changed<GlobalTransform>>,
 )
 {
+    // joints that did not move keep their matrix from the previous frame
+    for skins in skins_query.iter() {
+        unsafe {
+            let size = skins.skin_buffer.size.min(skins.prev_skin_buffer.size) as usize;
+            (skins.prev_skin_buffer.get_memory() as *mut u8)
+                .copy_from_nonoverlapping(skins.skin_buffer.get_memory() as *const u8, size);
+        }
+    }
+
     for (entity, global_transform, joint_ref) in joint_query.iter() {
         if let Ok(skin_transform) = transform_query.get(joint_ref.skin_entity) {
             if let Ok(skins) = skins_query.get(joint_ref.model_entity) {
@@ -0,0 +1,304 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use ash::vk;
+use bevy::prelude::*;
+use bevy::asset::LoadState;
+use bevy::tasks::AsyncComputeTaskPool;
+use crate::render::gltf_asset_loader::GltfAsset;
+use crate::render::model::{PreparedModel, ModelUpload};
+use crate::render::model_renderer::{ModelRenderer, ShadeNames};
+use crate::render::render_context::RenderContext;
+use crate::render::forward_render::ForwardRenderPass;
+use crate::render::render_runner::RenderRunner;
+
+/// Bytes recorded into the upload command buffer per frame unless changed on `ModelUploads`.
+pub const DEFAULT_UPLOAD_BYTES_PER_FRAME: u64 = 32 * 1024 * 1024;
+
+const PBR_SHADER_NAMES: ShadeNames = ShadeNames {
+    vertex: "pbr_vert",
+    frag: "pbr_frag",
+    shadow_vertex: "pbr_shadow_vert",
+    shadow_frag: "pbr_shadow_frag",
+};
+
+/// How far the model of an entity's `Handle<GltfAsset>` is from being drawn, kept
+/// up to date by the render plugin. Show a placeholder until it is `Ready`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelLoadState {
+    /// the asset file is still loading
+    Loading,
+    /// meshes and textures are decoded on the async compute pool
+    Preparing,
+    /// buffers and textures are copied to the device, a few per frame
+    Uploading { uploaded_bytes: u64, total_bytes: u64 },
+    Ready,
+    Failed(String),
+}
+
+impl ModelLoadState {
+    pub fn is_ready(&self) -> bool {
+        *self == ModelLoadState::Ready
+    }
+
+    /// 0 until the upload starts, 1 once the model is drawn
+    pub fn progress(&self) -> f32 {
+        match self {
+            ModelLoadState::Uploading { uploaded_bytes, total_bytes } if *total_bytes > 0 => {
+                *uploaded_bytes as f32 / *total_bytes as f32
+            }
+            ModelLoadState::Ready => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+type PreparedResult = (Handle<GltfAsset>, u64, Result<ModelUpload, String>);
+
+/// Bytes recorded into the upload command buffer this frame.
+struct UploadBudget {
+    bytes_per_frame: u64,
+    recorded: u64,
+}
+
+impl UploadBudget {
+    fn new(bytes_per_frame: u64) -> Self {
+        Self { bytes_per_frame, recorded: 0 }
+    }
+
+    /// The first step of a frame always fits, however large it is.
+    fn fits(&self, size: u64) -> bool {
+        self.recorded == 0 || self.recorded + size <= self.bytes_per_frame
+    }
+
+    fn spend(&mut self, size: u64) {
+        self.recorded += size;
+    }
+}
+
+/// Models on their way from a loaded asset to the render context. Decoding runs on
+/// the async compute pool, uploads are spread over frames under `bytes_per_frame`.
+pub struct ModelUploads {
+    /// a single buffer or texture larger than this is uploaded alone in its frame
+    pub bytes_per_frame: u64,
+    next_id: u64,
+    /// id of the latest preparation of each handle, results of older ones are dropped
+    preparing: HashMap<Handle<GltfAsset>, u64>,
+    prepared: Arc<Mutex<Vec<PreparedResult>>>,
+    uploading: VecDeque<(Handle<GltfAsset>, ModelUpload)>,
+    failed: HashMap<Handle<GltfAsset>, String>,
+}
+
+impl Default for ModelUploads {
+    fn default() -> Self {
+        Self {
+            bytes_per_frame: DEFAULT_UPLOAD_BYTES_PER_FRAME,
+            next_id: 0,
+            preparing: HashMap::new(),
+            prepared: Arc::new(Mutex::new(vec![])),
+            uploading: VecDeque::new(),
+            failed: HashMap::new(),
+        }
+    }
+}
+
+impl ModelUploads {
+    /// Starts decoding the asset, a load of the same handle in progress is dropped.
+    pub fn prepare(&mut self, context: &mut RenderContext, task_pool: &AsyncComputeTaskPool,
+                   handle: &Handle<GltfAsset>, asset: &GltfAsset) {
+        self.cancel(context, handle);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.preparing.insert(handle.clone_weak(), id);
+
+        let handle = handle.clone_weak();
+        let asset = asset.clone();
+        let formats = context.format_support();
+        let prepared = self.prepared.clone();
+        task_pool.spawn(async move {
+            // a panic would take the worker thread down and leave the model preparing forever
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let model = PreparedModel::from_gltf(&asset, &formats);
+                ModelUpload::new(asset, model)
+            })).map_err(|_| "decoding the model panicked".to_string());
+            prepared.lock().unwrap().push((handle, id, result));
+        }).detach();
+    }
+
+    /// Drops the load of the handle, resources uploaded so far are released.
+    pub fn cancel(&mut self, context: &mut RenderContext, handle: &Handle<GltfAsset>) {
+        self.preparing.remove(handle);
+        self.failed.remove(handle);
+        if let Some(index) = self.uploading.iter().position(|(h, _)| h == handle) {
+            let (_, upload) = self.uploading.remove(index).unwrap();
+            upload.cancel(context);
+        }
+    }
+
+    /// Forgets every load without releasing anything, the device they were uploaded to is gone.
+    pub fn clear(&mut self) {
+        self.preparing.clear();
+        self.uploading.clear();
+        self.failed.clear();
+    }
+
+    fn receive_prepared(&mut self) {
+        let results = std::mem::take(&mut *self.prepared.lock().unwrap());
+        for (handle, id, result) in results {
+            if self.preparing.get(&handle) != Some(&id) {
+                continue;
+            }
+            self.preparing.remove(&handle);
+            match result {
+                Ok(upload) => self.uploading.push_back((handle, upload)),
+                Err(e) => {
+                    error!("failed to prepare model {:?}: {}", handle, e);
+                    self.failed.insert(handle, e);
+                }
+            }
+        }
+    }
+
+    /// Records uploads until the byte budget of the frame is spent. Models with every
+    /// resource on the device get their pipelines and are inserted into the context.
+    pub fn upload(&mut self, context: &mut RenderContext, render_pass: &ForwardRenderPass,
+                  command_buffer: vk::CommandBuffer) {
+        self.receive_prepared();
+
+        let mut budget = UploadBudget::new(self.bytes_per_frame);
+        while let Some((_, upload)) = self.uploading.front_mut() {
+            match upload.next_step_size() {
+                Some(size) => {
+                    if !budget.fits(size) {
+                        break;
+                    }
+                    if let Err(e) = upload.upload_next(context, command_buffer) {
+                        let (handle, upload) = self.uploading.pop_front().unwrap();
+                        error!("failed to upload model {:?}: {}", handle, e);
+                        upload.cancel(context);
+                        self.failed.insert(handle, e.to_string());
+                        continue;
+                    }
+                    budget.spend(size);
+                }
+                None => {
+                    let (handle, upload) = self.uploading.pop_front().unwrap();
+                    match ModelRenderer::create(context, render_pass, upload.finish(), &PBR_SHADER_NAMES) {
+                        Ok(model) => context.insert_model(handle, model),
+                        Err(e) => {
+                            error!("failed to create the renderer of model {:?}: {}", handle, e);
+                            self.failed.insert(handle, e.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// `None` when the handle is neither loading nor failed.
+    pub fn state(&self, handle: &Handle<GltfAsset>) -> Option<ModelLoadState> {
+        if let Some(e) = self.failed.get(handle) {
+            return Some(ModelLoadState::Failed(e.clone()));
+        }
+        if self.preparing.contains_key(handle) {
+            return Some(ModelLoadState::Preparing);
+        }
+        self.uploading.iter()
+            .find(|(h, _)| h == handle)
+            .map(|(_, upload)| ModelLoadState::Uploading {
+                uploaded_bytes: upload.uploaded_bytes(),
+                total_bytes: upload.total_bytes(),
+            })
+    }
+}
+
+pub fn update_model_load_state_system(mut commands: Commands,
+                                      runner: Option<Res<RenderRunner>>,
+                                      uploads: Res<ModelUploads>,
+                                      asset_server: Res<AssetServer>,
+                                      query: Query<(Entity, &Handle<GltfAsset>, Option<&ModelLoadState>)>) {
+    let runner = match runner {
+        Some(runner) => runner,
+        None => return,
+    };
+
+    for (entity, handle, current) in query.iter() {
+        let state = if runner.context.get_model(handle).is_some() {
+            ModelLoadState::Ready
+        } else if let Some(state) = uploads.state(handle) {
+            state
+        } else if asset_server.get_load_state(handle) == LoadState::Failed {
+            ModelLoadState::Failed("failed to load the asset".to_string())
+        } else {
+            ModelLoadState::Loading
+        };
+
+        if current != Some(&state) {
+            commands.entity(entity).insert(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::asset::HandleId;
+
+    fn handle() -> Handle<GltfAsset> {
+        Handle::weak(HandleId::random::<GltfAsset>())
+    }
+
+    #[test]
+    fn budget_admits_an_oversized_first_step_alone() {
+        let mut budget = UploadBudget::new(100);
+        assert!(budget.fits(250));
+        budget.spend(250);
+        assert!(!budget.fits(1));
+    }
+
+    #[test]
+    fn budget_is_spent_up_to_the_limit() {
+        let mut budget = UploadBudget::new(100);
+        budget.spend(60);
+        assert!(budget.fits(40));
+        assert!(!budget.fits(41));
+        budget.spend(40);
+        assert!(!budget.fits(1));
+    }
+
+    #[test]
+    fn prepared_results_of_older_loads_are_dropped() {
+        let mut uploads = ModelUploads::default();
+        let model = handle();
+        uploads.preparing.insert(model.clone_weak(), 1);
+        uploads.prepared.lock().unwrap().push((model.clone_weak(), 0, Err("stale".to_string())));
+        uploads.receive_prepared();
+        assert_eq!(uploads.state(&model), Some(ModelLoadState::Preparing));
+
+        uploads.prepared.lock().unwrap().push((model.clone_weak(), 1, Err("broken".to_string())));
+        uploads.receive_prepared();
+        assert_eq!(uploads.state(&model), Some(ModelLoadState::Failed("broken".to_string())));
+        assert!(uploads.preparing.is_empty());
+    }
+
+    #[test]
+    fn unknown_and_cleared_handles_have_no_state() {
+        let mut uploads = ModelUploads::default();
+        let model = handle();
+        assert_eq!(uploads.state(&model), None);
+        uploads.preparing.insert(model.clone_weak(), 0);
+        uploads.failed.insert(handle(), "broken".to_string());
+        uploads.clear();
+        assert_eq!(uploads.state(&model), None);
+        assert!(uploads.failed.is_empty());
+    }
+
+    #[test]
+    fn progress_follows_the_uploaded_bytes() {
+        assert_eq!(ModelLoadState::Preparing.progress(), 0.0);
+        assert_eq!(ModelLoadState::Uploading { uploaded_bytes: 50, total_bytes: 200 }.progress(), 0.25);
+        assert_eq!(ModelLoadState::Uploading { uploaded_bytes: 0, total_bytes: 0 }.progress(), 0.0);
+        assert_eq!(ModelLoadState::Ready.progress(), 1.0);
+        assert!(!ModelLoadState::Failed("broken".to_string()).is_ready());
+    }
+}
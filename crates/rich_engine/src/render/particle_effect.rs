@@ -0,0 +1,242 @@
+use std::ops::{Add, Mul};
+use bevy::{
+    prelude::*,
+    asset::{AssetLoader, AssetPath, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use crate::render::gltf_asset_loader::GltfAsset;
+
+/// Values baked per curve, the shaders interpolate between them over the age of a particle.
+pub const CURVE_SAMPLES: usize = 32;
+
+/// Keys over the normalized age of a particle, 0 at spawn and 1 when it dies. Values are
+/// interpolated linearly between keys and held outside them, keys are sorted by age.
+/// Written as a list in RON, `[(0.0, 1.0), (1.0, 0.0)]`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Curve<T> {
+    pub keys: Vec<(f32, T)>,
+}
+
+impl<T> Curve<T> where T: Copy + Add<Output=T> + Mul<f32, Output=T> {
+    pub fn constant(value: T) -> Self {
+        Self {
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    /// `None` without keys.
+    pub fn sample(&self, age: f32) -> Option<T> {
+        let first = self.keys.first()?;
+        if age <= first.0 {
+            return Some(first.1);
+        }
+
+        for pair in self.keys.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if age <= b.0 {
+                let t = if b.0 > a.0 { (age - a.0) / (b.0 - a.0) } else { 1.0 };
+                return Some(a.1 * (1.0 - t) + b.1 * t);
+            }
+        }
+
+        self.keys.last().map(|key| key.1)
+    }
+
+    /// Samples evenly spaced over the age, `default` for a curve without keys.
+    pub fn bake(&self, default: T) -> [T; CURVE_SAMPLES] {
+        let mut samples = [default; CURVE_SAMPLES];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let age = i as f32 / (CURVE_SAMPLES - 1) as f32;
+            *sample = self.sample(age).unwrap_or(default);
+        }
+        samples
+    }
+}
+
+/// Where particles spawn relative to the emitter and the direction they start moving in.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum EmitterShape {
+    /// at the emitter, in any direction
+    Point,
+    /// inside the sphere, away from its center
+    Sphere { radius: f32 },
+    /// on a disc of `radius` around the local Y axis, within `angle` radians of it
+    Cone { angle: f32, radius: f32 },
+    /// inside the box, along the local Y axis
+    Box { half_extents: Vec3 },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum ParticleRender {
+    /// quads facing the camera, turned by the particle rotation
+    Billboard,
+    /// quads along the velocity, stretched by the distance covered in `length_scale` seconds
+    Stretched { length_scale: f32 },
+    /// the first mesh of a glTF file relative to `assets/`, scaled by the size curve
+    Mesh { path: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ParticleBlend {
+    Alpha,
+    Additive,
+}
+
+/// Bounces particles off the scene depth of the main camera. Only surfaces visible
+/// to it in the previous frame are hit.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ParticleCollision {
+    /// part of the velocity along the surface normal kept after a hit
+    pub bounce: f32,
+    /// part of the velocity along the surface lost on a hit
+    pub friction: f32,
+    /// particles further behind a surface than this keep falling, in meters
+    pub thickness: f32,
+    /// particles die on their first hit instead of bouncing
+    pub kill: bool,
+}
+
+impl Default for ParticleCollision {
+    fn default() -> Self {
+        Self {
+            bounce: 0.4,
+            friction: 0.2,
+            thickness: 0.5,
+            kill: false,
+        }
+    }
+}
+
+/// A particle emitter definition, loaded from `*.particle.ron` files. Missing fields
+/// keep their default. Entities with a `ParticleEmitter` pointing at the asset are
+/// rebuilt when the file changes while the asset server watches for changes.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TypeUuid)]
+#[uuid = "5ca2ae28-3fd3-4701-ac2f-d5d5ea8d0271"]
+#[serde(default)]
+pub struct ParticleEffect {
+    /// particles alive at once per emitter, the oldest ones are replaced when it is full
+    pub capacity: u32,
+    /// particles spawned per second while emitting
+    pub rate: f32,
+    /// particles spawned at once when the emitter starts emitting
+    pub burst: u32,
+    /// seconds, picked per particle between min and max
+    pub lifetime: (f32, f32),
+    /// initial speed in meters per second, picked per particle between min and max
+    pub speed: (f32, f32),
+    pub shape: EmitterShape,
+    /// acceleration in meters per second squared, in world space
+    pub gravity: Vec3,
+    /// part of the velocity lost per second
+    pub drag: f32,
+    /// rotation speed in radians per second, picked per particle between min and max.
+    /// The initial rotation is random.
+    pub spin: (f32, f32),
+    pub render: ParticleRender,
+    pub blend: ParticleBlend,
+    /// image file relative to `assets/`, white when empty
+    pub texture: String,
+    /// multiplies the texture
+    pub color: Curve<Vec4>,
+    /// quad width or mesh scale in meters
+    pub size: Curve<f32>,
+    pub collision: Option<ParticleCollision>,
+    /// sorts particles back to front for the main camera, alpha blended effects need it
+    pub sort: bool,
+    /// glTF of `ParticleRender::Mesh`, loaded by the asset server along with the effect
+    #[serde(skip)]
+    pub mesh: Option<Handle<GltfAsset>>,
+}
+
+impl Default for ParticleEffect {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            rate: 50.0,
+            burst: 0,
+            lifetime: (1.0, 2.0),
+            speed: (1.0, 2.0),
+            shape: EmitterShape::Point,
+            gravity: Vec3::ZERO,
+            drag: 0.0,
+            spin: (0.0, 0.0),
+            render: ParticleRender::Billboard,
+            blend: ParticleBlend::Alpha,
+            texture: String::new(),
+            color: Curve::constant(Vec4::ONE),
+            size: Curve::constant(0.1),
+            collision: None,
+            sort: true,
+            mesh: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ParticleEffectLoader;
+
+impl AssetLoader for ParticleEffectLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut effect: ParticleEffect = ron::de::from_bytes(bytes)?;
+            let mut dependencies = vec![];
+            if let ParticleRender::Mesh { path } = &effect.render {
+                let path = AssetPath::from(path.as_str()).to_owned();
+                effect.mesh = Some(load_context.get_handle(path.get_id()));
+                dependencies.push(path);
+            }
+            load_context.set_default_asset(LoadedAsset::new(effect).with_dependencies(dependencies));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["particle.ron"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_holds_outside_the_keys() {
+        let curve = Curve { keys: vec![(0.25, 1.0), (0.75, 3.0)] };
+        assert_eq!(curve.sample(0.0), Some(1.0));
+        assert_eq!(curve.sample(-1.0), Some(1.0));
+        assert_eq!(curve.sample(0.5), Some(2.0));
+        assert_eq!(curve.sample(1.0), Some(3.0));
+        assert_eq!(curve.sample(2.0), Some(3.0));
+    }
+
+    #[test]
+    fn sample_steps_at_equal_ages() {
+        let curve = Curve { keys: vec![(0.0, 0.0), (0.5, 1.0), (0.5, 5.0), (1.0, 5.0)] };
+        assert_eq!(curve.sample(0.25), Some(0.5));
+        // the first key at an age wins at that age, the last one after it
+        assert_eq!(curve.sample(0.5), Some(1.0));
+        assert_eq!(curve.sample(0.75), Some(5.0));
+    }
+
+    #[test]
+    fn sample_without_keys() {
+        let curve = Curve::<f32> { keys: vec![] };
+        assert_eq!(curve.sample(0.5), None);
+        assert_eq!(curve.bake(0.1), [0.1; CURVE_SAMPLES]);
+    }
+
+    #[test]
+    fn bake_covers_the_whole_age() {
+        let baked = Curve { keys: vec![(0.0, 0.0), (1.0, 1.0)] }.bake(0.0);
+        assert_eq!(baked[0], 0.0);
+        assert_eq!(baked[CURVE_SAMPLES - 1], 1.0);
+        assert!((baked[CURVE_SAMPLES / 2] - (CURVE_SAMPLES / 2) as f32 / (CURVE_SAMPLES - 1) as f32).abs() < 1e-6);
+
+        let constant = Curve::constant(Vec4::new(1.0, 0.5, 0.25, 1.0)).bake(Vec4::ZERO);
+        assert!(constant.iter().all(|sample| *sample == Vec4::new(1.0, 0.5, 0.25, 1.0)));
+    }
+}
@@ -0,0 +1,1001 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::ops::DerefMut;
+use anyhow::anyhow;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::buffer::Buffer;
+use crate::render::deletion_queue::DeferredDestroy;
+use crate::render::forward_render::ForwardRenderPass;
+use crate::render::gltf_asset_loader::GltfAsset;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo};
+use crate::render::particle_effect::{CURVE_SAMPLES, EmitterShape, ParticleBlend, ParticleEffect, ParticleRender};
+use crate::render::render_context::{DummyResources, RenderContext};
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_runner::RenderRunner;
+use crate::render::render_view::RenderView;
+use crate::render::texture_cache::TextureCache;
+use crate::render::util;
+
+/// Emitters with GPU state at once, further ones start when others are removed.
+pub const MAX_PARTICLE_EMITTERS: usize = 64;
+/// Upper bound of `ParticleEffect::capacity`.
+pub const MAX_PARTICLES_PER_EMITTER: u32 = 65536;
+
+/// local size of particle_update_comp and particle_sort_comp
+const WORKGROUP_SIZE: u32 = 64;
+/// position and age, velocity and lifetime, rotation, spin and seed
+const PARTICLE_SIZE: u64 = 48;
+/// sort key and particle index
+const SORT_ENTRY_SIZE: u64 = 8;
+/// vertices of a billboard or stretched quad, generated by the vertex shader
+const QUAD_VERTEX_COUNT: u32 = 6;
+
+/// `UpdateConstant::flags`
+const FLAG_DEPTH: u32 = 1;
+const FLAG_REVERSE_Z: u32 = 2;
+const FLAG_SORT: u32 = 4;
+/// kills every particle, set on the first update of an emitter
+const FLAG_RESET: u32 = 8;
+
+/// Spawns the particles of a `ParticleEffect` at the entity. Particles move in world
+/// space once spawned and the scale of the transform is not applied, size them in
+/// the effect.
+#[derive(Debug, Clone)]
+pub struct ParticleEmitter {
+    pub effect: Handle<ParticleEffect>,
+    /// stops spawning when false, live particles finish their lifetime. Turning it
+    /// back on spawns the burst of the effect again.
+    pub emitting: bool,
+}
+
+impl ParticleEmitter {
+    pub fn new(effect: Handle<ParticleEffect>) -> Self {
+        Self {
+            effect,
+            emitting: true,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuEffect {
+    color: [Vec4; CURVE_SAMPLES],
+    size: [f32; CURVE_SAMPLES],
+    /// xyz gravity, w drag
+    gravity_drag: Vec4,
+    /// lifetime min and max, speed min and max
+    lifetime_speed: Vec4,
+    /// x 0 point, 1 sphere, 2 cone, 3 box, y radius, z cone angle
+    shape: Vec4,
+    /// xyz box half extents
+    half_extents: Vec4,
+    /// spin min and max, z stretch length scale
+    spin_stretch: Vec4,
+    /// x bounce, y friction, z thickness, w 0 no collision, 1 bounce, 2 kill
+    collision: Vec4,
+    /// x capacity, y sort entries
+    counts: UVec4,
+}
+
+impl GpuEffect {
+    fn from(effect: &ParticleEffect, capacity: u32) -> Self {
+        let (shape, radius, angle, half_extents) = match &effect.shape {
+            EmitterShape::Point => (0.0, 0.0, 0.0, Vec3::ZERO),
+            EmitterShape::Sphere { radius } => (1.0, *radius, 0.0, Vec3::ZERO),
+            EmitterShape::Cone { angle, radius } => (2.0, *radius, *angle, Vec3::ZERO),
+            EmitterShape::Box { half_extents } => (3.0, 0.0, 0.0, *half_extents),
+        };
+        let stretch = match &effect.render {
+            ParticleRender::Stretched { length_scale } => *length_scale,
+            _ => 0.0,
+        };
+        let collision = match &effect.collision {
+            Some(c) => Vec4::new(c.bounce, c.friction, c.thickness, if c.kill { 2.0 } else { 1.0 }),
+            None => Vec4::ZERO,
+        };
+
+        Self {
+            color: effect.color.bake(Vec4::ONE),
+            size: effect.size.bake(0.1),
+            gravity_drag: Vec4::from((effect.gravity, effect.drag)),
+            lifetime_speed: Vec4::new(effect.lifetime.0, effect.lifetime.1, effect.speed.0, effect.speed.1),
+            shape: Vec4::new(shape, radius, angle, 0.0),
+            half_extents: Vec4::from((half_extents, 0.0)),
+            spin_stretch: Vec4::new(effect.spin.0, effect.spin.1, stretch, 0.0),
+            collision,
+            counts: UVec4::new(capacity, sort_entry_count(capacity), 0, 0),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UpdateConstant {
+    /// projection of the main camera when it wrote the scene depth, the previous frame
+    depth_view_proj: Mat4,
+    /// pixel rect of that camera, xy offset and zw size
+    depth_rect: Vec4,
+    /// xyz emitter position, w delta time
+    position: Vec4,
+    rotation: Quat,
+    /// ring buffer slots overwritten by new particles this frame
+    spawn_start: u32,
+    spawn_count: u32,
+    seed: u32,
+    flags: u32,
+}
+
+/// One compare and swap pass of the bitonic sort.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SortConstant {
+    block: u32,
+    step: u32,
+}
+
+/// Sort entries are a power of two, the padding sorts behind the particles.
+fn sort_entry_count(capacity: u32) -> u32 {
+    capacity.next_power_of_two()
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MeshVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+    uv: [f32; 2],
+}
+
+struct ParticleMesh {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    index_count: u32,
+}
+
+impl ParticleMesh {
+    fn destroy(&mut self, context: &RenderContext) {
+        self.vertex_buffer.destroy(context);
+        self.index_buffer.destroy(context);
+    }
+
+    /// The first primitive of the first mesh, without its material.
+    fn load(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, asset: &GltfAsset,
+            path: &str) -> anyhow::Result<Self> {
+        let (document, buffers, _) = asset.export();
+        let primitive = document.meshes().next()
+            .and_then(|mesh| mesh.primitives().next())
+            .ok_or(anyhow!("no mesh in {}", path))?;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let positions = reader.read_positions().ok_or(anyhow!("no positions in {}", path))?.collect::<Vec<_>>();
+        let normals = reader.read_normals().map(|n| n.collect::<Vec<_>>()).unwrap_or_default();
+        let uvs = reader.read_tex_coords(0).map(|uv| uv.into_f32().collect::<Vec<_>>()).unwrap_or_default();
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let vertices = positions.iter().enumerate()
+            .map(|(i, position)| MeshVertex {
+                position: *position,
+                normal: normals.get(i).copied().unwrap_or([0.0, 1.0, 0.0]),
+                uv: uvs.get(i).copied().unwrap_or([0.0, 0.0]),
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_buffer = Buffer::create_device_local_buffer(context, upload_command_buffer,
+                                                               vk::BufferUsageFlags::VERTEX_BUFFER, &vertices);
+        let index_buffer = Buffer::create_device_local_buffer(context, upload_command_buffer,
+                                                              vk::BufferUsageFlags::INDEX_BUFFER, &indices);
+        Ok(Self {
+            vertex_buffer,
+            index_buffer,
+            index_count: indices.len() as u32,
+        })
+    }
+}
+
+/// Device objects of one effect asset, shared by its emitters.
+struct EffectResources {
+    effect: ParticleEffect,
+    capacity: u32,
+    params: Buffer,
+    mesh: Option<ParticleMesh>,
+    pipeline: GraphicPipeline,
+}
+
+impl EffectResources {
+    fn destroy(&mut self, context: &RenderContext) {
+        self.pipeline.destroy(context);
+        self.params.destroy(context);
+        if let Some(mesh) = self.mesh.as_mut() {
+            mesh.destroy(context);
+        }
+    }
+}
+
+impl DeferredDestroy for EffectResources {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+}
+
+/// Particles of one `ParticleEmitter` entity.
+struct EmitterInstance {
+    effect: Handle<ParticleEffect>,
+    particles: Buffer,
+    sort_entries: Buffer,
+    descriptor_set: vk::DescriptorSet,
+    capacity: u32,
+    /// next ring buffer slot to spawn into
+    spawn_cursor: u32,
+    /// fraction of a particle carried over to the next frame
+    spawn_accumulator: f32,
+    was_emitting: bool,
+    /// false until the first update cleared the particles
+    simulated: bool,
+}
+
+impl EmitterInstance {
+    fn destroy(&mut self, context: &RenderContext) {
+        self.particles.destroy(context);
+        self.sort_entries.destroy(context);
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
+        }
+    }
+
+    fn sort_count(&self) -> u32 {
+        sort_entry_count(self.capacity)
+    }
+
+    /// Particles to spawn this frame, moves the ring buffer cursor past them.
+    fn take_spawn(&mut self, effect: &ParticleEffect, emitting: bool, delta_time: f32) -> (u32, u32) {
+        let mut count = 0;
+        if emitting {
+            if !self.was_emitting {
+                count += effect.burst;
+            }
+            self.spawn_accumulator += effect.rate.max(0.0) * delta_time;
+            let whole = self.spawn_accumulator.floor();
+            self.spawn_accumulator -= whole;
+            count += whole as u32;
+        } else {
+            self.spawn_accumulator = 0.0;
+        }
+        self.was_emitting = emitting;
+
+        let count = count.min(self.capacity);
+        let start = self.spawn_cursor;
+        self.spawn_cursor = (self.spawn_cursor + count) % self.capacity;
+        (start, count)
+    }
+}
+
+impl DeferredDestroy for EmitterInstance {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+}
+
+/// Compute pipelines shared by every emitter, rebuilt with the forward targets since
+/// the update reads the scene depth.
+struct ParticleCompute {
+    pipeline_layout: vk::PipelineLayout,
+    update_pipeline: vk::Pipeline,
+    sort_pipeline: vk::Pipeline,
+}
+
+impl ParticleCompute {
+    fn destroy(&mut self, context: &RenderContext) {
+        unsafe {
+            context.device.destroy_pipeline(self.update_pipeline, None);
+            context.device.destroy_pipeline(self.sort_pipeline, None);
+            context.device.destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+
+    fn create(context: &mut RenderContext, set_layout: vk::DescriptorSetLayout) -> RenderResult<Self> {
+        let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+        let set_layouts = [frame_uniform_layout, set_layout];
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<UpdateConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE).build(),
+        ];
+        let pipeline_layout = {
+            let ci = vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(&constant_ranges)
+                .build();
+            unsafe { context.device.create_pipeline_layout(&ci, None) }
+                .map_err(RenderError::vk("vkCreatePipelineLayout"))?
+        };
+
+        let mut defines = vec![];
+        if context.render_config.msaa != vk::SampleCountFlags::TYPE_1 {
+            defines.push("MSAA_DEPTH");
+        }
+
+        let pipelines = Self::create_pipeline(context, pipeline_layout, "particle_update_comp", &defines)
+            .and_then(|update| match Self::create_pipeline(context, pipeline_layout, "particle_sort_comp", &[]) {
+                Ok(sort) => Ok((update, sort)),
+                Err(e) => {
+                    unsafe { context.device.destroy_pipeline(update, None); }
+                    Err(e)
+                }
+            });
+        let (update_pipeline, sort_pipeline) = match pipelines {
+            Ok(pipelines) => pipelines,
+            Err(e) => {
+                unsafe { context.device.destroy_pipeline_layout(pipeline_layout, None); }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            pipeline_layout,
+            update_pipeline,
+            sort_pipeline,
+        })
+    }
+
+    fn create_pipeline(context: &mut RenderContext, layout: vk::PipelineLayout, name: &str,
+                       defines: &[&str]) -> RenderResult<vk::Pipeline> {
+        let bindings = ParticleSystem::set_layout_bindings();
+        context.shader_modules.check_stages(&context.device, &[name], defines, &[(1, &bindings[..])])?;
+        let stage = context.shader_modules.create_shader_stage(&context.device, name, defines,
+                                                               vk::ShaderStageFlags::COMPUTE)?;
+        let ci = vk::ComputePipelineCreateInfo::builder().stage(stage).layout(layout).build();
+        unsafe { context.device.create_compute_pipelines(vk::PipelineCache::null(), &[ci], None) }
+            .map(|pipelines| pipelines[0])
+            .map_err(|(_, e)| RenderError::vk("vkCreateComputePipelines")(e))
+    }
+}
+
+/// Simulates `ParticleEmitter` entities in compute and draws them in every view.
+/// Spawning and the update run in one dispatch per emitter at the start of the frame,
+/// sorted effects are then ordered back to front for the main camera with a bitonic
+/// sort. Collisions use the scene depth the main camera wrote the frame before.
+pub struct ParticleSystem {
+    set_layout: vk::DescriptorSetLayout,
+    depth_sampler: vk::Sampler,
+    compute: ParticleCompute,
+    effects: HashMap<Handle<ParticleEffect>, EffectResources>,
+    /// effects that failed to build, tried again when the asset changes
+    failed: HashSet<Handle<ParticleEffect>>,
+    emitters: HashMap<Entity, EmitterInstance>,
+    /// view projection and rect of the main camera when it last wrote the scene depth
+    depth_view: Option<(Mat4, vk::Rect2D)>,
+    frame: u32,
+}
+
+impl ParticleSystem {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        for (_, mut instance) in self.emitters.drain() {
+            instance.destroy(context);
+        }
+        for (_, mut effect) in self.effects.drain() {
+            effect.destroy(context);
+        }
+        self.compute.destroy(context);
+        unsafe {
+            context.device.destroy_descriptor_set_layout(self.set_layout, None);
+            context.device.destroy_sampler(self.depth_sampler, None);
+        }
+    }
+
+    pub fn create(context: &mut RenderContext) -> RenderResult<Self> {
+        let depth_sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+
+        let set_layout = {
+            let bindings = Self::set_layout_bindings();
+            let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+            match unsafe { context.device.create_descriptor_set_layout(&ci, None) } {
+                Ok(set_layout) => set_layout,
+                Err(e) => {
+                    unsafe { context.device.destroy_sampler(depth_sampler, None); }
+                    return Err(RenderError::vk("vkCreateDescriptorSetLayout")(e));
+                }
+            }
+        };
+
+        let compute = match ParticleCompute::create(context, set_layout) {
+            Ok(compute) => compute,
+            Err(e) => {
+                unsafe {
+                    context.device.destroy_descriptor_set_layout(set_layout, None);
+                    context.device.destroy_sampler(depth_sampler, None);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            set_layout,
+            depth_sampler,
+            compute,
+            effects: HashMap::new(),
+            failed: HashSet::new(),
+            emitters: HashMap::new(),
+            depth_view: None,
+            frame: 0,
+        })
+    }
+
+    /// The emitter set of the compute and draw pipelines, checked against each shader.
+    fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 5] {
+        let storage = |binding: u32, stage_flags: vk::ShaderStageFlags| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(stage_flags)
+            .build();
+        [
+            //particles
+            storage(0, vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX),
+            //sort entries
+            storage(1, vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX),
+            //effect
+            storage(2, vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
+            //scene depth
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(3)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            //texture
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(4)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ]
+    }
+
+    /// Rebuilds the pipelines against new forward targets or settings and points the
+    /// emitters at the new scene depth. Call while the device is idle.
+    pub fn recreate(&mut self, context: &mut RenderContext, forward_render: &ForwardRenderPass) -> RenderResult<()> {
+        self.compute.destroy(context);
+        self.compute = ParticleCompute::create(context, self.set_layout)?;
+        for resources in self.effects.values_mut() {
+            resources.pipeline.destroy(context);
+            resources.pipeline = Self::create_pipeline(context, forward_render, self.set_layout,
+                                                       &resources.effect, resources.mesh.is_some())?;
+        }
+        for instance in self.emitters.values() {
+            self.write_depth(context, instance.descriptor_set, forward_render);
+        }
+        self.depth_view = None;
+        Ok(())
+    }
+
+    fn create_pipeline(context: &mut RenderContext, forward_render: &ForwardRenderPass, set_layout: vk::DescriptorSetLayout,
+                       effect: &ParticleEffect, mesh: bool) -> RenderResult<GraphicPipeline> {
+        let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+        let set_layouts = [frame_uniform_layout, set_layout];
+        let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .build();
+
+        let bindings = [
+            vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: size_of::<MeshVertex>() as _,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
+        ];
+        let attributes = [
+            vk::VertexInputAttributeDescription { location: 0, binding: 0, format: vk::Format::R32G32B32_SFLOAT, offset: 0 },
+            vk::VertexInputAttributeDescription { location: 1, binding: 0, format: vk::Format::R32G32B32_SFLOAT, offset: 12 },
+            vk::VertexInputAttributeDescription { location: 2, binding: 0, format: vk::Format::R32G32_SFLOAT, offset: 24 },
+        ];
+        let (vertex_input, define) = match (&effect.render, mesh) {
+            (ParticleRender::Mesh { .. }, true) => (PipelineVertexInputInfo::from(&bindings, &attributes), "PARTICLE_MESH"),
+            (ParticleRender::Stretched { .. }, _) => (PipelineVertexInputInfo::from(&[], &[])
+                                                          .with_cull_mode(vk::CullModeFlags::NONE), "PARTICLE_STRETCHED"),
+            _ => (PipelineVertexInputInfo::from(&[], &[])
+                      .with_cull_mode(vk::CullModeFlags::NONE), "PARTICLE_BILLBOARD"),
+        };
+        let vertex_input = match effect.blend {
+            ParticleBlend::Alpha => vertex_input.with_alpha_blend(true),
+            ParticleBlend::Additive => vertex_input.with_additive_blend(true).with_depth_test(true),
+        };
+
+        let bindings = Self::set_layout_bindings();
+        context.shader_modules.check_stages(&context.device, &["particle_vert", "particle_frag"], &[define],
+                                            &[(1, &bindings[..])])?;
+
+        let msaa = context.render_config.msaa;
+        GraphicPipeline::create(context, forward_render.get_native_render_pass(), &vertex_input, &pipeline_layout_ci,
+                                msaa, "particle_vert", "particle_frag", &[define])
+    }
+
+    fn write_depth(&self, context: &RenderContext, set: vk::DescriptorSet, forward_render: &ForwardRenderPass) {
+        let depth_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .image_view(forward_render.get_depth_view())
+            .sampler(self.depth_sampler)
+            .build()];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(3)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&depth_info)
+            .build()];
+        unsafe {
+            context.device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    /// Retires the effect and the emitters using it, they are built again from the
+    /// asset as it is now.
+    pub fn remove_effect(&mut self, context: &mut RenderContext, handle: &Handle<ParticleEffect>) {
+        self.failed.remove(handle);
+        let removed = self.emitters.iter()
+            .filter(|(_, instance)| &instance.effect == handle)
+            .map(|(entity, _)| *entity)
+            .collect::<Vec<_>>();
+        for entity in removed {
+            let instance = self.emitters.remove(&entity).unwrap();
+            context.defer_destroy(&format!("particle emitter {:?}", entity), instance);
+        }
+        if let Some(resources) = self.effects.remove(handle) {
+            context.defer_destroy(&format!("particle effect {:?}", handle.id), resources);
+        }
+    }
+
+    /// Retires the effects drawing the texture of `path`, they are built again once it is
+    /// uploaded.
+    pub fn remove_texture(&mut self, context: &mut RenderContext, path: &str) {
+        let removed = self.effects.iter()
+            .filter(|(_, resources)| resources.effect.texture == path)
+            .map(|(effect, _)| effect.clone_weak())
+            .collect::<Vec<_>>();
+        for effect in removed {
+            self.remove_effect(context, &effect);
+        }
+    }
+
+    /// Retires the effects drawing the mesh of `handle`, they are built again once it is loaded.
+    pub fn remove_mesh(&mut self, context: &mut RenderContext, handle: &Handle<GltfAsset>) {
+        let removed = self.effects.iter()
+            .filter(|(_, resources)| resources.effect.mesh.as_ref() == Some(handle))
+            .map(|(effect, _)| effect.clone_weak())
+            .collect::<Vec<_>>();
+        for effect in removed {
+            self.remove_effect(context, &effect);
+        }
+    }
+
+    fn create_effect(&self, context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                     forward_render: &ForwardRenderPass, effect: &ParticleEffect,
+                     mesh: Option<&GltfAsset>) -> anyhow::Result<EffectResources> {
+        let capacity = effect.capacity.clamp(1, MAX_PARTICLES_PER_EMITTER);
+        let mut mesh = match (&effect.render, mesh) {
+            (ParticleRender::Mesh { path }, Some(asset)) => Some(ParticleMesh::load(context, upload_command_buffer,
+                                                                                    asset, path)?),
+            (ParticleRender::Mesh { path }, None) => return Err(anyhow!("mesh {} is not loaded", path)),
+            _ => None,
+        };
+
+        let pipeline = match Self::create_pipeline(context, forward_render, self.set_layout, effect, mesh.is_some()) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                if let Some(mesh) = mesh.as_mut() {
+                    mesh.destroy(context);
+                }
+                return Err(e.into());
+            }
+        };
+
+        let params = Buffer::create_host_visible_buffer(context, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                        &[GpuEffect::from(effect, capacity)]);
+
+        Ok(EffectResources {
+            effect: effect.clone(),
+            capacity,
+            params,
+            mesh,
+            pipeline,
+        })
+    }
+
+    fn create_emitter(&self, context: &RenderContext, textures: &TextureCache, forward_render: &ForwardRenderPass,
+                      handle: &Handle<ParticleEffect>, resources: &EffectResources) -> EmitterInstance {
+        let capacity = resources.capacity;
+        let particles = Buffer::create(context, capacity as u64 * PARTICLE_SIZE, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                       vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let sort_entries = Buffer::create(context, sort_entry_count(capacity) as u64 * SORT_ENTRY_SIZE,
+                                          vk::BufferUsageFlags::STORAGE_BUFFER, vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let descriptor_set = util::create_descriptor_set(context, self.set_layout);
+
+        let buffer_info = |buffer: &Buffer| [vk::DescriptorBufferInfo::builder()
+            .buffer(buffer.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+        let particles_info = buffer_info(&particles);
+        let sort_info = buffer_info(&sort_entries);
+        let effect_info = buffer_info(&resources.params);
+
+        // untextured effects and textures that failed to load draw white
+        let texture = match textures.texture(&resources.effect.texture) {
+            Some(texture) => &texture.texture,
+            None => &context.get_resource::<DummyResources>().white_texture,
+        };
+        let texture_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(texture.view)
+            .sampler(texture.sampler)
+            .build()];
+
+        let storage_write = |binding: u32, info: &[vk::DescriptorBufferInfo]| vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(binding)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(info)
+            .build();
+        let writes = [
+            storage_write(0, &particles_info),
+            storage_write(1, &sort_info),
+            storage_write(2, &effect_info),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(4)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&texture_info)
+                .build(),
+        ];
+        unsafe {
+            context.device.update_descriptor_sets(&writes, &[]);
+        }
+        self.write_depth(context, descriptor_set, forward_render);
+
+        EmitterInstance {
+            effect: handle.clone_weak(),
+            particles,
+            sort_entries,
+            descriptor_set,
+            capacity,
+            spawn_cursor: 0,
+            spawn_accumulator: 0.0,
+            was_emitting: false,
+            simulated: false,
+        }
+    }
+
+    /// Builds the device objects of new emitters and of the effects they use once the
+    /// asset loaded, and retires the ones of removed emitters or emitters whose effect
+    /// handle changed.
+    pub fn prepare<'a>(&mut self, context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                       forward_render: &ForwardRenderPass, textures: &TextureCache,
+                       effects: &Assets<ParticleEffect>, meshes: &Assets<GltfAsset>,
+                       emitters: impl Iterator<Item=(Entity, &'a ParticleEmitter)>) {
+        let emitters = emitters.collect::<Vec<_>>();
+        let live = emitters.iter()
+            .map(|(entity, emitter)| (*entity, &emitter.effect))
+            .collect::<HashMap<_, _>>();
+        let retired = self.emitters.iter()
+            .filter(|(entity, instance)| live.get(entity) != Some(&&instance.effect))
+            .map(|(entity, _)| *entity)
+            .collect::<Vec<_>>();
+        for entity in retired {
+            let instance = self.emitters.remove(&entity).unwrap();
+            context.defer_destroy(&format!("particle emitter {:?}", entity), instance);
+        }
+
+        for (entity, emitter) in emitters {
+            if self.emitters.contains_key(&entity) || self.emitters.len() >= MAX_PARTICLE_EMITTERS
+                || self.failed.contains(&emitter.effect) {
+                continue;
+            }
+            let effect = match effects.get(&emitter.effect) {
+                Some(effect) => effect,
+                None => continue,
+            };
+            // textured effects wait for the texture cache, failed textures draw white
+            if !effect.texture.is_empty() && textures.texture(&effect.texture).is_none()
+                && !textures.is_failed(&effect.texture) {
+                continue;
+            }
+
+            if !self.effects.contains_key(&emitter.effect) {
+                // mesh effects wait for the asset server to load their mesh
+                let mesh = effect.mesh.as_ref().and_then(|handle| meshes.get(handle));
+                if effect.mesh.is_some() && mesh.is_none() {
+                    continue;
+                }
+                match self.create_effect(context, upload_command_buffer, forward_render, effect, mesh) {
+                    Ok(resources) => {
+                        self.effects.insert(emitter.effect.clone_weak(), resources);
+                    }
+                    Err(e) => {
+                        error!("failed to create particle effect {:?}: {}", emitter.effect.id, e);
+                        self.failed.insert(emitter.effect.clone_weak());
+                        continue;
+                    }
+                }
+            }
+
+            let instance = self.create_emitter(context, textures, forward_render, &emitter.effect, &self.effects[&emitter.effect]);
+            self.emitters.insert(entity, instance);
+        }
+    }
+
+    fn cmd_compute_barrier(context: &RenderContext, command_buffer: vk::CommandBuffer, dst_stage: vk::PipelineStageFlags) {
+        let barriers = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, dst_stage,
+                                                vk::DependencyFlags::empty(), &barriers, &[], &[]);
+        }
+    }
+
+    /// Spawns, moves and sorts the particles of every prepared emitter. Call before the
+    /// scene passes of the frame. `main_view` is the main camera of the primary window,
+    /// its depth is collided against the next frame.
+    pub fn simulate<'a>(&mut self, context: &RenderContext, command_buffer: vk::CommandBuffer,
+                        forward_render: &ForwardRenderPass, main_view: Option<&RenderView>, delta_time: f32,
+                        emitters: impl Iterator<Item=(Entity, &'a ParticleEmitter, &'a GlobalTransform)>) {
+        let depth_view = self.depth_view.take();
+        self.depth_view = main_view.map(|view| (view.view_proj, view.rect));
+        self.frame = self.frame.wrapping_add(1);
+
+        let mut updates = Vec::new();
+        for (entity, emitter, transform) in emitters {
+            let instance = match self.emitters.get_mut(&entity) {
+                Some(instance) => instance,
+                None => continue,
+            };
+            let resources = &self.effects[&instance.effect];
+            let (spawn_start, spawn_count) = instance.take_spawn(&resources.effect, emitter.emitting, delta_time);
+
+            let mut flags = 0;
+            if depth_view.is_some() && resources.effect.collision.is_some() {
+                flags |= FLAG_DEPTH;
+            }
+            if context.render_config.reverse_z {
+                flags |= FLAG_REVERSE_Z;
+            }
+            if resources.effect.sort {
+                flags |= FLAG_SORT;
+            }
+            if !instance.simulated {
+                flags |= FLAG_RESET;
+                instance.simulated = true;
+            }
+
+            let (depth_view_proj, depth_rect) = depth_view
+                .map(|(view_proj, rect)| (view_proj, Vec4::new(rect.offset.x as f32, rect.offset.y as f32,
+                                                               rect.extent.width as f32, rect.extent.height as f32)))
+                .unwrap_or((Mat4::IDENTITY, Vec4::ZERO));
+            let constant = UpdateConstant {
+                depth_view_proj,
+                depth_rect,
+                position: Vec4::from((transform.translation, delta_time)),
+                rotation: transform.rotation,
+                spawn_start,
+                spawn_count,
+                seed: self.frame.wrapping_mul(0x9E37_79B9) ^ entity.id(),
+                flags,
+            };
+            updates.push((entity, constant));
+        }
+        if updates.is_empty() {
+            return;
+        }
+
+        let device = &context.device;
+        let layout = self.compute.pipeline_layout;
+        let frame_set = context.per_frame_uniform.as_ref().unwrap().descriptor_set;
+        unsafe {
+            // the previous frame may still draw the particles this frame overwrites
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::VERTEX_SHADER,
+                                        vk::PipelineStageFlags::COMPUTE_SHADER, vk::DependencyFlags::empty(),
+                                        &[], &[], &[]);
+        }
+        let collide = updates.iter().any(|(_, constant)| constant.flags & FLAG_DEPTH != 0);
+        if collide {
+            forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, true);
+        }
+
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.compute.update_pipeline);
+            for (entity, constant) in &updates {
+                let instance = &self.emitters[entity];
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, layout, 0,
+                                                &[frame_set, instance.descriptor_set], &[]);
+                device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                          util::any_as_u8_slice(constant));
+                device.cmd_dispatch(command_buffer, (instance.sort_count() + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1);
+            }
+        }
+        Self::cmd_compute_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER);
+
+        if collide {
+            forward_render.cmd_depth_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, false);
+        }
+
+        let sorted = updates.iter()
+            .map(|(entity, _)| &self.emitters[entity])
+            .filter(|instance| self.effects[&instance.effect].effect.sort)
+            .collect::<Vec<_>>();
+        let max_sort_count = sorted.iter().map(|instance| instance.sort_count()).max().unwrap_or(0);
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.compute.sort_pipeline);
+        }
+        let mut block = 2;
+        while block <= max_sort_count {
+            let mut step = block / 2;
+            while step > 0 {
+                let constant = SortConstant { block, step };
+                for instance in sorted.iter().filter(|instance| instance.sort_count() >= block) {
+                    unsafe {
+                        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, layout, 0,
+                                                        &[frame_set, instance.descriptor_set], &[]);
+                        device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                                  util::any_as_u8_slice(&constant));
+                        // one compare and swap per invocation
+                        let pairs = instance.sort_count() / 2;
+                        device.cmd_dispatch(command_buffer, (pairs + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1);
+                    }
+                }
+                Self::cmd_compute_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER);
+                step /= 2;
+            }
+            block *= 2;
+        }
+
+        Self::cmd_compute_barrier(context, command_buffer, vk::PipelineStageFlags::VERTEX_SHADER);
+    }
+
+    /// Draws the simulated emitters inside the scene pass of a view, after its opaque models.
+    pub fn draw(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, frame_descriptor_set: vk::DescriptorSet) {
+        let device = &context.device;
+        for instance in self.emitters.values().filter(|instance| instance.simulated) {
+            let resources = &self.effects[&instance.effect];
+            let pipeline = &resources.pipeline;
+            unsafe {
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_pipeline());
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.get_layout(), 0,
+                                                &[frame_descriptor_set, instance.descriptor_set], &[]);
+                // dead particles are collapsed by the vertex shader
+                match &resources.mesh {
+                    Some(mesh) => {
+                        device.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex_buffer.buffer], &[0]);
+                        device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer.buffer, 0, vk::IndexType::UINT32);
+                        device.cmd_draw_indexed(command_buffer, mesh.index_count, instance.capacity, 0, 0, 0);
+                    }
+                    None => device.cmd_draw(command_buffer, QUAD_VERTEX_COUNT, instance.capacity, 0, 0),
+                }
+            }
+        }
+    }
+}
+
+/// Rebuilds the emitters of changed effect, mesh and texture assets and builds new emitters with
+/// the upload command buffer of the frame.
+pub fn prepare_particle_emitters_system(mut runner: Option<ResMut<RenderRunner>>,
+                                        asset_server: Res<AssetServer>,
+                                        effects: Res<Assets<ParticleEffect>>,
+                                        meshes: Res<Assets<GltfAsset>>,
+                                        mut effect_events: EventReader<AssetEvent<ParticleEffect>>,
+                                        mut mesh_events: EventReader<AssetEvent<GltfAsset>>,
+                                        query: Query<(Entity, &ParticleEmitter)>) {
+    if let Some(runner) = &mut runner {
+        let runner: &mut RenderRunner = runner.deref_mut();
+        let command_buffer = runner.get_upload_command_buffer();
+        for event in effect_events.iter() {
+            match event {
+                AssetEvent::Created { .. } => {}
+                AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                    runner.particles.remove_effect(&mut runner.context, handle);
+                }
+            }
+        }
+        for event in mesh_events.iter() {
+            if let AssetEvent::Modified { handle } | AssetEvent::Removed { handle } = event {
+                runner.particles.remove_mesh(&mut runner.context, handle);
+            }
+        }
+        for path in runner.texture_cache.replaced() {
+            runner.particles.remove_texture(&mut runner.context, path);
+        }
+        for (_, emitter) in query.iter() {
+            if let Some(effect) = effects.get(&emitter.effect) {
+                runner.texture_cache.request_texture(&asset_server, &effect.texture);
+            }
+        }
+
+        runner.particles.prepare(&mut runner.context, command_buffer, &runner.forward_render_pass,
+                                 &runner.texture_cache, &effects, &meshes, query.iter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(capacity: u32) -> EmitterInstance {
+        EmitterInstance {
+            effect: Handle::default(),
+            particles: Buffer::default(),
+            sort_entries: Buffer::default(),
+            descriptor_set: vk::DescriptorSet::null(),
+            capacity,
+            spawn_cursor: 0,
+            spawn_accumulator: 0.0,
+            was_emitting: false,
+            simulated: false,
+        }
+    }
+
+    fn effect(rate: f32, burst: u32) -> ParticleEffect {
+        ParticleEffect {
+            rate,
+            burst,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fractional_rates_carry_over() {
+        let mut instance = instance(100);
+        let effect = effect(4.0, 0);
+        assert_eq!(instance.take_spawn(&effect, true, 0.375), (0, 1));
+        // half a particle left from the first frame
+        assert_eq!(instance.take_spawn(&effect, true, 0.375), (1, 2));
+        assert_eq!(instance.take_spawn(&effect, true, 0.0625), (3, 0));
+        assert_eq!(instance.take_spawn(&effect, true, 0.1875), (3, 1));
+    }
+
+    #[test]
+    fn stopping_drops_the_fraction() {
+        let mut instance = instance(100);
+        let effect = effect(10.0, 0);
+        assert_eq!(instance.take_spawn(&effect, true, 0.15), (0, 1));
+        assert_eq!(instance.take_spawn(&effect, false, 1.0), (1, 0));
+        assert_eq!(instance.take_spawn(&effect, true, 0.05), (1, 0));
+    }
+
+    #[test]
+    fn burst_on_each_start() {
+        let mut instance = instance(100);
+        let effect = effect(0.0, 5);
+        assert_eq!(instance.take_spawn(&effect, true, 0.1), (0, 5));
+        assert_eq!(instance.take_spawn(&effect, true, 0.1), (5, 0));
+        assert_eq!(instance.take_spawn(&effect, false, 0.1), (5, 0));
+        assert_eq!(instance.take_spawn(&effect, true, 0.1), (5, 5));
+    }
+
+    #[test]
+    fn spawns_wrap_around_the_capacity() {
+        let mut instance = instance(8);
+        // more than the capacity replaces every particle once
+        assert_eq!(instance.take_spawn(&effect(0.0, 20), true, 0.0), (0, 8));
+        assert_eq!(instance.spawn_cursor, 0);
+
+        let rate = effect(40.0, 0);
+        assert_eq!(instance.take_spawn(&rate, true, 0.125), (0, 5));
+        assert_eq!(instance.take_spawn(&rate, true, 0.125), (5, 5));
+        assert_eq!(instance.spawn_cursor, 2);
+    }
+}
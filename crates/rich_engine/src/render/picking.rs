@@ -0,0 +1,125 @@
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+use crate::core::destroy::Destroy;
+use crate::render::camera::{Camera, CameraTarget, Ray};
+use crate::render::gltf_asset_loader::GltfAsset;
+use crate::render::model_runtime::{ModelRuntime, ModelSkins};
+use crate::render::render_plugin::RenderCamera;
+use crate::render::render_runner::RenderRunner;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PickHit {
+    /// entity holding the `Handle<GltfAsset>`
+    pub entity: Entity,
+    /// node entity of the hit mesh, the model entity for bounds hits
+    pub node: Entity,
+    /// (primitive inside the mesh, triangle inside the primitive), `None` for bounds hits
+    pub triangle: Option<(usize, usize)>,
+    pub position: Vec3,
+    pub distance: f32,
+}
+
+/// Ray casts against the models uploaded by the renderer. Static meshes are tested
+/// with their triangle BVH, skinned models only with their bounds.
+#[derive(SystemParam)]
+pub struct Picking<'a> {
+    runner: Option<Res<'a, RenderRunner>>,
+    render_camera: Res<'a, RenderCamera>,
+    cameras: Query<'a, (&'static Camera, &'static Transform)>,
+    models: Query<'a, (Entity, &'static ModelRuntime, Option<&'static ModelSkins>, &'static Handle<GltfAsset>, &'static GlobalTransform),
+        Without<Destroy>>,
+    transforms: Query<'a, &'static GlobalTransform>,
+}
+
+impl<'a> Picking<'a> {
+    /// Ray of the render camera through the cursor, `None` when the cursor is outside
+    /// the window or the camera viewport.
+    pub fn cursor_ray(&self, window: &Window) -> Option<Ray> {
+        let (camera, transform) = self.cameras.get(self.render_camera.camera).ok()?;
+        if camera.target != CameraTarget::Window(window.id()) {
+            return None;
+        }
+
+        let cursor = window.cursor_position()?;
+        let window_size = Vec2::new(window.width(), window.height());
+        // bevy reports the cursor from the bottom left
+        let cursor = Vec2::new(cursor.x, window_size.y - cursor.y);
+
+        let viewport = &camera.viewport;
+        let viewport_pos = Vec2::new(viewport.x, viewport.y) * window_size;
+        let viewport_size = Vec2::new(viewport.width, viewport.height) * window_size;
+        let pos = cursor - viewport_pos;
+        if pos.x < 0.0 || pos.y < 0.0 || pos.x > viewport_size.x || pos.y > viewport_size.y {
+            return None;
+        }
+
+        Some(camera.viewport_to_ray(transform, pos, viewport_size))
+    }
+
+    /// Closest model hit by the ray.
+    pub fn cast_ray(&self, ray: &Ray) -> Option<PickHit> {
+        let runner = self.runner.as_ref()?;
+        let mut closest: Option<PickHit> = None;
+        let mut keep_closest = |hit: PickHit| {
+            if closest.map_or(true, |c| hit.distance < c.distance) {
+                closest = Some(hit);
+            }
+        };
+
+        for (entity, runtime, skins, handle, transform) in self.models.iter() {
+            let model = match runner.context.get_model(handle) {
+                Some(model_renderer) => model_renderer.get_model(),
+                None => continue,
+            };
+
+            if skins.is_some() {
+                let model_matrix = transform.compute_matrix();
+                let world_2_model = model_matrix.inverse();
+                let origin = world_2_model.transform_point3(ray.origin);
+                let direction = world_2_model.transform_vector3(ray.direction);
+                if let Some(t) = model.aabb().ray_distance(origin, direction) {
+                    let position = model_matrix.transform_point3(origin + direction * t);
+                    keep_closest(PickHit {
+                        entity,
+                        node: entity,
+                        triangle: None,
+                        position,
+                        distance: (position - ray.origin).length(),
+                    });
+                }
+                continue;
+            }
+
+            for model_node in runtime.get_nodes() {
+                let bvh = match model_node.node.mesh_index().and_then(|idx| model.get_meshes()[idx].bvh()) {
+                    Some(bvh) => bvh,
+                    None => continue,
+                };
+                let node_matrix = match self.transforms.get(model_node.entity) {
+                    Ok(t) => t.compute_matrix(),
+                    Err(_) => continue,
+                };
+
+                let world_2_node = node_matrix.inverse();
+                let origin = world_2_node.transform_point3(ray.origin);
+                let direction = world_2_node.transform_vector3(ray.direction);
+                if let Some(hit) = bvh.intersect(origin, direction) {
+                    let position = node_matrix.transform_point3(hit.position);
+                    keep_closest(PickHit {
+                        entity,
+                        node: model_node.entity,
+                        triangle: Some((hit.primitive, hit.triangle)),
+                        position,
+                        distance: (position - ray.origin).length(),
+                    });
+                }
+            }
+        }
+
+        closest
+    }
+
+    pub fn pick_cursor(&self, window: &Window) -> Option<PickHit> {
+        self.cursor_ray(window).and_then(|ray| self.cast_ray(&ray))
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::time::Instant;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::render_context::RenderContext;
+use crate::render::graphic_pipeline::{GraphicPipeline, PipelineVertexInputInfo, ShaderStages};
+use crate::render::render_error::{RenderError, RenderResult};
+
+/// Fixed function state of a pipeline, `PipelineVertexInputInfo` without the vertex input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderState {
+    pub cull_mode: vk::CullModeFlags,
+    pub polygon_mode: vk::PolygonMode,
+    pub additive_blend: bool,
+    pub alpha_blend: bool,
+    pub depth_test: bool,
+    pub depth_compare_op: vk::CompareOp,
+    pub color_attachments: usize,
+    pub msaa: vk::SampleCountFlags,
+    /// vertex only with the depth bias of the shadow pass, see `GraphicPipeline::create_vert_only`
+    pub shadow_caster: bool,
+}
+
+impl RenderState {
+    /// Opaque triangles culling back faces, with the depth test of the current config.
+    pub fn new(context: &RenderContext, msaa: vk::SampleCountFlags) -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            additive_blend: false,
+            alpha_blend: false,
+            depth_test: true,
+            depth_compare_op: context.render_config.depth_compare_op(),
+            color_attachments: 1,
+            msaa,
+            shadow_caster: false,
+        }
+    }
+
+    pub fn shadow_caster(context: &RenderContext) -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::NONE,
+            color_attachments: 0,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            shadow_caster: true,
+            ..Self::new(context, vk::SampleCountFlags::TYPE_1)
+        }
+    }
+
+    pub fn with_cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn with_polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    /// Disables the depth test like `PipelineVertexInputInfo::with_additive_blend`.
+    pub fn with_additive_blend(mut self, additive_blend: bool) -> Self {
+        self.additive_blend = additive_blend;
+        if additive_blend {
+            self.depth_test = false;
+        }
+        self
+    }
+
+    pub fn with_alpha_blend(mut self, alpha_blend: bool) -> Self {
+        self.alpha_blend = alpha_blend;
+        self
+    }
+
+    pub fn with_color_attachments(mut self, count: usize) -> Self {
+        self.color_attachments = count;
+        self
+    }
+}
+
+/// Everything a cached pipeline is built from. Pipelines with equal keys are shared.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub render_pass: vk::RenderPass,
+    pub vertex_shader: &'static str,
+    /// `None` for depth only pipelines
+    pub fragment_shader: Option<&'static str>,
+    /// sorted without duplicates, see `PipelineKey::new`
+    pub defines: Vec<String>,
+    /// binding and stride of each vertex binding
+    pub vertex_bindings: Vec<(u32, u32)>,
+    /// location, binding, format and offset of each vertex attribute
+    pub vertex_attributes: Vec<(u32, u32, vk::Format, u32)>,
+    pub state: RenderState,
+    pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    /// stages, offset and size of each push constant range
+    pub push_constant_ranges: Vec<(vk::ShaderStageFlags, u32, u32)>,
+}
+
+impl PipelineKey {
+    pub fn new(render_pass: vk::RenderPass, vertex_shader: &'static str, fragment_shader: Option<&'static str>,
+               defines: &[&str], bindings: &[vk::VertexInputBindingDescription],
+               attributes: &[vk::VertexInputAttributeDescription], state: RenderState,
+               set_layouts: &[vk::DescriptorSetLayout], push_constant_ranges: &[vk::PushConstantRange]) -> Self {
+        let mut defines = defines.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        defines.sort();
+        defines.dedup();
+        Self {
+            render_pass,
+            vertex_shader,
+            fragment_shader,
+            defines,
+            vertex_bindings: bindings.iter().map(|b| (b.binding, b.stride)).collect(),
+            vertex_attributes: attributes.iter().map(|a| (a.location, a.binding, a.format, a.offset)).collect(),
+            state,
+            set_layouts: set_layouts.to_vec(),
+            push_constant_ranges: push_constant_ranges.iter().map(|r| (r.stage_flags, r.offset, r.size)).collect(),
+        }
+    }
+}
+
+/// Handles of a pipeline owned by the `PipelineCache`, valid until its key is released.
+#[derive(Clone, Copy, Debug)]
+pub struct CachedPipeline {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+}
+
+impl CachedPipeline {
+    pub fn get_pipeline(&self) -> vk::Pipeline {
+        self.pipeline
+    }
+
+    pub fn get_layout(&self) -> vk::PipelineLayout {
+        self.pipeline_layout
+    }
+}
+
+struct CacheEntry {
+    pipeline: GraphicPipeline,
+    users: usize,
+}
+
+/// Model pipelines shared across primitives and models, counted by their users. A
+/// pipeline is destroyed when its last user releases it, so pipelines built against
+/// render passes that were recreated go away with the models rebuilding theirs.
+/// Descriptor set layouts of equal bindings are shared as well and live as long as
+/// the cache.
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<PipelineKey, CacheEntry>,
+    set_layouts: HashMap<Vec<(u32, vk::DescriptorType, vk::ShaderStageFlags)>, vk::DescriptorSetLayout>,
+}
+
+impl PipelineCache {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        if !self.pipelines.is_empty() {
+            warn!("{} cached pipelines still in use", self.pipelines.len());
+        }
+        for (_, mut entry) in self.pipelines.drain() {
+            entry.pipeline.destroy(context);
+        }
+        for (_, layout) in self.set_layouts.drain() {
+            unsafe {
+                context.device.destroy_descriptor_set_layout(layout, None);
+            }
+        }
+    }
+
+    /// Returns the shared layout of `bindings`, each with a descriptor count of 1.
+    pub fn get_set_layout(&mut self, device: &ash::Device,
+                          bindings: &[vk::DescriptorSetLayoutBinding]) -> RenderResult<vk::DescriptorSetLayout> {
+        let mut key = bindings.iter().map(|b| (b.binding, b.descriptor_type, b.stage_flags)).collect::<Vec<_>>();
+        key.sort_by_key(|b| b.0);
+        if let Some(layout) = self.set_layouts.get(&key) {
+            return Ok(*layout);
+        }
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings).build();
+        let layout = unsafe {
+            device.create_descriptor_set_layout(&layout_info, None)
+                .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?
+        };
+        self.set_layouts.insert(key, layout);
+        Ok(layout)
+    }
+
+    /// Returns the pipeline of `key`, creating it on first use. Every acquire is paired
+    /// with a `release` of the same key.
+    pub fn acquire(context: &mut RenderContext, key: &PipelineKey) -> RenderResult<CachedPipeline> {
+        if let Some(handles) = context.pipeline_cache.add_user(key) {
+            return Ok(handles);
+        }
+
+        let start = Instant::now();
+        let pipeline = Self::create_pipeline(context, key)?;
+        let cache = &mut context.pipeline_cache;
+        let handles = cache.insert(key.clone(), pipeline);
+        info!("pipeline {} / {} {:?} created in {:.2} ms, {} cached",
+              key.vertex_shader, key.fragment_shader.unwrap_or("-"), key.defines,
+              start.elapsed().as_secs_f64() * 1000.0, cache.pipelines.len());
+        Ok(handles)
+    }
+
+    /// Drops a user of the pipeline, the last one destroys it. Call once the frames
+    /// drawing with it are done.
+    pub fn release(context: &mut RenderContext, key: &PipelineKey) {
+        if let Some(mut pipeline) = context.pipeline_cache.remove_user(key) {
+            pipeline.destroy(context);
+        }
+    }
+
+    /// Counts a user of the pipeline of `key`, `None` when it is not cached.
+    fn add_user(&mut self, key: &PipelineKey) -> Option<CachedPipeline> {
+        let entry = self.pipelines.get_mut(key)?;
+        entry.users += 1;
+        Some(Self::handles(&entry.pipeline))
+    }
+
+    /// Caches a pipeline created for its first user.
+    fn insert(&mut self, key: PipelineKey, pipeline: GraphicPipeline) -> CachedPipeline {
+        let handles = Self::handles(&pipeline);
+        self.pipelines.insert(key, CacheEntry { pipeline, users: 1 });
+        handles
+    }
+
+    /// Drops a user of the pipeline of `key` and returns the pipeline once the last one
+    /// is gone, the caller destroys it.
+    fn remove_user(&mut self, key: &PipelineKey) -> Option<GraphicPipeline> {
+        let entry = self.pipelines.get_mut(key)?;
+        entry.users -= 1;
+        if entry.users > 0 {
+            return None;
+        }
+        self.pipelines.remove(key).map(|entry| entry.pipeline)
+    }
+
+    fn handles(pipeline: &GraphicPipeline) -> CachedPipeline {
+        CachedPipeline {
+            pipeline: pipeline.get_pipeline(),
+            pipeline_layout: pipeline.get_layout(),
+        }
+    }
+
+    fn create_pipeline(context: &mut RenderContext, key: &PipelineKey) -> RenderResult<GraphicPipeline> {
+        let bindings = key.vertex_bindings.iter().map(|(binding, stride)| {
+            vk::VertexInputBindingDescription::builder()
+                .binding(*binding)
+                .stride(*stride)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .build()
+        }).collect::<Vec<_>>();
+        let attributes = key.vertex_attributes.iter().map(|(location, binding, format, offset)| {
+            vk::VertexInputAttributeDescription::builder()
+                .location(*location)
+                .binding(*binding)
+                .format(*format)
+                .offset(*offset)
+                .build()
+        }).collect::<Vec<_>>();
+        let push_constant_ranges = key.push_constant_ranges.iter().map(|(stages, offset, size)| {
+            vk::PushConstantRange::builder().stage_flags(*stages).offset(*offset).size(*size).build()
+        }).collect::<Vec<_>>();
+        let layout_ci = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&key.set_layouts)
+            .push_constant_ranges(&push_constant_ranges)
+            .build();
+        let defines = key.defines.iter().map(|d| d.as_str()).collect::<Vec<_>>();
+
+        let state = &key.state;
+        let vertex_input = PipelineVertexInputInfo::from(&bindings, &attributes)
+            .with_cull_mode(state.cull_mode)
+            .with_polygon_mode(state.polygon_mode)
+            .with_additive_blend(state.additive_blend)
+            .with_alpha_blend(state.alpha_blend)
+            .with_depth_test(state.depth_test)
+            .with_depth_compare_op(state.depth_compare_op)
+            .with_color_attachments(state.color_attachments);
+
+        if state.shadow_caster {
+            return GraphicPipeline::create_vert_only(context, key.render_pass, &vertex_input, &layout_ci,
+                                                     state.msaa, key.vertex_shader, &defines);
+        }
+
+        let entry_point_name = CString::new("main").unwrap();
+        let stages = ShaderStages {
+            vert: Some(key.vertex_shader),
+            frag: key.fragment_shader,
+            tesc: None,
+            tese: None,
+        }.to_shader_stage_create_info_array(context, &defines, &entry_point_name)?;
+        GraphicPipeline::create_with_info(context, key.render_pass, &vertex_input, &layout_ci, state.msaa, &stages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> RenderState {
+        RenderState {
+            cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            additive_blend: false,
+            alpha_blend: false,
+            depth_test: true,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            color_attachments: 1,
+            msaa: vk::SampleCountFlags::TYPE_1,
+            shadow_caster: false,
+        }
+    }
+
+    fn key(defines: &[&str]) -> PipelineKey {
+        let bindings = [vk::VertexInputBindingDescription::builder().binding(0).stride(12).build()];
+        let attributes = [vk::VertexInputAttributeDescription::builder()
+            .location(0).binding(0).format(vk::Format::R32G32B32_SFLOAT).offset(0).build()];
+        let ranges = [vk::PushConstantRange::builder().stage_flags(vk::ShaderStageFlags::VERTEX).offset(0).size(64).build()];
+        PipelineKey::new(vk::RenderPass::null(), "pbr_vert", Some("pbr_frag"), defines, &bindings, &attributes,
+                         state(), &[], &ranges)
+    }
+
+    #[test]
+    fn defines_are_sorted_and_deduplicated() {
+        let expected = key(&["IN_NORMAL", "SKIN"]);
+        assert_eq!(expected.defines, vec!["IN_NORMAL".to_string(), "SKIN".to_string()]);
+        assert_eq!(key(&["SKIN", "IN_NORMAL", "SKIN"]), expected);
+        assert_ne!(key(&["SKIN"]), expected);
+
+        let mut cache = PipelineCache::default();
+        cache.insert(expected, GraphicPipeline::default());
+        assert!(cache.add_user(&key(&["SKIN", "IN_NORMAL"])).is_some());
+    }
+
+    #[test]
+    fn depth_compare_op_is_part_of_the_key() {
+        let reversed = PipelineKey {
+            state: RenderState { depth_compare_op: vk::CompareOp::GREATER_OR_EQUAL, ..state() },
+            ..key(&[])
+        };
+        assert_ne!(reversed, key(&[]));
+    }
+
+    #[test]
+    fn last_release_returns_the_pipeline() {
+        let mut cache = PipelineCache::default();
+        let key = key(&["SKIN"]);
+        assert!(cache.add_user(&key).is_none());
+        cache.insert(key.clone(), GraphicPipeline::default());
+        assert!(cache.add_user(&key).is_some());
+
+        assert!(cache.remove_user(&key).is_none());
+        assert!(cache.remove_user(&key).is_some());
+        assert!(cache.pipelines.is_empty());
+        // released keys are created again by the next user
+        assert!(cache.add_user(&key).is_none());
+        assert!(cache.remove_user(&key).is_none());
+    }
+}
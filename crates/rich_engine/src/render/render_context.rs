@@ -14,12 +14,21 @@ use std::cell::RefCell;
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use crate::render::gltf_asset_loader::GltfAsset;
 use crate::render::model_renderer::ModelRenderer;
+use crate::render::forward_render::ForwardRenderPass;
 use std::mem::size_of;
 use crate::render::shader_collection::ShaderCollection;
+use crate::render::shader_reflect::ShaderReflection;
 use crate::render::texture::Texture;
 use crate::render::model::ModelTexture;
+use crate::render::sampler_cache::{SamplerCache, SamplerDesc};
+use crate::render::pipeline_cache::PipelineCache;
 use crate::render::render_statistic::RenderStatistic;
+use crate::render::render_settings::{RenderSettings, ShadowQuality, DeviceSelection, DeviceType, DebugView, RenderPath, AmbientOcclusion};
+use crate::render::capabilities::{RenderCapabilities, FormatSupport};
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::deletion_queue::{DeletionQueue, DeferredDestroy};
 
+#[derive(Clone, Debug)]
 pub struct RenderConfig {
     pub msaa: vk::SampleCountFlags,
     pub apply_post_effect: bool,
@@ -27,6 +36,65 @@ pub struct RenderConfig {
     pub color_format: vk::Format,
     pub depth_format: vk::Format,
     pub shadow_map_dim: f32,
+    /// 1.0 disables anisotropic filtering, clamped to the device limit
+    pub max_anisotropy: f32,
+    /// `maxSamplerAnisotropy`, 1.0 when the feature is not enabled
+    pub supported_max_anisotropy: f32,
+    /// preferred mode, the swapchain falls back to FIFO
+    pub present_mode: vk::PresentModeKHR,
+    pub render_scale: f32,
+    /// sample counts usable for both color and depth targets
+    pub supported_msaa: vk::SampleCountFlags,
+    pub reverse_z: bool,
+    /// names textures and objects retired to the deletion queue for the validation layer
+    pub debug_object_names: bool,
+    pub debug_view: DebugView,
+    /// `fill_mode_non_solid` is enabled, needed by the wireframe view
+    pub supports_wireframe: bool,
+    /// fixed when the context is created
+    pub render_path: RenderPath,
+    pub ambient_occlusion: AmbientOcclusion,
+    pub temporal_aa: bool,
+}
+
+impl RenderConfig {
+    /// Takes the device independent part from the settings, MSAA is lowered
+    /// to the highest supported count.
+    pub fn apply_settings(&mut self, settings: &RenderSettings) {
+        let mut msaa = settings.msaa();
+        if self.render_path == RenderPath::Deferred {
+            // the G-buffer is single sampled
+            msaa = vk::SampleCountFlags::TYPE_1;
+        }
+        while msaa != vk::SampleCountFlags::TYPE_1 && !self.supported_msaa.contains(msaa) {
+            msaa = vk::SampleCountFlags::from_raw(msaa.as_raw() >> 1);
+        }
+
+        self.msaa = msaa;
+        self.apply_shadow = settings.shadow_quality != ShadowQuality::Off;
+        self.shadow_map_dim = settings.shadow_quality.shadow_map_dim();
+        self.present_mode = settings.present_mode.to_vk();
+        self.render_scale = settings.clamped_render_scale();
+        self.max_anisotropy = settings.anisotropy.max(1.0).min(self.supported_max_anisotropy);
+        self.reverse_z = settings.reverse_z;
+        self.debug_object_names = settings.debug_object_names;
+        self.ambient_occlusion = settings.ambient_occlusion;
+        self.temporal_aa = settings.temporal_aa;
+        self.debug_view = settings.debug_view;
+        if self.debug_view == DebugView::Wireframe && !self.supports_wireframe {
+            warn!("the device does not support wireframe rendering, debug view disabled");
+            self.debug_view = DebugView::Off;
+        }
+    }
+
+    /// Depth test of scene pipelines, the shadow pass always uses the standard depth.
+    pub fn depth_compare_op(&self) -> vk::CompareOp {
+        if self.reverse_z { vk::CompareOp::GREATER_OR_EQUAL } else { vk::CompareOp::LESS_OR_EQUAL }
+    }
+
+    pub fn clear_depth(&self) -> f32 {
+        if self.reverse_z { 0.0 } else { 1.0 }
+    }
 }
 
 #[repr(C)]
@@ -40,6 +108,20 @@ pub struct PerFrameData {
     pub camera_dir: Vec4,
     pub delta_time: f32,
     pub total_time: f32,
+    /// 0 when the ambient occlusion map was not computed for this camera
+    pub ao_strength: f32,
+    /// window target pixels to occlusion map texels
+    pub ao_scale: f32,
+    /// rgb and the maximum opacity, see `Fog`
+    pub fog_color: Vec4,
+    /// distance density, height density, height falloff and base height
+    pub fog_params: Vec4,
+    /// unjittered view projection of the previous frame, for motion vectors
+    pub prev_view_proj: Mat4,
+    /// projection jitter of this frame in xy and of the previous frame in zw, in NDC
+    pub jitter: Vec4,
+    /// `proj` without the jitter, for whatever is drawn after the temporal resolve
+    pub unjittered_proj: Mat4,
 }
 
 impl PerFrameData {
@@ -53,12 +135,25 @@ impl PerFrameData {
             camera_dir: Vec4::Z,
             delta_time: 0f32,
             total_time: 0f32,
+            ao_strength: 0f32,
+            ao_scale: 1f32,
+            fog_color: Vec4::ZERO,
+            fog_params: Vec4::ZERO,
+            prev_view_proj: Mat4::IDENTITY,
+            jitter: Vec4::ZERO,
+            unjittered_proj: Mat4::IDENTITY,
         }
     }
 }
 
+/// Skinned shaders bind the joint matrices with this layout, it is reflected from the
+/// skinned shadow vertex shader which declares only them after the frame data.
+const SKIN_LAYOUT_SHADER: &str = "pbr_shadow_vert";
+const SKIN_LAYOUT_SET: u32 = 1;
+
 pub struct SkinBufferMgr {
     pub descriptor_set_layout: vk::DescriptorSetLayout,
+    reflection: ShaderReflection,
 }
 
 impl SkinBufferMgr {
@@ -68,27 +163,34 @@ impl SkinBufferMgr {
         }
     }
 
-    pub fn create(device: &ash::Device) -> Self {
-        let mut bindings = [
-            vk::DescriptorSetLayoutBinding::builder()
-                .binding(0)
-                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::VERTEX)
-                .build()
-        ];
+    /// The joint matrices block, skinned shaders are checked against it by their reflection.
+    pub fn bindings(&self) -> Vec<vk::DescriptorSetLayoutBinding> {
+        self.reflection.set_layout_bindings(SKIN_LAYOUT_SET)
+    }
+
+    pub fn create(device: &ash::Device, shaders: &mut ShaderCollection) -> RenderResult<Self> {
+        let reflection = shaders.reflect(device, SKIN_LAYOUT_SHADER, &["SKIN"])?.clone();
+        let joints = reflection.bindings.iter().find(|b| b.set == SKIN_LAYOUT_SET && b.binding == 0);
+        if !matches!(joints, Some(b) if b.descriptor_type == vk::DescriptorType::UNIFORM_BUFFER) {
+            return Err(RenderError::ShaderInterface {
+                name: SKIN_LAYOUT_SHADER.to_string(),
+                message: format!("the joint matrices are not a uniform block at set {} binding 0", SKIN_LAYOUT_SET),
+            });
+        }
+        let bindings = reflection.set_layout_bindings(SKIN_LAYOUT_SET);
 
         let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
 
         let descriptor_set_layout = unsafe {
             device
                 .create_descriptor_set_layout(&layout_info, None)
-                .unwrap()
+                .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?
         };
 
-        Self {
-            descriptor_set_layout
-        }
+        Ok(Self {
+            descriptor_set_layout,
+            reflection,
+        })
     }
 }
 
@@ -100,6 +202,7 @@ pub trait RenderResource: 'static + Send + Sync {
 
 pub struct DummyResources {
     pub white_texture: ModelTexture,
+    pub normal_texture: ModelTexture,
 }
 
 impl RenderResource for DummyResources {
@@ -119,13 +222,19 @@ impl RenderResource for DummyResources {
 impl DummyResources {
     pub fn destroy(&mut self, context: &mut RenderContext) {
         self.white_texture.destroy(context);
+        self.normal_texture.destroy(context);
     }
 
-    pub fn create(context: &mut RenderContext, command_buffer: vk::CommandBuffer) -> Self {
-        let t = Texture::create_from_rgba(context, command_buffer, 1, 1, &[std::u8::MAX; 4]);
-        DummyResources {
-            white_texture: ModelTexture::from(context, t)
-        }
+    pub fn create(context: &mut RenderContext, command_buffer: vk::CommandBuffer) -> RenderResult<Self> {
+        let t = Texture::create_from_rgba(context, command_buffer, 1, 1, &[std::u8::MAX; 4])?;
+        let n = Texture::create_from_rgba(context, command_buffer, 1, 1, &[128, 128, std::u8::MAX, std::u8::MAX])?;
+        // a single level, the sampler is not anisotropic and outlives anisotropy changes, the
+        // descriptors of every pass bind it
+        let sampler_desc = SamplerDesc { use_mipmaps: false, ..Default::default() };
+        Ok(DummyResources {
+            white_texture: ModelTexture::from(context, t, &sampler_desc)?,
+            normal_texture: ModelTexture::from(context, n, &sampler_desc)?,
+        })
     }
 }
 
@@ -147,16 +256,22 @@ pub struct RenderContext {
     pub device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub swapchain_loader: ash::extensions::khr::Swapchain,
     pub graphics_queue_family_index: u32,
+    pub present_queue_family_index: u32,
     pub compute_queue_family_index: u32,
     pub render_config: RenderConfig,
     pub descriptor_pool: vk::DescriptorPool,
     staging_buffers: Vec<Buffer>,
     resources: HashMap<TypeId, Box<dyn RenderResource>>,
     models: HashMap<Handle<GltfAsset>, ModelRenderer>,
+    deletion_queue: DeletionQueue,
     pub per_frame_uniform: Option<UniformObject<PerFrameData>>,
     pub min_uniform_buffer_offset_alignment: u32,
     pub shader_modules: ShaderCollection,
     pub skin_buffer_mgr: SkinBufferMgr,
+    pub sampler_cache: SamplerCache,
+    /// model pipelines and material set layouts shared across models
+    pub pipeline_cache: PipelineCache,
+    pub capabilities: RenderCapabilities,
     #[cfg(feature = "statistic")]
     pub statistic: RenderStatistic,
 }
@@ -237,9 +352,14 @@ impl RenderContext {
             for (_, res) in models.iter_mut() {
                 (*res).destroy(self);
             }
-            let mut pf = std::mem::take(&mut self.per_frame_uniform);
-            let uo = pf.as_mut().unwrap();
-            uo.destroy(self);
+            self.destroy_all_retired();
+            let mut pipeline_cache = std::mem::take(&mut self.pipeline_cache);
+            pipeline_cache.destroy(self);
+            if let Some(mut uo) = self.per_frame_uniform.take() {
+                uo.destroy(self);
+            }
+
+            self.sampler_cache.destroy(&self.device);
 
             self.device.destroy_descriptor_pool(self.descriptor_pool, None);
             self.device.destroy_device(None);
@@ -262,12 +382,56 @@ impl RenderContext {
             let details = SwapchainSupportDetails::new(device, surface, surface_khr);
             !details.formats.is_empty() && !details.present_modes.is_empty()
         };
+        // anisotropy, pipeline statistics and wide lines are optional
         let features = unsafe { instance.get_physical_device_features(device) };
         graphics.is_some()
             && present.is_some()
             && extention_support
             && is_swapchain_adequate
-            && features.sampler_anisotropy == vk::TRUE
+            && features.tessellation_shader == vk::TRUE
+            && features.shader_clip_distance == vk::TRUE
+    }
+
+    /// Picks the suitable device matching `selection`, falls back to `Auto` with a warning.
+    fn select_physical_device(
+        instance: &ash::Instance,
+        surface: &ash::extensions::khr::Surface,
+        surface_khr: vk::SurfaceKHR,
+        devices: &[vk::PhysicalDevice],
+        selection: &DeviceSelection,
+    ) -> Option<usize> {
+        let suitable = devices.iter().enumerate()
+            .filter(|(_, device)| Self::is_device_suitable(instance, surface, surface_khr, **device))
+            .map(|(index, device)| {
+                let props = unsafe { instance.get_physical_device_properties(*device) };
+                (index, DeviceType::from_vk(props.device_type), RenderCapabilities::device_name(instance, *device))
+            })
+            .collect::<Vec<_>>();
+
+        let selected = match selection {
+            DeviceSelection::Auto => None,
+            DeviceSelection::Index(index) => suitable.iter().find(|(i, _, _)| i == index),
+            DeviceSelection::Name(name) => {
+                let name = name.to_lowercase();
+                suitable.iter().find(|(_, _, n)| n.to_lowercase().contains(&name))
+            }
+            DeviceSelection::Type(device_type) => suitable.iter().find(|(_, t, _)| *t == Some(*device_type)),
+        };
+        if let Some((index, _, _)) = selected {
+            return Some(*index);
+        }
+        if *selection != DeviceSelection::Auto {
+            warn!("no suitable device matches {:?}, selecting one automatically", selection);
+        }
+
+        let rank = |device_type: &Option<DeviceType>| match device_type {
+            Some(DeviceType::Discrete) => 0,
+            Some(DeviceType::Integrated) => 1,
+            Some(DeviceType::Virtual) => 2,
+            Some(DeviceType::Cpu) => 3,
+            None => 4,
+        };
+        suitable.iter().min_by_key(|(index, t, _)| (rank(t), *index)).map(|(index, _, _)| *index)
     }
 
     fn get_required_device_extensions() -> [&'static CStr; 2] {
@@ -352,16 +516,29 @@ impl RenderContext {
     }
 
 
-    pub unsafe fn create<W: HasRawWindowHandle>(window: &W, window_width: u32, window_height: u32) -> Self {
+    pub unsafe fn create<W: HasRawWindowHandle>(window: &W, window_width: u32, window_height: u32,
+                                                settings: &RenderSettings) -> RenderResult<Self> {
         let app_name = CString::new("RichRender").unwrap();
 
-        let layer_names = [CString::new("VK_LAYER_KHRONOS_validation").unwrap()];
-        let layers_names_raw: Vec<*const i8> = layer_names
-            .iter()
-            .map(|raw_name| raw_name.as_ptr())
-            .collect();
+        let entry = ash::Entry::new().map_err(|e| RenderError::Loading(e.to_string()))?;
 
-        let surface_extensions = ash_window::enumerate_required_extensions(window).unwrap();
+        // the validation layer is only installed with the sdk
+        let validation_layer = CString::new("VK_LAYER_KHRONOS_validation").unwrap();
+        let available_layers = entry.enumerate_instance_layer_properties()
+            .map_err(RenderError::vk("vkEnumerateInstanceLayerProperties"))?;
+        let has_validation = available_layers.iter()
+            .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == validation_layer.as_c_str());
+        if !has_validation {
+            warn!("{:?} is not available, validation is disabled", validation_layer);
+        }
+        let layers_names_raw: Vec<*const i8> = if has_validation {
+            vec![validation_layer.as_ptr()]
+        } else {
+            vec![]
+        };
+
+        let surface_extensions = ash_window::enumerate_required_extensions(window)
+            .map_err(RenderError::vk("vkEnumerateInstanceExtensionProperties"))?;
         let mut extension_names_raw = surface_extensions
             .iter()
             .map(|ext| ext.as_ptr())
@@ -381,10 +558,12 @@ impl RenderContext {
             .enabled_layer_names(&layers_names_raw)
             .enabled_extension_names(&extension_names_raw);
 
-        let entry = ash::Entry::new().unwrap();
         let instance: ash::Instance = entry
             .create_instance(&create_info, None)
-            .expect("Instance creation error");
+            .map_err(|e| match e {
+                ash::InstanceError::LoadError(missing) => RenderError::Loading(missing.join("; ")),
+                ash::InstanceError::VkError(result) => RenderError::vk("vkCreateInstance")(result),
+            })?;
 
         let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
             .message_severity(
@@ -398,34 +577,43 @@ impl RenderContext {
         let debug_utils_loader = ash::extensions::ext::DebugUtils::new(&entry, &instance);
         let debug_call_back = debug_utils_loader
             .create_debug_utils_messenger(&debug_info, None)
-            .unwrap();
-        let surface = ash_window::create_surface(&entry, &instance, window, None).unwrap();
+            .map_err(RenderError::vk("vkCreateDebugUtilsMessengerEXT"))?;
+        let surface = ash_window::create_surface(&entry, &instance, window, None)
+            .map_err(RenderError::vk("vkCreateSurfaceKHR"))?;
 
         let surface_loader = ash::extensions::khr::Surface::new(&entry, &instance);
 
         let devices = instance
             .enumerate_physical_devices()
-            .expect("Physical device error");
-
-        let physical_device = devices
-            .into_iter()
-            .find(|device| Self::is_device_suitable(&instance, &surface_loader, surface, *device))
-            .expect("No suitable physical device.");
+            .map_err(RenderError::vk("vkEnumeratePhysicalDevices"))?;
+
+        let selection = DeviceSelection::from_env().unwrap_or_else(|| settings.device.clone());
+        let device_index = Self::select_physical_device(&instance, &surface_loader, surface, &devices, &selection)
+            .ok_or(RenderError::NoSuitableDevice)?;
+        let physical_device = devices[device_index];
+        let capabilities = RenderCapabilities::query(&instance, physical_device, device_index,
+                                                     devices.iter().map(|d| RenderCapabilities::device_name(&instance, *d)).collect());
+        capabilities.log();
         let (graphics_index_o, present_index_o, compute_index_o, transfer_index_o) = Self::find_queue_families(&instance,
                                                                                              &surface_loader, surface, physical_device);
 
-        let graphics_index = graphics_index_o.expect("no graphic queue found");
-        let present_index = present_index_o.expect("no present queue found");
-        let compute_index = compute_index_o.expect("no compute queue found");
-        let transfer_index = transfer_index_o.expect("no transfer queue found");
+        let graphics_index = graphics_index_o.ok_or(RenderError::MissingQueue("graphics"))?;
+        let present_index = present_index_o.ok_or(RenderError::MissingQueue("present"))?;
+        let compute_index = compute_index_o.ok_or(RenderError::MissingQueue("compute"))?;
+        let transfer_index = transfer_index_o.ok_or(RenderError::MissingQueue("transfer"))?;
 
         let device_extension_names_raw = [ash::extensions::khr::Swapchain::name().as_ptr(),
             ash::extensions::khr::Maintenance1::name().as_ptr()];
+        let supported_features = instance.get_physical_device_features(physical_device);
         let features = vk::PhysicalDeviceFeatures {
             shader_clip_distance: 1,
             tessellation_shader: 1,
-            pipeline_statistics_query: Self::pipeline_statistic(),
-            sampler_anisotropy: 1,
+            pipeline_statistics_query: Self::pipeline_statistic() & supported_features.pipeline_statistics_query,
+            sampler_anisotropy: supported_features.sampler_anisotropy,
+            wide_lines: supported_features.wide_lines,
+            // compressed model textures fall back to RGBA when BC is missing
+            texture_compression_bc: supported_features.texture_compression_bc,
+            fill_mode_non_solid: supported_features.fill_mode_non_solid,
             ..Default::default()
         };
 
@@ -453,7 +641,7 @@ impl RenderContext {
 
         let device: ash::Device = instance
             .create_device(physical_device, &device_create_info, None)
-            .unwrap();
+            .map_err(RenderError::vk("vkCreateDevice"))?;
 
         let present_queue = device.get_device_queue(present_index, 0);
         let graphics_queue = device.get_device_queue(graphics_index, 0);
@@ -462,14 +650,6 @@ impl RenderContext {
         let device_memory_properties = instance.get_physical_device_memory_properties(physical_device);
         let swapchain_loader = ash::extensions::khr::Swapchain::new(&instance, &device);
 
-        let render_config = RenderConfig {
-            msaa: vk::SampleCountFlags::TYPE_1,
-            apply_post_effect: false,
-            apply_shadow: false,
-            color_format: vk::Format::B8G8R8A8_UNORM,
-            depth_format: vk::Format::D32_SFLOAT,
-            shadow_map_dim: 2048f32,
-        };
 
         //todo description size
         let pool_size = [
@@ -481,6 +661,14 @@ impl RenderContext {
                 ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
                 descriptor_count: 1000,
             },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 400,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 10,
+            },
         ];
 
         let descriptor_pool = device.create_descriptor_pool(
@@ -488,7 +676,7 @@ impl RenderContext {
                 .max_sets(2000)
                 .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
                 .pool_sizes(&pool_size).build(), None,
-        ).expect("create descriptor pool failed");
+        ).map_err(RenderError::vk("vkCreateDescriptorPool"))?;
 
 
         let props = unsafe {
@@ -497,14 +685,37 @@ impl RenderContext {
         };
         let min_uniform_buffer_offset_alignment = props.limits.min_uniform_buffer_offset_alignment as u32;
 
-        let skin_buffer_mgr = SkinBufferMgr::create(&device);
+        let mut render_config = RenderConfig {
+            msaa: vk::SampleCountFlags::TYPE_1,
+            apply_post_effect: false,
+            apply_shadow: false,
+            color_format: vk::Format::B8G8R8A8_UNORM,
+            depth_format: vk::Format::D32_SFLOAT,
+            shadow_map_dim: 2048f32,
+            max_anisotropy: 16f32.min(capabilities.max_sampler_anisotropy),
+            supported_max_anisotropy: capabilities.max_sampler_anisotropy,
+            present_mode: vk::PresentModeKHR::FIFO,
+            render_scale: 1.0,
+            supported_msaa: props.limits.framebuffer_color_sample_counts & props.limits.framebuffer_depth_sample_counts,
+            reverse_z: false,
+            debug_object_names: false,
+            debug_view: DebugView::Off,
+            supports_wireframe: capabilities.fill_mode_non_solid,
+            render_path: settings.render_path,
+            ambient_occlusion: AmbientOcclusion::Off,
+            temporal_aa: false,
+        };
+        render_config.apply_settings(settings);
+        let sampler_cache = SamplerCache::create(render_config.max_anisotropy);
+
+        let mut collection = ShaderCollection::create();
 
-        let collection = ShaderCollection::create();
+        let skin_buffer_mgr = SkinBufferMgr::create(&device, &mut collection)?;
 
         #[cfg(feature = "statistic")]
-            let statistic = RenderStatistic::create(&device);
+            let statistic = RenderStatistic::create(&device, features.pipeline_statistics_query == vk::TRUE);
 
-        RenderContext {
+        Ok(RenderContext {
             window_width,
             window_height,
             entry,
@@ -522,6 +733,7 @@ impl RenderContext {
             surface,
             debug_call_back,
             graphics_queue_family_index: graphics_index,
+            present_queue_family_index: present_index,
             compute_queue_family_index: compute_index,
             render_config,
             descriptor_pool,
@@ -529,12 +741,16 @@ impl RenderContext {
             resources: HashMap::new(),
             per_frame_uniform: None,
             models: HashMap::new(),
+            deletion_queue: DeletionQueue::default(),
             min_uniform_buffer_offset_alignment,
             skin_buffer_mgr,
+            sampler_cache,
+            pipeline_cache: PipelineCache::default(),
             shader_modules: collection,
+            capabilities,
             #[cfg(feature = "statistic")]
             statistic,
-        }
+        })
     }
 
     pub fn find_memory_type_index(
@@ -552,6 +768,25 @@ impl RenderContext {
             .map(|(index, _memory_type)| index as _)
     }
 
+    /// Format queries of the device, usable from worker threads.
+    pub fn format_support(&self) -> FormatSupport {
+        FormatSupport::new(&self.instance, self.physical_device)
+    }
+
+    /// Size of the scene render targets, the window size scaled by the render scale.
+    pub fn render_extent(&self) -> vk::Extent2D {
+        self.scaled_extent(self.window_width, self.window_height)
+    }
+
+    /// Scene extent for a window of the given size.
+    pub fn scaled_extent(&self, window_width: u32, window_height: u32) -> vk::Extent2D {
+        let scale = self.render_config.render_scale;
+        vk::Extent2D {
+            width: ((window_width as f32 * scale) as u32).max(1),
+            height: ((window_height as f32 * scale) as u32).max(1),
+        }
+    }
+
     pub fn push_staging_buffer(&mut self, buffer: Buffer) {
         self.staging_buffers.push(buffer);
     }
@@ -589,9 +824,10 @@ impl RenderContext {
         self.models.insert(handle, model);
     }
 
+    /// The model is destroyed once the frames in flight that may draw it have retired.
     pub fn remove_model(&mut self, handle: &Handle<GltfAsset>) {
-        if let Some(mut m) = self.models.remove(handle) {
-            m.destroy(self);
+        if let Some(m) = self.models.remove(handle) {
+            self.defer_destroy(&format!("model {:?}", handle.id), m);
         }
     }
 
@@ -599,6 +835,73 @@ impl RenderContext {
         self.models.get(&handle)
     }
 
+    /// Rebuilds the pipelines and descriptors of every model against new render targets.
+    /// Models whose pipelines fail are removed, the last error is returned.
+    pub fn recreate_model_pipelines(&mut self, render_pass: &ForwardRenderPass) -> RenderResult<()> {
+        let mut models = mem::take(&mut self.models);
+        // every pipeline is released before any is created again, cached pipelines keyed
+        // by destroyed render passes could otherwise match new passes reusing the handles
+        for model in models.values_mut() {
+            model.release_pipelines(self);
+        }
+        let mut result = Ok(());
+        models.retain(|_, model| match model.recreate_pipelines(self, render_pass) {
+            Ok(()) => true,
+            Err(e) => {
+                model.destroy(self);
+                result = Err(e);
+                false
+            }
+        });
+        self.models = models;
+        result
+    }
+
+    /// Queues the object for destruction after the frames currently in flight.
+    pub fn defer_destroy<T: DeferredDestroy>(&mut self, label: &str, object: T) {
+        let label = format!("{} (retired at frame {})", label, self.deletion_queue.frame());
+        if self.render_config.debug_object_names {
+            for (object_type, handle) in object.debug_objects() {
+                self.set_object_name(object_type, handle, &label);
+            }
+        }
+        self.deletion_queue.push(label, Box::new(object));
+    }
+
+    /// Starts a new frame, destroying the objects retired `frames_in_flight` frames ago.
+    /// Called once the fence of the frame being reused has been waited for.
+    pub fn destroy_retired(&mut self, frames_in_flight: usize) {
+        self.deletion_queue.next_frame();
+        let retired = self.deletion_queue.take_retired(frames_in_flight as u64);
+        for (label, mut object) in retired {
+            debug!("destroy {}", label);
+            object.destroy_deferred(self);
+        }
+    }
+
+    /// Destroys every queued object, only valid while the device is idle.
+    pub fn destroy_all_retired(&mut self) {
+        for (_, mut object) in self.deletion_queue.take_all() {
+            object.destroy_deferred(self);
+        }
+    }
+
+    pub fn pending_destroy_count(&self) -> usize {
+        self.deletion_queue.len()
+    }
+
+    /// Attaches a name shown by the validation layer, ignored when it fails.
+    pub fn set_object_name(&self, object_type: vk::ObjectType, handle: u64, name: &str) {
+        let name = CString::new(name.replace('\0', "")).unwrap();
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(object_type)
+            .object_handle(handle)
+            .object_name(&name);
+        unsafe {
+            let _ = self.debug_utils_loader.debug_utils_set_object_name(self.device.handle(), &name_info);
+        }
+    }
+
     pub fn get_ubo_alignment<T>(&self) -> u32 {
         let min_alignment = self.min_uniform_buffer_offset_alignment;
         let t_size = size_of::<T>() as u32;
@@ -0,0 +1,51 @@
+use std::fmt;
+use ash::vk;
+
+pub type RenderResult<T> = Result<T, RenderError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderError {
+    /// the vulkan loader library could not be loaded
+    Loading(String),
+    NoSuitableDevice,
+    MissingQueue(&'static str),
+    ShaderCompile { name: String, message: String },
+    /// the resources or vertex inputs of shader `name` don't match what they are bound to
+    ShaderInterface { name: String, message: String },
+    OutOfMemory,
+    DeviceLost,
+    /// any other failed vulkan call, `call` names it
+    Vulkan { call: &'static str, result: vk::Result },
+}
+
+impl RenderError {
+    /// Maps the error of the vulkan call `call`, for use with `map_err`.
+    pub fn vk(call: &'static str) -> impl Fn(vk::Result) -> RenderError {
+        move |result| match result {
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => RenderError::OutOfMemory,
+            vk::Result::ERROR_DEVICE_LOST => RenderError::DeviceLost,
+            result => RenderError::Vulkan { call, result },
+        }
+    }
+
+    pub fn is_device_lost(&self) -> bool {
+        *self == RenderError::DeviceLost
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Loading(message) => write!(f, "failed to load vulkan: {}", message),
+            RenderError::NoSuitableDevice => write!(f, "no suitable vulkan device found"),
+            RenderError::MissingQueue(queue) => write!(f, "the device has no {} queue", queue),
+            RenderError::ShaderCompile { name, message } => write!(f, "failed to compile shader {}: {}", name, message),
+            RenderError::ShaderInterface { name, message } => write!(f, "shader {} does not match its bindings: {}", name, message),
+            RenderError::OutOfMemory => write!(f, "out of memory"),
+            RenderError::DeviceLost => write!(f, "the vulkan device was lost"),
+            RenderError::Vulkan { call, result } => write!(f, "{} failed: {}", call, result),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
@@ -1,71 +1,222 @@
 use bevy::prelude::*;
 use bevy::app::{ManualEventReader, Events};
-use bevy::window::{WindowCreated, WindowResized, WindowId};
+use bevy::window::{WindowCloseRequested, WindowCreated, WindowResized, WindowId};
 use bevy::winit::WinitWindows;
 use crate::render::swapchain_mgr::SwapChainMgr;
 use crate::render::render_runner::RenderRunner;
-use crate::render::graphic_pipeline::{PipelineVertexInputInfo, GraphicPipeline};
+use crate::render::graphic_pipeline::{PipelineVertexInputInfo, GraphicPipeline, set_flipped_viewport_rect};
 use ash::vk;
 use crate::render::{CameraOpEvent, RenderStage, vertex};
-use crate::render::camera::Camera;
+use crate::render::camera::{Camera, CameraTarget, Fog};
 use crate::render::fly_camera::{FlyCamera, FlyCameraPlugin};
 use crate::render::render_context::PerFrameData;
 use crate::render::gltf_asset_loader::{GltfAsset, GltfAssetLoader};
 use std::collections::HashSet;
-use crate::render::model_renderer::{ModelRenderer, ModelData, ShadeNames};
-use std::ops::DerefMut;
+use crate::render::model_renderer::{ModelData, DrawPass};
+use crate::render::deferred_render::PointLight;
+use crate::render::decal::{self, Decal};
+use crate::render::particles::{self, ParticleEmitter};
+use crate::render::billboard;
+use crate::render::hud::{self, HudAtlas, HudAtlasLoader};
+use crate::render::font::{GlyphAtlas, GlyphAtlasLoader};
+use crate::render::texture_cache::{self, ImageAsset, ImageAssetLoader};
+use crate::render::taa::jitter_offset;
+use crate::render::particle_effect::{ParticleEffect, ParticleEffectLoader};
+use crate::render::environment::{Environment, EnvironmentMap, EnvironmentMapLoader};
+use std::ops::{Deref, DerefMut};
 use std::sync::Mutex;
 use bevy::math::Vec4Swizzles;
-use bevy::tasks::ComputeTaskPool;
+use bevy::tasks::{ComputeTaskPool, AsyncComputeTaskPool};
 use bevy::transform::TransformSystem;
 use crate::core::destroy::{Destroy, DestroyStage};
 use crate::DisplayName;
 use super::animation_system;
 use crate::render::model_runtime;
-use crate::render::model_runtime::{ModelRuntime, ModelSkins};
+use crate::render::model_runtime::{ModelRuntime, ModelSkins, MorphWeights};
+use crate::render::render_settings::{RenderSettings, RenderTargetsRecreatedEvent, RENDER_SETTINGS_PATH};
+use crate::render::render_error::RenderError;
+use crate::render::model_upload::{self, ModelUploads};
 
+/// Sent when the renderer is created, and again when it was recreated after the device
+/// was lost. Device objects created from the previous context are invalid then.
 pub struct RenderInitEvent {}
 
+/// Sent instead of `RenderInitEvent` when the renderer can not be created, there is no
+/// `RenderRunner` resource afterwards.
+#[derive(Debug, Clone)]
+pub struct RenderInitFailed {
+    pub error: RenderError,
+}
+
 pub struct MainLight {}
 
 
 struct RenderMgr {
     window_created_event_reader: ManualEventReader<WindowCreated>,
     window_resized_event_reader: ManualEventReader<WindowResized>,
+    window_close_requested_event_reader: ManualEventReader<WindowCloseRequested>,
+    /// secondary windows hidden after a close request, bevy keeps them alive
+    closed_windows: HashSet<WindowId>,
 }
 
 
 impl RenderMgr {
     fn handle_window_created_event(&mut self, world: &mut World) {
-        let windows = world.get_resource::<Windows>().unwrap();
-        let window_created_events = world.get_resource::<Events<WindowCreated>>().unwrap();
-
-        let ww = {
-            self.window_created_event_reader.iter(&window_created_events).find_map(|window_created_event| {
-                let window = windows
-                    .get(window_created_event.id)
-                    .expect("Received window created event for non-existent window.");
-                if window.id() == WindowId::primary() {
-                    let winit_windows = world.get_resource::<WinitWindows>().unwrap();
-                    let winit_window = winit_windows.get_window(window.id()).unwrap();
-                    Some((window, winit_window))
-                } else {
-                    None
+        let mut created = {
+            let window_created_events = world.get_resource::<Events<WindowCreated>>().unwrap();
+            self.window_created_event_reader.iter(&window_created_events).map(|e| e.id).collect::<Vec<_>>()
+        };
+        // the primary window creates the render context the other windows share
+        created.sort_by_key(|id| !id.is_primary());
+
+        for id in created {
+            if id.is_primary() {
+                if Self::create_runner(world) {
+                    let mut fire = world.get_resource_mut::<Events<RenderInitEvent>>().unwrap();
+                    fire.send(RenderInitEvent {});
                 }
-            })
+            } else {
+                Self::add_window(world, id);
+            }
+        }
+    }
+
+    /// Creates the renderer for the primary window, sends `RenderInitFailed` on errors.
+    fn create_runner(world: &mut World) -> bool {
+        let id = WindowId::primary();
+        let windows = world.get_resource::<Windows>().unwrap();
+        let window = windows
+            .get(id)
+            .expect("Received window created event for non-existent window.");
+        let winit_windows = world.get_resource::<WinitWindows>().unwrap();
+        let winit_window = winit_windows.get_window(id).unwrap();
+
+        let settings = world.get_resource::<RenderSettings>().cloned().unwrap_or_default();
+        match RenderRunner::create(winit_window, window.physical_width(), window.physical_height(), &settings) {
+            Ok(render_runner) => {
+                world.insert_resource(render_runner.context.capabilities.clone());
+                world.insert_resource(render_runner);
+                true
+            }
+            Err(error) => {
+                error!("failed to create the renderer: {}", error);
+                let mut fire = world.get_resource_mut::<Events<RenderInitFailed>>().unwrap();
+                fire.send(RenderInitFailed { error });
+                false
+            }
+        }
+    }
+
+    fn add_window(world: &mut World, id: WindowId) {
+        if !world.contains_resource::<RenderRunner>() {
+            return;
+        }
+
+        world.resource_scope(|world, mut runner: Mut<RenderRunner>| {
+            let windows = world.get_resource::<Windows>().unwrap();
+            let window = windows
+                .get(id)
+                .expect("Received window created event for non-existent window.");
+            let winit_windows = world.get_resource::<WinitWindows>().unwrap();
+            let winit_window = winit_windows.get_window(id).unwrap();
+            runner.add_window(id, winit_window, window.physical_width(), window.physical_height());
+        });
+    }
+
+    /// Tears the renderer down after `VK_ERROR_DEVICE_LOST` and creates it again. Models
+    /// and skins are uploaded again, other device objects must be recreated on
+    /// `RenderInitEvent`.
+    fn recover_lost_device(&mut self, world: &mut World) {
+        let lost = world.get_resource::<RenderRunner>().map_or(false, |runner| runner.is_device_lost());
+        if !lost {
+            return;
+        }
+
+        warn!("the render device was lost, recreating the renderer");
+        drop(world.remove_resource::<RenderRunner>());
+        if !Self::create_runner(world) {
+            return;
+        }
+
+        let secondary = world.get_resource::<Windows>().unwrap().iter()
+            .map(|window| window.id())
+            .filter(|id| !id.is_primary() && !self.closed_windows.contains(id))
+            .collect::<Vec<_>>();
+        for id in secondary {
+            Self::add_window(world, id);
+        }
+
+        model_runtime::recreate_model_skins(world);
+        if let Some(mut uploads) = world.get_resource_mut::<ModelUploads>() {
+            uploads.clear();
+        }
+        let handles = world.get_resource::<Assets<GltfAsset>>()
+            .map(|assets| assets.iter().map(|(id, _)| Handle::<GltfAsset>::weak(id)).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if let Some(mut events) = world.get_resource_mut::<Events<AssetEvent<GltfAsset>>>() {
+            for handle in handles {
+                events.send(AssetEvent::Created { handle });
+            }
+        }
+
+        world.get_resource_mut::<Events<RenderInitEvent>>().unwrap().send(RenderInitEvent {});
+        world.get_resource_mut::<Events<RenderTargetsRecreatedEvent>>().unwrap().send(RenderTargetsRecreatedEvent {});
+    }
+
+    /// The primary swapchain is not resized, other windows rebuild their swapchain.
+    fn handle_window_resized_event(&mut self, world: &mut World) {
+        let resized = {
+            let window_resized_events = world.get_resource::<Events<WindowResized>>().unwrap();
+            self.window_resized_event_reader.iter(&window_resized_events)
+                .filter(|e| !e.id.is_primary())
+                .map(|e| e.id)
+                .collect::<HashSet<_>>()
         };
+        if resized.is_empty() || !world.contains_resource::<RenderRunner>() {
+            return;
+        }
 
-        if let Some((window, winit_window)) = ww {
-            let render_runner = RenderRunner::create(winit_window, window.physical_width(), window.physical_height());
-            world.insert_resource(render_runner);
+        world.resource_scope(|world, mut runner: Mut<RenderRunner>| {
+            let windows = world.get_resource::<Windows>().unwrap();
+            for id in resized {
+                if let Some(window) = windows.get(id) {
+                    if window.physical_width() > 0 && window.physical_height() > 0 {
+                        runner.resize_window(id, window.physical_width(), window.physical_height());
+                    }
+                }
+            }
+        });
+    }
 
-            let mut fire = world.get_resource_mut::<Events<RenderInitEvent>>().unwrap();
-            fire.send(RenderInitEvent {});
+    /// Closing the primary window exits the app. Windows can not be destroyed, the
+    /// renderer drops the surface of other windows and hides them.
+    fn handle_window_close_requested_event(&mut self, world: &mut World) {
+        let closed = {
+            let window_close_requested_events = world.get_resource::<Events<WindowCloseRequested>>().unwrap();
+            self.window_close_requested_event_reader.iter(&window_close_requested_events)
+                .filter(|e| !e.id.is_primary())
+                .map(|e| e.id)
+                .collect::<HashSet<_>>()
         };
+
+        for id in closed {
+            if !self.closed_windows.insert(id) {
+                continue;
+            }
+            if let Some(winit_window) = world.get_resource::<WinitWindows>().unwrap().get_window(id) {
+                winit_window.set_visible(false);
+            }
+            if let Some(mut runner) = world.get_resource_mut::<RenderRunner>() {
+                runner.remove_window(id);
+            }
+        }
     }
 
     pub fn update(&mut self, world: &mut World) {
+        self.recover_lost_device(world);
         self.handle_window_created_event(world);
+        self.handle_window_resized_event(world);
+        self.handle_window_close_requested_event(world);
     }
 }
 
@@ -74,6 +225,8 @@ fn get_render_system(world: &mut World) -> impl FnMut(&mut World) {
     let mut r = RenderMgr {
         window_created_event_reader: Default::default(),
         window_resized_event_reader: Default::default(),
+        window_close_requested_event_reader: Default::default(),
+        closed_windows: HashSet::new(),
     };
 
     move |pworld| {
@@ -83,7 +236,12 @@ fn get_render_system(world: &mut World) -> impl FnMut(&mut World) {
 
 
 fn draw_models_system(mut runner: Option<ResMut<RenderRunner>>,
+                      time: Res<Time>,
                       mut transform_query: Query<&GlobalTransform>,
+                      morph_query: Query<&MorphWeights>,
+                      light_query: Query<(&PointLight, &GlobalTransform)>,
+                      decal_query: Query<(&Decal, &GlobalTransform)>,
+                      emitter_query: Query<(Entity, &ParticleEmitter, &GlobalTransform)>,
                       mut model_query: Query<(&ModelRuntime, Option<&ModelSkins>, &Handle<GltfAsset>, &GlobalTransform),
                           Without<Destroy>>) {
     if let Some(runner) = &mut runner {
@@ -95,6 +253,9 @@ fn draw_models_system(mut runner: Option<ResMut<RenderRunner>>,
             #[cfg(feature = "statistic")]
                 context.statistic.begin_query(&context.device, command_buffer);
 
+            if let Some(deferred) = runner.forward_render_pass.get_deferred_mut() {
+                deferred.set_lights(context, light_query.iter());
+            }
             let forward_render_pass = &runner.forward_render_pass;
 
             if runner.grass.enable_draw {
@@ -103,30 +264,173 @@ fn draw_models_system(mut runner: Option<ResMut<RenderRunner>>,
                 runner.grass.cmd_barrier(context, command_buffer);
             }
 
-            //shadow
+            //shadow, the pass still runs when disabled to clear the map to unshadowed
             forward_render_pass.begin_shadow_pass(context, command_buffer);
 
+            let apply_shadow = context.render_config.apply_shadow;
             let mut list = Vec::new();
             for (runtime, skins, handle, transform) in model_query.iter_mut() {
                 let model_renderer = context.get_model(handle);
                 if let Some(mr) = model_renderer {
-                    mr.draw_shadow(context, command_buffer, &runtime, skins, &transform_query);
+                    if apply_shadow {
+                        mr.draw_shadow(context, command_buffer, &runtime, skins, &transform_query, &morph_query);
+                    }
                     list.push((handle, skins, transform, runtime));
                 }
             }
             forward_render_pass.end_shadow_pass(context, command_buffer);
 
-            //draw
-            forward_render_pass.begin_render_pass(context, command_buffer);
+            let views = runner.views.sorted_views();
+
+            let main_view = views.iter().copied().find(|v| v.is_main && v.window == Some(WindowId::primary()));
 
-            for (handle, skins, _, runtime) in list {
-                let mr = context.get_model(handle).unwrap();
-                mr.draw(context, command_buffer, &runtime, skins, &transform_query);
+            //ambient occlusion of the main camera, read by every model pipeline
+            if let Some(ssao) = forward_render_pass.get_ssao() {
+                if let Some(view) = main_view {
+                    ssao.begin_prepass(context, command_buffer, view.rect);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::DepthPrepass);
+                    }
+                    ssao.end_prepass(context, command_buffer);
+                    ssao.compute(context, command_buffer, view.frame_descriptor_set);
+                } else {
+                    ssao.clear(context, command_buffer);
+                }
             }
 
-            runner.grass.draw(context, command_buffer);
+            //screen motion of the main camera for temporal anti-aliasing
+            if let (Some(taa), Some(view)) = (forward_render_pass.get_taa(), main_view) {
+                if runner.motion_history.begin_frame(view.camera, view.rect.extent) {
+                    taa.reset_history();
+                }
+                taa.begin_velocity_pass(context, command_buffer, view.rect);
+                for (handle, skins, _, runtime) in &list {
+                    let mr = context.get_model(handle).unwrap();
+                    mr.draw_velocity(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query,
+                                     &morph_query, &mut runner.motion_history);
+                }
+                runner.grass.draw_velocity(context, command_buffer);
+                taa.end_velocity_pass(context, command_buffer);
+                runner.motion_history.end_frame();
+            }
 
-            forward_render_pass.end_render_pass(context, command_buffer);
+            //particles, a long frame would spawn them in one burst
+            let delta_time = time.delta_seconds().min(0.1);
+            runner.particles.simulate(context, command_buffer, forward_render_pass, main_view, delta_time, emitter_query.iter());
+
+            //cameras rendering to textures
+            for view in views.iter().filter(|v| v.offscreen) {
+                let target = match runner.views.get_target(view.camera) {
+                    Some(target) => target,
+                    None => continue,
+                };
+                forward_render_pass.begin_target_pass(context, command_buffer, target, view.clear_color);
+                set_flipped_viewport_rect(context, command_buffer, view.rect);
+                for (handle, skins, _, runtime) in &list {
+                    let mr = context.get_model(handle).unwrap();
+                    mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::Forward);
+                }
+                runner.particles.draw(context, command_buffer, view.frame_descriptor_set);
+                runner.billboards.draw(context, &runner.texture_cache, command_buffer, view.frame_descriptor_set);
+                forward_render_pass.end_render_pass(context, command_buffer);
+                target.cmd_barrier_for_sampling(context, command_buffer);
+            }
+
+            //cameras of other windows, presented after the main submission
+            for (id, window) in runner.windows.iter().filter(|(_, w)| w.is_acquired()) {
+                forward_render_pass.begin_target_pass(context, command_buffer, window.get_target(), Vec4::new(0.0, 0.0, 0.0, 1.0));
+                for view in views.iter().filter(|v| v.window == Some(*id)) {
+                    forward_render_pass.clear_view(context, command_buffer, view.rect, view.clear_color);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::Forward);
+                    }
+
+                    if view.is_main {
+                        runner.grass.draw(context, command_buffer);
+                    }
+                    runner.particles.draw(context, command_buffer, view.frame_descriptor_set);
+                    runner.billboards.draw(context, &runner.texture_cache, command_buffer, view.frame_descriptor_set);
+                }
+                forward_render_pass.end_render_pass(context, command_buffer);
+            }
+
+            //primary window cameras, later ones draw over earlier ones. Decals go between the
+            //opaque geometry and the blended one
+            let primary_views = views.iter().copied().filter(|v| v.window == Some(WindowId::primary())).collect::<Vec<_>>();
+            runner.decals.prepare(context, &runner.texture_cache, &primary_views, decal_query.iter());
+            if let Some(deferred) = forward_render_pass.get_deferred() {
+                deferred.begin_gbuffer_pass(context, command_buffer);
+                for view in &primary_views {
+                    deferred.clear_view(context, command_buffer, view.rect);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::GBuffer);
+                    }
+                }
+                deferred.end_pass(context, command_buffer);
+
+                deferred.begin_lighting_pass(context, command_buffer);
+                for view in &primary_views {
+                    deferred.draw_lighting(context, command_buffer, view.frame_descriptor_set, view.rect, view.clear_color);
+                }
+                deferred.end_pass(context, command_buffer);
+
+                runner.decals.draw(context, &runner.texture_cache, command_buffer, forward_render_pass, &primary_views, true);
+
+                deferred.begin_transparent_pass(context, command_buffer, forward_render_pass.get_target());
+                for view in &primary_views {
+                    set_flipped_viewport_rect(context, command_buffer, view.rect);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::Transparent);
+                    }
+
+                    if view.is_main {
+                        runner.grass.draw(context, command_buffer);
+                    }
+                    runner.particles.draw(context, command_buffer, view.frame_descriptor_set);
+                    runner.billboards.draw(context, &runner.texture_cache, command_buffer, view.frame_descriptor_set);
+                }
+                deferred.end_pass(context, command_buffer);
+            } else {
+                forward_render_pass.begin_render_pass(context, command_buffer);
+                for view in &primary_views {
+                    forward_render_pass.clear_view(context, command_buffer, view.rect, view.clear_color);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::Opaque);
+                    }
+                }
+                forward_render_pass.end_render_pass(context, command_buffer);
+
+                runner.decals.draw(context, &runner.texture_cache, command_buffer, forward_render_pass, &primary_views, false);
+
+                forward_render_pass.begin_transparent_pass(context, command_buffer);
+                for view in &primary_views {
+                    set_flipped_viewport_rect(context, command_buffer, view.rect);
+                    for (handle, skins, _, runtime) in &list {
+                        let mr = context.get_model(handle).unwrap();
+                        mr.draw(context, command_buffer, view.frame_descriptor_set, runtime, *skins, &transform_query, &morph_query, DrawPass::Transparent);
+                    }
+
+                    // grass is culled for the main camera only
+                    if view.is_main {
+                        runner.grass.draw(context, command_buffer);
+                    }
+                    runner.particles.draw(context, command_buffer, view.frame_descriptor_set);
+                    runner.billboards.draw(context, &runner.texture_cache, command_buffer, view.frame_descriptor_set);
+                }
+                forward_render_pass.end_render_pass(context, command_buffer);
+            }
+            if let Some(taa) = forward_render_pass.get_taa() {
+                match main_view {
+                    Some(view) => taa.resolve(context, command_buffer, forward_render_pass.get_target(), view.frame_descriptor_set),
+                    None => taa.reset_history(),
+                }
+            }
+            runner.debug_overlay.draw(context, command_buffer, forward_render_pass);
 
             #[cfg(feature = "statistic")]
                 context.statistic.end_query(&context.device, command_buffer);
@@ -139,11 +443,31 @@ fn draw_models_system(mut runner: Option<ResMut<RenderRunner>>,
 fn end_draw_system(mut runner: Option<ResMut<RenderRunner>>) {
     if let Some(runner) = &mut runner {
         if let Some(cb) = runner.get_current_command_buffer() {
+            // over everything recorded in the draw stages, third party effects included
+            runner.hud.draw(&runner.context, &runner.texture_cache, cb, &runner.forward_render_pass);
             runner.end_draw(cb);
         }
     }
 }
 
+fn apply_render_settings_system(mut runner: Option<ResMut<RenderRunner>>,
+                                settings: Res<RenderSettings>,
+                                mut recreated_events: EventWriter<RenderTargetsRecreatedEvent>) {
+    if let Some(runner) = &mut runner {
+        if runner.get_settings() == settings.deref() {
+            return;
+        }
+
+        match runner.apply_settings(&settings) {
+            Ok(true) => recreated_events.send(RenderTargetsRecreatedEvent {}),
+            Ok(false) => {}
+            Err(e) => runner.record_error(e),
+        }
+    }
+}
+
+/// The main camera. It is one of the rendered cameras and additionally drives grass
+/// culling, effects and the editor camera controls.
 pub struct RenderCamera {
     pub camera: Entity,
 }
@@ -152,28 +476,63 @@ fn update_render_state_from_camera(mut commands: Commands,
                                    render_camera: Res<RenderCamera>,
                                    mut runner: Option<ResMut<RenderRunner>>,
                                    time: Res<Time>,
-                                   camera_query: Query<(&Camera, &Transform)>,
+                                   environment: Res<Environment>,
+                                   environment_maps: Res<Assets<EnvironmentMap>>,
+                                   mut camera_query: Query<(Entity, &mut Camera, &Transform, Option<&Fog>)>,
                                    main_light_query: Query<(&MainLight, &Transform)>,
 )
 {
     if let Some(runner) = &mut runner {
+        let runner = runner.deref_mut();
+        runner.views.begin_frame();
+        let environment_color = environment.horizon_color(&environment_maps);
+
         if let Ok((light, light_transform)) = main_light_query.single() {
-            if let Ok((camera, transform)) = camera_query.get(render_camera.camera) {
-                let light_view = light_transform.compute_matrix().inverse();
-                let light_dir = light_view.transform_vector3(Vec3::Z);
+            let light_view = light_transform.compute_matrix().inverse();
+            let light_dir = light_view.transform_vector3(Vec3::Z);
+
+            let light_project = Mat4::orthographic_rh(-10.0, 10.0, -10.0, 10.0, 1.0, 20.0);
+            let light_matrix = light_project * light_view;
+
+            for (entity, mut camera, transform, fog) in camera_query.iter_mut() {
+                let rect = match runner.views.prepare_camera(&mut runner.context, &runner.forward_render_pass, &runner.windows,
+                                                             entity, &camera) {
+                    Some(rect) => rect,
+                    None => continue,
+                };
+                let aspect = rect.extent.width as f32 / rect.extent.height as f32;
+                if camera.aspect != aspect {
+                    camera.aspect = aspect;
+                }
+                let reverse_z = runner.context.render_config.reverse_z;
+                if camera.reverse_z != reverse_z {
+                    camera.reverse_z = reverse_z;
+                }
 
-                let light_project = Mat4::orthographic_rh(-10.0, 10.0, -10.0, 10.0, 1.0, 20.0);
-                let light_matrix = light_project * light_view;
                 let pos = transform.translation;
 
-                let proj = Mat4::perspective_rh(
-                    camera.fov,
-                    camera.aspect,
-                    camera.z_near,
-                    camera.z_far);
+                let proj = camera.projection_matrix();
 
                 let view = transform.compute_matrix().inverse();
 
+                let is_main = entity == render_camera.camera;
+                // temporal anti-aliasing resolves the primary window, only its main camera is jittered
+                let jitter = match (runner.forward_render_pass.get_taa(), camera.target) {
+                    (Some(_), CameraTarget::Window(id)) if is_main && id.is_primary() =>
+                        jitter_offset(runner.views.frame_index(), rect.extent),
+                    _ => Vec2::ZERO,
+                };
+                let (prev_view_proj, prev_jitter) = runner.views.swap_history(entity, proj * view, jitter);
+                let unjittered_proj = proj;
+                let proj = Mat4::from_translation(Vec3::new(jitter.x, jitter.y, 0.0)) * proj;
+                // the occlusion map is computed for the main camera of the primary window
+                let ao_quality = runner.forward_render_pass.get_ssao().map(|ssao| ssao.get_quality());
+                let (ao_strength, ao_scale) = match (ao_quality, camera.target) {
+                    (Some(quality), CameraTarget::Window(id)) if is_main && id.is_primary() => (1.0, quality.resolution_scale()),
+                    _ => (0.0, 1.0),
+                };
+                let (fog_color, fog_params) = Fog::frame_data(fog, camera.clear_color, environment_color);
+
                 let frame_data = PerFrameData {
                     view: view,
                     proj: proj,
@@ -183,11 +542,20 @@ fn update_render_state_from_camera(mut commands: Commands,
                     camera_dir: Vec4::from((transform.rotation.mul_vec3(Vec3::Z), 1.0)),
                     delta_time: 0.016,
                     total_time: time.seconds_since_startup() as _,
+                    ao_strength,
+                    ao_scale,
+                    fog_color,
+                    fog_params,
+                    prev_view_proj,
+                    jitter: Vec4::new(jitter.x, jitter.y, prev_jitter.x, prev_jitter.y),
+                    unjittered_proj,
                 };
 
-                runner.upload_per_frame_data(frame_data);
+                runner.views.push_view(&mut runner.context, entity, &camera, rect, frame_data, is_main);
             }
         }
+
+        runner.views.end_prepare(&mut runner.context);
     }
 }
 
@@ -228,14 +596,17 @@ fn it_works() {
     num2 += num1;
 }
 
+/// Created and modified assets are decoded on the async compute pool, `ModelUploads`
+/// then uploads them over the following frames.
 fn load_gltf_2_device_system(mut runner: Option<ResMut<RenderRunner>>,
-                             mut assets: ResMut<Assets<GltfAsset>>,
+                             assets: Res<Assets<GltfAsset>>,
+                             mut uploads: ResMut<ModelUploads>,
+                             task_pool: Res<AsyncComputeTaskPool>,
                              mut gltf_events: EventReader<AssetEvent<GltfAsset>>) {
     if let Some(runner) = &mut runner {
         let runner: &mut RenderRunner = runner.deref_mut();
         let command_buffer = runner.get_upload_command_buffer();
         let context = &mut runner.context;
-        let swap_mgr = &runner.swapchain_mgr;
 
         let mut changed_gltf_set: HashSet<Handle<GltfAsset>> = HashSet::default();
         let mut destroy_gltf_set: HashSet<Handle<GltfAsset>> = HashSet::default();
@@ -257,33 +628,17 @@ fn load_gltf_2_device_system(mut runner: Option<ResMut<RenderRunner>>,
         }
 
         for destroy_handle in &destroy_gltf_set {
+            uploads.cancel(context, destroy_handle);
             context.remove_model(destroy_handle);
             info!("remove gltf asset");
         }
 
-        if changed_gltf_set.len() == 0 {
-            return;
-        }
-
-
         for changed_gltf_handle in changed_gltf_set.iter() {
             let gltf_asset = assets.get(changed_gltf_handle).expect("failed to find asset gltf");
-
-
-            let model = ModelRenderer::create(context,
-                                              swap_mgr,
-                                              &runner.forward_render_pass,
-                                              command_buffer,
-                                              gltf_asset,
-                                              &ShadeNames {
-                                                  vertex: "pbr_vert",
-                                                  frag: "pbr_frag",
-                                                  shadow_vertex: "pbr_shadow_vert",
-                                                  shadow_frag: "pbr_shadow_frag",
-                                              });
-
-            context.insert_model(changed_gltf_handle.clone_weak(), model);
+            uploads.prepare(context, &task_pool, changed_gltf_handle, gltf_asset);
         }
+
+        uploads.upload(context, &runner.forward_render_pass, command_buffer);
     }
 }
 
@@ -293,6 +648,14 @@ pub struct RenderPlugin {}
 enum UploadLabel {
     Model,
     Skin,
+    Textures,
+}
+
+/// Settings may rebuild the passes the views and the sprites are prepared for.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+enum PrepareLabel {
+    Settings,
+    Views,
 }
 
 impl Plugin for RenderPlugin {
@@ -319,12 +682,28 @@ impl Plugin for RenderPlugin {
             .insert(Transform::from_matrix(light_mat))
             .insert(DisplayName::from_str("main_light"));
 
+        world.get_resource_or_insert_with(|| RenderSettings::load(RENDER_SETTINGS_PATH));
+
         let render_system = get_render_system(app.world_mut());
         app.init_asset_loader::<GltfAssetLoader>();
         app.add_asset::<GltfAsset>();
+        app.init_asset_loader::<ParticleEffectLoader>();
+        app.add_asset::<ParticleEffect>();
+        app.init_asset_loader::<EnvironmentMapLoader>();
+        app.add_asset::<EnvironmentMap>();
+        app.init_asset_loader::<ImageAssetLoader>();
+        app.add_asset::<ImageAsset>();
+        app.init_asset_loader::<GlyphAtlasLoader>();
+        app.add_asset::<GlyphAtlas>();
+        app.init_asset_loader::<HudAtlasLoader>();
+        app.add_asset::<HudAtlas>();
+        app.init_resource::<Environment>();
 
         app.add_event::<RenderInitEvent>();
+        app.add_event::<RenderInitFailed>();
         app.add_event::<CameraOpEvent>();
+        app.add_event::<RenderTargetsRecreatedEvent>();
+        app.init_resource::<ModelUploads>();
 
         //upload
         app.add_stage_after(CoreStage::PreUpdate, RenderStage::BeginUpload, SystemStage::parallel());
@@ -335,6 +714,15 @@ impl Plugin for RenderPlugin {
         app.add_system_to_stage(RenderStage::BeginUpload, begin_upload.system());
         app.add_system_to_stage(RenderStage::Upload, load_gltf_2_device_system.system().label(UploadLabel::Model));
         app.add_system_to_stage(RenderStage::Upload, model_runtime::init_model_runtime_system.system().after(UploadLabel::Model));
+        app.add_system_to_stage(RenderStage::Upload, model_upload::update_model_load_state_system.system().after(UploadLabel::Model));
+        app.add_system_to_stage(RenderStage::Upload, decal::request_decal_textures_system.system().before(UploadLabel::Textures));
+        app.add_system_to_stage(RenderStage::Upload, particles::prepare_particle_emitters_system.system()
+            .after(UploadLabel::Textures));
+        app.add_system_to_stage(RenderStage::Upload, billboard::request_billboard_resources_system.system()
+            .before(UploadLabel::Textures));
+        app.add_system_to_stage(RenderStage::Upload, hud::request_hud_resources_system.system().before(UploadLabel::Textures));
+        app.add_system_to_stage(RenderStage::Upload, texture_cache::update_texture_cache_system.system()
+            .label(UploadLabel::Textures));
         app.add_system_to_stage(RenderStage::EndUpload, end_upload.system());
 
         //draw
@@ -346,7 +734,11 @@ impl Plugin for RenderPlugin {
 
         app.add_system_to_stage(RenderStage::PrepareDraw, Camera::update_camera_op_event_system.system());
         app.add_system_to_stage(RenderStage::PrepareDraw, render_system.exclusive_system());
-        app.add_system_to_stage(RenderStage::PrepareDraw, update_render_state_from_camera.system());
+        app.add_system_to_stage(RenderStage::PrepareDraw, apply_render_settings_system.system().label(PrepareLabel::Settings));
+        app.add_system_to_stage(RenderStage::PrepareDraw, update_render_state_from_camera.system()
+            .label(PrepareLabel::Views).after(PrepareLabel::Settings));
+        app.add_system_to_stage(RenderStage::PrepareDraw, billboard::prepare_billboards_system.system().after(PrepareLabel::Views));
+        app.add_system_to_stage(RenderStage::PrepareDraw, hud::prepare_hud_system.system().after(PrepareLabel::Views));
 
         app.add_system_to_stage(RenderStage::BeginDraw, draw_models_system.system());
         app.add_system_to_stage(RenderStage::EndDraw, end_draw_system.system());
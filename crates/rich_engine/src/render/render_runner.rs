@@ -9,8 +9,21 @@ use crate::render::model::Model;
 use crate::render::model_renderer::ModelRenderer;
 use bevy::prelude::*;
 use crate::render::grass::GrassMgr;
+use crate::render::debug::DebugOverlay;
+use crate::render::decal::DecalRenderer;
+use crate::render::particles::ParticleSystem;
+use crate::render::billboard::BillboardRenderer;
+use crate::render::hud::HudRenderer;
+use crate::render::texture_cache::TextureCache;
+use crate::render::taa::MotionHistory;
 use crate::render::uniform::UniformObject;
 use std::sync::{Arc, Mutex};
+use crate::render::render_settings::RenderSettings;
+use crate::render::render_view::RenderViews;
+use crate::render::window_surface::{WindowSurface, cmd_blit_to_present};
+use bevy::window::WindowId;
+use std::collections::HashMap;
+use crate::render::render_error::{RenderError, RenderResult};
 
 pub struct RenderRunner {
     pub context: RenderContext,
@@ -18,15 +31,43 @@ pub struct RenderRunner {
     pub command_buffer_list: CommandBufferList,
     pub forward_render_pass: ForwardRenderPass,
     pub grass: GrassMgr,
+    pub debug_overlay: DebugOverlay,
+    pub decals: DecalRenderer,
+    pub particles: ParticleSystem,
+    pub billboards: BillboardRenderer,
+    pub hud: HudRenderer,
+    /// images and fonts of the HUD, billboards, decals and particles
+    pub texture_cache: TextureCache,
+    pub views: RenderViews,
+    /// node transforms of the previous frame for the velocity pass of temporal anti-aliasing
+    pub motion_history: MotionHistory,
+    /// windows other than the primary one, see `add_window`
+    pub windows: HashMap<WindowId, WindowSurface>,
+    /// the settings the current targets were built with
+    settings: RenderSettings,
     last_tick: SystemTime,
     pub current_present_index: i32,
     pub mutex: Arc<Mutex<i32>>,
+    /// set when a call returned `VK_ERROR_DEVICE_LOST`, the render plugin then
+    /// creates the renderer again
+    device_lost: bool,
 }
 
 impl Drop for RenderRunner {
     fn drop(&mut self) {
-        unsafe { self.context.device.device_wait_idle().unwrap(); }
+        // fails when the device was lost, the resources are released anyway
+        let _ = unsafe { self.context.device.device_wait_idle() };
         self.grass.destroy(&self.context);
+        self.debug_overlay.destroy(&self.context);
+        self.decals.destroy(&self.context);
+        self.particles.destroy(&self.context);
+        self.billboards.destroy(&self.context);
+        self.hud.destroy(&self.context);
+        self.texture_cache.destroy(&self.context);
+        self.views.destroy(&self.context);
+        for (_, mut window) in self.windows.drain() {
+            window.destroy(&self.context);
+        }
         self.command_buffer_list.destroy(&self.context);
         self.forward_render_pass.destroy(&self.context);
         self.swapchain_mgr.destroy(&self.context);
@@ -35,74 +76,302 @@ impl Drop for RenderRunner {
 }
 
 
+/// What `RenderRunner::create` has built so far, destroyed in reverse order when a later
+/// step fails so the device, the surface and the instance are not leaked.
+#[derive(Default)]
+struct PartialRunner {
+    context: Option<RenderContext>,
+    swapchain: Option<SwapChainMgr>,
+    command_buffer_list: Option<CommandBufferList>,
+    forward_render_pass: Option<ForwardRenderPass>,
+    grass: Option<GrassMgr>,
+    debug_overlay: Option<DebugOverlay>,
+    texture_cache: Option<TextureCache>,
+    decals: Option<DecalRenderer>,
+    particles: Option<ParticleSystem>,
+    billboards: Option<BillboardRenderer>,
+    hud: Option<HudRenderer>,
+}
+
+impl Drop for PartialRunner {
+    fn drop(&mut self) {
+        let mut context = match self.context.take() {
+            Some(context) => context,
+            None => return,
+        };
+        let _ = unsafe { context.device.device_wait_idle() };
+        if let Some(mut hud) = self.hud.take() {
+            hud.destroy(&context);
+        }
+        if let Some(mut billboards) = self.billboards.take() {
+            billboards.destroy(&context);
+        }
+        if let Some(mut particles) = self.particles.take() {
+            particles.destroy(&context);
+        }
+        if let Some(mut decals) = self.decals.take() {
+            decals.destroy(&context);
+        }
+        if let Some(mut texture_cache) = self.texture_cache.take() {
+            texture_cache.destroy(&context);
+        }
+        if let Some(mut debug_overlay) = self.debug_overlay.take() {
+            debug_overlay.destroy(&context);
+        }
+        if let Some(mut grass) = self.grass.take() {
+            grass.destroy(&context);
+        }
+        if let Some(mut command_buffer_list) = self.command_buffer_list.take() {
+            command_buffer_list.destroy(&context);
+        }
+        if let Some(mut forward_render_pass) = self.forward_render_pass.take() {
+            forward_render_pass.destroy(&context);
+        }
+        if let Some(mut swapchain) = self.swapchain.take() {
+            swapchain.destroy(&context);
+        }
+        context.destroy();
+    }
+}
+
 impl RenderRunner {
-    pub fn create<W: raw_window_handle::HasRawWindowHandle>(window: &W, window_width: u32, window_height: u32) -> Self {
+    pub fn create<W: raw_window_handle::HasRawWindowHandle>(window: &W, window_width: u32, window_height: u32,
+                                                            settings: &RenderSettings) -> RenderResult<Self> {
+        info!("start up");
+        let mut parts = PartialRunner::default();
+        let context = parts.context.insert(unsafe { RenderContext::create(window, window_width, window_height, settings)? });
+        let per_frame_data = UniformObject::<PerFrameData>::create(context,
+                                                                   PerFrameData::create(),
+                                                                   vk::DescriptorType::UNIFORM_BUFFER,
+                                                                   vk::ShaderStageFlags::VERTEX |
+                                                                       vk::ShaderStageFlags::FRAGMENT |
+                                                                       vk::ShaderStageFlags::TESSELLATION_EVALUATION |
+                                                                       vk::ShaderStageFlags::COMPUTE);
+        context.per_frame_uniform = Some(per_frame_data);
+        //context.push_resource(per_frame_data);
+
+        info!("render context create complete");
+        let swapchain = parts.swapchain.insert(unsafe { SwapChainMgr::create(context, window_width, window_height)? });
+        let command_buffer_list = parts.command_buffer_list.insert(
+            CommandBufferList::create(swapchain.get_present_image_count(), context));
+        let forward_render_pass = parts.forward_render_pass.insert(
+            ForwardRenderPass::create(context, swapchain, command_buffer_list)?);
+
+        let command_buffer = command_buffer_list.get_upload_command_buffer();
         unsafe {
-            info!("start up");
-            let mut context = RenderContext::create(window, window_width, window_height);
-            let per_frame_data = UniformObject::<PerFrameData>::create(&mut context,
-                                                                       PerFrameData::create(),
-                                                                       vk::DescriptorType::UNIFORM_BUFFER,
-                                                                       vk::ShaderStageFlags::VERTEX |
-                                                                           vk::ShaderStageFlags::FRAGMENT |
-                                                                           vk::ShaderStageFlags::TESSELLATION_EVALUATION |
-                                                                           vk::ShaderStageFlags::COMPUTE);
-            context.per_frame_uniform = Some(per_frame_data);
-            //context.push_resource(per_frame_data);
-
-            info!("render context create complete");
-            let swapchain = SwapChainMgr::create(&context, window_width, window_height);
-            let command_buffer_list = CommandBufferList::create(swapchain.get_present_image_count(), &context);
-            let forward_render_pass = ForwardRenderPass::create(&mut context, &swapchain, &command_buffer_list);
-
-            let command_buffer = command_buffer_list.get_upload_command_buffer();
-            unsafe {
-                context.device.begin_command_buffer(command_buffer,
-                                                    &vk::CommandBufferBeginInfo::builder().
-                                                        flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT).build());
-            }
+            context.device.begin_command_buffer(command_buffer,
+                                                &vk::CommandBufferBeginInfo::builder().
+                                                    flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT).build())
+                .map_err(RenderError::vk("vkBeginCommandBuffer"))?;
+        }
 
-            let grass = GrassMgr::create(&mut context, &swapchain, &forward_render_pass, command_buffer);
+        let grass = parts.grass.insert(GrassMgr::create(context, swapchain, forward_render_pass, command_buffer)?);
+        grass.enable_draw = settings.grass;
 
-            let dummy_res = DummyResources::create(&mut context, command_buffer);
-            context.insert_resource(dummy_res);
+        let debug_overlay = parts.debug_overlay.insert(DebugOverlay::create(context, forward_render_pass)?);
+        debug_overlay.enable_draw = settings.debug_overlay;
 
-            unsafe {
-                context.device.end_command_buffer(command_buffer);
-                context.device.queue_submit(context.transfer_queue, &[vk::SubmitInfo::builder().command_buffers(&[command_buffer]).build()],
-                                            vk::Fence::null());
-                context.device.device_wait_idle();
-            }
+        let dummy_res = DummyResources::create(context, command_buffer)?;
+        context.insert_resource(dummy_res);
+        let texture_set_layout = parts.texture_cache.insert(TextureCache::create(context)?).set_layout();
+
+        parts.decals = Some(DecalRenderer::create(context, forward_render_pass, texture_set_layout)?);
+        parts.particles = Some(ParticleSystem::create(context)?);
+        parts.billboards = Some(BillboardRenderer::create(context, forward_render_pass, texture_set_layout)?);
+        parts.hud = Some(HudRenderer::create(context, forward_render_pass, texture_set_layout)?);
 
-            context.flush_staging_buffer();
-
-            info!("forward render pass create complete");
-            // unsafe {
-            //     let p = context.instance.get_physical_context_image_format_properties(context.physical_context,
-            //                                                                         vk::Format::R8G8B8_USCALED, vk::ImageType::TYPE_2D, vk::ImageTiling::OPTIMAL,
-            //                                                                         vk::ImageUsageFlags::SAMPLED, vk::ImageCreateFlags::empty());
-            // 
-            //     let pk = match p {
-            //         Ok(f) => {},
-            //         Err(error) => {
-            //             panic!("error {}", error);
-            //         }
-            //     };
-            // }
-
-            info!("model renderer created complete");
-
-            RenderRunner {
-                context,
-                swapchain_mgr: swapchain,
-                command_buffer_list,
-                forward_render_pass,
+        unsafe {
+            context.device.end_command_buffer(command_buffer)
+                .map_err(RenderError::vk("vkEndCommandBuffer"))?;
+            context.device.queue_submit(context.transfer_queue, &[vk::SubmitInfo::builder().command_buffers(&[command_buffer]).build()],
+                                        vk::Fence::null())
+                .map_err(RenderError::vk("vkQueueSubmit"))?;
+            context.device.device_wait_idle()
+                .map_err(RenderError::vk("vkDeviceWaitIdle"))?;
+        }
+
+        context.flush_staging_buffer();
+
+        info!("forward render pass create complete");
+        info!("model renderer created complete");
+
+        // every part is built, nothing is left for the guard to destroy
+        let mut take = || -> Option<RenderRunner> {
+            Some(RenderRunner {
+                context: parts.context.take()?,
+                swapchain_mgr: parts.swapchain.take()?,
+                command_buffer_list: parts.command_buffer_list.take()?,
+                forward_render_pass: parts.forward_render_pass.take()?,
                 last_tick: SystemTime::now(),
                 current_present_index: -1,
-                grass,
+                grass: parts.grass.take()?,
+                debug_overlay: parts.debug_overlay.take()?,
+                decals: parts.decals.take()?,
+                particles: parts.particles.take()?,
+                billboards: parts.billboards.take()?,
+                hud: parts.hud.take()?,
+                texture_cache: parts.texture_cache.take()?,
+                views: RenderViews::default(),
+                motion_history: MotionHistory::default(),
+                windows: HashMap::new(),
+                settings: settings.clone(),
                 mutex: Arc::new(Mutex::new(0)),
+                device_lost: false,
+            })
+        };
+        Ok(take().expect("every part of the renderer is created"))
+    }
+
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost
+    }
+
+    /// Logs a failed frame, a lost device stops drawing until the renderer is recreated.
+    pub fn record_error(&mut self, error: RenderError) {
+        if !self.device_lost {
+            error!("render error: {}", error);
+        }
+        if error.is_device_lost() {
+            self.device_lost = true;
+        }
+    }
+
+    fn wait_idle(&mut self) {
+        if let Err(e) = unsafe { self.context.device.device_wait_idle() } {
+            self.record_error(RenderError::vk("vkDeviceWaitIdle")(e));
+        }
+    }
+
+    pub fn get_settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    /// Applies changed settings, waiting for the device when targets must be rebuilt.
+    /// Returns true when the forward targets or the command pool were recreated.
+    pub fn apply_settings(&mut self, settings: &RenderSettings) -> RenderResult<bool> {
+        if &self.settings == settings {
+            return Ok(false);
+        }
+
+        let old_config = self.context.render_config.clone();
+        self.context.render_config.apply_settings(settings);
+        self.grass.enable_draw = settings.grass;
+        self.debug_overlay.enable_draw = settings.debug_overlay;
+        let config = &self.context.render_config;
+
+        let swapchain_changed = config.present_mode != old_config.present_mode;
+        let targets_changed = config.msaa != old_config.msaa ||
+            config.shadow_map_dim != old_config.shadow_map_dim ||
+            config.ambient_occlusion != old_config.ambient_occlusion ||
+            config.temporal_aa != old_config.temporal_aa ||
+            self.context.render_extent() != self.forward_render_pass.get_extent();
+        let pipelines_changed = config.reverse_z != old_config.reverse_z ||
+            config.debug_view != old_config.debug_view;
+        let samplers_changed = config.max_anisotropy != self.context.sampler_cache.get_max_anisotropy();
+        if settings.device != self.settings.device {
+            info!("render device selection {:?} applies on the next start", settings.device);
+        }
+        if settings.render_path != self.settings.render_path {
+            info!("render path {:?} applies on the next start", settings.render_path);
+        }
+        self.settings = settings.clone();
+
+        if !swapchain_changed && !targets_changed && !pipelines_changed && !samplers_changed {
+            return Ok(false);
+        }
+
+        unsafe { self.context.device.device_wait_idle() }.map_err(RenderError::vk("vkDeviceWaitIdle"))?;
+        self.context.destroy_all_retired();
+        let mut pool_changed = false;
+
+        if samplers_changed {
+            let max_anisotropy = self.context.render_config.max_anisotropy;
+            self.context.sampler_cache.set_max_anisotropy(&self.context.device, max_anisotropy);
+            info!("texture anisotropy set to {}", max_anisotropy);
+        }
+
+        if swapchain_changed {
+            let image_count = self.swapchain_mgr.get_present_image_count();
+            self.swapchain_mgr.destroy(&self.context);
+            self.swapchain_mgr = unsafe {
+                SwapChainMgr::create(&self.context, self.context.window_width, self.context.window_height)?
+            };
+            if self.swapchain_mgr.get_present_image_count() != image_count {
+                self.command_buffer_list.destroy(&self.context);
+                self.command_buffer_list = CommandBufferList::create(self.swapchain_mgr.get_present_image_count(), &self.context);
+                pool_changed = true;
+            }
+            info!("swapchain recreated with {:?}", self.context.render_config.present_mode);
+        }
+
+        if swapchain_changed {
+            for window in self.windows.values_mut() {
+                window.recreate_swapchain(&self.context)?;
+            }
+        }
+
+        if targets_changed {
+            self.views.destroy_targets(&self.context);
+            self.forward_render_pass.destroy(&self.context);
+            self.forward_render_pass = ForwardRenderPass::create(&mut self.context, &self.swapchain_mgr, &self.command_buffer_list)?;
+            self.grass.recreate_pipeline(&mut self.context, &self.forward_render_pass)?;
+            self.debug_overlay.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.decals.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.particles.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.billboards.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.hud.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.context.recreate_model_pipelines(&self.forward_render_pass)?;
+            for window in self.windows.values_mut() {
+                window.recreate_target(&self.context, &self.forward_render_pass)?;
             }
+            info!("render targets recreated, msaa {:?}, extent {:?}",
+                  self.context.render_config.msaa, self.forward_render_pass.get_extent());
+        } else if pipelines_changed {
+            self.grass.recreate_pipeline(&mut self.context, &self.forward_render_pass)?;
+            self.particles.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.billboards.recreate(&mut self.context, &self.forward_render_pass)?;
+            self.context.recreate_model_pipelines(&self.forward_render_pass)?;
+        } else if samplers_changed {
+            // model descriptors are written with the samplers destroyed above
+            self.context.recreate_model_pipelines(&self.forward_render_pass)?;
         }
+
+        Ok(targets_changed || pool_changed)
+    }
+
+    /// Gives a window other than the primary one its own surface and swapchain, cameras
+    /// target it with `CameraTarget::Window(id)`.
+    pub fn add_window<W: raw_window_handle::HasRawWindowHandle>(&mut self, id: WindowId, window: &W, width: u32, height: u32) {
+        if let Some(surface) = WindowSurface::create(&self.context, &self.forward_render_pass, window, width, height) {
+            info!("window {:?} added to the renderer", id);
+            if let Some(old) = self.windows.insert(id, surface) {
+                self.context.defer_destroy("window_surface", old);
+            }
+        }
+    }
+
+    /// Drops the surface and swapchain of a closed window, cameras targeting it draw nothing.
+    pub fn remove_window(&mut self, id: WindowId) {
+        if let Some(window) = self.windows.remove(&id) {
+            self.context.defer_destroy("window_surface", window);
+        }
+    }
+
+    pub fn resize_window(&mut self, id: WindowId, width: u32, height: u32) {
+        if !self.windows.contains_key(&id) {
+            return;
+        }
+        self.wait_idle();
+        let window = self.windows.get_mut(&id).unwrap();
+        if let Err(e) = window.recreate(&self.context, &self.forward_render_pass, width, height) {
+            self.record_error(e);
+        }
+    }
+
+    /// Color view of a texture camera, readable in `SHADER_READ_ONLY_OPTIMAL` once the
+    /// frame rendered. The view changes when the target is recreated, query it every frame.
+    pub fn get_camera_target_view(&self, camera: Entity) -> Option<vk::ImageView> {
+        self.views.get_target(camera).map(|t| t.get_final_render_image_view())
     }
 
     pub fn upload_per_frame_data(&mut self, data: PerFrameData) {
@@ -116,11 +385,31 @@ impl RenderRunner {
     pub fn begin_draw(&mut self) -> Option<(usize, vk::CommandBuffer)> {
         let now = SystemTime::now();
         self.last_tick = now;
-        let (success, present_index) = self.swapchain_mgr.wait_for_swap_chain(&mut self.context);
-        if !success {
+        if self.device_lost {
             return None;
         }
 
+        let present_index = match self.swapchain_mgr.wait_for_swap_chain(&self.context) {
+            Ok(Some(present_index)) => present_index,
+            Ok(None) => return None,
+            Err(e) => {
+                self.record_error(e);
+                return None;
+            }
+        };
+
+        // the fence of the reused frame is signaled, objects retired that many frames ago are unused
+        let frames_in_flight = self.swapchain_mgr.get_present_image_count() as usize;
+        self.context.destroy_retired(frames_in_flight);
+
+        let context = &self.context;
+        let errors = self.windows.values_mut()
+            .filter_map(|window| window.acquire(context).err())
+            .collect::<Vec<_>>();
+        for e in errors {
+            self.record_error(e);
+        }
+
         let command_buffer = self.command_buffer_list.get_command_buffer(present_index);
         {
             let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder().
@@ -157,95 +446,9 @@ impl RenderRunner {
                                           &mut render_finish_semaphore, &mut cmd_buf_execute_fence);
 
 
-        {
-            let image_barriers = [
-                vk::ImageMemoryBarrier::builder().image(self.forward_render_pass.get_final_render_image())
-                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
-                    .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-                    .subresource_range(vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    }).build(),
-                vk::ImageMemoryBarrier::builder().image(self.swapchain_mgr.get_current_present_image())
-                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
-                    .old_layout(vk::ImageLayout::UNDEFINED)
-                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                    .subresource_range(vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    }).build(),
-            ];
-
-            unsafe {
-                self.context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
-                                                         vk::PipelineStageFlags::ALL_COMMANDS,
-                                                         vk::DependencyFlags::empty(), &[], &[],
-                                                         &image_barriers);
-            }
-        }
-
-
-        let surface_resolution = self.swapchain_mgr.surface_resolution;
-
-        unsafe {
-            self.context.device.cmd_copy_image(command_buffer,
-                                               self.forward_render_pass.get_final_render_image(),
-                                               vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                                               self.swapchain_mgr.get_current_present_image(),
-                                               vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                                               &[vk::ImageCopy {
-                                                   src_subresource: vk::ImageSubresourceLayers {
-                                                       aspect_mask: vk::ImageAspectFlags::COLOR,
-                                                       layer_count: 1,
-                                                       ..Default::default()
-                                                   },
-                                                   dst_subresource: vk::ImageSubresourceLayers {
-                                                       aspect_mask: vk::ImageAspectFlags::COLOR,
-                                                       layer_count: 1,
-                                                       ..Default::default()
-                                                   },
-                                                   src_offset: Default::default(),
-                                                   dst_offset: Default::default(),
-                                                   extent: vk::Extent3D {
-                                                       width: surface_resolution.width,
-                                                       height: surface_resolution.height,
-                                                       depth: 1,
-                                                   },
-                                               }]);
-        }
-
-        {
-            let image_barriers = [
-                vk::ImageMemoryBarrier::builder().image(self.swapchain_mgr.get_current_present_image())
-                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
-                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                    .subresource_range(vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    }).build(),
-            ];
-
-            unsafe {
-                self.context.device.cmd_pipeline_barrier(command_buffer,
-                                                         vk::PipelineStageFlags::ALL_COMMANDS,
-                                                         vk::PipelineStageFlags::ALL_COMMANDS,
-                                                         vk::DependencyFlags::empty(),
-                                                         &[],
-                                                         &[],
-                                                         &image_barriers);
-            }
-        }
+        cmd_blit_to_present(&self.context, command_buffer,
+                            self.forward_render_pass.get_final_render_image(), self.forward_render_pass.get_extent(),
+                            self.swapchain_mgr.get_current_present_image(), self.swapchain_mgr.surface_resolution);
 
         unsafe {
             let g = self.mutex.lock();
@@ -260,20 +463,33 @@ impl RenderRunner {
             .wait_semaphores(&wait_semaphores).wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
             .command_buffers(&command_buffers).signal_semaphores(&signal_semaphores).build();
 
-        unsafe {
+        let submitted = unsafe {
             let mut guard = self.mutex.lock().unwrap();
-            self.context.device.queue_submit(self.context.graphics_queue, &[submit_info], cmd_buf_execute_fence);
+            let result = self.context.device.queue_submit(self.context.graphics_queue, &[submit_info], cmd_buf_execute_fence);
             drop(guard);
+            result
+        };
+        if let Err(e) = submitted {
+            self.record_error(RenderError::vk("vkQueueSubmit")(e));
+            return;
         }
 
         #[cfg(feature = "statistic")]
             self.context.statistic.require_results(&self.context.device);
 
+        let mut errors = vec![];
         {
             let mut guard = self.mutex.lock().unwrap();
-            self.swapchain_mgr.present(&self.context);
+            errors.extend(self.swapchain_mgr.present(&self.context).err());
+            // submitted after the main command buffer that rendered their targets
+            for window in self.windows.values_mut() {
+                errors.extend(window.submit_and_present(&self.context).err());
+            }
             drop(guard);
         }
+        for e in errors {
+            self.record_error(e);
+        }
     }
 
     fn on_window_size_changed(&mut self, window_width: u32, window_height: u32) {
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use bevy::prelude::*;
+use ash::vk;
+
+pub const RENDER_SETTINGS_PATH: &str = "./assets/config/render_settings.ron";
+/// Overrides `RenderSettings::device`: an index, `discrete`, `integrated`, `virtual`, `cpu`
+/// or a part of the device name.
+pub const RENDER_DEVICE_ENV: &str = "RICH_RENDER_DEVICE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum DeviceType {
+    Discrete,
+    Integrated,
+    Virtual,
+    Cpu,
+}
+
+impl DeviceType {
+    pub fn from_vk(device_type: vk::PhysicalDeviceType) -> Option<Self> {
+        match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => Some(DeviceType::Discrete),
+            vk::PhysicalDeviceType::INTEGRATED_GPU => Some(DeviceType::Integrated),
+            vk::PhysicalDeviceType::VIRTUAL_GPU => Some(DeviceType::Virtual),
+            vk::PhysicalDeviceType::CPU => Some(DeviceType::Cpu),
+            _ => None,
+        }
+    }
+}
+
+/// Which physical device renders, read once at startup.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum DeviceSelection {
+    /// discrete first, then integrated, virtual and CPU devices
+    Auto,
+    /// index in the device list of the capability report
+    Index(usize),
+    /// case insensitive part of the device name
+    Name(String),
+    Type(DeviceType),
+}
+
+impl DeviceSelection {
+    pub fn from_env() -> Option<Self> {
+        Self::parse(&std::env::var(RENDER_DEVICE_ENV).ok()?)
+    }
+
+    /// A device type, an index or a part of the name, `None` when blank.
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            return None;
+        }
+
+        let selection = match value.to_lowercase().as_str() {
+            "auto" => DeviceSelection::Auto,
+            "discrete" => DeviceSelection::Type(DeviceType::Discrete),
+            "integrated" => DeviceSelection::Type(DeviceType::Integrated),
+            "virtual" => DeviceSelection::Type(DeviceType::Virtual),
+            "cpu" => DeviceSelection::Type(DeviceType::Cpu),
+            _ => match value.parse::<usize>() {
+                Ok(index) => DeviceSelection::Index(index),
+                Err(_) => DeviceSelection::Name(value.to_string()),
+            },
+        };
+        Some(selection)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum PresentMode {
+    /// vsync
+    Fifo,
+    /// vsync without blocking, falls back to fifo when unsupported
+    Mailbox,
+    /// no vsync, falls back to fifo when unsupported
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn to_vk(self) -> vk::PresentModeKHR {
+        match self {
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ShadowQuality {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality {
+    /// `Off` keeps a single texel map cleared to the far plane, so the
+    /// shaders sample it as fully lit.
+    pub fn shadow_map_dim(self) -> f32 {
+        match self {
+            ShadowQuality::Off => 1.0,
+            ShadowQuality::Low => 1024.0,
+            ShadowQuality::Medium => 2048.0,
+            ShadowQuality::High => 4096.0,
+        }
+    }
+}
+
+/// Screen space ambient occlusion of the main camera in the primary window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum AmbientOcclusion {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl AmbientOcclusion {
+    pub const ALL: [AmbientOcclusion; 4] = [
+        AmbientOcclusion::Off,
+        AmbientOcclusion::Low,
+        AmbientOcclusion::Medium,
+        AmbientOcclusion::High,
+    ];
+
+    /// Hemisphere samples per texel, 0 when off.
+    pub fn sample_count(self) -> u32 {
+        match self {
+            AmbientOcclusion::Off => 0,
+            AmbientOcclusion::Low => 8,
+            AmbientOcclusion::Medium => 16,
+            AmbientOcclusion::High => 32,
+        }
+    }
+
+    /// Resolution of the occlusion map relative to the window target.
+    pub fn resolution_scale(self) -> f32 {
+        match self {
+            AmbientOcclusion::Off | AmbientOcclusion::Low | AmbientOcclusion::Medium => 0.5,
+            AmbientOcclusion::High => 1.0,
+        }
+    }
+}
+
+/// How the window target is shaded, read once at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum RenderPath {
+    /// every light is shaded by the model shaders, only the main light is supported
+    Forward,
+    /// opaque models write a G-buffer shaded by a lighting pass with the point lights,
+    /// transparent models are drawn forward afterwards. MSAA is not available.
+    Deferred,
+}
+
+/// Replaces the shading of models with intermediate data to inspect why art looks wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum DebugView {
+    Off,
+    Albedo,
+    Normals,
+    Uvs,
+    MetallicRoughness,
+    /// cascade index of the shadow map lookup, texels outside every cascade stay black
+    ShadowCascade,
+    /// brighter where more fragments were drawn, depth test is off
+    Overdraw,
+    /// falls back to `Off` when the device can not rasterize lines
+    Wireframe,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 8] = [
+        DebugView::Off,
+        DebugView::Albedo,
+        DebugView::Normals,
+        DebugView::Uvs,
+        DebugView::MetallicRoughness,
+        DebugView::ShadowCascade,
+        DebugView::Overdraw,
+        DebugView::Wireframe,
+    ];
+
+    /// Define selecting the permutation of the forward shaders, `None` for the regular shading.
+    pub fn shader_define(self) -> Option<&'static str> {
+        match self {
+            DebugView::Off => None,
+            DebugView::Albedo => Some("DEBUG_ALBEDO"),
+            DebugView::Normals => Some("DEBUG_NORMALS"),
+            DebugView::Uvs => Some("DEBUG_UV"),
+            DebugView::MetallicRoughness => Some("DEBUG_METALLIC_ROUGHNESS"),
+            DebugView::ShadowCascade => Some("DEBUG_SHADOW_CASCADE"),
+            DebugView::Overdraw => Some("DEBUG_OVERDRAW"),
+            DebugView::Wireframe => Some("DEBUG_WIREFRAME"),
+        }
+    }
+
+    pub fn polygon_mode(self) -> vk::PolygonMode {
+        if self == DebugView::Wireframe { vk::PolygonMode::LINE } else { vk::PolygonMode::FILL }
+    }
+
+    /// Overdraw adds up every fragment instead of keeping the nearest one.
+    pub fn is_additive(self) -> bool {
+        self == DebugView::Overdraw
+    }
+}
+
+/// User facing render options. Edit the resource to apply them, the renderer
+/// rebuilds the affected passes and pipelines before the next frame.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// 1, 2, 4 or 8, clamped to what the device supports
+    pub msaa_samples: u32,
+    pub present_mode: PresentMode,
+    pub shadow_quality: ShadowQuality,
+    /// scene resolution relative to the window, the result is upscaled
+    pub render_scale: f32,
+    /// anisotropic filtering of mipmapped model textures, 1 disables it,
+    /// clamped to the device limit
+    pub anisotropy: f32,
+    pub grass: bool,
+    /// contact shadows of the ambient light, darkens where models meet the ground
+    pub ambient_occlusion: AmbientOcclusion,
+    /// jitters the main camera and blends its frames over time, smooths the shader
+    /// aliasing MSAA misses on grass and highlights
+    pub temporal_aa: bool,
+    /// depth 1 at the near plane and 0 at an infinite far plane, keeps precision
+    /// for large views. Effects drawn by the vfx plugin assume the standard depth.
+    pub reverse_z: bool,
+    /// applied on the next start, `RICH_RENDER_DEVICE` takes precedence
+    pub device: DeviceSelection,
+    /// applied on the next start
+    pub render_path: RenderPath,
+    /// names vulkan objects so validation messages tell which texture or retired
+    /// object was used after it was destroyed
+    pub debug_object_names: bool,
+    /// shader permutation drawn by model pipelines instead of the regular shading
+    pub debug_view: DebugView,
+    /// shows the shadow map and the depth buffer as pictures in the primary window
+    pub debug_overlay: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            msaa_samples: 1,
+            present_mode: PresentMode::Fifo,
+            shadow_quality: ShadowQuality::Medium,
+            render_scale: 1.0,
+            anisotropy: 16.0,
+            grass: false,
+            ambient_occlusion: AmbientOcclusion::Off,
+            temporal_aa: false,
+            reverse_z: false,
+            device: DeviceSelection::Auto,
+            render_path: RenderPath::Forward,
+            debug_object_names: false,
+            debug_view: DebugView::Off,
+            debug_overlay: false,
+        }
+    }
+}
+
+impl RenderSettings {
+    pub const MIN_RENDER_SCALE: f32 = 0.25;
+    pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+    /// Falls back to the defaults when the file is missing or invalid.
+    pub fn load(path: &str) -> Self {
+        let mut data = vec![];
+        let loaded = File::open(path).and_then(|mut f| f.read_to_end(&mut data));
+        if let Err(e) = loaded {
+            info!("no render settings at {} ({}), use defaults", path, e);
+            return Self::default();
+        }
+
+        match ron::de::from_bytes::<RenderSettings>(&data) {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("failed to parse render settings {}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        let mut f = File::create(path)?;
+        f.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn msaa(&self) -> vk::SampleCountFlags {
+        match self.msaa_samples {
+            0 | 1 => vk::SampleCountFlags::TYPE_1,
+            2 => vk::SampleCountFlags::TYPE_2,
+            3 | 4 => vk::SampleCountFlags::TYPE_4,
+            _ => vk::SampleCountFlags::TYPE_8,
+        }
+    }
+
+    pub fn clamped_render_scale(&self) -> f32 {
+        self.render_scale.max(Self::MIN_RENDER_SCALE).min(Self::MAX_RENDER_SCALE)
+    }
+}
+
+/// Sent after the scene render targets were recreated, users holding their
+/// views or sharing the render pass must rebuild.
+pub struct RenderTargetsRecreatedEvent {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_selection_parses_types_indices_and_names() {
+        assert_eq!(DeviceSelection::parse("auto"), Some(DeviceSelection::Auto));
+        assert_eq!(DeviceSelection::parse(" Discrete "), Some(DeviceSelection::Type(DeviceType::Discrete)));
+        assert_eq!(DeviceSelection::parse("CPU"), Some(DeviceSelection::Type(DeviceType::Cpu)));
+        assert_eq!(DeviceSelection::parse("1"), Some(DeviceSelection::Index(1)));
+        assert_eq!(DeviceSelection::parse("GeForce RTX"), Some(DeviceSelection::Name("GeForce RTX".to_string())));
+        assert_eq!(DeviceSelection::parse("-1"), Some(DeviceSelection::Name("-1".to_string())));
+        assert_eq!(DeviceSelection::parse("  "), None);
+    }
+
+    #[test]
+    fn device_selection_from_env() {
+        std::env::set_var(RENDER_DEVICE_ENV, "integrated");
+        assert_eq!(DeviceSelection::from_env(), Some(DeviceSelection::Type(DeviceType::Integrated)));
+        std::env::remove_var(RENDER_DEVICE_ENV);
+        assert_eq!(DeviceSelection::from_env(), None);
+    }
+}
@@ -8,12 +8,16 @@ pub struct RenderStatistic {
 
 impl RenderStatistic {
     pub fn destroy(&mut self, device: &ash::Device) {
+        if self.query_pool == vk::QueryPool::null() {
+            return;
+        }
         unsafe {
             device.destroy_query_pool(self.query_pool, None);
         }
     }
 
-    pub fn create(device: &ash::Device) -> Self {
+    /// Without device support the pool stays null and no statistics are collected.
+    pub fn create(device: &ash::Device, supported: bool) -> Self {
         let stats_names = vec![
             "Input assembly vertex count",
             "Input assembly primitives count",
@@ -26,6 +30,13 @@ impl RenderStatistic {
         ];
 
         let stats_values = vec![0u64; stats_names.len() as _];
+        if !supported {
+            return Self {
+                stats_names,
+                stats_values,
+                query_pool: vk::QueryPool::null(),
+            };
+        }
 
         let qci = vk::QueryPoolCreateInfo::builder()
             .query_type(vk::QueryType::PIPELINE_STATISTICS)
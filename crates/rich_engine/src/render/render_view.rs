@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use ash::vk;
+use bevy::prelude::*;
+use bevy::window::WindowId;
+use crate::render::camera::{Camera, CameraTarget};
+use crate::render::forward_render::{ForwardRenderPass, SceneTarget};
+use crate::render::render_context::{RenderContext, PerFrameData};
+use crate::render::uniform::UniformObject;
+use crate::render::window_surface::WindowSurface;
+
+/// Everything the draw pass needs from one camera this frame.
+pub struct RenderView {
+    pub camera: Entity,
+    pub priority: i32,
+    pub clear_color: Vec4,
+    /// pixel rect inside the target
+    pub rect: vk::Rect2D,
+    pub frame_descriptor_set: vk::DescriptorSet,
+    /// projection times view of the camera
+    pub view_proj: Mat4,
+    pub offscreen: bool,
+    /// `None` for offscreen views
+    pub window: Option<WindowId>,
+    pub is_main: bool,
+}
+
+/// Per camera uniforms and offscreen targets. The main camera uses the context
+/// per frame uniform, which compute passes and third party renderers read.
+#[derive(Default)]
+pub struct RenderViews {
+    uniforms: HashMap<Entity, UniformObject<PerFrameData>>,
+    targets: HashMap<Entity, SceneTarget>,
+    views: Vec<RenderView>,
+    /// unjittered view projection and projection jitter of each camera in the previous frame
+    history: HashMap<Entity, (Mat4, Vec2)>,
+    frame_index: u32,
+}
+
+impl RenderViews {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        for (_, mut uniform) in self.uniforms.drain() {
+            uniform.destroy(context);
+        }
+        self.destroy_targets(context);
+        self.views.clear();
+    }
+
+    /// Offscreen targets are bound to the forward render pass, they are created
+    /// again on the next frame.
+    pub fn destroy_targets(&mut self, context: &RenderContext) {
+        for (_, mut target) in self.targets.drain() {
+            target.destroy(context);
+        }
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.views.clear();
+        self.frame_index = self.frame_index.wrapping_add(1);
+    }
+
+    /// Counts frames, drives the projection jitter sequence.
+    pub fn frame_index(&self) -> u32 {
+        self.frame_index
+    }
+
+    /// Stores the unjittered view projection and the jitter of a camera for this frame
+    /// and returns those of the previous frame, the given ones for a new camera.
+    pub fn swap_history(&mut self, entity: Entity, view_proj: Mat4, jitter: Vec2) -> (Mat4, Vec2) {
+        self.history.insert(entity, (view_proj, jitter)).unwrap_or((view_proj, jitter))
+    }
+
+    /// Returns the pixel rect of the camera, creating its offscreen target if needed.
+    /// `None` when the target window is not rendered or the target can not be created.
+    pub fn prepare_camera(&mut self, context: &mut RenderContext, forward: &ForwardRenderPass,
+                          windows: &HashMap<WindowId, WindowSurface>,
+                          entity: Entity, camera: &Camera) -> Option<vk::Rect2D> {
+        let extent = match camera.target {
+            CameraTarget::Window(id) if id.is_primary() => forward.get_extent(),
+            CameraTarget::Window(id) => match windows.get(&id) {
+                Some(window) => window.get_target().get_extent(),
+                None => return None,
+            },
+            CameraTarget::Texture { width, height } => {
+                let extent = vk::Extent2D { width: width.max(1), height: height.max(1) };
+                let outdated = self.targets.get(&entity).map_or(true, |t| t.get_extent() != extent);
+                if outdated {
+                    if let Some(old) = self.targets.remove(&entity) {
+                        context.defer_destroy("camera_target", old);
+                    }
+                    match forward.create_target(context, extent, "camera_target") {
+                        Ok(target) => {
+                            self.targets.insert(entity, target);
+                        }
+                        Err(e) => {
+                            error!("failed to create camera target: {}", e);
+                            return None;
+                        }
+                    }
+                }
+                extent
+            }
+        };
+
+        Some(camera.viewport.to_rect(extent))
+    }
+
+    pub fn push_view(&mut self, context: &mut RenderContext, entity: Entity, camera: &Camera, rect: vk::Rect2D,
+                     data: PerFrameData, is_main: bool) {
+        let frame_descriptor_set = if is_main {
+            let mut pf = std::mem::take(&mut context.per_frame_uniform);
+            let uo = pf.as_mut().unwrap();
+            uo.upload_data_2_device(context, data);
+            let set = uo.descriptor_set;
+            context.per_frame_uniform = pf;
+            set
+        } else {
+            // same layout as the context uniform so the sets are compatible with every pipeline
+            let uniform = self.uniforms.entry(entity).or_insert_with(|| {
+                UniformObject::<PerFrameData>::create(context, data, vk::DescriptorType::UNIFORM_BUFFER,
+                                                      vk::ShaderStageFlags::VERTEX |
+                                                          vk::ShaderStageFlags::FRAGMENT |
+                                                          vk::ShaderStageFlags::TESSELLATION_EVALUATION |
+                                                          vk::ShaderStageFlags::COMPUTE)
+            });
+            uniform.upload_data_2_device(context, data);
+            uniform.descriptor_set
+        };
+
+        self.views.push(RenderView {
+            camera: entity,
+            priority: camera.priority,
+            clear_color: camera.clear_color,
+            rect,
+            frame_descriptor_set,
+            view_proj: data.proj * data.view,
+            offscreen: matches!(camera.target, CameraTarget::Texture { .. }),
+            window: match camera.target {
+                CameraTarget::Window(id) => Some(id),
+                CameraTarget::Texture { .. } => None,
+            },
+            is_main,
+        });
+    }
+
+    /// Drops resources of cameras that did not render this frame.
+    pub fn end_prepare(&mut self, context: &mut RenderContext) {
+        let views = &self.views;
+        self.history.retain(|e, _| views.iter().any(|v| v.camera == *e));
+        let stale_uniforms = self.uniforms.keys().filter(|e| !views.iter().any(|v| !v.is_main && v.camera == **e))
+            .copied().collect::<Vec<_>>();
+        let stale_targets = self.targets.keys().filter(|e| !views.iter().any(|v| v.offscreen && v.camera == **e))
+            .copied().collect::<Vec<_>>();
+        for entity in stale_uniforms {
+            context.defer_destroy("camera_uniform", self.uniforms.remove(&entity).unwrap());
+        }
+        for entity in stale_targets {
+            context.defer_destroy("camera_target", self.targets.remove(&entity).unwrap());
+        }
+    }
+
+    /// Views sorted by priority, offscreen ones first.
+    pub fn sorted_views(&self) -> Vec<&RenderView> {
+        let mut views = self.views.iter().collect::<Vec<_>>();
+        views.sort_by_key(|v| (!v.offscreen, v.priority));
+        views
+    }
+
+    pub fn get_target(&self, camera: Entity) -> Option<&SceneTarget> {
+        self.targets.get(&camera)
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use ash::vk;
+use crate::render::render_error::{RenderError, RenderResult};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    /// false samples the base level only
+    pub use_mipmaps: bool,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            use_mipmaps: true,
+        }
+    }
+}
+
+fn has_mipmaps(filter: gltf::texture::MinFilter) -> bool {
+    filter != gltf::texture::MinFilter::Linear &&
+        filter != gltf::texture::MinFilter::Nearest
+}
+
+fn map_mipmap_filter(min_filter: gltf::texture::MinFilter) -> vk::SamplerMipmapMode {
+    match min_filter {
+        gltf::texture::MinFilter::Nearest => vk::SamplerMipmapMode::NEAREST,
+        gltf::texture::MinFilter::Linear => vk::SamplerMipmapMode::NEAREST,
+        gltf::texture::MinFilter::NearestMipmapNearest => vk::SamplerMipmapMode::NEAREST,
+        gltf::texture::MinFilter::LinearMipmapNearest => vk::SamplerMipmapMode::NEAREST,
+        gltf::texture::MinFilter::NearestMipmapLinear => vk::SamplerMipmapMode::LINEAR,
+        gltf::texture::MinFilter::LinearMipmapLinear => vk::SamplerMipmapMode::LINEAR,
+    }
+}
+
+fn map_mag_filter(mag_filter: gltf::texture::MagFilter) -> vk::Filter {
+    match mag_filter {
+        gltf::texture::MagFilter::Nearest => vk::Filter::NEAREST,
+        gltf::texture::MagFilter::Linear => vk::Filter::LINEAR,
+    }
+}
+
+fn map_min_filter(min_filter: gltf::texture::MinFilter) -> vk::Filter {
+    match min_filter {
+        gltf::texture::MinFilter::Nearest => vk::Filter::NEAREST,
+        gltf::texture::MinFilter::Linear => vk::Filter::LINEAR,
+        gltf::texture::MinFilter::NearestMipmapNearest => vk::Filter::NEAREST,
+        gltf::texture::MinFilter::LinearMipmapNearest => vk::Filter::LINEAR,
+        gltf::texture::MinFilter::NearestMipmapLinear => vk::Filter::NEAREST,
+        gltf::texture::MinFilter::LinearMipmapLinear => vk::Filter::LINEAR,
+    }
+}
+
+fn map_wrap_mode(wrap_mode: gltf::texture::WrappingMode) -> vk::SamplerAddressMode {
+    match wrap_mode {
+        gltf::texture::WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        gltf::texture::WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        gltf::texture::WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    }
+}
+
+impl SamplerDesc {
+    /// glTF leaves the filters to the implementation when they are undefined,
+    /// we use trilinear filtering in that case.
+    pub fn from_gltf(gltf_sampler: &gltf::texture::Sampler) -> Self {
+        let min_filter = gltf_sampler.min_filter().unwrap_or(gltf::texture::MinFilter::LinearMipmapLinear);
+        let mag_filter = gltf_sampler.mag_filter().unwrap_or(gltf::texture::MagFilter::Linear);
+
+        Self {
+            mag_filter: map_mag_filter(mag_filter),
+            min_filter: map_min_filter(min_filter),
+            mipmap_mode: map_mipmap_filter(min_filter),
+            address_mode_u: map_wrap_mode(gltf_sampler.wrap_s()),
+            address_mode_v: map_wrap_mode(gltf_sampler.wrap_t()),
+            use_mipmaps: has_mipmaps(min_filter),
+        }
+    }
+}
+
+/// Samplers shared by all the textures, keyed by their description and the
+/// anisotropy level they were created with.
+#[derive(Default)]
+pub struct SamplerCache {
+    samplers: HashMap<(SamplerDesc, u32), vk::Sampler>,
+    max_anisotropy: f32,
+}
+
+impl SamplerCache {
+    pub fn create(max_anisotropy: f32) -> Self {
+        Self {
+            samplers: HashMap::new(),
+            max_anisotropy,
+        }
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for (_, sampler) in self.samplers.drain() {
+            unsafe {
+                device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+
+    pub fn get_max_anisotropy(&self) -> f32 {
+        self.max_anisotropy
+    }
+
+    /// Destroys the samplers created with another anisotropy level, the device must be
+    /// idle. Textures using them get a new sampler with `ModelTexture::refresh_sampler`
+    /// and their descriptors must be rebuilt. Samplers without mipmaps are never
+    /// anisotropic and are kept.
+    pub fn set_max_anisotropy(&mut self, device: &ash::Device, max_anisotropy: f32) {
+        self.max_anisotropy = max_anisotropy;
+        let mut stale = vec![];
+        for (key, sampler) in self.samplers.iter() {
+            if key.1 != self.anisotropy(&key.0).to_bits() {
+                stale.push((*key, *sampler));
+            }
+        }
+        for (key, sampler) in stale {
+            self.samplers.remove(&key);
+            unsafe {
+                device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+
+    fn anisotropy(&self, desc: &SamplerDesc) -> f32 {
+        // anisotropic filtering only matters across mip levels
+        if desc.use_mipmaps { self.max_anisotropy.max(1.0) } else { 1.0 }
+    }
+
+    pub fn get(&mut self, device: &ash::Device, desc: &SamplerDesc) -> RenderResult<vk::Sampler> {
+        let anisotropy = self.anisotropy(desc);
+        let key = (*desc, anisotropy.to_bits());
+        if let Some(sampler) = self.samplers.get(&key) {
+            return Ok(*sampler);
+        }
+
+        let max_lod = if desc.use_mipmaps { vk::LOD_CLAMP_NONE } else { 0.0 };
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .address_mode_u(desc.address_mode_u)
+            .address_mode_v(desc.address_mode_v)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .anisotropy_enable(anisotropy > 1.0)
+            .max_anisotropy(anisotropy)
+            .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+            .unnormalized_coordinates(false)
+            .compare_enable(false)
+            .compare_op(vk::CompareOp::ALWAYS)
+            .mipmap_mode(desc.mipmap_mode)
+            .mip_lod_bias(0.0)
+            .min_lod(0.0)
+            .max_lod(max_lod);
+
+        let sampler = unsafe {
+            device
+                .create_sampler(&sampler_info, None)
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+        self.samplers.insert(key, sampler);
+        Ok(sampler)
+    }
+}
@@ -8,9 +8,13 @@ use std::path::Path;
 use std::io::Cursor;
 use bevy::prelude::*;
 use bevy::reflect::List;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::shader_reflect::ShaderReflection;
 
 pub struct ShaderCollection {
     modules: HashMap<u64, vk::ShaderModule>,
+    /// reflection of each module, by the same id
+    reflections: HashMap<u64, ShaderReflection>,
     default_entry_name: CString,
 }
 
@@ -20,14 +24,14 @@ impl Default for ShaderCollection {
     }
 }
 
-fn load_from_file(path: &str) -> Cursor<Vec<u8>> {
+fn load_from_file(path: &str) -> std::io::Result<Cursor<Vec<u8>>> {
     use std::fs::File;
     use std::io::Read;
     let mut buf = Vec::new();
     let fullpath = &Path::new(path);
-    let mut file = File::open(&fullpath).unwrap();
-    file.read_to_end(&mut buf).unwrap();
-    Cursor::new(buf)
+    let mut file = File::open(&fullpath)?;
+    file.read_to_end(&mut buf)?;
+    Ok(Cursor::new(buf))
 }
 
 
@@ -36,11 +40,13 @@ impl ShaderCollection {
         let entry_point_name = CString::new("main").unwrap();
         ShaderCollection {
             modules: HashMap::default(),
+            reflections: HashMap::default(),
             default_entry_name: entry_point_name,
         }
     }
 
     pub fn destroy(&mut self, context: &mut RenderContext) {
+        self.reflections.clear();
         let map = std::mem::take(&mut self.modules);
         for (_, shader) in map {
             unsafe {
@@ -50,23 +56,56 @@ impl ShaderCollection {
     }
 
     pub fn create_shader_stage(&mut self, device: &ash::Device, name: &str,
-                               defines: &[&str], stage_flags: vk::ShaderStageFlags) -> vk::PipelineShaderStageCreateInfo {
-        let sm = self.create_shader(device, name, defines);
-        vk::PipelineShaderStageCreateInfo::builder().stage(stage_flags).module(sm).name(&self.default_entry_name).build()
+                               defines: &[&str], stage_flags: vk::ShaderStageFlags) -> RenderResult<vk::PipelineShaderStageCreateInfo> {
+        let sm = self.create_shader(device, name, defines)?;
+        Ok(vk::PipelineShaderStageCreateInfo::builder().stage(stage_flags).module(sm).name(&self.default_entry_name).build())
     }
 
-    pub fn create_shader(&mut self, device: &ash::Device, name: &str, defines: &[&str]) -> vk::ShaderModule {
-        use std::process::Command;
+    /// Reflection of the shader `name` compiled with `defines`, compiling it if needed.
+    pub fn reflect(&mut self, device: &ash::Device, name: &str, defines: &[&str]) -> RenderResult<&ShaderReflection> {
+        self.create_shader(device, name, defines)?;
+        Ok(&self.reflections[&Self::shader_id(name, defines)])
+    }
+
+    /// Merged reflection of the stages `names` of one pipeline, compiled with the same defines.
+    pub fn reflect_stages(&mut self, device: &ash::Device, names: &[&str], defines: &[&str]) -> RenderResult<ShaderReflection> {
+        let mut reflection = ShaderReflection::default();
+        for name in names {
+            let stage = self.reflect(device, name, defines)?;
+            reflection.merge(stage)
+                .map_err(|message| RenderError::ShaderInterface { name: names.join(" / "), message })?;
+        }
+        Ok(reflection)
+    }
+
+    /// Merged reflection of the stages `names`, with the hand-written layout of each of
+    /// `sets` checked against the bindings the shaders declare in it.
+    pub fn check_stages(&mut self, device: &ash::Device, names: &[&str], defines: &[&str],
+                        sets: &[(u32, &[vk::DescriptorSetLayoutBinding])]) -> RenderResult<ShaderReflection> {
+        let reflection = self.reflect_stages(device, names, defines)?;
+        let interface_error = |message: String| RenderError::ShaderInterface { name: names.join(" / "), message };
+        for (set, bindings) in sets {
+            reflection.check_set_layout(*set, bindings).map_err(&interface_error)?;
+        }
+        Ok(reflection)
+    }
 
+    fn shader_id(name: &str, defines: &[&str]) -> u64 {
         let mut s = DefaultHasher::new();
         name.hash(&mut s);
         for d in defines {
             d.hash(&mut s);
         }
-        let id = s.finish();
+        s.finish()
+    }
+
+    pub fn create_shader(&mut self, device: &ash::Device, name: &str, defines: &[&str]) -> RenderResult<vk::ShaderModule> {
+        use std::process::Command;
+
+        let id = Self::shader_id(name, defines);
 
         if let Some(sd) = self.modules.get(&id) {
-            return *sd;
+            return Ok(*sd);
         }
 
         let source_path = format!("assets/shaders/{}.glsl", name);
@@ -78,8 +117,9 @@ impl ShaderCollection {
             args.push(d.as_str());
         }
 
+        let compile_error = |message: String| RenderError::ShaderCompile { name: name.to_string(), message };
         let output = Command::new("cmd").args(&args)
-            .output().expect("failed to execute command");
+            .output().map_err(|e| compile_error(format!("failed to run glslc: {}", e)))?;
 
         for out in String::from_utf8(output.stdout).iter() {
             if out.len() > 0 {
@@ -87,23 +127,23 @@ impl ShaderCollection {
             }
         }
 
-        let mut has_error = false;
-        for out in String::from_utf8(output.stderr).iter() {
-            if out.len() > 0 {
-                error!("compile info: {}", out);
-                has_error = true;
-            }
-        }
-
-        if has_error {
-            panic!("compile shader panic");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.is_empty() {
+            error!("compile info: {}", stderr);
+            return Err(compile_error(stderr.into_owned()));
         }
 
-        let mut cursor = load_from_file(&out_path);
-        let res = ash::util::read_spv(&mut cursor).expect(format!("failed to read spv {}", source_path).as_str());
+        let mut cursor = load_from_file(&out_path)
+            .map_err(|e| compile_error(format!("failed to read {}: {}", out_path, e)))?;
+        let res = ash::util::read_spv(&mut cursor)
+            .map_err(|e| compile_error(format!("failed to read spv {}: {}", source_path, e)))?;
+        let reflection = ShaderReflection::parse(&res)
+            .map_err(|e| compile_error(format!("failed to reflect {}: {}", out_path, e)))?;
         let create_info = vk::ShaderModuleCreateInfo::builder().code(res.as_slice()).build();
-        let sd = unsafe { device.create_shader_module(&create_info, None).unwrap() };
+        let sd = unsafe { device.create_shader_module(&create_info, None) }
+            .map_err(RenderError::vk("vkCreateShaderModule"))?;
         self.modules.insert(id, sd);
-        sd
+        self.reflections.insert(id, reflection);
+        Ok(sd)
     }
 }
\ No newline at end of file
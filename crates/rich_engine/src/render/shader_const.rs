@@ -5,14 +5,25 @@ pub const LOCATION_IN_NORMAL: u32 = 1;
 pub const LOCATION_IN_TEX_COORD: u32 = 2;
 pub const LOCATION_IN_WEIGHTS: u32 = 3;
 pub const LOCATION_IN_JOINTS: u32 = 4;
+pub const LOCATION_IN_TANGENT: u32 = 5;
+pub const LOCATION_IN_COLOR: u32 = 6;
+pub const LOCATION_IN_TEX_COORD1: u32 = 7;
 
 lazy_static! {
     static ref DEFINE_MAP: HashMap<u32,&'static str> = [
         (LOCATION_IN_TEX_COORD, "IN_TEX_COORD"),
         (LOCATION_IN_NORMAL, "IN_NORMAL"),
+        (LOCATION_IN_TANGENT, "IN_TANGENT"),
+        (LOCATION_IN_COLOR, "IN_COLOR"),
+        (LOCATION_IN_TEX_COORD1, "IN_TEX_COORD1"),
     ].iter().copied().collect();
 }
 
 pub fn get_shader_define_name(location: u32) -> Option<&'static str> {
     DEFINE_MAP.get(&location).and_then(|s| { Some(*s) })
 }
+
+/// the attribute is declared as `uvec`/`ivec` in the shader
+pub fn is_integer_location(location: u32) -> bool {
+    location == LOCATION_IN_JOINTS
+}
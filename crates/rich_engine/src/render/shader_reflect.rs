@@ -0,0 +1,654 @@
+use std::collections::HashMap;
+use ash::vk;
+use crate::render::vertex_layout::VertexLayout;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NumericClass {
+    Float,
+    SInt,
+    UInt,
+}
+
+#[derive(Clone, Debug)]
+enum SpvType {
+    Scalar { class: NumericClass, width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+#[derive(Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    array_stride: Option<u32>,
+    built_in: bool,
+    buffer_block: bool,
+}
+
+/// A descriptor binding declared by a shader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+}
+
+/// An input of a vertex shader, `format` is the 32 bit format of its GLSL type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflectedInput {
+    pub location: u32,
+    pub format: vk::Format,
+}
+
+/// Resources declared by the SPIR-V of one or more stages of a pipeline, to build
+/// descriptor set layouts and push constant ranges from instead of mirroring the GLSL
+/// by hand.
+#[derive(Clone, Debug, Default)]
+pub struct ShaderReflection {
+    pub stages: vk::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    /// bytes of the push constant block used by each stage
+    pub push_constants: Vec<vk::PushConstantRange>,
+    /// vertex stage inputs sorted by location, built-ins excluded
+    pub inputs: Vec<ReflectedInput>,
+}
+
+impl ShaderReflection {
+    pub fn parse(words: &[u32]) -> Result<Self, String> {
+        if words.len() < 5 || words[0] != SPIRV_MAGIC {
+            return Err("not a SPIR-V module".to_string());
+        }
+
+        let mut stages = vk::ShaderStageFlags::empty();
+        let mut types = HashMap::new();
+        let mut constants = HashMap::new();
+        let mut decorations: HashMap<u32, Decorations> = HashMap::new();
+        let mut member_offsets = HashMap::new();
+        let mut matrix_strides = HashMap::new();
+        let mut variables = Vec::new();
+
+        let mut index = 5;
+        while index < words.len() {
+            let word_count = (words[index] >> 16) as usize;
+            let opcode = words[index] & 0xffff;
+            if word_count == 0 || index + word_count > words.len() {
+                return Err(format!("truncated instruction at word {}", index));
+            }
+            let operands = &words[index + 1..index + word_count];
+            index += word_count;
+
+            let operand = |i: usize| operands.get(i).copied()
+                .ok_or_else(|| format!("missing operand {} of opcode {}", i, opcode));
+            match opcode {
+                OP_ENTRY_POINT => stages |= execution_model_stage(operand(0)?),
+                OP_TYPE_BOOL => {
+                    types.insert(operand(0)?, SpvType::Scalar { class: NumericClass::UInt, width: 32 });
+                }
+                OP_TYPE_INT => {
+                    let class = if operand(2)? == 0 { NumericClass::UInt } else { NumericClass::SInt };
+                    types.insert(operand(0)?, SpvType::Scalar { class, width: operand(1)? });
+                }
+                OP_TYPE_FLOAT => {
+                    types.insert(operand(0)?, SpvType::Scalar { class: NumericClass::Float, width: operand(1)? });
+                }
+                OP_TYPE_VECTOR => {
+                    types.insert(operand(0)?, SpvType::Vector { component: operand(1)?, count: operand(2)? });
+                }
+                OP_TYPE_MATRIX => {
+                    types.insert(operand(0)?, SpvType::Matrix { column: operand(1)?, count: operand(2)? });
+                }
+                OP_TYPE_IMAGE => {
+                    types.insert(operand(0)?, SpvType::Image { dim: operand(2)?, sampled: operand(6)? });
+                }
+                OP_TYPE_SAMPLER => {
+                    types.insert(operand(0)?, SpvType::Sampler);
+                }
+                OP_TYPE_SAMPLED_IMAGE => {
+                    types.insert(operand(0)?, SpvType::SampledImage);
+                }
+                OP_TYPE_ARRAY => {
+                    // the length is the id of a constant, resolved once everything is read
+                    types.insert(operand(0)?, SpvType::Array { element: operand(1)?, length: operand(2)? });
+                }
+                OP_TYPE_RUNTIME_ARRAY => {
+                    types.insert(operand(0)?, SpvType::RuntimeArray { element: operand(1)? });
+                }
+                OP_TYPE_STRUCT => {
+                    types.insert(operand(0)?, SpvType::Struct { members: operands[1..].to_vec() });
+                }
+                OP_TYPE_POINTER => {
+                    types.insert(operand(0)?, SpvType::Pointer { pointee: operand(2)? });
+                }
+                OP_CONSTANT => {
+                    constants.insert(operand(1)?, operand(2)?);
+                }
+                OP_VARIABLE => variables.push((operand(0)?, operand(1)?, operand(2)?)),
+                OP_DECORATE => {
+                    let decoration = decorations.entry(operand(0)?).or_default();
+                    match operand(1)? {
+                        DECORATION_BUFFER_BLOCK => decoration.buffer_block = true,
+                        DECORATION_BUILT_IN => decoration.built_in = true,
+                        DECORATION_ARRAY_STRIDE => decoration.array_stride = Some(operand(2)?),
+                        DECORATION_LOCATION => decoration.location = Some(operand(2)?),
+                        DECORATION_BINDING => decoration.binding = Some(operand(2)?),
+                        DECORATION_DESCRIPTOR_SET => decoration.set = Some(operand(2)?),
+                        _ => {}
+                    }
+                }
+                OP_MEMBER_DECORATE => {
+                    let member = (operand(0)?, operand(1)?);
+                    match operand(2)? {
+                        DECORATION_OFFSET => { member_offsets.insert(member, operand(3)?); }
+                        DECORATION_MATRIX_STRIDE => { matrix_strides.insert(member, operand(3)?); }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let module = Module { types, constants, decorations, member_offsets, matrix_strides };
+        let mut reflection = ShaderReflection { stages, ..Default::default() };
+        for (type_id, id, storage) in variables {
+            let pointee = match module.types.get(&type_id) {
+                Some(SpvType::Pointer { pointee }) => *pointee,
+                _ => return Err(format!("variable %{} is not a pointer", id)),
+            };
+            let decoration = module.decorations.get(&id);
+            match storage {
+                STORAGE_INPUT if stages.contains(vk::ShaderStageFlags::VERTEX) => {
+                    let location = match decoration {
+                        Some(Decorations { built_in: false, location: Some(location), .. }) => *location,
+                        _ => continue,
+                    };
+                    reflection.inputs.extend(module.input_formats(pointee)?.into_iter().enumerate()
+                        .map(|(i, format)| ReflectedInput { location: location + i as u32, format }));
+                }
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let binding = match decoration.and_then(|d| d.binding) {
+                        Some(binding) => binding,
+                        None => continue,
+                    };
+                    let (descriptor_type, count) = module.descriptor_type(storage, pointee)?;
+                    reflection.bindings.push(ReflectedBinding {
+                        set: decoration.and_then(|d| d.set).unwrap_or(0),
+                        binding,
+                        descriptor_type,
+                        count,
+                        stages,
+                    });
+                }
+                STORAGE_PUSH_CONSTANT => {
+                    let (offset, end) = module.block_range(pointee)?;
+                    reflection.push_constants.push(vk::PushConstantRange::builder()
+                        .stage_flags(stages)
+                        .offset(offset)
+                        .size(end - offset)
+                        .build());
+                }
+                _ => {}
+            }
+        }
+        reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+        reflection.inputs.sort_by_key(|i| i.location);
+        Ok(reflection)
+    }
+
+    /// Adds the resources of another stage of the same pipeline. Fails when both
+    /// declare a binding with different types.
+    pub fn merge(&mut self, other: &ShaderReflection) -> Result<(), String> {
+        self.stages |= other.stages;
+        for binding in &other.bindings {
+            match self.bindings.iter_mut().find(|b| b.set == binding.set && b.binding == binding.binding) {
+                Some(b) if b.descriptor_type != binding.descriptor_type || b.count != binding.count => {
+                    return Err(format!("set {} binding {} is {:?}[{}] in {:?} but {:?}[{}] in {:?}",
+                                       b.set, b.binding, b.descriptor_type, b.count, b.stages,
+                                       binding.descriptor_type, binding.count, binding.stages));
+                }
+                Some(b) => b.stages |= binding.stages,
+                None => self.bindings.push(*binding),
+            }
+        }
+        self.bindings.sort_by_key(|b| (b.set, b.binding));
+        self.push_constants.extend_from_slice(&other.push_constants);
+        if !other.inputs.is_empty() {
+            self.inputs = other.inputs.clone();
+        }
+        Ok(())
+    }
+
+    pub fn set_layout_bindings(&self, set: u32) -> Vec<vk::DescriptorSetLayoutBinding> {
+        self.bindings.iter().filter(|b| b.set == set).map(|b| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(b.binding)
+                .descriptor_type(b.descriptor_type)
+                .descriptor_count(b.count)
+                .stage_flags(b.stages)
+                .build()
+        }).collect()
+    }
+
+    pub fn has_binding(&self, set: u32, binding: u32) -> bool {
+        self.bindings.iter().any(|b| b.set == set && b.binding == binding)
+    }
+
+    /// One range per stage, for `vk::PipelineLayoutCreateInfo`.
+    pub fn push_constant_ranges(&self) -> Vec<vk::PushConstantRange> {
+        let mut ranges = self.push_constants.clone();
+        ranges.sort_by_key(|r| (r.offset, r.stage_flags.as_raw()));
+        ranges
+    }
+
+    /// The stages whose push constant range overlaps `offset..offset + size`, which is
+    /// what `cmd_push_constants` must be given for those bytes.
+    pub fn push_constant_stages(&self, offset: u32, size: u32) -> vk::ShaderStageFlags {
+        self.push_constants.iter()
+            .filter(|r| r.offset < offset + size && offset < r.offset + r.size)
+            .fold(vk::ShaderStageFlags::empty(), |stages, r| stages | r.stage_flags)
+    }
+
+    /// Checks that a hand-written set layout covers every binding the shaders declare
+    /// in `set` with the same type and stages.
+    pub fn check_set_layout(&self, set: u32, bindings: &[vk::DescriptorSetLayoutBinding]) -> Result<(), String> {
+        for reflected in self.bindings.iter().filter(|b| b.set == set) {
+            match bindings.iter().find(|b| b.binding == reflected.binding) {
+                None => return Err(format!("set {} binding {} is not in the layout", set, reflected.binding)),
+                Some(b) if b.descriptor_type != reflected.descriptor_type => {
+                    return Err(format!("set {} binding {} is {:?} in the shader but {:?} in the layout",
+                                       set, reflected.binding, reflected.descriptor_type, b.descriptor_type));
+                }
+                Some(b) if !b.stage_flags.contains(reflected.stages) => {
+                    return Err(format!("set {} binding {} is used by {:?} but only visible to {:?}",
+                                       set, reflected.binding, reflected.stages, b.stage_flags));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every vertex input is fed by an attribute of the same numeric type.
+    /// Component counts may differ, vulkan fills missing ones.
+    pub fn check_vertex_attributes(&self, attributes: &[vk::VertexInputAttributeDescription]) -> Result<(), String> {
+        for input in &self.inputs {
+            let attribute = attributes.iter().find(|a| a.location == input.location)
+                .ok_or_else(|| format!("no vertex attribute for input location {} ({:?})", input.location, input.format))?;
+            if format_class(attribute.format) != format_class(input.format) {
+                return Err(format!("input location {} is {:?} but the attribute is {:?}",
+                                   input.location, input.format, attribute.format));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_vertex_layout(&self, layout: &VertexLayout) -> Result<(), String> {
+        self.check_vertex_attributes(&layout.build_vk_attributes())
+    }
+}
+
+struct Module {
+    types: HashMap<u32, SpvType>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+    member_offsets: HashMap<(u32, u32), u32>,
+    matrix_strides: HashMap<(u32, u32), u32>,
+}
+
+impl Module {
+    fn get(&self, id: u32) -> Result<&SpvType, String> {
+        self.types.get(&id).ok_or_else(|| format!("unknown type %{}", id))
+    }
+
+    fn array_length(&self, length: u32) -> Result<u32, String> {
+        self.constants.get(&length).copied()
+            .ok_or_else(|| format!("array length %{} is not a constant", length))
+    }
+
+    fn descriptor_type(&self, storage: u32, type_id: u32) -> Result<(vk::DescriptorType, u32), String> {
+        let (type_id, count) = match self.get(type_id)? {
+            SpvType::Array { element, length } => (*element, self.array_length(*length)?),
+            SpvType::RuntimeArray { element } => (*element, 1),
+            _ => (type_id, 1),
+        };
+        let descriptor_type = match self.get(type_id)? {
+            SpvType::SampledImage => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            SpvType::Sampler => vk::DescriptorType::SAMPLER,
+            SpvType::Image { dim: DIM_BUFFER, sampled: 2 } => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+            SpvType::Image { dim: DIM_BUFFER, .. } => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+            SpvType::Image { dim: DIM_SUBPASS_DATA, .. } => vk::DescriptorType::INPUT_ATTACHMENT,
+            SpvType::Image { sampled: 2, .. } => vk::DescriptorType::STORAGE_IMAGE,
+            SpvType::Image { .. } => vk::DescriptorType::SAMPLED_IMAGE,
+            SpvType::Struct { .. } if storage == STORAGE_STORAGE_BUFFER => vk::DescriptorType::STORAGE_BUFFER,
+            SpvType::Struct { .. } if matches!(self.decorations.get(&type_id), Some(d) if d.buffer_block) => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            SpvType::Struct { .. } => vk::DescriptorType::UNIFORM_BUFFER,
+            other => return Err(format!("unsupported descriptor type {:?}", other)),
+        };
+        Ok((descriptor_type, count))
+    }
+
+    /// First and past the end byte of the members of a block.
+    fn block_range(&self, type_id: u32) -> Result<(u32, u32), String> {
+        let members = match self.get(type_id)? {
+            SpvType::Struct { members } => members,
+            _ => return Err(format!("push constant %{} is not a block", type_id)),
+        };
+        let mut range: Option<(u32, u32)> = None;
+        for (index, member) in members.iter().enumerate() {
+            let offset = self.member_offsets.get(&(type_id, index as u32)).copied().unwrap_or(0);
+            let end = offset + self.size_of(*member, self.matrix_strides.get(&(type_id, index as u32)).copied())?;
+            range = Some(match range {
+                Some((start, stop)) => (start.min(offset), stop.max(end)),
+                None => (offset, end),
+            });
+        }
+        Ok(range.unwrap_or((0, 0)))
+    }
+
+    fn size_of(&self, type_id: u32, matrix_stride: Option<u32>) -> Result<u32, String> {
+        Ok(match self.get(type_id)? {
+            SpvType::Scalar { width, .. } => width / 8,
+            SpvType::Vector { component, count } => self.size_of(*component, None)? * count,
+            SpvType::Matrix { column, count } => {
+                let stride = match matrix_stride {
+                    Some(stride) => stride,
+                    None => (self.size_of(*column, None)? + 15) & !15,
+                };
+                stride * count
+            }
+            SpvType::Array { element, length } => {
+                let stride = match self.decorations.get(&type_id).and_then(|d| d.array_stride) {
+                    Some(stride) => stride,
+                    None => self.size_of(*element, matrix_stride)?,
+                };
+                stride * self.array_length(*length)?
+            }
+            SpvType::Struct { .. } => self.block_range(type_id)?.1,
+            other => return Err(format!("type {:?} has no size", other)),
+        })
+    }
+
+    /// Formats of the locations a vertex input takes, one per matrix column.
+    fn input_formats(&self, type_id: u32) -> Result<Vec<vk::Format>, String> {
+        Ok(match self.get(type_id)? {
+            SpvType::Scalar { class, .. } => vec![vertex_format(*class, 1)],
+            SpvType::Vector { component, count } => match self.get(*component)? {
+                SpvType::Scalar { class, .. } => vec![vertex_format(*class, *count)],
+                other => return Err(format!("unsupported vector component {:?}", other)),
+            },
+            SpvType::Matrix { column, count } => {
+                let column = self.input_formats(*column)?;
+                (0..*count).map(|_| column[0]).collect()
+            }
+            other => return Err(format!("unsupported vertex input type {:?}", other)),
+        })
+    }
+}
+
+fn execution_model_stage(model: u32) -> vk::ShaderStageFlags {
+    match model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        _ => vk::ShaderStageFlags::empty(),
+    }
+}
+
+fn vertex_format(class: NumericClass, count: u32) -> vk::Format {
+    match (class, count) {
+        (NumericClass::Float, 1) => vk::Format::R32_SFLOAT,
+        (NumericClass::Float, 2) => vk::Format::R32G32_SFLOAT,
+        (NumericClass::Float, 3) => vk::Format::R32G32B32_SFLOAT,
+        (NumericClass::Float, _) => vk::Format::R32G32B32A32_SFLOAT,
+        (NumericClass::SInt, 1) => vk::Format::R32_SINT,
+        (NumericClass::SInt, 2) => vk::Format::R32G32_SINT,
+        (NumericClass::SInt, 3) => vk::Format::R32G32B32_SINT,
+        (NumericClass::SInt, _) => vk::Format::R32G32B32A32_SINT,
+        (NumericClass::UInt, 1) => vk::Format::R32_UINT,
+        (NumericClass::UInt, 2) => vk::Format::R32G32_UINT,
+        (NumericClass::UInt, 3) => vk::Format::R32G32B32_UINT,
+        (NumericClass::UInt, _) => vk::Format::R32G32B32A32_UINT,
+    }
+}
+
+/// How a shader reads an attribute of `format`, normalized and scaled formats read as floats.
+fn format_class(format: vk::Format) -> NumericClass {
+    match format {
+        vk::Format::R8_SINT | vk::Format::R8G8_SINT | vk::Format::R8G8B8_SINT | vk::Format::R8G8B8A8_SINT |
+        vk::Format::R16_SINT | vk::Format::R16G16_SINT | vk::Format::R16G16B16_SINT | vk::Format::R16G16B16A16_SINT |
+        vk::Format::R32_SINT | vk::Format::R32G32_SINT | vk::Format::R32G32B32_SINT | vk::Format::R32G32B32A32_SINT => {
+            NumericClass::SInt
+        }
+        vk::Format::R8_UINT | vk::Format::R8G8_UINT | vk::Format::R8G8B8_UINT | vk::Format::R8G8B8A8_UINT |
+        vk::Format::R16_UINT | vk::Format::R16G16_UINT | vk::Format::R16G16B16_UINT | vk::Format::R16G16B16A16_UINT |
+        vk::Format::R32_UINT | vk::Format::R32G32_UINT | vk::Format::R32G32B32_UINT | vk::Format::R32G32B32A32_UINT => {
+            NumericClass::UInt
+        }
+        _ => NumericClass::Float,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn egui_stage(spv: &[u8]) -> ShaderReflection {
+        let words = ash::util::read_spv(&mut std::io::Cursor::new(spv)).unwrap();
+        ShaderReflection::parse(&words).unwrap()
+    }
+
+    fn egui_vertex() -> ShaderReflection {
+        egui_stage(include_bytes!("../../../rich_editor/src/shaders/spv/vert.spv"))
+    }
+
+    fn egui_fragment() -> ShaderReflection {
+        egui_stage(include_bytes!("../../../rich_editor/src/shaders/spv/frag.spv"))
+    }
+
+    fn attribute(location: u32, format: vk::Format) -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription { location, binding: 0, format, offset: 0 }
+    }
+
+    fn uniform(set: u32, binding: u32, stages: vk::ShaderStageFlags) -> ReflectedBinding {
+        ReflectedBinding { set, binding, descriptor_type: vk::DescriptorType::UNIFORM_BUFFER, count: 1, stages }
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert!(ShaderReflection::parse(&[]).is_err());
+        assert!(ShaderReflection::parse(&[0xdead_beef, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn egui_vertex_stage() {
+        let vertex = egui_vertex();
+        assert_eq!(vertex.stages, vk::ShaderStageFlags::VERTEX);
+        assert!(vertex.bindings.is_empty());
+        // vec2 screen_size
+        assert_eq!(vertex.push_constant_ranges().len(), 1);
+        let range = vertex.push_constant_ranges()[0];
+        assert_eq!((range.stage_flags, range.offset, range.size), (vk::ShaderStageFlags::VERTEX, 0, 8));
+        assert_eq!(vertex.inputs, vec![
+            ReflectedInput { location: 0, format: vk::Format::R32G32_SFLOAT },
+            ReflectedInput { location: 1, format: vk::Format::R32G32_SFLOAT },
+            ReflectedInput { location: 2, format: vk::Format::R32G32B32A32_SFLOAT },
+        ]);
+    }
+
+    #[test]
+    fn egui_fragment_stage() {
+        let fragment = egui_fragment();
+        assert_eq!(fragment.stages, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(fragment.bindings, vec![ReflectedBinding {
+            set: 0,
+            binding: 0,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            count: 1,
+            stages: vk::ShaderStageFlags::FRAGMENT,
+        }]);
+        assert!(fragment.push_constants.is_empty());
+        // only vertex stage inputs are reflected
+        assert!(fragment.inputs.is_empty());
+    }
+
+    #[test]
+    fn egui_pipeline() {
+        let mut pipeline = egui_vertex();
+        pipeline.merge(&egui_fragment()).unwrap();
+        assert_eq!(pipeline.stages, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(pipeline.set_layout_bindings(0).len(), 1);
+        assert!(pipeline.has_binding(0, 0));
+        assert!(!pipeline.has_binding(1, 0));
+        assert_eq!(pipeline.push_constant_stages(0, 8), vk::ShaderStageFlags::VERTEX);
+        assert_eq!(pipeline.push_constant_stages(8, 4), vk::ShaderStageFlags::empty());
+        assert_eq!(pipeline.inputs.len(), 3);
+
+        // the egui vertex: position, uv and unorm color
+        let attributes = [
+            attribute(0, vk::Format::R32G32_SFLOAT),
+            attribute(1, vk::Format::R32G32_SFLOAT),
+            attribute(2, vk::Format::R8G8B8A8_UNORM),
+        ];
+        pipeline.check_vertex_attributes(&attributes).unwrap();
+
+        let layout = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build()];
+        pipeline.check_set_layout(0, &layout).unwrap();
+        let vertex_only = [vk::DescriptorSetLayoutBinding { stage_flags: vk::ShaderStageFlags::VERTEX, ..layout[0] }];
+        assert!(pipeline.check_set_layout(0, &vertex_only).is_err());
+        assert!(pipeline.check_set_layout(0, &[]).is_err());
+    }
+
+    #[test]
+    fn merge_joins_the_stages_of_a_binding() {
+        let mut vertex = ShaderReflection {
+            stages: vk::ShaderStageFlags::VERTEX,
+            bindings: vec![uniform(0, 0, vk::ShaderStageFlags::VERTEX)],
+            ..Default::default()
+        };
+        let fragment = ShaderReflection {
+            stages: vk::ShaderStageFlags::FRAGMENT,
+            bindings: vec![uniform(1, 0, vk::ShaderStageFlags::FRAGMENT), uniform(0, 0, vk::ShaderStageFlags::FRAGMENT)],
+            ..Default::default()
+        };
+        vertex.merge(&fragment).unwrap();
+        assert_eq!(vertex.bindings, vec![
+            uniform(0, 0, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
+            uniform(1, 0, vk::ShaderStageFlags::FRAGMENT),
+        ]);
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let vertex = ShaderReflection {
+            stages: vk::ShaderStageFlags::VERTEX,
+            bindings: vec![uniform(0, 1, vk::ShaderStageFlags::VERTEX)],
+            ..Default::default()
+        };
+
+        let mut other_type = ReflectedBinding {
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            ..uniform(0, 1, vk::ShaderStageFlags::FRAGMENT)
+        };
+        let fragment = ShaderReflection {
+            stages: vk::ShaderStageFlags::FRAGMENT,
+            bindings: vec![other_type],
+            ..Default::default()
+        };
+        assert!(vertex.clone().merge(&fragment).is_err());
+
+        other_type.descriptor_type = vk::DescriptorType::UNIFORM_BUFFER;
+        other_type.count = 4;
+        let fragment = ShaderReflection { bindings: vec![other_type], ..fragment };
+        assert!(vertex.clone().merge(&fragment).is_err());
+    }
+
+    #[test]
+    fn vertex_attribute_mismatches() {
+        let vertex = egui_vertex();
+
+        // location 2 is not fed
+        let missing = [attribute(0, vk::Format::R32G32_SFLOAT), attribute(1, vk::Format::R32G32_SFLOAT)];
+        assert!(vertex.check_vertex_attributes(&missing).is_err());
+
+        // integer attributes can't feed float inputs
+        let integer = [
+            attribute(0, vk::Format::R32G32_SFLOAT),
+            attribute(1, vk::Format::R16G16_UINT),
+            attribute(2, vk::Format::R8G8B8A8_UNORM),
+        ];
+        assert!(vertex.check_vertex_attributes(&integer).is_err());
+
+        // missing components are filled in
+        let narrower = [
+            attribute(0, vk::Format::R32_SFLOAT),
+            attribute(1, vk::Format::R16G16_UNORM),
+            attribute(2, vk::Format::R32G32B32_SFLOAT),
+        ];
+        vertex.check_vertex_attributes(&narrower).unwrap();
+
+        let uint_input = ShaderReflection {
+            inputs: vec![ReflectedInput { location: 0, format: vk::Format::R32G32B32A32_UINT }],
+            ..Default::default()
+        };
+        assert!(uint_input.check_vertex_attributes(&[attribute(0, vk::Format::R8G8B8A8_UNORM)]).is_err());
+        assert!(uint_input.check_vertex_attributes(&[attribute(0, vk::Format::R8G8B8A8_SINT)]).is_err());
+        uint_input.check_vertex_attributes(&[attribute(0, vk::Format::R16G16B16A16_UINT)]).unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+use std::mem::size_of;
+use ash::vk;
+use crate::render::texture::Texture;
+use crate::render::render_context::RenderContext;
+use crate::render::render_settings::AmbientOcclusion;
+use crate::render::graphic_pipeline::set_flipped_viewport_rect;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::util;
+
+/// View space distance in which occluders darken a point.
+pub const SSAO_RADIUS: f32 = 0.5;
+
+const AO_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+const GROUP_SIZE: u32 = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SsaoConstant {
+    radius: f32,
+    sample_count: u32,
+    /// texel step of the blur, zero for the occlusion pass
+    blur_direction: [i32; 2],
+    reverse_z: u32,
+}
+
+/// Ambient occlusion of the main camera. Opaque models are drawn into a depth prepass,
+/// a compute pass reconstructs view space normals from it and samples the hemisphere,
+/// then two bilateral blur passes smooth the result. The forward target depth is only
+/// complete after the pass that shades with the occlusion, so it can't be used.
+///
+/// The map is bound to every model descriptor set and scales the ambient term,
+/// `PerFrameData::ao_strength` is 0 for cameras it was not computed for.
+pub struct SsaoPass {
+    quality: AmbientOcclusion,
+    /// resolution of the prepass and the occlusion map
+    extent: vk::Extent2D,
+    depth_texture: Texture,
+    depth_view: vk::ImageView,
+    prepass: vk::RenderPass,
+    prepass_frame_buffer: vk::Framebuffer,
+    ao_texture: Texture,
+    ao_view: vk::ImageView,
+    blur_texture: Texture,
+    blur_view: vk::ImageView,
+    sampler: vk::Sampler,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    /// occlusion, horizontal blur into `blur_texture`, vertical blur back into `ao_texture`
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    pipeline_layout: vk::PipelineLayout,
+    ao_pipeline: vk::Pipeline,
+    blur_pipeline: vk::Pipeline,
+}
+
+impl SsaoPass {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.depth_texture.destroy(context);
+        self.ao_texture.destroy(context);
+        self.blur_texture.destroy(context);
+        let device = &context.device;
+        unsafe {
+            device.destroy_pipeline(self.ao_pipeline, None);
+            device.destroy_pipeline(self.blur_pipeline, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            if !self.descriptor_sets.is_empty() {
+                device.free_descriptor_sets(context.descriptor_pool, &self.descriptor_sets);
+            }
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_image_view(self.depth_view, None);
+            device.destroy_image_view(self.ao_view, None);
+            device.destroy_image_view(self.blur_view, None);
+            device.destroy_framebuffer(self.prepass_frame_buffer, None);
+            device.destroy_render_pass(self.prepass, None);
+        }
+    }
+
+    /// `target_extent` is the extent of the window target, the map is scaled by the quality.
+    pub fn create(context: &mut RenderContext, quality: AmbientOcclusion, target_extent: vk::Extent2D) -> RenderResult<Self> {
+        let scale = quality.resolution_scale();
+        let extent = vk::Extent2D {
+            width: ((target_extent.width as f32 * scale) as u32).max(1),
+            height: ((target_extent.height as f32 * scale) as u32).max(1),
+        };
+
+        let depth_texture = Texture::create_as_depth_stencil(context, extent.width, extent.height,
+                                                             context.render_config.depth_format,
+                                                             vk::SampleCountFlags::TYPE_1, "ssao_depth")?;
+        let depth_view = depth_texture.create_depth_view(context)?;
+
+        let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        let ao_texture = Texture::create_as_render_target(context, extent.width, extent.height, AO_FORMAT,
+                                                          vk::SampleCountFlags::TYPE_1, usage, "ssao",
+                                                          vk::ImageCreateFlags::empty())?;
+        let ao_view = ao_texture.create_color_view(context)?;
+        let blur_texture = Texture::create_as_render_target(context, extent.width, extent.height, AO_FORMAT,
+                                                            vk::SampleCountFlags::TYPE_1, usage, "ssao_blur",
+                                                            vk::ImageCreateFlags::empty())?;
+        let blur_view = blur_texture.create_color_view(context)?;
+
+        let prepass = Self::create_prepass(context)?;
+        let views = [depth_view];
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(prepass).layers(1)
+            .width(extent.width).height(extent.height).attachments(&views).build();
+        let prepass_frame_buffer = unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) }
+            .map_err(RenderError::vk("vkCreateFramebuffer"))?;
+
+        let sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+
+        let mut pass = Self {
+            quality,
+            extent,
+            depth_texture,
+            depth_view,
+            prepass,
+            prepass_frame_buffer,
+            ao_texture,
+            ao_view,
+            blur_texture,
+            blur_view,
+            sampler,
+            descriptor_set_layout: vk::DescriptorSetLayout::null(),
+            descriptor_sets: Vec::new(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            ao_pipeline: vk::Pipeline::null(),
+            blur_pipeline: vk::Pipeline::null(),
+        };
+
+        // destroying null handles is a no-op, so a failed step releases everything
+        let pipelines = pass.create_descriptors(context)
+            .and_then(|_| Self::create_compute_pipeline(context, pass.pipeline_layout, "ssao_comp"))
+            .and_then(|ao| {
+                pass.ao_pipeline = ao;
+                Self::create_compute_pipeline(context, pass.pipeline_layout, "ssao_blur_comp")
+            });
+        match pipelines {
+            Ok(blur) => pass.blur_pipeline = blur,
+            Err(e) => {
+                pass.destroy(context);
+                return Err(e);
+            }
+        }
+
+        Ok(pass)
+    }
+
+    /// The three dispatch sets and the pipeline layout shared by the dispatches.
+    fn create_descriptors(&mut self, context: &mut RenderContext) -> RenderResult<()> {
+        let descriptor_set_layout = {
+            let bindings = Self::set_layout_bindings();
+            let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+            unsafe { context.device.create_descriptor_set_layout(&ci, None) }
+                .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?
+        };
+        self.descriptor_set_layout = descriptor_set_layout;
+
+        self.descriptor_sets = {
+            let layouts = [descriptor_set_layout; 3];
+            let ai = vk::DescriptorSetAllocateInfo::builder().descriptor_pool(context.descriptor_pool)
+                .set_layouts(&layouts).build();
+            unsafe { context.device.allocate_descriptor_sets(&ai) }
+                .map_err(RenderError::vk("vkAllocateDescriptorSets"))?
+        };
+        let (depth_view, ao_view, blur_view, sampler) = (self.depth_view, self.ao_view, self.blur_view, self.sampler);
+
+        // (input, output) of each dispatch, the occlusion pass reads the depth alone
+        let passes = [(depth_view, ao_view), (ao_view, blur_view), (blur_view, ao_view)];
+        for (set, (input, output)) in self.descriptor_sets.iter().zip(passes.iter()) {
+            let depth_info = [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .image_view(depth_view)
+                .sampler(sampler)
+                .build()];
+            let input_layout = if *input == depth_view {
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+            } else {
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            };
+            let input_info = [vk::DescriptorImageInfo::builder()
+                .image_layout(input_layout)
+                .image_view(*input)
+                .sampler(sampler)
+                .build()];
+            let output_info = [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(*output)
+                .build()];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&depth_info)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&input_info)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&output_info)
+                    .build(),
+            ];
+            unsafe {
+                context.device.update_descriptor_sets(&writes, &[]);
+            }
+        }
+
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<SsaoConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE).build()
+        ];
+        self.pipeline_layout = {
+            let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+            let descriptor_layouts = [frame_uniform_layout, descriptor_set_layout];
+            let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_layouts)
+                .push_constant_ranges(&constant_ranges).build();
+            unsafe { context.device.create_pipeline_layout(&pipeline_layout_ci, None) }
+                .map_err(RenderError::vk("vkCreatePipelineLayout"))?
+        };
+        Ok(())
+    }
+
+    /// The depth, the input and the output of a dispatch, checked against both shaders.
+    fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 3] {
+        let binding = |binding: u32, descriptor_type: vk::DescriptorType| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(descriptor_type)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .build();
+        [
+            binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            binding(2, vk::DescriptorType::STORAGE_IMAGE),
+        ]
+    }
+
+    fn create_compute_pipeline(context: &mut RenderContext, layout: vk::PipelineLayout, name: &str) -> RenderResult<vk::Pipeline> {
+        let bindings = Self::set_layout_bindings();
+        context.shader_modules.check_stages(&context.device, &[name], &[], &[(1, &bindings[..])])?;
+        let stage = context.shader_modules.create_shader_stage(&context.device, name, &[],
+                                                               vk::ShaderStageFlags::COMPUTE)?;
+        let ci = vk::ComputePipelineCreateInfo::builder().stage(stage).layout(layout).build();
+        unsafe { context.device.create_compute_pipelines(vk::PipelineCache::null(), &[ci], None) }
+            .map(|pipelines| pipelines[0])
+            .map_err(|(_, result)| RenderError::vk("vkCreateComputePipelines")(result))
+    }
+
+    fn create_prepass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: context.render_config.depth_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let depth_ref = vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let dependencies = [
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_subpass(0)
+                .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE).build(),
+            vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ).build(),
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .depth_stencil_attachment(&depth_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder().attachments(&attachments)
+            .subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    pub fn get_quality(&self) -> AmbientOcclusion {
+        self.quality
+    }
+
+    /// Render pass of the depth prepass pipelines, which have no color attachment.
+    pub fn get_prepass_render_pass(&self) -> vk::RenderPass {
+        self.prepass
+    }
+
+    /// Sampled by the model shaders in `SHADER_READ_ONLY_OPTIMAL` once the frame computed it.
+    pub fn get_ao_view(&self) -> vk::ImageView {
+        self.ao_view
+    }
+
+    pub fn get_sampler(&self) -> vk::Sampler {
+        self.sampler
+    }
+
+    /// Begins the depth prepass, `rect` is the viewport of the camera in the window target.
+    pub fn begin_prepass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, rect: vk::Rect2D) {
+        let clear_values = [
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: context.render_config.clear_depth(),
+                    stencil: 0,
+                },
+            },
+        ];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.prepass)
+            .framebuffer(self.prepass_frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .clear_values(&clear_values)
+            .build();
+
+        unsafe {
+            context.device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
+        }
+
+        let scale = self.quality.resolution_scale();
+        set_flipped_viewport_rect(context, command_buffer, vk::Rect2D {
+            offset: vk::Offset2D {
+                x: (rect.offset.x as f32 * scale) as i32,
+                y: (rect.offset.y as f32 * scale) as i32,
+            },
+            extent: vk::Extent2D {
+                width: ((rect.extent.width as f32 * scale) as u32).max(1).min(self.extent.width),
+                height: ((rect.extent.height as f32 * scale) as u32).max(1).min(self.extent.height),
+            },
+        });
+    }
+
+    pub fn end_prepass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            context.device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    fn image_barrier(image: vk::Image, src_access: vk::AccessFlags, dst_access: vk::AccessFlags,
+                     old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder().image(image)
+            .src_access_mask(src_access).dst_access_mask(dst_access)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            }).build()
+    }
+
+    fn cmd_barrier(context: &RenderContext, command_buffer: vk::CommandBuffer, src_stage: vk::PipelineStageFlags,
+                   dst_stage: vk::PipelineStageFlags, barriers: &[vk::ImageMemoryBarrier]) {
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, src_stage, dst_stage,
+                                                vk::DependencyFlags::empty(), &[], &[], barriers);
+        }
+    }
+
+    /// Computes and blurs the occlusion from the prepass depth. `frame_descriptor_set`
+    /// holds the `PerFrameData` of the camera drawn in the prepass.
+    pub fn compute(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, frame_descriptor_set: vk::DescriptorSet) {
+        let ao_image = self.ao_texture.get_image();
+        let blur_image = self.blur_texture.get_image();
+        let device = &context.device;
+        let group_x = (self.extent.width + GROUP_SIZE - 1) / GROUP_SIZE;
+        let group_y = (self.extent.height + GROUP_SIZE - 1) / GROUP_SIZE;
+        let dispatch = |pipeline: vk::Pipeline, set: vk::DescriptorSet, blur_direction: [i32; 2]| {
+            let constant = SsaoConstant {
+                radius: SSAO_RADIUS,
+                sample_count: self.quality.sample_count(),
+                blur_direction,
+                reverse_z: context.render_config.reverse_z as u32,
+            };
+            unsafe {
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline_layout, 0,
+                                                &[frame_descriptor_set, set], &[]);
+                device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                          util::any_as_u8_slice(&constant));
+                device.cmd_dispatch(command_buffer, group_x, group_y, 1);
+            }
+        };
+
+        // the previous frame may still sample the map
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER, vk::PipelineStageFlags::COMPUTE_SHADER, &[
+            Self::image_barrier(ao_image, vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE,
+                                vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            Self::image_barrier(blur_image, vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE,
+                                vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        ]);
+        dispatch(self.ao_pipeline, self.descriptor_sets[0], [0, 0]);
+
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, vk::PipelineStageFlags::COMPUTE_SHADER, &[
+            Self::image_barrier(ao_image, vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::SHADER_READ,
+                                vk::ImageLayout::GENERAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        ]);
+        dispatch(self.blur_pipeline, self.descriptor_sets[1], [1, 0]);
+
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, vk::PipelineStageFlags::COMPUTE_SHADER, &[
+            Self::image_barrier(blur_image, vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::SHADER_READ,
+                                vk::ImageLayout::GENERAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            Self::image_barrier(ao_image, vk::AccessFlags::SHADER_READ, vk::AccessFlags::SHADER_WRITE,
+                                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::ImageLayout::GENERAL),
+        ]);
+        dispatch(self.blur_pipeline, self.descriptor_sets[2], [0, 1]);
+
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, vk::PipelineStageFlags::FRAGMENT_SHADER, &[
+            Self::image_barrier(ao_image, vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::SHADER_READ,
+                                vk::ImageLayout::GENERAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        ]);
+    }
+
+    /// Fills the map with no occlusion when no camera computed it this frame, so the
+    /// model descriptors always reference a readable image.
+    pub fn clear(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let ao_image = self.ao_texture.get_image();
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[],
+                                                &[Self::image_barrier(ao_image, vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE,
+                                                                      vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL)]);
+            context.device.cmd_clear_color_image(command_buffer, ao_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                 &vk::ClearColorValue { float32: [1.0; 4] }, &[range]);
+            context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::FRAGMENT_SHADER, vk::DependencyFlags::empty(), &[], &[],
+                                                &[Self::image_barrier(ao_image, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::SHADER_READ,
+                                                                      vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)]);
+        }
+    }
+}
@@ -2,6 +2,8 @@ use ash::vk;
 use crate::render::render_context::RenderContext;
 use ash::vk::{ImageView, Fence};
 use ash::extensions::khr::Surface;
+use bevy::log::warn;
+use crate::render::render_error::{RenderError, RenderResult};
 
 pub struct SwapChainMgr {
     swapchain: vk::SwapchainKHR,
@@ -22,11 +24,17 @@ pub struct SwapChainMgr {
 }
 
 impl SwapChainMgr {
-    pub unsafe fn create(device: &RenderContext, window_width: u32, window_height: u32) -> Self {
+    pub unsafe fn create(device: &RenderContext, window_width: u32, window_height: u32) -> RenderResult<Self> {
+        Self::create_with_surface(device, device.surface, window_width, window_height)
+    }
+
+    /// Swapchain of an additional window surface created on the same device.
+    pub unsafe fn create_with_surface(device: &RenderContext, surface: vk::SurfaceKHR,
+                                      window_width: u32, window_height: u32) -> RenderResult<Self> {
         let surface_loader = &device.surface_loader;
         let surface_capabilities = surface_loader
-            .get_physical_device_surface_capabilities(device.physical_device, device.surface)
-            .unwrap();
+            .get_physical_device_surface_capabilities(device.physical_device, surface)
+            .map_err(RenderError::vk("vkGetPhysicalDeviceSurfaceCapabilitiesKHR"))?;
         let mut desired_image_count = surface_capabilities.min_image_count + 1;
         if surface_capabilities.max_image_count > 0
             && desired_image_count > surface_capabilities.max_image_count
@@ -35,8 +43,8 @@ impl SwapChainMgr {
         }
 
         let surface_format = surface_loader
-            .get_physical_device_surface_formats(device.physical_device, device.surface)
-            .unwrap()[0];
+            .get_physical_device_surface_formats(device.physical_device, surface)
+            .map_err(RenderError::vk("vkGetPhysicalDeviceSurfaceFormatsKHR"))?[0];
 
         let pre_transform = if surface_capabilities
             .supported_transforms
@@ -47,13 +55,17 @@ impl SwapChainMgr {
             surface_capabilities.current_transform
         };
         let present_modes = device.surface_loader
-            .get_physical_device_surface_present_modes(device.physical_device, device.surface)
-            .unwrap();
+            .get_physical_device_surface_present_modes(device.physical_device, surface)
+            .map_err(RenderError::vk("vkGetPhysicalDeviceSurfacePresentModesKHR"))?;
+        let preferred_mode = device.render_config.present_mode;
         let present_mode = present_modes
             .iter()
             .cloned()
-            .find(|&mode| mode == vk::PresentModeKHR::FIFO)
-            .expect("failed to find present mode FIFO");
+            .find(|&mode| mode == preferred_mode)
+            .unwrap_or_else(|| {
+                warn!("present mode {:?} is not supported, fall back to FIFO", preferred_mode);
+                vk::PresentModeKHR::FIFO
+            });
 
         let surface_resolution = match surface_capabilities.current_extent.width {
             std::u32::MAX => vk::Extent2D {
@@ -64,7 +76,7 @@ impl SwapChainMgr {
         };
 
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
-            .surface(device.surface)
+            .surface(surface)
             .min_image_count(desired_image_count)
             .image_color_space(surface_format.color_space)
             .image_format(surface_format.format)
@@ -79,9 +91,10 @@ impl SwapChainMgr {
 
         let swapchain = device.swapchain_loader
             .create_swapchain(&swapchain_create_info, None)
-            .unwrap();
+            .map_err(RenderError::vk("vkCreateSwapchainKHR"))?;
 
-        let present_images = device.swapchain_loader.get_swapchain_images(swapchain).unwrap();
+        let present_images = device.swapchain_loader.get_swapchain_images(swapchain)
+            .map_err(RenderError::vk("vkGetSwapchainImagesKHR"))?;
 
         let present_image_views = present_images.iter().map(|&image| {
             let create_view_info = vk::ImageViewCreateInfo::builder().
@@ -123,7 +136,7 @@ impl SwapChainMgr {
         }
 
 
-        SwapChainMgr {
+        Ok(SwapChainMgr {
             format: surface_format.format,
             surface_resolution,
             swapchain,
@@ -137,7 +150,7 @@ impl SwapChainMgr {
             image_index_to_present: 0,
             semaphore_index: 0,
             prev_semaphore_index: 0,
-        }
+        })
     }
 
     pub fn destroy(&mut self, device: &RenderContext) {
@@ -176,20 +189,18 @@ impl SwapChainMgr {
         self.present_images[self.image_index_to_present]
     }
 
-    pub fn wait_for_swap_chain(&mut self, device_mgr: &RenderContext) -> (bool, usize) {
+    /// Index of the acquired image, `None` when the swapchain is out of date.
+    pub fn wait_for_swap_chain(&mut self, device_mgr: &RenderContext) -> RenderResult<Option<usize>> {
         unsafe {
             let result = device_mgr.swapchain_loader.
                 acquire_next_image(self.swapchain, std::u64::MAX,
                                    self.image_available_semaphores[self.semaphore_index], vk::Fence::null());
-            let mut present_index: u32 = 0;
-            match result {
-                Ok((image_index, _)) => {
-                    present_index = image_index;
-                }
+            let present_index = match result {
+                Ok((image_index, _)) => image_index,
                 Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
-                    return (false, 0);
+                    return Ok(None);
                 }
-                Err(error) => panic!("Error while acquiring next image. Cause: {}", error),
+                Err(error) => return Err(RenderError::vk("vkAcquireNextImageKHR")(error)),
             };
 
             self.prev_semaphore_index = self.semaphore_index;
@@ -198,23 +209,24 @@ impl SwapChainMgr {
                 self.semaphore_index = 0;
             }
 
-            device_mgr.device.wait_for_fences(&[self.cmd_buf_execute_fences[self.prev_semaphore_index]], true, std::u64::MAX).expect("wait fence failed");
-            device_mgr.device.reset_fences(&[self.cmd_buf_execute_fences[self.prev_semaphore_index]]).expect("reset fence failed");
+            device_mgr.device.wait_for_fences(&[self.cmd_buf_execute_fences[self.prev_semaphore_index]], true, std::u64::MAX)
+                .map_err(RenderError::vk("vkWaitForFences"))?;
+            device_mgr.device.reset_fences(&[self.cmd_buf_execute_fences[self.prev_semaphore_index]])
+                .map_err(RenderError::vk("vkResetFences"))?;
 
             self.image_index_to_present = present_index as usize;
-            (true, self.image_index_to_present)
+            Ok(Some(self.image_index_to_present))
         }
     }
 
-    pub fn present(&self, device_mgr: &RenderContext) {
+    pub fn present(&self, device_mgr: &RenderContext) -> RenderResult<()> {
         unsafe {
             let present_ci = vk::PresentInfoKHR::builder().wait_semaphores(&[self.render_finish_semaphores[self.semaphore_index]]).
                 swapchains(&[self.swapchain]).image_indices(&[self.image_index_to_present as u32]).build();
             let result = device_mgr.swapchain_loader.queue_present(device_mgr.present_queue, &present_ci);
             match result {
-                Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {}
-                Err(error) => panic!("Failed to present queue. Cause: {}", error),
-                _ => {}
+                Ok(_) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(()),
+                Err(error) => Err(RenderError::vk("vkQueuePresentKHR")(error)),
             }
         }
     }
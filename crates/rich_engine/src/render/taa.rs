@@ -0,0 +1,604 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::texture::Texture;
+use crate::render::render_context::RenderContext;
+use crate::render::forward_render::SceneTarget;
+use crate::render::graphic_pipeline::set_flipped_viewport_rect;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::util;
+
+/// Screen space motion in NDC from the previous frame to this one.
+const VELOCITY_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+/// Storage support is required for it, the resolve blits the history to the final image.
+const HISTORY_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const GROUP_SIZE: u32 = 8;
+/// Length of the jitter sequence, the history converges over about as many frames.
+const JITTER_SAMPLES: u32 = 8;
+/// Weight of the history in the resolved color, the rest comes from this frame.
+const HISTORY_FEEDBACK: f32 = 0.9;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TaaConstant {
+    feedback: f32,
+    /// 1 when the history is invalid and this frame is copied alone
+    reset: u32,
+}
+
+/// Element of the Halton sequence with `base`, in 0..1.
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// Sub-pixel offset of the projection for a frame, in NDC of a viewport of `extent`.
+pub fn jitter_offset(frame_index: u32, extent: vk::Extent2D) -> Vec2 {
+    // the sequence starts at 1, index 0 would be the pixel corner on both axes
+    let index = frame_index % JITTER_SAMPLES + 1;
+    let pixel = Vec2::new(halton(index, 2), halton(index, 3)) - Vec2::splat(0.5);
+    pixel * 2.0 / Vec2::new(extent.width.max(1) as f32, extent.height.max(1) as f32)
+}
+
+/// World transforms of the model nodes drawn into the velocity buffer in the previous
+/// frame, so their motion can be told apart from the camera motion.
+#[derive(Default)]
+pub struct MotionHistory {
+    previous: HashMap<Entity, Mat4>,
+    current: HashMap<Entity, Mat4>,
+    /// main camera and viewport extent of the last velocity pass
+    view: Option<(Entity, vk::Extent2D)>,
+}
+
+impl MotionHistory {
+    /// Call before the velocity pass of `camera` drawn in `extent`. Returns true when the
+    /// main camera changed or the viewport was resized, the previous transforms are then
+    /// forgotten and the resolve has to start over from this frame.
+    pub fn begin_frame(&mut self, camera: Entity, extent: vk::Extent2D) -> bool {
+        let view = Some((camera, extent));
+        if self.view == view {
+            return false;
+        }
+        self.view = view;
+        self.previous.clear();
+        true
+    }
+
+    /// Records the transform of this frame and returns the previous one, the same
+    /// transform for nodes that were not drawn in the previous frame.
+    pub fn record(&mut self, entity: Entity, transform: Mat4) -> Mat4 {
+        self.current.insert(entity, transform);
+        self.previous.get(&entity).copied().unwrap_or(transform)
+    }
+
+    /// Call once per frame, nodes not recorded this frame are forgotten.
+    pub fn end_frame(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+    }
+}
+
+/// Temporal anti-aliasing of the window target. The main camera is jittered by a
+/// sub-pixel offset every frame, see `PerFrameData::jitter`, and opaque models and
+/// grass write their screen motion into a velocity prepass. A compute pass then
+/// reprojects the history along the motion, clamps it to the neighborhood of the
+/// current pixel to reject disoccluded colors and blends the current frame into it.
+/// The result is copied back to the final image before overlays, effects and the HUD,
+/// which project with `PerFrameData::unjittered_proj` when they need the camera.
+///
+/// The whole window target is resolved, other cameras drawn into it are not jittered.
+pub struct TaaPass {
+    extent: vk::Extent2D,
+    velocity_texture: Texture,
+    velocity_view: vk::ImageView,
+    depth_texture: Texture,
+    depth_view: vk::ImageView,
+    velocity_pass: vk::RenderPass,
+    velocity_frame_buffer: vk::Framebuffer,
+    history_textures: [Texture; 2],
+    history_views: [vk::ImageView; 2],
+    sampler: vk::Sampler,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    /// set `i` reads history `1 - i` and writes history `i`
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// history written by the next resolve
+    current: Cell<usize>,
+    /// false until a frame was resolved, and after frames without a main camera
+    history_valid: Cell<bool>,
+}
+
+impl TaaPass {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.velocity_texture.destroy(context);
+        self.depth_texture.destroy(context);
+        for texture in &mut self.history_textures {
+            texture.destroy(context);
+        }
+        let device = &context.device;
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.free_descriptor_sets(context.descriptor_pool, &self.descriptor_sets);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.sampler, None);
+            for view in &self.history_views {
+                device.destroy_image_view(*view, None);
+            }
+            device.destroy_image_view(self.velocity_view, None);
+            device.destroy_image_view(self.depth_view, None);
+            device.destroy_framebuffer(self.velocity_frame_buffer, None);
+            device.destroy_render_pass(self.velocity_pass, None);
+        }
+    }
+
+    /// `target` is the window target, its final image is resolved in place.
+    pub fn create(context: &mut RenderContext, target: &SceneTarget) -> RenderResult<Self> {
+        let extent = target.get_extent();
+        let velocity_texture = Texture::create_as_render_target(context, extent.width, extent.height, VELOCITY_FORMAT,
+                                                                vk::SampleCountFlags::TYPE_1,
+                                                                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                                                                "taa_velocity", vk::ImageCreateFlags::empty())?;
+        let velocity_view = velocity_texture.create_color_view(context)?;
+        let depth_texture = Texture::create_as_depth_stencil(context, extent.width, extent.height,
+                                                             context.render_config.depth_format,
+                                                             vk::SampleCountFlags::TYPE_1, "taa_velocity_depth")?;
+        let depth_view = depth_texture.create_depth_view(context)?;
+
+        let history_usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC;
+        let history_textures = [
+            Texture::create_as_render_target(context, extent.width, extent.height, HISTORY_FORMAT,
+                                             vk::SampleCountFlags::TYPE_1, history_usage, "taa_history_0",
+                                             vk::ImageCreateFlags::empty())?,
+            Texture::create_as_render_target(context, extent.width, extent.height, HISTORY_FORMAT,
+                                             vk::SampleCountFlags::TYPE_1, history_usage, "taa_history_1",
+                                             vk::ImageCreateFlags::empty())?,
+        ];
+        let history_views = [history_textures[0].create_color_view(context)?, history_textures[1].create_color_view(context)?];
+
+        let velocity_pass = Self::create_velocity_pass(context)?;
+        let views = [velocity_view, depth_view];
+        let frame_buffer_ci = vk::FramebufferCreateInfo::builder().render_pass(velocity_pass).layers(1)
+            .width(extent.width).height(extent.height).attachments(&views).build();
+        let velocity_frame_buffer = unsafe { context.device.create_framebuffer(&frame_buffer_ci, None) }
+            .map_err(RenderError::vk("vkCreateFramebuffer"))?;
+
+        let sampler = {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .max_lod(0.0);
+
+            unsafe { context.device.create_sampler(&sampler_info, None) }
+                .map_err(RenderError::vk("vkCreateSampler"))?
+        };
+
+        // scene color, velocity, previous history and the history written
+        let binding = |binding: u32, descriptor_type: vk::DescriptorType| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(descriptor_type)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .build();
+        let bindings = [
+            binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            binding(3, vk::DescriptorType::STORAGE_IMAGE),
+        ];
+        let descriptor_set_layout = {
+            let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+            unsafe { context.device.create_descriptor_set_layout(&ci, None) }
+                .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?
+        };
+
+        let descriptor_sets = {
+            let layouts = [descriptor_set_layout; 2];
+            let ai = vk::DescriptorSetAllocateInfo::builder().descriptor_pool(context.descriptor_pool)
+                .set_layouts(&layouts).build();
+            unsafe { context.device.allocate_descriptor_sets(&ai) }
+                .map_err(RenderError::vk("vkAllocateDescriptorSets"))?
+        };
+
+        for (i, set) in descriptor_sets.iter().enumerate() {
+            let sampled = |view: vk::ImageView| [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(view)
+                .sampler(sampler)
+                .build()];
+            let color_info = sampled(target.get_final_render_image_view());
+            let velocity_info = sampled(velocity_view);
+            let history_info = sampled(history_views[1 - i]);
+            let output_info = [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(history_views[i])
+                .build()];
+            let write = |binding: u32, descriptor_type: vk::DescriptorType, info: &[vk::DescriptorImageInfo]| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(binding)
+                    .descriptor_type(descriptor_type)
+                    .image_info(info)
+                    .build()
+            };
+            let writes = [
+                write(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &color_info),
+                write(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &velocity_info),
+                write(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, &history_info),
+                write(3, vk::DescriptorType::STORAGE_IMAGE, &output_info),
+            ];
+            unsafe {
+                context.device.update_descriptor_sets(&writes, &[]);
+            }
+        }
+
+        let constant_ranges = [
+            vk::PushConstantRange::builder().offset(0).size(size_of::<TaaConstant>() as _)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE).build()
+        ];
+        let pipeline_layout = {
+            let frame_uniform_layout = context.per_frame_uniform.as_ref().unwrap().descriptor_set_layout;
+            let descriptor_layouts = [frame_uniform_layout, descriptor_set_layout];
+            let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_layouts)
+                .push_constant_ranges(&constant_ranges).build();
+            unsafe { context.device.create_pipeline_layout(&pipeline_layout_ci, None) }
+                .map_err(RenderError::vk("vkCreatePipelineLayout"))?
+        };
+
+        let mut pass = Self {
+            extent,
+            velocity_texture,
+            velocity_view,
+            depth_texture,
+            depth_view,
+            velocity_pass,
+            velocity_frame_buffer,
+            history_textures,
+            history_views,
+            sampler,
+            descriptor_set_layout,
+            descriptor_sets,
+            pipeline_layout,
+            pipeline: vk::Pipeline::null(),
+            current: Cell::new(0),
+            history_valid: Cell::new(false),
+        };
+
+        // destroying a null pipeline is a no-op, so a failed shader releases everything
+        let stage = context.shader_modules
+            .check_stages(&context.device, &["taa_resolve_comp"], &[], &[(1, &bindings[..])])
+            .and_then(|_| context.shader_modules.create_shader_stage(&context.device, "taa_resolve_comp", &[],
+                                                                     vk::ShaderStageFlags::COMPUTE));
+        let pipeline = stage.and_then(|stage| {
+            let ci = vk::ComputePipelineCreateInfo::builder().stage(stage).layout(pipeline_layout).build();
+            unsafe { context.device.create_compute_pipelines(vk::PipelineCache::null(), &[ci], None) }
+                .map(|pipelines| pipelines[0])
+                .map_err(|(_, result)| RenderError::vk("vkCreateComputePipelines")(result))
+        });
+        match pipeline {
+            Ok(pipeline) => pass.pipeline = pipeline,
+            Err(e) => {
+                pass.destroy(context);
+                return Err(e);
+            }
+        }
+
+        Ok(pass)
+    }
+
+    /// Velocity and its own depth, the forward depth is only complete after the
+    /// velocity is needed.
+    fn create_velocity_pass(context: &RenderContext) -> RenderResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription {
+                format: VELOCITY_FORMAT,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ..Default::default()
+            },
+            vk::AttachmentDescription {
+                format: context.render_config.depth_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::DONT_CARE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let dependencies = [
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_subpass(0)
+                .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+                .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE).build(),
+            vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ).build(),
+        ];
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let render_pass_ci = vk::RenderPassCreateInfo::builder().attachments(&attachments)
+            .subpasses(&subpasses).dependencies(&dependencies).build();
+        unsafe { context.device.create_render_pass(&render_pass_ci, None) }
+            .map_err(RenderError::vk("vkCreateRenderPass"))
+    }
+
+    /// Render pass of the velocity pipelines, one color attachment without MSAA.
+    pub fn get_velocity_render_pass(&self) -> vk::RenderPass {
+        self.velocity_pass
+    }
+
+    /// Motion of this frame in `SHADER_READ_ONLY_OPTIMAL` after the velocity pass, for
+    /// effects such as motion blur.
+    pub fn get_velocity_view(&self) -> vk::ImageView {
+        self.velocity_view
+    }
+
+    /// Begins the velocity prepass, `rect` is the viewport of the main camera in the window target.
+    pub fn begin_velocity_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, rect: vk::Rect2D) {
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0; 4],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: context.render_config.clear_depth(),
+                    stencil: 0,
+                },
+            },
+        ];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.velocity_pass)
+            .framebuffer(self.velocity_frame_buffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            })
+            .clear_values(&clear_values)
+            .build();
+
+        unsafe {
+            context.device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
+        }
+        set_flipped_viewport_rect(context, command_buffer, rect);
+    }
+
+    pub fn end_velocity_pass(&self, context: &RenderContext, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            context.device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    /// The next resolve starts over from the current frame, call on frames without a
+    /// main camera in the window and when `MotionHistory::begin_frame` reports a cut.
+    pub fn reset_history(&self) {
+        self.history_valid.set(false);
+    }
+
+    fn image_barrier(image: vk::Image, src_access: vk::AccessFlags, dst_access: vk::AccessFlags,
+                     old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder().image(image)
+            .src_access_mask(src_access).dst_access_mask(dst_access)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            }).build()
+    }
+
+    fn cmd_barrier(context: &RenderContext, command_buffer: vk::CommandBuffer, src_stage: vk::PipelineStageFlags,
+                   dst_stage: vk::PipelineStageFlags, barriers: &[vk::ImageMemoryBarrier]) {
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, src_stage, dst_stage,
+                                                vk::DependencyFlags::empty(), &[], &[], barriers);
+        }
+    }
+
+    /// Blends the final image of `target` with the history and blits the result back,
+    /// call after the last scene pass. `frame_descriptor_set` holds the `PerFrameData`
+    /// of the main camera. The final image is left in the attachment layout.
+    pub fn resolve(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, target: &SceneTarget,
+                   frame_descriptor_set: vk::DescriptorSet) {
+        let current = self.current.get();
+        let output = self.history_textures[current].get_image();
+        let input = self.history_textures[1 - current].get_image();
+        let final_image = target.get_final_render_image();
+        let valid = self.history_valid.get();
+
+        let mut barriers = vec![
+            Self::image_barrier(final_image, vk::AccessFlags::COLOR_ATTACHMENT_WRITE, vk::AccessFlags::SHADER_READ,
+                                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            Self::image_barrier(output, vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE,
+                                vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        ];
+        if !valid {
+            // not read, but the bound descriptor expects the layout
+            barriers.push(Self::image_barrier(input, vk::AccessFlags::empty(), vk::AccessFlags::SHADER_READ,
+                                              vk::ImageLayout::UNDEFINED, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL));
+        }
+        Self::cmd_barrier(context, command_buffer,
+                          vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER,
+                          vk::PipelineStageFlags::COMPUTE_SHADER, &barriers);
+
+        let constant = TaaConstant {
+            feedback: HISTORY_FEEDBACK,
+            reset: !valid as u32,
+        };
+        let device = &context.device;
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline_layout, 0,
+                                            &[frame_descriptor_set, self.descriptor_sets[current]], &[]);
+            device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                      util::any_as_u8_slice(&constant));
+            device.cmd_dispatch(command_buffer, (self.extent.width + GROUP_SIZE - 1) / GROUP_SIZE,
+                                (self.extent.height + GROUP_SIZE - 1) / GROUP_SIZE, 1);
+        }
+
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER, vk::PipelineStageFlags::TRANSFER, &[
+            Self::image_barrier(output, vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::TRANSFER_READ,
+                                vk::ImageLayout::GENERAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            Self::image_barrier(final_image, vk::AccessFlags::SHADER_READ, vk::AccessFlags::TRANSFER_WRITE,
+                                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        ]);
+
+        let layers = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corners = [
+            vk::Offset3D::default(),
+            vk::Offset3D {
+                x: self.extent.width as i32,
+                y: self.extent.height as i32,
+                z: 1,
+            },
+        ];
+        // a blit, the history is kept in a wider format than the final image
+        let region = vk::ImageBlit {
+            src_subresource: layers,
+            src_offsets: corners,
+            dst_subresource: layers,
+            dst_offsets: corners,
+        };
+        unsafe {
+            device.cmd_blit_image(command_buffer, output, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                  final_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region], vk::Filter::NEAREST);
+        }
+
+        Self::cmd_barrier(context, command_buffer, vk::PipelineStageFlags::TRANSFER,
+                          vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::COMPUTE_SHADER, &[
+            Self::image_barrier(final_image, vk::AccessFlags::TRANSFER_WRITE,
+                                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                                vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            Self::image_barrier(output, vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::SHADER_READ,
+                                vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        ]);
+
+        self.current.set(1 - current);
+        self.history_valid.set(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENT: vk::Extent2D = vk::Extent2D { width: 1920, height: 1080 };
+
+    #[test]
+    fn halton_sequence() {
+        assert_eq!(halton(0, 2), 0.0);
+        assert_eq!(halton(1, 2), 0.5);
+        assert_eq!(halton(2, 2), 0.25);
+        assert_eq!(halton(3, 2), 0.75);
+        assert_eq!(halton(5, 2), 0.625);
+        assert!((halton(1, 3) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((halton(2, 3) - 2.0 / 3.0).abs() < 1e-6);
+        assert!((halton(3, 3) - 1.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn jitter_stays_within_a_pixel() {
+        let pixel = Vec2::new(2.0 / EXTENT.width as f32, 2.0 / EXTENT.height as f32);
+        for frame in 0..JITTER_SAMPLES {
+            let offset = jitter_offset(frame, EXTENT);
+            assert!(offset.x.abs() <= pixel.x * 0.5 && offset.y.abs() <= pixel.y * 0.5, "{:?}", offset);
+            assert_ne!(offset, Vec2::ZERO);
+            assert_eq!(jitter_offset(frame + JITTER_SAMPLES, EXTENT), offset);
+        }
+        let offset = jitter_offset(0, vk::Extent2D { width: 0, height: 0 });
+        assert!(offset.is_finite());
+    }
+
+    #[test]
+    fn previous_transform_of_drawn_nodes() {
+        let mut history = MotionHistory::default();
+        let entity = Entity::new(0);
+        let first = Mat4::from_translation(Vec3::X);
+        let second = Mat4::from_translation(Vec3::Y);
+        // a node not drawn before does not move
+        assert_eq!(history.record(entity, first), first);
+        history.end_frame();
+        assert_eq!(history.record(entity, second), first);
+        history.end_frame();
+        // forgotten after a frame without it
+        history.end_frame();
+        assert_eq!(history.record(entity, first), first);
+    }
+
+    #[test]
+    fn resize_and_camera_cut_reset_the_history() {
+        let mut history = MotionHistory::default();
+        let camera = Entity::new(1);
+        let node = Entity::new(2);
+        let first = Mat4::from_translation(Vec3::X);
+        let second = Mat4::from_translation(Vec3::Y);
+
+        assert!(history.begin_frame(camera, EXTENT));
+        history.record(node, first);
+        history.end_frame();
+        assert!(!history.begin_frame(camera, EXTENT));
+        assert_eq!(history.record(node, second), first);
+        history.end_frame();
+
+        let resized = vk::Extent2D { width: 1280, height: 720 };
+        assert!(history.begin_frame(camera, resized));
+        assert_eq!(history.record(node, first), first);
+        history.end_frame();
+
+        assert!(history.begin_frame(Entity::new(3), resized));
+        assert_eq!(history.record(node, second), second);
+    }
+}
@@ -0,0 +1,70 @@
+use bevy_mikktspace::Geometry;
+
+/// Indexed triangle list fed to MikkTSpace, tangents are written per vertex.
+pub struct TangentGenerator {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    tex_coords: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+    tangents: Vec<[f32; 4]>,
+}
+
+impl TangentGenerator {
+    pub fn new(positions: Vec<[f32; 3]>, normals: Vec<[f32; 3]>, tex_coords: Vec<[f32; 2]>, indices: Vec<u32>) -> Self {
+        let tangents = vec![[1f32, 0f32, 0f32, 1f32]; positions.len()];
+        TangentGenerator {
+            positions,
+            normals,
+            tex_coords,
+            indices,
+            tangents,
+        }
+    }
+
+    /// Returns one `vec4` tangent per vertex (w is the bitangent sign), `None` if
+    /// the input is degenerate.
+    pub fn generate(mut self) -> Option<Vec<[f32; 4]>> {
+        if self.indices.len() % 3 != 0
+            || self.normals.len() != self.positions.len()
+            || self.tex_coords.len() != self.positions.len() {
+            return None;
+        }
+
+        if bevy_mikktspace::generate_tangents(&mut self) {
+            Some(self.tangents)
+        } else {
+            None
+        }
+    }
+
+    fn vertex_index(&self, face: usize, vert: usize) -> usize {
+        self.indices[face * 3 + vert] as usize
+    }
+}
+
+impl Geometry for TangentGenerator {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.vertex_index(face, vert)]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.normals[self.vertex_index(face, vert)]
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.tex_coords[self.vertex_index(face, vert)]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let idx = self.vertex_index(face, vert);
+        self.tangents[idx] = tangent;
+    }
+}
@@ -1,10 +1,13 @@
 use ash::vk;
 use crate::render::render_context::RenderContext;
 use ash::vk::ImageUsageFlags;
+use ash::vk::Handle;
 use gltf::image::Format;
 use crate::render::buffer::Buffer;
 use std::mem::size_of;
 use crate::render::util;
+use crate::render::render_error::{RenderError, RenderResult};
+use bevy::log::warn;
 
 pub struct TextureHead {
     width: u32,
@@ -31,7 +34,7 @@ impl Texture {
     }
 
 
-    pub fn create(context: &RenderContext, image_info: &vk::ImageCreateInfo, name: &str) -> Self {
+    pub fn create(context: &RenderContext, image_info: &vk::ImageCreateInfo, name: &str) -> RenderResult<Self> {
         let head = TextureHead {
             format: image_info.format,
             width: image_info.extent.width,
@@ -42,28 +45,46 @@ impl Texture {
         };
 
         unsafe {
-            let image = context.device.create_image(&image_info, None).unwrap();
+            let image = context.device.create_image(&image_info, None).map_err(RenderError::vk("vkCreateImage"))?;
             let mem_req = context.device.get_image_memory_requirements(image);
-            let texture_memory_index = context.find_memory_type_index(&mem_req, vk::MemoryPropertyFlags::DEVICE_LOCAL).
-                expect("failed to find mem index for texture");
+            let texture_memory_index = match context.find_memory_type_index(&mem_req, vk::MemoryPropertyFlags::DEVICE_LOCAL) {
+                Some(index) => index,
+                None => {
+                    context.device.destroy_image(image, None);
+                    return Err(RenderError::OutOfMemory);
+                }
+            };
             let texture_allocate_info = vk::MemoryAllocateInfo {
                 allocation_size: mem_req.size,
                 memory_type_index: texture_memory_index,
                 ..Default::default()
             };
-            let device_memory = context.device.allocate_memory(&texture_allocate_info, None).unwrap();
-            context.device.bind_image_memory(image, device_memory, 0).expect("unable to bind texture memory");
+            let device_memory = match context.device.allocate_memory(&texture_allocate_info, None) {
+                Ok(memory) => memory,
+                Err(e) => {
+                    context.device.destroy_image(image, None);
+                    return Err(RenderError::vk("vkAllocateMemory")(e));
+                }
+            };
+            if let Err(e) = context.device.bind_image_memory(image, device_memory, 0) {
+                context.device.destroy_image(image, None);
+                context.device.free_memory(device_memory, None);
+                return Err(RenderError::vk("vkBindImageMemory")(e));
+            }
+            if context.render_config.debug_object_names {
+                context.set_object_name(vk::ObjectType::IMAGE, image.as_raw(), name);
+            }
 
-            Self {
+            Ok(Self {
                 image,
                 device_memory,
                 head,
-            }
+            })
         }
     }
 
     pub fn create_from_data_with_format(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, width: u32, height: u32,
-                                        format: vk::Format, data: &[u8]) -> Self {
+                                        format: vk::Format, data: &[u8]) -> RenderResult<Self> {
         let image_ci = vk::ImageCreateInfo::builder()
             .extent(vk::Extent3D { width: width, height: height, depth: 1 })
             .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
@@ -81,8 +102,8 @@ impl Texture {
         Self::create_from_data(context, upload_command_buffer, &image_ci, data)
     }
 
-    pub fn create_from_data(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, image_ci: &vk::ImageCreateInfo, data: &[u8]) -> Self {
-        let texture = Self::create(context, image_ci, "image");
+    pub fn create_from_data(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, image_ci: &vk::ImageCreateInfo, data: &[u8]) -> RenderResult<Self> {
+        let mut texture = Self::create(context, image_ci, "image")?;
         let image_size = (data.len() * size_of::<u8>()) as vk::DeviceSize;
         let mut buffer = Buffer::create(
             context,
@@ -109,10 +130,77 @@ impl Texture {
 
         context.push_staging_buffer(buffer);
 
-        texture
+        Ok(texture)
     }
 
-    pub fn create_from_rgba(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, width: u32, height: u32, data: &[u8]) -> Self {
+    /// Uploads every mip level given, nothing is generated. Levels are tightly
+    /// packed in the image format, largest first.
+    pub fn create_from_levels(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, image_ci: &vk::ImageCreateInfo, levels: &[Vec<u8>]) -> RenderResult<Self> {
+        let texture = Self::create(context, image_ci, "image")?;
+
+        // offsets must be a multiple of the texel block size and of 4
+        let mut offsets = Vec::with_capacity(levels.len());
+        let mut image_size = 0 as vk::DeviceSize;
+        for level in levels {
+            image_size = (image_size + 15) & !15;
+            offsets.push(image_size);
+            image_size += level.len() as vk::DeviceSize;
+        }
+
+        let mut buffer = Buffer::create(
+            context,
+            image_size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        );
+
+        unsafe {
+            let ptr = buffer.map_memory(context) as *mut u8;
+            for (level, offset) in levels.iter().zip(offsets.iter()) {
+                std::ptr::copy_nonoverlapping(level.as_ptr(), ptr.add(*offset as usize), level.len());
+            }
+        }
+
+        let regions = offsets.iter().enumerate().map(|(level, offset)| {
+            vk::BufferImageCopy::builder()
+                .buffer_offset(*offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level as u32,
+                    base_array_layer: 0,
+                    layer_count: texture.head.array_size,
+                })
+                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                .image_extent(vk::Extent3D {
+                    width: (texture.head.width >> level).max(1),
+                    height: (texture.head.height >> level).max(1),
+                    depth: 1,
+                })
+                .build()
+        }).collect::<Vec<_>>();
+
+        texture.cmd_transition_image_layout(context, upload_command_buffer,
+                                            vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        unsafe {
+            context.device.cmd_copy_buffer_to_image(
+                upload_command_buffer,
+                buffer.buffer,
+                texture.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            )
+        }
+        texture.cmd_transition_image_layout(context, upload_command_buffer,
+                                            vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+        context.push_staging_buffer(buffer);
+
+        Ok(texture)
+    }
+
+    pub fn create_from_rgba(context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer, width: u32, height: u32, data: &[u8]) -> RenderResult<Self> {
         let image_ci = vk::ImageCreateInfo::builder()
             .extent(vk::Extent3D { width: width, height: height, depth: 1 })
             .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
@@ -132,7 +220,7 @@ impl Texture {
 
     pub fn create_as_render_target(context: &RenderContext, width: u32, height: u32, format: vk::Format,
                                    msaa: vk::SampleCountFlags, usage: vk::ImageUsageFlags,
-                                   name: &str, flags: vk::ImageCreateFlags) -> Self {
+                                   name: &str, flags: vk::ImageCreateFlags) -> RenderResult<Self> {
         let image_info = vk::ImageCreateInfo {
             format: format,
             extent: vk::Extent3D {
@@ -159,7 +247,7 @@ impl Texture {
     }
 
     pub fn create_as_depth_stencil(context: &RenderContext, width: u32, height: u32,
-                                   format: vk::Format, msaa: vk::SampleCountFlags, name: &str) -> Texture {
+                                   format: vk::Format, msaa: vk::SampleCountFlags, name: &str) -> RenderResult<Texture> {
         let image_info = vk::ImageCreateInfo {
             format: format,
             extent: vk::Extent3D {
@@ -188,7 +276,7 @@ impl Texture {
         self.head.format
     }
 
-    pub fn create_color_view(&self, context: &RenderContext) -> vk::ImageView {
+    pub fn create_color_view(&self, context: &RenderContext) -> RenderResult<vk::ImageView> {
         let view_ci = vk::ImageViewCreateInfo::builder().image(self.image).
             format(self.head.format).subresource_range(vk::ImageSubresourceRange {
             aspect_mask: vk::ImageAspectFlags::COLOR,
@@ -199,40 +287,11 @@ impl Texture {
         }).view_type(vk::ImageViewType::TYPE_2D).build();
 
         unsafe {
-            context.device.create_image_view(&view_ci, None).unwrap()
+            context.device.create_image_view(&view_ci, None).map_err(RenderError::vk("vkCreateImageView"))
         }
     }
 
-    pub fn create_sample(&self, context: &RenderContext) -> vk::Sampler {
-        let sampler = {
-            let sampler_info = vk::SamplerCreateInfo::builder()
-                .mag_filter(vk::Filter::LINEAR)
-                .min_filter(vk::Filter::LINEAR)
-                .address_mode_u(vk::SamplerAddressMode::REPEAT)
-                .address_mode_v(vk::SamplerAddressMode::REPEAT)
-                .address_mode_w(vk::SamplerAddressMode::REPEAT)
-                .anisotropy_enable(true)
-                .max_anisotropy(16.0)
-                .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
-                .unnormalized_coordinates(false)
-                .compare_enable(false)
-                .compare_op(vk::CompareOp::ALWAYS)
-                .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-                .mip_lod_bias(0.0)
-                .min_lod(0.0)
-                .max_lod(self.head.mip_map_count as _);
-
-            unsafe {
-                context.device
-                    .create_sampler(&sampler_info, None)
-                    .expect("Failed to create sampler")
-            }
-        };
-
-        sampler
-    }
-
-    pub fn create_depth_view(&self, context: &RenderContext) -> vk::ImageView {
+    pub fn create_depth_view(&self, context: &RenderContext) -> RenderResult<vk::ImageView> {
         let view_ci = vk::ImageViewCreateInfo::builder().image(self.image).
             format(self.head.format).subresource_range(vk::ImageSubresourceRange {
             aspect_mask: vk::ImageAspectFlags::DEPTH,
@@ -243,7 +302,7 @@ impl Texture {
         }).view_type(vk::ImageViewType::TYPE_2D).build();
 
         unsafe {
-            context.device.create_image_view(&view_ci, None).unwrap()
+            context.device.create_image_view(&view_ci, None).map_err(RenderError::vk("vkCreateImageView"))
         }
     }
 
@@ -283,7 +342,9 @@ impl Texture {
         }
     }
 
-    pub fn cmd_generate_mipmaps(&self, context: &RenderContext, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
+    /// Blits every level from the previous one. Formats without linear filtering keep
+    /// the base level only, views created afterwards cover that level.
+    pub fn cmd_generate_mipmaps(&mut self, context: &RenderContext, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
         let format_properties = unsafe {
             context.instance
                 .get_physical_device_format_properties(context.physical_device, self.get_format())
@@ -292,10 +353,13 @@ impl Texture {
             .optimal_tiling_features
             .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
         {
-            panic!(
-                "Linear blitting is not supported for format {:?}.",
-                self.get_format()
-            )
+            if self.head.mip_map_count > 1 {
+                warn!("format {:?} has no linear filtering, mipmaps are not generated", self.get_format());
+            }
+            self.cmd_transition_image_layout(context, command_buffer,
+                                             vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+            self.head.mip_map_count = 1;
+            return;
         }
 
         let mut barrier = vk::ImageMemoryBarrier::builder()
@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
+use ash::vk;
+use bevy::{
+    prelude::*,
+    asset::{AssetLoader, LoadContext, LoadedAsset, LoadState},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use crate::render::deletion_queue::DeferredDestroy;
+use crate::render::font::{Font, GlyphAtlas, font_path};
+use crate::render::model::{ModelTexture, ModelTextures};
+use crate::render::render_context::{DummyResources, RenderContext};
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::render_runner::RenderRunner;
+use crate::render::sampler_cache::SamplerDesc;
+
+/// An image decoded by the asset server from `*.png`, `*.jpg` and `*.jpeg` files, as
+/// RGBA pixels in sRGB.
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "4f2f780b-819f-405b-a51e-83adfb5f1a97"]
+pub struct ImageAsset {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct ImageAssetLoader;
+
+impl AssetLoader for ImageAssetLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let image = image::load_from_memory(bytes)?.to_rgba8();
+            let (width, height) = image.dimensions();
+            if width == 0 || height == 0 {
+                anyhow::bail!("empty image");
+            }
+            load_context.set_default_asset(LoadedAsset::new(ImageAsset { width, height, pixels: image.into_raw() }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["png", "jpg", "jpeg"]
+    }
+}
+
+/// An uploaded image and its set of `TextureCache::set_layout`.
+pub struct CachedTexture {
+    pub texture: ModelTexture,
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+impl CachedTexture {
+    fn destroy(&mut self, context: &RenderContext) {
+        self.texture.destroy(context);
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.descriptor_set]);
+        }
+    }
+}
+
+impl DeferredDestroy for CachedTexture {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+}
+
+/// Images and bitmap fonts of the HUD, billboards, decals and particles, keyed by their
+/// path relative to `assets/`. The asset server reads and decodes them, they are
+/// uploaded with the upload command buffer once loaded and replaced when the file
+/// changes while the asset server watches for changes.
+pub struct TextureCache {
+    /// one combined image sampler at binding 0 for the fragment stage
+    set_layout: vk::DescriptorSetLayout,
+    /// the dummy white texture
+    white_set: vk::DescriptorSet,
+    /// requested images and fonts, the handles keep them loaded
+    images: HashMap<String, Handle<ImageAsset>>,
+    atlases: HashMap<String, Handle<GlyphAtlas>>,
+    textures: HashMap<String, CachedTexture>,
+    fonts: HashMap<String, Font>,
+    /// files that failed to load, not tried again
+    failed: HashSet<String>,
+    /// images whose texture was replaced or dropped by the last `update`
+    replaced: Vec<String>,
+}
+
+impl TextureCache {
+    pub fn destroy(&mut self, context: &RenderContext) {
+        for (_, mut texture) in self.textures.drain() {
+            texture.destroy(context);
+        }
+        unsafe {
+            context.device.free_descriptor_sets(context.descriptor_pool, &[self.white_set]);
+            context.device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+
+    pub fn create(context: &RenderContext) -> RenderResult<Self> {
+        let bindings = Self::set_layout_bindings();
+        let ci = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings).build();
+        let set_layout = unsafe { context.device.create_descriptor_set_layout(&ci, None) }
+            .map_err(RenderError::vk("vkCreateDescriptorSetLayout"))?;
+
+        let white = &context.get_resource::<DummyResources>().white_texture;
+        let white_set = match Self::create_texture_set(context, set_layout, white) {
+            Ok(set) => set,
+            Err(e) => {
+                unsafe { context.device.destroy_descriptor_set_layout(set_layout, None); }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            set_layout,
+            white_set,
+            images: HashMap::new(),
+            atlases: HashMap::new(),
+            textures: HashMap::new(),
+            fonts: HashMap::new(),
+            failed: HashSet::new(),
+            replaced: vec![],
+        })
+    }
+
+    fn create_texture_set(context: &RenderContext, layout: vk::DescriptorSetLayout,
+                          texture: &ModelTexture) -> RenderResult<vk::DescriptorSet> {
+        let layouts = [layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(context.descriptor_pool)
+            .set_layouts(&layouts);
+        let set = unsafe { context.device.allocate_descriptor_sets(&allocate_info) }
+            .map_err(RenderError::vk("vkAllocateDescriptorSets"))?[0];
+        let image_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(texture.view)
+            .sampler(texture.sampler)
+            .build()];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info)
+            .build()];
+        unsafe {
+            context.device.update_descriptor_sets(&writes, &[]);
+        }
+        Ok(set)
+    }
+
+    /// Layout of the texture sets, for the pipelines drawing with them.
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// The bindings of `set_layout`, a sampled image read by the fragment stage.
+    pub fn set_layout_bindings() -> [vk::DescriptorSetLayoutBinding; 1] {
+        [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ]
+    }
+
+    /// Starts loading an image the first time it is asked for, an empty path is white.
+    pub fn request_texture(&mut self, asset_server: &AssetServer, path: &str) {
+        if !path.is_empty() && !self.images.contains_key(path) {
+            self.images.insert(path.to_string(), asset_server.load(path));
+        }
+    }
+
+    /// Starts loading a font and then its image, an empty path loads `DEFAULT_FONT`.
+    pub fn request_font(&mut self, asset_server: &AssetServer, path: &str) {
+        let path = font_path(path);
+        if !self.atlases.contains_key(path) {
+            self.atlases.insert(path.to_string(), asset_server.load(path));
+        }
+    }
+
+    /// `None` until the image is uploaded.
+    pub fn texture(&self, path: &str) -> Option<&CachedTexture> {
+        self.textures.get(path)
+    }
+
+    /// The set of an image, the white one for `None`. `None` until the image is uploaded.
+    pub fn texture_set(&self, path: Option<&str>) -> Option<vk::DescriptorSet> {
+        match path {
+            Some(path) => self.textures.get(path).map(|texture| texture.descriptor_set),
+            None => Some(self.white_set),
+        }
+    }
+
+    /// `None` until the font and its image are loaded, an empty path is `DEFAULT_FONT`.
+    pub(crate) fn font(&self, path: &str) -> Option<&Font> {
+        self.fonts.get(font_path(path))
+    }
+
+    pub fn is_failed(&self, path: &str) -> bool {
+        self.failed.contains(path)
+    }
+
+    /// Images whose texture was replaced or dropped by the last `update`, descriptors
+    /// written with them must be written again.
+    pub fn replaced(&self) -> &[String] {
+        &self.replaced
+    }
+
+    fn retire_texture(&mut self, context: &mut RenderContext, path: &str) {
+        if let Some(texture) = self.textures.remove(path) {
+            context.defer_destroy(&format!("texture {}", path), texture);
+        }
+        // a new image may have another size
+        self.fonts.retain(|_, font| font.atlas.texture != path);
+        self.replaced.push(path.to_string());
+    }
+
+    /// Drops the images and fonts changed on disk, then uploads the requested images and
+    /// builds the fonts whose assets finished loading.
+    pub fn update<'a>(&mut self, context: &mut RenderContext, upload_command_buffer: vk::CommandBuffer,
+                      asset_server: &AssetServer, images: &Assets<ImageAsset>, atlases: &Assets<GlyphAtlas>,
+                      image_events: impl Iterator<Item=&'a AssetEvent<ImageAsset>>,
+                      atlas_events: impl Iterator<Item=&'a AssetEvent<GlyphAtlas>>) {
+        self.replaced.clear();
+        for event in image_events {
+            if let AssetEvent::Modified { handle } | AssetEvent::Removed { handle } = event {
+                let changed = self.images.iter()
+                    .filter(|(_, image)| image == handle)
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                for path in changed {
+                    self.retire_texture(context, &path);
+                }
+            }
+        }
+        for event in atlas_events {
+            if let AssetEvent::Modified { handle } | AssetEvent::Removed { handle } = event {
+                let atlases = &self.atlases;
+                self.fonts.retain(|path, _| atlases.get(path) != Some(handle));
+            }
+        }
+
+        let sampler_desc = SamplerDesc {
+            address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            // single level, anisotropy changes keep the sampler
+            use_mipmaps: false,
+            ..Default::default()
+        };
+        let pending = self.images.iter()
+            .filter(|(path, _)| !self.textures.contains_key(*path) && !self.failed.contains(*path))
+            .map(|(path, handle)| (path.clone(), handle.clone_weak()))
+            .collect::<Vec<_>>();
+        for (path, handle) in pending {
+            let image = match images.get(&handle) {
+                Some(image) => image,
+                None => {
+                    if asset_server.get_load_state(&handle) == LoadState::Failed {
+                        error!("failed to load texture {}", path);
+                        self.failed.insert(path);
+                    }
+                    continue;
+                }
+            };
+
+            let texture = match ModelTexture::from_rgba(context, upload_command_buffer, image.width, image.height,
+                                                        &image.pixels, &sampler_desc) {
+                Ok(texture) => texture,
+                Err(e) => {
+                    error!("failed to upload texture {}: {}", path, e);
+                    self.failed.insert(path);
+                    continue;
+                }
+            };
+            let descriptor_set = match Self::create_texture_set(context, self.set_layout, &texture) {
+                Ok(set) => set,
+                Err(e) => {
+                    error!("failed to create the texture set of {}: {}", path, e);
+                    // the upload recorded this frame still reads it
+                    context.defer_destroy("texture without set", ModelTextures { textures: vec![texture] });
+                    self.failed.insert(path);
+                    continue;
+                }
+            };
+            self.textures.insert(path, CachedTexture { texture, descriptor_set });
+        }
+
+        let pending = self.atlases.iter()
+            .filter(|(path, _)| !self.fonts.contains_key(*path) && !self.failed.contains(*path))
+            .map(|(path, handle)| (path.clone(), handle.clone_weak()))
+            .collect::<Vec<_>>();
+        for (path, handle) in pending {
+            let atlas = match atlases.get(&handle) {
+                Some(atlas) => atlas,
+                None => {
+                    if asset_server.get_load_state(&handle) == LoadState::Failed {
+                        error!("failed to load font {}", path);
+                        self.failed.insert(path);
+                    }
+                    continue;
+                }
+            };
+
+            // built on a later update once the image is uploaded
+            self.request_texture(asset_server, &atlas.texture);
+            if let Some(texture) = self.textures.get(&atlas.texture) {
+                let font = Font::new(atlas.clone(), texture.texture.texture.get_size());
+                self.fonts.insert(path, font);
+            } else if self.failed.contains(&atlas.texture) {
+                self.failed.insert(path);
+            }
+        }
+    }
+}
+
+/// Uploads the images and fonts requested by the previous systems once the asset server
+/// loaded them, and replaces the ones changed on disk.
+pub fn update_texture_cache_system(mut runner: Option<ResMut<RenderRunner>>,
+                                   asset_server: Res<AssetServer>,
+                                   images: Res<Assets<ImageAsset>>,
+                                   atlases: Res<Assets<GlyphAtlas>>,
+                                   mut image_events: EventReader<AssetEvent<ImageAsset>>,
+                                   mut atlas_events: EventReader<AssetEvent<GlyphAtlas>>) {
+    if let Some(runner) = &mut runner {
+        let runner: &mut RenderRunner = runner.deref_mut();
+        let command_buffer = runner.get_upload_command_buffer();
+        runner.texture_cache.update(&mut runner.context, command_buffer, &asset_server, &images, &atlases,
+                                    image_events.iter(), atlas_events.iter());
+    }
+}
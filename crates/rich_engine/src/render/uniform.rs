@@ -2,6 +2,7 @@ use crate::render::render_context::{RenderContext, RenderResource};
 use ash::vk;
 use crate::render::buffer::Buffer;
 use std::any::Any;
+use crate::render::deletion_queue::DeferredDestroy;
 
 #[derive(Default)]
 pub struct UniformObject<T> {
@@ -25,6 +26,16 @@ impl<T: 'static + Send + Sync + Copy> RenderResource for UniformObject<T> {
     }
 }
 
+impl<T: 'static + Send + Sync + Copy> DeferredDestroy for UniformObject<T> {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        self.buffer.debug_objects()
+    }
+}
+
 impl<T> UniformObject<T> where T: Copy {
     pub fn destroy(&mut self, context: &RenderContext) {
         unsafe {
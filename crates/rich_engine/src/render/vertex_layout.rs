@@ -28,54 +28,87 @@ impl VertexLayout {
         }
     }
 
-    pub fn gltf_data_type_2_vk_format(data_type: DataType, data_count: u32) -> vk::Format {
-        match (data_type, data_count) {
+    /// Map a gltf accessor to the vulkan vertex format.
+    ///
+    /// `normalized` integers become UNORM/SNORM, integer attributes read by the
+    /// shader as floats (quantized positions, uvs...) become USCALED/SSCALED and
+    /// `integer` attributes (joints) keep the UINT/SINT formats.
+    pub fn gltf_data_type_2_vk_format(data_type: DataType, data_count: u32, normalized: bool, integer: bool) -> vk::Format {
+        const F32: [vk::Format; 4] = [vk::Format::R32_SFLOAT, vk::Format::R32G32_SFLOAT, vk::Format::R32G32B32_SFLOAT, vk::Format::R32G32B32A32_SFLOAT];
+
+        const U8_UINT: [vk::Format; 4] = [vk::Format::R8_UINT, vk::Format::R8G8_UINT, vk::Format::R8G8B8_UINT, vk::Format::R8G8B8A8_UINT];
+        const U8_UNORM: [vk::Format; 4] = [vk::Format::R8_UNORM, vk::Format::R8G8_UNORM, vk::Format::R8G8B8_UNORM, vk::Format::R8G8B8A8_UNORM];
+        const U8_USCALED: [vk::Format; 4] = [vk::Format::R8_USCALED, vk::Format::R8G8_USCALED, vk::Format::R8G8B8_USCALED, vk::Format::R8G8B8A8_USCALED];
+
+        const I8_SINT: [vk::Format; 4] = [vk::Format::R8_SINT, vk::Format::R8G8_SINT, vk::Format::R8G8B8_SINT, vk::Format::R8G8B8A8_SINT];
+        const I8_SNORM: [vk::Format; 4] = [vk::Format::R8_SNORM, vk::Format::R8G8_SNORM, vk::Format::R8G8B8_SNORM, vk::Format::R8G8B8A8_SNORM];
+        const I8_SSCALED: [vk::Format; 4] = [vk::Format::R8_SSCALED, vk::Format::R8G8_SSCALED, vk::Format::R8G8B8_SSCALED, vk::Format::R8G8B8A8_SSCALED];
+
+        const U16_UINT: [vk::Format; 4] = [vk::Format::R16_UINT, vk::Format::R16G16_UINT, vk::Format::R16G16B16_UINT, vk::Format::R16G16B16A16_UINT];
+        const U16_UNORM: [vk::Format; 4] = [vk::Format::R16_UNORM, vk::Format::R16G16_UNORM, vk::Format::R16G16B16_UNORM, vk::Format::R16G16B16A16_UNORM];
+        const U16_USCALED: [vk::Format; 4] = [vk::Format::R16_USCALED, vk::Format::R16G16_USCALED, vk::Format::R16G16B16_USCALED, vk::Format::R16G16B16A16_USCALED];
+
+        const I16_SINT: [vk::Format; 4] = [vk::Format::R16_SINT, vk::Format::R16G16_SINT, vk::Format::R16G16B16_SINT, vk::Format::R16G16B16A16_SINT];
+        const I16_SNORM: [vk::Format; 4] = [vk::Format::R16_SNORM, vk::Format::R16G16_SNORM, vk::Format::R16G16B16_SNORM, vk::Format::R16G16B16A16_SNORM];
+        const I16_SSCALED: [vk::Format; 4] = [vk::Format::R16_SSCALED, vk::Format::R16G16_SSCALED, vk::Format::R16G16B16_SSCALED, vk::Format::R16G16B16A16_SSCALED];
+
+        const U32_UINT: [vk::Format; 4] = [vk::Format::R32_UINT, vk::Format::R32G32_UINT, vk::Format::R32G32B32_UINT, vk::Format::R32G32B32A32_UINT];
+
+        if data_count == 0 || data_count > 4 {
+            panic!("unsupported gltf data type {:?} {}", data_type, data_count);
+        }
 
-            //indices
-            (DataType::U8, 1) => vk::Format::R8_UINT,
-            (DataType::U16, 1) => vk::Format::R16_UINT,
-            (DataType::U32, 1) => vk::Format::R32_UINT,
+        let formats = match (data_type, normalized, integer) {
+            (DataType::F32, _, _) => &F32,
 
-            //tex_coord
-            (DataType::U8, 2) => vk::Format::R8G8_UINT,
-            (DataType::U32, 2) => vk::Format::R32G32_UINT,
-            (DataType::F32, 2) => vk::Format::R32G32_SFLOAT,
+            (DataType::U8, _, true) => &U8_UINT,
+            (DataType::U8, true, false) => &U8_UNORM,
+            (DataType::U8, false, false) => &U8_USCALED,
 
-            //position 
-            (DataType::F32, 3) => vk::Format::R32G32B32_SFLOAT,
+            (DataType::I8, _, true) => &I8_SINT,
+            (DataType::I8, true, false) => &I8_SNORM,
+            (DataType::I8, false, false) => &I8_SSCALED,
 
-            //weights
-            (DataType::F32, 4) => vk::Format::R32G32B32A32_SFLOAT,
+            (DataType::U16, _, true) => &U16_UINT,
+            (DataType::U16, true, false) => &U16_UNORM,
+            (DataType::U16, false, false) => &U16_USCALED,
 
-            //joints
-            (DataType::U8, 4) => vk::Format::R8G8B8A8_UINT,
-            (DataType::U16, 4) => vk::Format::R16G16B16A16_UINT,
-            (DataType::U32, 4) => vk::Format::R32G32B32A32_UINT,
+            (DataType::I16, _, true) => &I16_SINT,
+            (DataType::I16, true, false) => &I16_SNORM,
+            (DataType::I16, false, false) => &I16_SSCALED,
 
-            _ => { panic!("unsupported gltf data type {:?} {}", data_type, data_count) }
-        }
+            // glTF never normalizes u32, there is no 32-bit scaled format either
+            (DataType::U32, _, _) => &U32_UINT,
+        };
+
+        formats[(data_count - 1) as usize]
     }
 
-    pub fn vk_format_size(format: vk::Format) -> u32 {
-        match format {
-            vk::Format::R8_UINT => 1,
-            vk::Format::R16_UINT => 2,
-            vk::Format::R32_UINT => 4,
-            vk::Format::R8G8_UINT => 2,
-            vk::Format::R32G32_UINT => 8,
-            vk::Format::R32G32_SFLOAT => 8,
-            vk::Format::R32G32B32_SFLOAT => 12,
-            vk::Format::R32G32B32A32_SFLOAT => 16,
-            vk::Format::R8G8B8A8_UINT => 4,
-            vk::Format::R32G32B32A32_UINT => 16,
-            vk::Format::R16G16B16A16_UINT => 8,
-            _ => { panic!("unsupported vk format {:?}", format) }
+    /// Reads one little endian component the way a shader reads the format chosen by
+    /// `gltf_data_type_2_vk_format` for a non `integer` attribute.
+    pub fn read_float_component(bytes: &[u8], data_type: DataType, normalized: bool) -> f32 {
+        let value = match data_type {
+            DataType::F32 => return f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            DataType::U8 => bytes[0] as f32,
+            DataType::I8 => bytes[0] as i8 as f32,
+            DataType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+            DataType::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+            DataType::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32,
+        };
+        if !normalized {
+            return value;
+        }
+        match data_type {
+            DataType::U8 => value / 255.0,
+            DataType::I8 => (value / 127.0).max(-1.0),
+            DataType::U16 => value / 65535.0,
+            DataType::I16 => (value / 32767.0).max(-1.0),
+            _ => value,
         }
     }
 
     pub fn calculate_stride(data_type: DataType, element_count: u32) -> u32 {
-        let format = Self::gltf_data_type_2_vk_format(data_type, element_count);
-        return Self::vk_format_size(format);
+        (data_type.size() as u32) * element_count
     }
 
     pub fn create() -> Self {
@@ -93,9 +126,10 @@ impl VertexLayout {
         self.indices_type = Self::gltf_data_type_vk_index(data_type);
     }
 
-    pub fn push_meta(&mut self, data_type: DataType, element_count: u32, offset_in_buffer: usize, location: u32, size: u32) {
-        let format = Self::gltf_data_type_2_vk_format(data_type, element_count);
-        //let size = Self::vk_format_size(format);
+    pub fn push_meta(&mut self, data_type: DataType, element_count: u32, normalized: bool,
+                     offset_in_buffer: usize, location: u32, size: u32) {
+        let integer = shader_const::is_integer_location(location);
+        let format = Self::gltf_data_type_2_vk_format(data_type, element_count, normalized, integer);
         self.metas.push(VertexMeta {
             format,
             size,
@@ -108,6 +142,10 @@ impl VertexLayout {
         self.buffers_ref_offsets = self.offsets.iter().map(|offset| *offset as vk::DeviceSize).collect::<Vec<_>>();
     }
 
+    pub fn has_location(&self, location: u32) -> bool {
+        self.metas.iter().any(|meta| meta.location == location)
+    }
+
     pub fn build_vk_bindings(&self) -> Vec<vk::VertexInputBindingDescription> {
         self.metas.iter().enumerate().map(|(index, meta)| {
             vk::VertexInputBindingDescription::builder()
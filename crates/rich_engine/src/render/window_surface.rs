@@ -0,0 +1,269 @@
+use ash::vk;
+use bevy::prelude::*;
+use crate::render::command_buffer_list::CommandBufferList;
+use crate::render::forward_render::{ForwardRenderPass, SceneTarget};
+use crate::render::render_context::RenderContext;
+use crate::render::swapchain_mgr::SwapChainMgr;
+use crate::render::render_error::{RenderError, RenderResult};
+use crate::render::deletion_queue::DeferredDestroy;
+
+/// Copies the scene image to the acquired swapchain image and leaves it ready to present.
+/// The scene image must be in `COLOR_ATTACHMENT_OPTIMAL`.
+pub fn cmd_blit_to_present(context: &RenderContext, command_buffer: vk::CommandBuffer,
+                           src_image: vk::Image, src_extent: vk::Extent2D,
+                           present_image: vk::Image, present_extent: vk::Extent2D) {
+    let subresource_range = vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+
+    {
+        let image_barriers = [
+            vk::ImageMemoryBarrier::builder().image(src_image)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .subresource_range(subresource_range).build(),
+            vk::ImageMemoryBarrier::builder().image(present_image)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .subresource_range(subresource_range).build(),
+        ];
+
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
+                                                vk::PipelineStageFlags::ALL_COMMANDS,
+                                                vk::DependencyFlags::empty(), &[], &[],
+                                                &image_barriers);
+        }
+    }
+
+    // the scene is rendered at the render scale, blit scales it to the window
+    unsafe {
+        context.device.cmd_blit_image(command_buffer,
+                                      src_image,
+                                      vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                      present_image,
+                                      vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                      &[vk::ImageBlit {
+                                          src_subresource: vk::ImageSubresourceLayers {
+                                              aspect_mask: vk::ImageAspectFlags::COLOR,
+                                              layer_count: 1,
+                                              ..Default::default()
+                                          },
+                                          dst_subresource: vk::ImageSubresourceLayers {
+                                              aspect_mask: vk::ImageAspectFlags::COLOR,
+                                              layer_count: 1,
+                                              ..Default::default()
+                                          },
+                                          src_offsets: [
+                                              vk::Offset3D::default(),
+                                              vk::Offset3D {
+                                                  x: src_extent.width as _,
+                                                  y: src_extent.height as _,
+                                                  z: 1,
+                                              },
+                                          ],
+                                          dst_offsets: [
+                                              vk::Offset3D::default(),
+                                              vk::Offset3D {
+                                                  x: present_extent.width as _,
+                                                  y: present_extent.height as _,
+                                                  z: 1,
+                                              },
+                                          ],
+                                      }],
+                                      vk::Filter::LINEAR);
+    }
+
+    {
+        let image_barriers = [
+            vk::ImageMemoryBarrier::builder().image(present_image)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE).dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .subresource_range(subresource_range).build(),
+        ];
+
+        unsafe {
+            context.device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::ALL_COMMANDS,
+                                                vk::PipelineStageFlags::ALL_COMMANDS,
+                                                vk::DependencyFlags::empty(),
+                                                &[],
+                                                &[],
+                                                &image_barriers);
+        }
+    }
+}
+
+/// Surface, swapchain and scene target of a window other than the primary one. The
+/// primary window uses the context surface and the forward pass target.
+///
+/// The scene is drawn into `target` by the main command buffer, then the window
+/// submits its own blit after the main submission so it has its own fences.
+pub struct WindowSurface {
+    surface: vk::SurfaceKHR,
+    swapchain_mgr: SwapChainMgr,
+    command_buffer_list: CommandBufferList,
+    target: SceneTarget,
+    present_index: Option<usize>,
+    width: u32,
+    height: u32,
+}
+
+impl WindowSurface {
+    pub fn create<W: raw_window_handle::HasRawWindowHandle>(context: &RenderContext, forward: &ForwardRenderPass,
+                                                            window: &W, width: u32, height: u32) -> Option<Self> {
+        let surface = match unsafe { ash_window::create_surface(&context.entry, &context.instance, window, None) } {
+            Ok(surface) => surface,
+            Err(e) => {
+                warn!("failed to create window surface: {}", e);
+                return None;
+            }
+        };
+
+        let supported = unsafe {
+            context.surface_loader.get_physical_device_surface_support(context.physical_device,
+                                                                      context.present_queue_family_index, surface)
+        }.unwrap_or(false);
+        if !supported {
+            warn!("the present queue can not present to the window, it is not rendered");
+            unsafe { context.surface_loader.destroy_surface(surface, None); }
+            return None;
+        }
+
+        let mut swapchain_mgr = match unsafe { SwapChainMgr::create_with_surface(context, surface, width, height) } {
+            Ok(swapchain_mgr) => swapchain_mgr,
+            Err(e) => {
+                error!("failed to create window swapchain: {}", e);
+                unsafe { context.surface_loader.destroy_surface(surface, None); }
+                return None;
+            }
+        };
+        let target = match forward.create_target(context, context.scaled_extent(width, height), "window_target") {
+            Ok(target) => target,
+            Err(e) => {
+                error!("failed to create window target: {}", e);
+                swapchain_mgr.destroy(context);
+                unsafe { context.surface_loader.destroy_surface(surface, None); }
+                return None;
+            }
+        };
+        let command_buffer_list = CommandBufferList::create(swapchain_mgr.get_present_image_count(), context);
+
+        Some(Self {
+            surface,
+            swapchain_mgr,
+            command_buffer_list,
+            target,
+            present_index: None,
+            width,
+            height,
+        })
+    }
+
+    pub fn destroy(&mut self, context: &RenderContext) {
+        self.target.destroy(context);
+        self.command_buffer_list.destroy(context);
+        self.swapchain_mgr.destroy(context);
+        unsafe { context.surface_loader.destroy_surface(self.surface, None); }
+    }
+
+    pub fn get_target(&self) -> &SceneTarget {
+        &self.target
+    }
+
+    /// Rebuilds the swapchain and the target, the device must be idle.
+    pub fn recreate(&mut self, context: &RenderContext, forward: &ForwardRenderPass, width: u32, height: u32) -> RenderResult<()> {
+        self.width = width;
+        self.height = height;
+        self.recreate_swapchain(context)?;
+        self.recreate_target(context, forward)
+    }
+
+    pub fn recreate_swapchain(&mut self, context: &RenderContext) -> RenderResult<()> {
+        let image_count = self.swapchain_mgr.get_present_image_count();
+        self.swapchain_mgr.destroy(context);
+        self.swapchain_mgr = unsafe { SwapChainMgr::create_with_surface(context, self.surface, self.width, self.height)? };
+        if self.swapchain_mgr.get_present_image_count() != image_count {
+            self.command_buffer_list.destroy(context);
+            self.command_buffer_list = CommandBufferList::create(self.swapchain_mgr.get_present_image_count(), context);
+        }
+        Ok(())
+    }
+
+    /// The target belongs to the forward render pass, recreate it with the pass.
+    pub fn recreate_target(&mut self, context: &RenderContext, forward: &ForwardRenderPass) -> RenderResult<()> {
+        self.target.destroy(context);
+        self.target = forward.create_target(context, context.scaled_extent(self.width, self.height), "window_target")?;
+        Ok(())
+    }
+
+    /// Acquires the next image, the window is skipped this frame when it is out of date.
+    pub fn acquire(&mut self, context: &RenderContext) -> RenderResult<bool> {
+        self.present_index = self.swapchain_mgr.wait_for_swap_chain(context)?;
+        Ok(self.present_index.is_some())
+    }
+
+    pub fn is_acquired(&self) -> bool {
+        self.present_index.is_some()
+    }
+
+    /// Blits the target to the acquired image and presents it, every acquired image
+    /// must be submitted or the next acquire waits forever on its fence.
+    pub fn submit_and_present(&mut self, context: &RenderContext) -> RenderResult<()> {
+        let present_index = match self.present_index.take() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        let mut image_available_semaphore = vk::Semaphore::null();
+        let mut render_finish_semaphore = vk::Semaphore::null();
+        let mut cmd_buf_execute_fence = vk::Fence::null();
+        self.swapchain_mgr.get_semaphores(&mut image_available_semaphore,
+                                          &mut render_finish_semaphore, &mut cmd_buf_execute_fence);
+
+        let command_buffer = self.command_buffer_list.get_command_buffer(present_index);
+        unsafe {
+            context.device.begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT).build())
+                .map_err(RenderError::vk("vkBeginCommandBuffer"))?;
+        }
+
+        // the barriers order the blit after the scene pass of the previous submission
+        cmd_blit_to_present(context, command_buffer,
+                            self.target.get_final_render_image(), self.target.get_extent(),
+                            self.swapchain_mgr.get_current_present_image(), self.swapchain_mgr.surface_resolution);
+
+        let command_buffers = [command_buffer];
+        let wait_semaphores = [image_available_semaphore];
+        let wait_stages = [vk::PipelineStageFlags::TRANSFER];
+        let signal_semaphores = [render_finish_semaphore];
+        let submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores).wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers).signal_semaphores(&signal_semaphores).build();
+
+        unsafe {
+            context.device.end_command_buffer(command_buffer).map_err(RenderError::vk("vkEndCommandBuffer"))?;
+            context.device.queue_submit(context.graphics_queue, &[submit_info], cmd_buf_execute_fence)
+                .map_err(RenderError::vk("vkQueueSubmit"))?;
+        }
+
+        self.swapchain_mgr.present(context)
+    }
+}
+
+impl DeferredDestroy for WindowSurface {
+    fn destroy_deferred(&mut self, context: &mut RenderContext) {
+        self.destroy(context);
+    }
+
+    fn debug_objects(&self) -> Vec<(vk::ObjectType, u64)> {
+        self.target.debug_objects()
+    }
+}
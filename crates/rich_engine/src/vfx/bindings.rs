@@ -214,6 +214,9 @@ extern "C" {
 extern "C" {
     pub fn Shutdown();
 }
+extern "C" {
+    pub fn AbandonContext();
+}
 extern "C" {
     pub fn StartupWithExternalVulkan(
         vk_device: u64,
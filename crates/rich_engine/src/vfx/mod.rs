@@ -23,6 +23,7 @@ pub use vfx_resource::VfxReq;
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
 enum VfxSystemLabel {
     Init,
+    Restart,
     Update,
     CreateEntity,
     PlayEffect,
@@ -66,6 +67,11 @@ impl Plugin for VfxPlugin {
                                     .after(TransformSystem::TransformPropagate),
         );
 
+        app.add_system_to_stage(
+            RenderStage::PostDraw,
+            restart_vfx_system.system().label(VfxSystemLabel::Restart).before(VfxSystemLabel::Update),
+        );
+
         app.add_system_to_stage(
             RenderStage::PostDraw,
             draw_vfx_system.system().label(VfxSystemLabel::Update),
@@ -49,6 +49,11 @@ impl VfxSystemState {
         self.map.insert(handle.clone_weak(), prefab);
     }
 
+    /// Forgets all prefabs without releasing them, used after the runtime was restarted.
+    pub(super) fn take_prefab_handles(&mut self) -> Vec<Handle<VfxAsset>> {
+        self.map.drain().map(|(handle, _)| handle).collect()
+    }
+
     pub(super) fn set_inited(&mut self) {
         self.inited = true;
     }
@@ -79,6 +84,27 @@ impl AssetLoader for VfxAssetLoader {
     }
 }
 
+pub(super) fn load_prefab(v: &VfxAsset) -> VfxPrefab {
+    use crate::vfx::bindings::*;
+
+    info!("start parse vfx");
+    let mut p = v.path.parent().unwrap();
+    let dir = p.to_str().unwrap();
+    let mut dir_utf16: Vec<u16> = dir.encode_utf16().collect();
+    let c = '\0' as u16;
+    dir_utf16.push(c);
+
+    let mut info = EffectInfo { duration: 0, prefabId: 0 };
+    unsafe {
+        let ptr = &mut info as *mut EffectInfo;
+        LoadEffectPrefab(v.bytes.as_ptr() as *const c_void, v.bytes.len() as c_int, dir_utf16.as_ptr() as _, ptr);
+    };
+
+    let prefab = VfxPrefab { id: info.prefabId, duration: info.duration, is_loop: info.duration == i32::MAX };
+    info!("parse vfx complete {}:{}f loop:{}", prefab.id, prefab.duration, prefab.is_loop);
+    prefab
+}
+
 pub(super) fn load_vfx_2_device_system(mut vfx_asset_events: EventReader<AssetEvent<VfxAsset>>,
                                        mut assets: Res<Assets<VfxAsset>>,
                                        mut state: ResMut<VfxSystemState>) {
@@ -111,30 +137,12 @@ pub(super) fn load_vfx_2_device_system(mut vfx_asset_events: EventReader<AssetEv
 
         for handle in &changed_set {
             if let Some(v) = assets.get(handle) {
-                use crate::vfx::bindings::*;
-
-                info!("start parse vfx");
-                let mut p = v.path.parent().unwrap();
-                let dir = p.to_str().unwrap();
-                let mut dir_utf16: Vec<u16> = dir.encode_utf16().collect();
-                let c = '\0' as u16;
-                dir_utf16.push(c);
-
-                let mut info = EffectInfo { duration: 0, prefabId: 0 };
-                unsafe {
-                    let ptr = &mut info as *mut EffectInfo;
-                    LoadEffectPrefab(v.bytes.as_ptr() as *const c_void, v.bytes.len() as c_int, dir_utf16.as_ptr() as _, ptr);
-                };
-
-                let prefab = VfxPrefab { id: info.prefabId, duration: info.duration, is_loop: info.duration == i32::MAX };
-                info!("parse vfx complete {}:{}f loop:{}", prefab.id, prefab.duration, prefab.is_loop);
-                state.insert_prefab(handle, prefab);
+                state.insert_prefab(handle, load_prefab(v));
             }
         }
     }
 }
 
-
 pub struct VfxReq
 {
     pub path: &'static str,
@@ -3,8 +3,8 @@ use std::ops::Deref;
 use std::sync::{Arc, Mutex, MutexGuard};
 use bevy::ecs::schedule::ShouldRun::No;
 
-use crate::{RenderCamera, RenderRunner};
-use crate::vfx::vfx_resource::{VfxAsset, VfxReq, VfxSystemState};
+use crate::{RenderCamera, RenderRunner, RenderInitEvent, RenderTargetsRecreatedEvent};
+use crate::vfx::vfx_resource::{VfxAsset, VfxReq, VfxSystemState, load_prefab};
 use crate::vfx::bindings::*;
 use crate::prelude::*;
 
@@ -86,6 +86,47 @@ pub(super) fn init_vfx_system(mut state: ResMut<VfxSystemState>, render_runner:
     }
 }
 
+/// Effekseer holds the shared color/depth targets and the command pool, restart it when
+/// they are recreated. Prefabs are reloaded and playing effects start over.
+pub(super) fn restart_vfx_system(mut state: ResMut<VfxSystemState>,
+                                 render_runner: Option<Res<RenderRunner>>,
+                                 assets: Res<Assets<VfxAsset>>,
+                                 mut init_events: EventReader<RenderInitEvent>,
+                                 mut recreated_events: EventReader<RenderTargetsRecreatedEvent>,
+                                 query: Query<Entity, With<VfxHasPlay>>,
+                                 mut commands: Commands) {
+    // a device recovered from loss sends both events, the old effekseer objects belong to
+    // the destroyed device then
+    let device_recreated = init_events.iter().count() > 0;
+    if recreated_events.iter().count() == 0 || !state.is_inited() {
+        return;
+    }
+
+    if let Some(render_runner) = render_runner {
+        if device_recreated {
+            unsafe { AbandonContext(); }
+        } else {
+            // effekseer frees its vulkan objects right away, they can't go through the deletion queue
+            if let Err(e) = unsafe { render_runner.context.device.device_wait_idle() } {
+                error!("vfx restart wait idle failed: {}", e);
+            }
+            unsafe { Shutdown(); }
+        }
+        startup_vfx_system(render_runner.deref());
+
+        for handle in state.take_prefab_handles() {
+            if let Some(v) = assets.get(&handle) {
+                state.insert_prefab(&handle, load_prefab(v));
+            }
+        }
+
+        for entity in query.iter() {
+            commands.entity(entity).remove::<VfxHasPlay>();
+        }
+        info!("vfx restarted for new render targets");
+    }
+}
+
 fn matrix_convert(value: &Mat4) -> super::bindings::Matrix {
     super::bindings::Matrix { Values: value.to_cols_array_2d() }
 }
@@ -104,7 +145,8 @@ pub(super) fn draw_vfx_system(mut state: ResMut<VfxSystemState>,
         let mut render_runner = render_runner.unwrap();
         let context = &render_runner.context;
         let data = context.per_frame_uniform.as_ref().unwrap();
-        let proj = matrix_convert(&data.data.proj);
+        // effects are drawn after the temporal resolve, the jitter would make them shake
+        let proj = matrix_convert(&data.data.unjittered_proj);
         let view = matrix_convert(&data.data.view);
         super::bindings::SyncProjectionMatrix(proj);
         super::bindings::SyncViewMatrix(view);
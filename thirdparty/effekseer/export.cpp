@@ -116,6 +116,16 @@ void Shutdown() {
     context = nullptr;
 }
 
+void AbandonContext() {
+    // the device the context was created on is already destroyed, releasing its
+    // objects would call into it, so the context is leaked on purpose
+    if (context) {
+        new std::shared_ptr<ContextLLGI>(std::move(context));
+    }
+
+    context = nullptr;
+}
+
 
 void UpdateFrame(void *vRenderPass, uint64_t externalCommandBufferHandle) {
 
@@ -37,6 +37,8 @@ __declspec( dllexport ) void UpdateFrame(void *renderPass, uint64_t externalComm
 
 __declspec( dllexport ) void Shutdown();
 
+__declspec( dllexport ) void AbandonContext();
+
 __declspec( dllexport ) uint64_t
 StartupWithExternalVulkan(uint64_t vk_device, uint64_t vk_phy_device,
                           uint64_t vk_queue, uint64_t